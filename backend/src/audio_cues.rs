@@ -0,0 +1,160 @@
+//! Sound cues played on workout transitions. Sound is played only if the crate is built
+//! with the "audio" feature, otherwise cues are just logged.
+use std::time::Duration;
+
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use crate::{workout_state::WorkoutState, WorkoutEvent};
+
+/// How long before the end of the step, or interval part, rider is warned
+const GET_READY_BEFORE: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cue {
+    /// Step, or interval part is about to end
+    GetReady,
+    /// Next step, or interval part has just started
+    Go,
+}
+
+impl Cue {
+    /// Frequency in Hz and length of the tone
+    fn tone(self) -> (f32, Duration) {
+        match self {
+            Cue::GetReady => (660.0, Duration::from_millis(150)),
+            Cue::Go => (1320.0, Duration::from_millis(600)),
+        }
+    }
+}
+
+/// Cue played at the moment of the transition
+pub fn event_cue(event: &WorkoutEvent) -> Option<Cue> {
+    match event {
+        WorkoutEvent::StepStarted { .. } | WorkoutEvent::IntervalFlipped { .. } => Some(Cue::Go),
+        _ => None,
+    }
+}
+
+/// Cue played ahead of the transition, once current step, or interval part is about to end
+pub fn state_cue(state: &WorkoutState) -> Option<Cue> {
+    if state.paused || state.manual {
+        return None;
+    }
+
+    let left = state.segment_remaining();
+
+    (!left.is_zero() && left <= GET_READY_BEFORE).then_some(Cue::GetReady)
+}
+
+/// Plays cues, until the workout is done
+pub async fn play_cues(
+    mut events_rx: Receiver<WorkoutEvent>,
+    mut state_rx: Receiver<WorkoutState>,
+) {
+    // Warn once per step, or interval part
+    let mut warned = None;
+
+    loop {
+        let cue = tokio::select! {
+            event = events_rx.recv() => match event {
+                Ok(event) => event_cue(&event),
+                Err(RecvError::Lagged(_)) => None,
+                Err(RecvError::Closed) => break,
+            },
+            state = state_rx.recv() => match state {
+                Ok(state) => {
+                    let segment = (
+                        state.current_step_number,
+                        state
+                            .current_interval
+                            .as_ref()
+                            .map(|interval| (interval.repetition, interval.is_work_interval)),
+                    );
+
+                    match state_cue(&state) {
+                        Some(cue) if warned != Some(segment) => {
+                            warned = Some(segment);
+                            Some(cue)
+                        }
+                        _ => None,
+                    }
+                }
+                Err(RecvError::Lagged(_)) => None,
+                // Workout is done
+                Err(RecvError::Closed) => break,
+            },
+        };
+
+        if let Some(cue) = cue {
+            tokio::task::spawn_blocking(move || play(cue));
+        }
+    }
+
+    debug!("Audio cues leave");
+}
+
+#[cfg(feature = "audio")]
+fn play(cue: Cue) {
+    use rodio::{source::SineWave, OutputStream, Sink, Source};
+
+    let (frequency, duration) = cue.tone();
+
+    // Stream has to outlive the sink
+    let (_stream, handle) = match OutputStream::try_default() {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("Cannot open audio output: {e}");
+            return;
+        }
+    };
+
+    match Sink::try_new(&handle) {
+        Ok(sink) => {
+            sink.append(
+                SineWave::new(frequency)
+                    .take_duration(duration)
+                    .amplify(0.3),
+            );
+            sink.sleep_until_end();
+        }
+        Err(e) => warn!("Cannot play {cue:?} cue: {e}"),
+    }
+}
+
+#[cfg(not(feature = "audio"))]
+fn play(cue: Cue) {
+    let (frequency, duration) = cue.tone();
+    debug!("{cue:?} cue, {frequency}Hz for {duration:?}, built without audio support");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions_are_mapped_to_cues() {
+        assert_eq!(
+            event_cue(&WorkoutEvent::StepStarted { number: 2 }),
+            Some(Cue::Go)
+        );
+        assert_eq!(
+            event_cue(&WorkoutEvent::IntervalFlipped { work: false }),
+            Some(Cue::Go)
+        );
+        assert_eq!(event_cue(&WorkoutEvent::Paused), None);
+        assert_eq!(event_cue(&WorkoutEvent::Completed), None);
+
+        let mut state = WorkoutState::manual(200.0);
+        state.manual = false;
+        state.current_step.duration = Duration::from_secs(60);
+
+        state.current_step.elapsed = Duration::from_secs(50);
+        assert_eq!(state_cue(&state), None);
+
+        state.current_step.elapsed = Duration::from_secs(57);
+        assert_eq!(state_cue(&state), Some(Cue::GetReady));
+
+        state.paused = true;
+        assert_eq!(state_cue(&state), None);
+    }
+}
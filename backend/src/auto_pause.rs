@@ -0,0 +1,129 @@
+//! Pauses the workout when the rider stops pedaling, like head units do
+use std::time::Duration;
+
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::Instant,
+};
+
+use crate::{cli::WorkoutCommands, indoor_bike_data_defs::BikeData};
+
+/// Tracks how long the cadence is 0
+#[derive(Debug)]
+struct AutoPause {
+    /// How long cadence has to be 0, before workout is paused
+    after: Duration,
+    stopped_since: Option<Instant>,
+    paused: bool,
+}
+
+impl AutoPause {
+    fn new(after: Duration) -> Self {
+        Self {
+            after,
+            stopped_since: None,
+            paused: false,
+        }
+    }
+
+    /// Returns the command to send to the workout, if pedaling state changed
+    fn on_cadence(&mut self, cadence: f64, now: Instant) -> Option<WorkoutCommands> {
+        if cadence > 0.0 {
+            self.stopped_since = None;
+
+            if self.paused {
+                self.paused = false;
+                return Some(WorkoutCommands::Resume);
+            }
+
+            return None;
+        }
+
+        let stopped_since = *self.stopped_since.get_or_insert(now);
+
+        if !self.paused && now.saturating_duration_since(stopped_since) >= self.after {
+            self.paused = true;
+            return Some(WorkoutCommands::Pause);
+        }
+
+        None
+    }
+}
+
+/// Sends Pause once cadence is 0 for given time, and Resume once it's back.
+/// Runs until bike data or the workout is gone.
+pub async fn auto_pause(
+    mut bike_rx: broadcast::Receiver<BikeData>,
+    control_workout_tx: mpsc::Sender<WorkoutCommands>,
+    after: Duration,
+) {
+    let mut auto_pause = AutoPause::new(after);
+
+    loop {
+        let bike_data = match bike_rx.recv().await {
+            Ok(bike_data) => bike_data,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                trace!("Auto pause skipped {skipped} bike data samples");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        // Not every notification carries the cadence
+        let cadence = match bike_data.inst_cadence {
+            Some(cadence) => cadence,
+            None => continue,
+        };
+
+        if let Some(command) = auto_pause.on_cadence(cadence, Instant::now()) {
+            info!("Cadence {cadence}, auto {command:?}");
+
+            if control_workout_tx.send(command).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    debug!("Auto pause leaves");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn workout_is_paused_and_resumed_with_cadence() {
+        let (bike_tx, bike_rx) = broadcast::channel(16);
+        let (control_workout_tx, mut control_workout_rx) = mpsc::channel(16);
+
+        tokio::spawn(auto_pause(
+            bike_rx,
+            control_workout_tx,
+            Duration::from_secs(3),
+        ));
+
+        // One sample per second, rider stops for 5 seconds
+        let trace = [90.0, 85.0, 0.0, 0.0, 0.0, 0.0, 0.0, 80.0, 90.0];
+        let mut commands = vec![];
+
+        for (second, cadence) in trace.iter().enumerate() {
+            bike_tx
+                .send(BikeData {
+                    inst_cadence: Some(*cadence),
+                    ..Default::default()
+                })
+                .unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+
+            while let Ok(command) = control_workout_rx.try_recv() {
+                commands.push((second, command));
+            }
+        }
+
+        // Paused 3 seconds after cadence dropped, resumed on the first stroke
+        assert_eq!(
+            commands,
+            vec![(5, WorkoutCommands::Pause), (7, WorkoutCommands::Resume)]
+        );
+    }
+}
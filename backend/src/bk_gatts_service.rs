@@ -1,12 +1,14 @@
 #![allow(dead_code)]
 
+use std::fmt;
 use std::str::from_utf8;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use btleplug::api::bleuuid::uuid_from_u16;
-use btleplug::api::{Characteristic, Peripheral as _, WriteType};
+use btleplug::api::{Characteristic, Peripheral as _, ValueNotification, WriteType};
 use btleplug::platform::Peripheral;
-use futures::StreamExt;
+use futures::{Stream, StreamExt};
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
@@ -16,6 +18,9 @@ const _SERVICE_UUID: Uuid = uuid_from_u16(0x00FF);
 const FILE_TRANS_UUID: Uuid = uuid_from_u16(0xFF01);
 const FILE_LIST_UUID: Uuid = uuid_from_u16(0xFF02);
 
+/// How long to wait for the next chunk of the file before giving up
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug)]
 pub struct BkClient {
     pub client: Peripheral,
@@ -26,8 +31,44 @@ pub struct FileInfo {
     id: usize,
     filename: String,
     size: usize,
+    /// CRC32 of the file content, present only if device reports it in the listing
+    crc: Option<u32>,
+}
+
+/// Reasons why downloaded file cannot be trusted
+#[derive(Debug, PartialEq)]
+pub enum FetchError {
+    /// Device stopped sending chunks before whole file was received
+    TimedOut { received: usize, expected: usize },
+    /// Device sent less (stream closed) or more data than announced in the listing
+    SizeMismatch { received: usize, expected: usize },
+    /// Content does not match the CRC from the listing
+    ChecksumMismatch { received: u32, expected: u32 },
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::TimedOut { received, expected } => write!(
+                f,
+                "timed out waiting for file chunk, got {received} of {expected} bytes"
+            ),
+            FetchError::SizeMismatch { received, expected } => {
+                write!(
+                    f,
+                    "file size mismatch, got {received} expected {expected} bytes"
+                )
+            }
+            FetchError::ChecksumMismatch { received, expected } => write!(
+                f,
+                "file checksum mismatch, got {received:#010x} expected {expected:#010x}"
+            ),
+        }
+    }
 }
 
+impl std::error::Error for FetchError {}
+
 impl BkClient {
     pub async fn list_bc_files(&self) -> Result<Vec<FileInfo>> {
         debug!("services listing");
@@ -37,31 +78,7 @@ impl BkClient {
         let response = from_utf8(&raw_response)?;
         info!("Got response {response}");
 
-        // Response is in somewhat CSV format
-        // filename1, size
-        // filename2, size
-
-        let mut files = vec![];
-
-        for (idx, line) in response.lines().enumerate() {
-            let split: Vec<&str> = line.split_terminator(", ").collect();
-
-            debug!("Got split {split:?}");
-
-            match *split.as_slice() {
-                [filename, size] => files.push(FileInfo {
-                    id: idx,
-                    filename: filename.to_string(),
-                    size: size.parse()?,
-                }),
-
-                _ => {
-                    return Err(anyhow!("Invalid split {split:?}"));
-                }
-            };
-        }
-
-        Ok(files)
+        parse_file_list(response)
     }
 
     pub async fn fetch_file(&self, file: &FileInfo) -> Result<()> {
@@ -82,26 +99,15 @@ impl BkClient {
 
         let mut notifications = self.client.notifications().await?;
 
-        let mut downloaded_file: Vec<u8> = Vec::with_capacity(file.size);
-
-        while let Some(data) = notifications.next().await {
-            if data.uuid == FILE_TRANS_UUID {
-                debug!("Got file chunk of size {}", data.value.len());
-                downloaded_file.extend_from_slice(&data.value);
-
-                // TODO: possible to avoid it? How while loop should change
-                if downloaded_file.len() == file.size {
-                    break;
-                }
-            } else {
-                warn!("Unexpected notification from uuid {}", data.uuid);
-            }
-        }
+        let downloaded_file = download_file(&mut notifications, file, CHUNK_TIMEOUT).await;
 
         info!("Unsub...");
 
         self.client.unsubscribe(&fetch_char).await?;
 
+        // Returned error can be downcasted to FetchError
+        let downloaded_file = downloaded_file?;
+
         info!("Writing the file {}...", file.filename);
         // TODO: spawn task?
         let mut filepath = OpenOptions::new()
@@ -128,3 +134,206 @@ impl BkClient {
         Ok(cmd_char.clone())
     }
 }
+
+/// Parses file listing, which is in somewhat CSV format, checksum column is optional:
+/// filename1, size[, crc32 in hex]
+/// filename2, size[, crc32 in hex]
+fn parse_file_list(response: &str) -> Result<Vec<FileInfo>> {
+    let mut files = vec![];
+
+    for (idx, line) in response.lines().enumerate() {
+        let split: Vec<&str> = line.split_terminator(", ").collect();
+
+        debug!("Got split {split:?}");
+
+        let (filename, size, crc) = match *split.as_slice() {
+            [filename, size] => (filename, size, None),
+            [filename, size, crc] => {
+                let crc = u32::from_str_radix(crc.trim_start_matches("0x"), 16)?;
+                (filename, size, Some(crc))
+            }
+            _ => {
+                return Err(anyhow!("Invalid split {split:?}"));
+            }
+        };
+
+        files.push(FileInfo {
+            id: idx,
+            filename: filename.to_string(),
+            size: size.parse()?,
+            crc,
+        });
+    }
+
+    Ok(files)
+}
+
+/// Collects chunks of the file from the notification stream.
+/// Finishes when announced size is reached, fails if stream stalls for longer than chunk_timeout,
+/// or device sends more data than expected.
+async fn download_file<S>(
+    notifications: &mut S,
+    file: &FileInfo,
+    chunk_timeout: Duration,
+) -> std::result::Result<Vec<u8>, FetchError>
+where
+    S: Stream<Item = ValueNotification> + Unpin,
+{
+    let mut downloaded_file: Vec<u8> = Vec::with_capacity(file.size);
+
+    while downloaded_file.len() < file.size {
+        let data = match tokio::time::timeout(chunk_timeout, notifications.next()).await {
+            Ok(Some(data)) => data,
+            // Stream closed, file is truncated
+            Ok(None) => break,
+            Err(_) => {
+                return Err(FetchError::TimedOut {
+                    received: downloaded_file.len(),
+                    expected: file.size,
+                })
+            }
+        };
+
+        if data.uuid == FILE_TRANS_UUID {
+            debug!("Got file chunk of size {}", data.value.len());
+            downloaded_file.extend_from_slice(&data.value);
+        } else {
+            warn!("Unexpected notification from uuid {}", data.uuid);
+        }
+    }
+
+    if downloaded_file.len() != file.size {
+        return Err(FetchError::SizeMismatch {
+            received: downloaded_file.len(),
+            expected: file.size,
+        });
+    }
+
+    if let Some(expected) = file.crc {
+        let received = crc32fast::hash(&downloaded_file);
+
+        if received != expected {
+            return Err(FetchError::ChecksumMismatch { received, expected });
+        }
+    }
+
+    Ok(downloaded_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+
+    use super::*;
+
+    fn chunk(value: &[u8]) -> ValueNotification {
+        ValueNotification {
+            uuid: FILE_TRANS_UUID,
+            value: value.to_vec(),
+        }
+    }
+
+    fn file(size: usize, crc: Option<u32>) -> FileInfo {
+        FileInfo {
+            id: 0,
+            filename: "test.fit".to_string(),
+            size,
+            crc,
+        }
+    }
+
+    #[test]
+    fn file_list_with_and_without_crc_is_parsed() {
+        let files = parse_file_list("a.fit, 10\nb.fit, 20, 0xcbf43926").unwrap();
+
+        assert_eq!(files[0].size, 10);
+        assert_eq!(files[0].crc, None);
+        assert_eq!(files[1].filename, "b.fit");
+        assert_eq!(files[1].crc, Some(0xcbf43926));
+    }
+
+    #[tokio::test]
+    async fn complete_file_is_downloaded() {
+        let content = b"123456789";
+        let mut notifications = stream::iter(vec![chunk(&content[..4]), chunk(&content[4..])]);
+
+        let res = download_file(
+            &mut notifications,
+            &file(content.len(), Some(crc32fast::hash(content))),
+            CHUNK_TIMEOUT,
+        )
+        .await;
+
+        assert_eq!(res, Ok(content.to_vec()));
+    }
+
+    #[tokio::test]
+    async fn stalled_stream_times_out() {
+        let mut notifications = stream::iter(vec![chunk(b"1234")]).chain(stream::pending());
+
+        let res = download_file(
+            &mut notifications,
+            &file(9, None),
+            Duration::from_millis(10),
+        )
+        .await;
+
+        assert_eq!(
+            res,
+            Err(FetchError::TimedOut {
+                received: 4,
+                expected: 9
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_stream_is_size_mismatch() {
+        let mut notifications = stream::iter(vec![chunk(b"1234"), chunk(b"56789")]);
+
+        let res = download_file(&mut notifications, &file(6, None), CHUNK_TIMEOUT).await;
+
+        assert_eq!(
+            res,
+            Err(FetchError::SizeMismatch {
+                received: 9,
+                expected: 6
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_stream_is_size_mismatch() {
+        let mut notifications = stream::iter(vec![chunk(b"1234")]);
+
+        let res = download_file(&mut notifications, &file(9, None), CHUNK_TIMEOUT).await;
+
+        assert_eq!(
+            res,
+            Err(FetchError::SizeMismatch {
+                received: 4,
+                expected: 9
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupted_content_is_checksum_mismatch() {
+        let mut notifications = stream::iter(vec![chunk(b"123456780")]);
+
+        let res = download_file(
+            &mut notifications,
+            &file(9, Some(0xcbf43926)),
+            CHUNK_TIMEOUT,
+        )
+        .await;
+
+        assert_eq!(
+            res,
+            Err(FetchError::ChecksumMismatch {
+                received: crc32fast::hash(b"123456780"),
+                expected: 0xcbf43926
+            })
+        );
+    }
+}
@@ -1,14 +1,126 @@
+use std::{collections::HashSet, fmt::Display, future::Future, time::Duration};
+
 use anyhow::Result;
-use btleplug::api::bleuuid::{BleUuid, uuid_from_u16};
-use btleplug::api::{Central, CentralEvent, Manager as _, Peripheral as _, ScanFilter};
+use btleplug::api::bleuuid::{uuid_from_u16, BleUuid};
+use btleplug::api::{
+    Central, CentralEvent, Characteristic, Manager as _, Peripheral as _, PeripheralProperties,
+    ScanFilter,
+};
 use btleplug::platform::{Adapter, Manager, Peripheral, PeripheralId};
 use futures::stream::StreamExt;
+use thiserror::Error;
 use uuid::Uuid;
 
 use crate::bk_gatts_service::{self, BkClient};
+use crate::ble_device::{self, ScanEvent};
+use crate::indoor_bike_data_defs::{self, ControlPointResult};
+
+/// Failures of the BLE layer, callers can match on them, top level wraps them in anyhow
+#[derive(Debug, Error)]
+pub enum BleError {
+    #[error("device not found")]
+    DeviceNotFound,
+    #[error("device does not provide service {0}")]
+    ServiceMissing(Uuid),
+    #[error("characteristic {0} not found")]
+    CharacteristicMissing(Uuid),
+    #[error("invalid data {data:?} in characteristic {uuid}")]
+    InvalidData { uuid: Uuid, data: Vec<u8> },
+    #[error("write request {op_code:#04x} failed")]
+    WriteFailed {
+        op_code: u8,
+        #[source]
+        source: btleplug::Error,
+    },
+    #[error("control point rejected the request: {0}")]
+    ControlRejected(ControlPointResult),
+    #[error(transparent)]
+    Btle(#[from] btleplug::Error),
+}
+
+/// Attempts of the characteristic read, before giving up
+pub const READ_ATTEMPTS: u32 = 3;
+/// Delay between the read attempts
+pub const READ_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Trainer looked for, if no other device name is given
+const DEFAULT_TRAINER_NAME: &str = "SUITO";
+
+/// Progress of looking for the device with given service, driven by the adapter events.
+/// Generic, so it can be stepped through in tests, without the adapter.
+#[derive(Debug, PartialEq)]
+pub enum ScanState<Id = PeripheralId, P = Peripheral> {
+    /// Waiting for a candidate
+    Scanning,
+    /// Connection to the candidate is requested
+    Connecting(Id),
+    /// Candidate is connected, its services are discovered
+    Verifying(Id),
+    /// Candidate has the service
+    Connected(P),
+}
+
+impl<Id: PartialEq, P> ScanState<Id, P> {
+    pub fn is_scanning(&self) -> bool {
+        matches!(self, ScanState::Scanning)
+    }
+
+    /// Candidate is taken only while scanning, one device is handled at a time
+    pub fn discovered(self, id: Id) -> Self {
+        match self {
+            ScanState::Scanning => ScanState::Connecting(id),
+            other => other,
+        }
+    }
+
+    /// Connection request failed, look for another candidate
+    pub fn connect_failed(self) -> Self {
+        match self {
+            ScanState::Connecting(_) => ScanState::Scanning,
+            other => other,
+        }
+    }
+
+    /// Some device got connected, only the candidate is verified,
+    /// other devices (like headphones) are none of our business
+    pub fn connected(self, id: &Id) -> Self {
+        match self {
+            ScanState::Connecting(candidate) if candidate == *id => ScanState::Verifying(candidate),
+            other => other,
+        }
+    }
+
+    /// Services of the candidate are known, without the requested one scanning continues
+    pub fn verified(self, peripheral: P, has_service: bool) -> Self {
+        match self {
+            ScanState::Verifying(_) if has_service => ScanState::Connected(peripheral),
+            ScanState::Verifying(_) => ScanState::Scanning,
+            other => other,
+        }
+    }
+
+    /// Candidate dropped the connection before it was verified
+    pub fn disconnected(self, id: &Id) -> Self {
+        match self {
+            ScanState::Connecting(candidate) | ScanState::Verifying(candidate)
+                if candidate == *id =>
+            {
+                ScanState::Scanning
+            }
+            other => other,
+        }
+    }
+
+    /// Device being handled at the moment
+    pub fn is_candidate(&self, id: &Id) -> bool {
+        matches!(self, ScanState::Connecting(candidate) | ScanState::Verifying(candidate) if candidate == id)
+    }
+}
 
 pub struct BleClient {
     adapter: Adapter,
+    /// Name of the trainer to connect to
+    device_name: Option<String>,
     // TODO: peripheral should be send via channel, no kept inside BleClient struct
     // fix it... someday
     bk_client: Option<BkClient>,
@@ -18,125 +130,49 @@ pub struct BleClient {
 
 impl BleClient {
     pub async fn new() -> Self {
-        let manager = Manager::new().await.unwrap();
-        let adapters = manager.adapters().await.unwrap();
+        Self::with_adapter(None).await.unwrap()
+    }
+
+    /// Uses the adapter which info contains given name, like "hci1", first adapter if name is not given
+    pub async fn with_adapter(name: Option<&str>) -> Result<Self> {
+        let manager = Manager::new().await?;
+        let adapters = manager.adapters().await?;
 
-        // Get first adapter
-        let adapter = adapters.into_iter().next().unwrap();
+        let mut adapter = None;
+        for candidate in adapters {
+            let info = candidate.adapter_info().await?;
+            debug!("Found adapter {info}");
 
-        Self {
+            if name.is_none_or(|name| info.contains(name)) {
+                adapter = Some(candidate);
+                break;
+            }
+        }
+
+        let adapter = adapter.ok_or_else(|| match name {
+            Some(name) => anyhow::anyhow!("BLE adapter {name} not found"),
+            None => anyhow::anyhow!("No BLE adapter found"),
+        })?;
+
+        Ok(Self {
             adapter,
+            device_name: None,
             bk_client: None,
-        }
+        })
+    }
+
+    /// Connect to the trainer with given name, instead of the default one
+    pub fn with_device_name(mut self, device_name: Option<String>) -> Self {
+        self.device_name = device_name;
+        self
     }
 
     /// Scans over devices, attempts to connect, looks for given service
     /// Returns peripheral of first found device that has requested service
-    pub async fn find_service(&self, gatts_service: Uuid) -> Result<Option<Peripheral>> {
-        // TODO: probably it's enough to use ScanFilter with the uuid
-        let speed_cadence = uuid_from_u16(0x1816);
-        let power = uuid_from_u16(0x1818);
-
-        self.adapter
-            .start_scan(ScanFilter {
-                services: vec![gatts_service, speed_cadence, power],
-            })
-            .await?;
-
-        info!("Started scanning for devices...");
-
-        let mut events = self.adapter.events().await?;
-
-        // Print based on whatever the event receiver outputs. Note that the event
-        // receiver blocks, so in a real program, this should be run in its own
-        // thread (not task, as this library does not yet use async channels).
-
-        // Instead of bool flags, do a state machine
-        let mut connection_successful = false;
-        let mut connected_device = "Not set".to_string();
-        while let Some(event) = events.next().await {
-            match event {
-                CentralEvent::DeviceDiscovered(id) => {
-                    if connection_successful {
-                        continue;
-                    }
-
-                    let peripheral = self.adapter.peripheral(&id).await?;
-
-                    let properties = peripheral.properties().await?;
-                    let is_connected = peripheral.is_connected().await?;
-                    let local_name = properties
-                        .unwrap()
-                        .local_name
-                        .unwrap_or_else(|| String::from("(peripheral name unknown)"));
-
-                    debug!("DeviceDiscovered: {local_name} {id:?}, connected {is_connected}");
-
-                    // TODO: to speedup the process...
-                    // TODO: comparing UUID would be more robust
-                    if local_name != "SUITO" {
-                        continue;
-                    }
-
-                    info!("Connecting to {local_name}...");
-                    // TODO: how to setup a reasonable timeout?
-                    if let Err(e) = peripheral.connect().await {
-                        warn!("Connection failed {e}");
-                        continue;
-                    } else {
-                        info!("Connected!");
-                        connection_successful = true;
-                        connected_device = local_name.to_string();
-                    }
-                }
-                CentralEvent::DeviceConnected(id) => {
-                    println!("DeviceConnected: {:?}", id);
-                    let peripheral = self.adapter.peripheral(&id).await?;
-
-                    peripheral.discover_services().await?;
-
-                    let found = peripheral
-                        .services()
-                        .into_iter()
-                        .find(|service| service.uuid == gatts_service);
-
-                    if found.is_some() {
-                        return Ok(Some(peripheral));
-                    } else {
-                        let local_name = connected_device;
-                        warn!("{local_name} Does not have requested service, disconnecting");
-
-                        // TODO: this disconnects unrelated BT devices, like headphones :D
-                        peripheral.disconnect().await?;
-                        connection_successful = false;
-                        connected_device = "Not set".to_string();
-                    }
-                }
-                CentralEvent::DeviceDisconnected(id) => {
-                    println!("DeviceDisconnected: {:?}", id);
-                }
-                CentralEvent::ManufacturerDataAdvertisement {
-                    id,
-                    manufacturer_data,
-                } => {
-                    println!(
-                        "ManufacturerDataAdvertisement: {:?}, {:?}",
-                        id, manufacturer_data
-                    );
-                }
-                CentralEvent::ServiceDataAdvertisement { id, service_data } => {
-                    println!("ServiceDataAdvertisement: {:?}, {:?}", id, service_data);
-                }
-                CentralEvent::ServicesAdvertisement { id, services } => {
-                    let services: Vec<String> =
-                        services.into_iter().map(|s| s.to_short_string()).collect();
-                    println!("ServicesAdvertisement: {:?}, {:?}", id, services);
-                }
-                CentralEvent::DeviceUpdated(id) => warn!("Got DeviceUpdated event for {id:?}"),
-            }
-        }
+    pub async fn find_service(&self, gatts_service: Uuid) -> Result<Peripheral, BleError> {
+        let trainer_name = self.device_name.as_deref().unwrap_or(DEFAULT_TRAINER_NAME);
 
-        Ok(None)
+        find_service(&self.adapter, gatts_service, trainer_name).await
     }
 
     #[allow(dead_code)]
@@ -152,7 +188,8 @@ impl BleClient {
         // thread (not task, as this library does not yet use async channels).
         while let Some(event) = events.next().await {
             match event {
-                CentralEvent::DeviceDiscovered(id) => {
+                // Name may be known only after the update
+                CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id) => {
                     self.device_discovered(&id).await?;
                 }
                 CentralEvent::DeviceConnected(id) => {
@@ -180,7 +217,6 @@ impl BleClient {
                         services.into_iter().map(|s| s.to_short_string()).collect();
                     println!("ServicesAdvertisement: {:?}, {:?}", id, services);
                 }
-                CentralEvent::DeviceUpdated(id) => warn!("Got DeviceUpdated event for {id:?}"),
             }
         }
 
@@ -188,14 +224,15 @@ impl BleClient {
     }
 
     async fn device_discovered(&mut self, id: &PeripheralId) -> Result<()> {
+        if self.bk_client.is_some() {
+            return Ok(());
+        }
+
         let peripheral = self.adapter.peripheral(id).await?;
 
         let properties = peripheral.properties().await?;
         let is_connected = peripheral.is_connected().await?;
-        let local_name = properties
-            .unwrap()
-            .local_name
-            .unwrap_or_else(|| String::from("(peripheral name unknown)"));
+        let local_name = peripheral_name(properties.as_ref());
 
         debug!("DeviceDiscovered: {local_name} {id:?}, connected {is_connected}");
 
@@ -241,3 +278,367 @@ impl BleClient {
         Ok(())
     }
 }
+
+/// Scans with given adapter, until a device with the service gets connected, or the adapter stops
+/// reporting events. Trainer is looked for by its name.
+pub async fn find_service<C: ble_device::CentralLike>(
+    central: &C,
+    gatts_service: Uuid,
+    trainer_name: &str,
+) -> Result<C::Peripheral, BleError> {
+    // Not imported at the top, btleplug Peripheral trait has the same methods
+    use ble_device::PeripheralLike;
+
+    // TODO: probably it's enough to use ScanFilter with the uuid
+    let speed_cadence = uuid_from_u16(0x1816);
+    let power = uuid_from_u16(0x1818);
+
+    central
+        .start_scan(ScanFilter {
+            services: vec![gatts_service, speed_cadence, power],
+        })
+        .await?;
+
+    info!("Started scanning for devices...");
+
+    let mut events = central.events().await?;
+
+    let mut state = ScanState::Scanning;
+    let mut service_missing = false;
+    // Devices without the service, updates of them are not interesting anymore
+    let mut rejected = HashSet::new();
+    while let Some(event) = events.next().await {
+        match event {
+            ScanEvent::Discovered(id) => {
+                if !state.is_scanning() || rejected.contains(&id) {
+                    continue;
+                }
+
+                let peripheral = central.peripheral(&id).await?;
+
+                let properties = peripheral.properties().await?;
+                let is_connected = peripheral.is_connected().await?;
+                let local_name = peripheral_name(properties.as_ref());
+
+                debug!("Discovered or updated: {local_name} {id:?}, connected {is_connected}");
+
+                if !is_candidate(properties.as_ref(), gatts_service, trainer_name) {
+                    continue;
+                }
+
+                info!("Connecting to {local_name}...");
+                state = state.discovered(id);
+                // TODO: how to setup a reasonable timeout?
+                if let Err(e) = peripheral.connect().await {
+                    warn!("Connection failed {e}");
+                    state = state.connect_failed();
+                } else {
+                    info!("Connected!");
+                }
+            }
+            ScanEvent::Connected(id) => {
+                debug!("DeviceConnected: {id:?}");
+                state = state.connected(&id);
+                if !matches!(&state, ScanState::Verifying(candidate) if *candidate == id) {
+                    debug!("Device {id:?} is not the candidate, ignoring it");
+                    continue;
+                }
+
+                let peripheral = central.peripheral(&id).await?;
+
+                peripheral.discover_services().await?;
+
+                let has_service = peripheral
+                    .services()
+                    .iter()
+                    .any(|service| service.uuid == gatts_service);
+
+                if !has_service {
+                    service_missing = true;
+                    rejected.insert(id.clone());
+                    let local_name = peripheral_name(peripheral.properties().await?.as_ref());
+                    warn!("{local_name} Does not have requested service, disconnecting");
+
+                    peripheral.disconnect().await?;
+                }
+
+                match state.verified(peripheral, has_service) {
+                    ScanState::Connected(peripheral) => return Ok(peripheral),
+                    next => state = next,
+                }
+            }
+            ScanEvent::Disconnected(id) => {
+                debug!("DeviceDisconnected: {id:?}");
+                if state.is_candidate(&id) {
+                    warn!("Device dropped the connection before it was verified");
+                }
+                state = state.disconnected(&id);
+            }
+        }
+    }
+
+    Err(scan_exhausted(gatts_service, service_missing))
+}
+
+/// Name advertised by the peripheral, falls back to a placeholder, since
+/// peripherals frequently advertise without the name, or properties are not known yet
+pub fn peripheral_name(properties: Option<&PeripheralProperties>) -> String {
+    properties
+        .and_then(|properties| properties.local_name.clone())
+        .unwrap_or_else(|| String::from("(unknown)"))
+}
+
+/// Reads the characteristic, transient errors of BLE stack are retried
+pub async fn read_with_retries(
+    peripheral: &impl ble_device::PeripheralLike,
+    characteristic: &Characteristic,
+) -> Result<Vec<u8>, btleplug::Error> {
+    with_retries(READ_ATTEMPTS, READ_BACKOFF, || {
+        ble_device::PeripheralLike::read(peripheral, characteristic)
+    })
+    .await
+}
+
+/// Runs the operation until it succeeds, waiting backoff between the attempts.
+/// Returns the last error if all attempts fail.
+pub async fn with_retries<T, E, F, Fut>(attempts: u32, backoff: Duration, mut op: F) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < attempts => {
+                warn!("Attempt {attempt}/{attempts} failed: {e}, retrying in {backoff:?}");
+                attempt += 1;
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Trainer is recognized by the name, other devices, like power meters, by the advertised service.
+/// Properties may be incomplete, until the peripheral gets updated.
+fn is_candidate(
+    properties: Option<&PeripheralProperties>,
+    gatts_service: Uuid,
+    trainer_name: &str,
+) -> bool {
+    // TODO: comparing UUID would be more robust
+    let is_trainer = gatts_service == indoor_bike_data_defs::SERVICE_UUID
+        && peripheral_name(properties) == trainer_name;
+    let advertises_service =
+        properties.is_some_and(|properties| properties.services.contains(&gatts_service));
+
+    is_trainer || advertises_service
+}
+
+/// Error reported when scan ends without finding the service
+fn scan_exhausted(gatts_service: Uuid, service_missing: bool) -> BleError {
+    if service_missing {
+        BleError::ServiceMissing(gatts_service)
+    } else {
+        BleError::DeviceNotFound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::ble_device::{
+        mock::{MockCentral, MockPeripheral},
+        PeripheralLike,
+    };
+
+    use super::*;
+
+    type TestState = ScanState<u32, &'static str>;
+
+    #[test]
+    fn scan_state_connects_to_the_device_with_service() {
+        let state = TestState::Scanning.discovered(1);
+        assert_eq!(state, ScanState::Connecting(1));
+
+        // Only one candidate at a time
+        let state = state.discovered(2);
+        assert_eq!(state, ScanState::Connecting(1));
+
+        // Unrelated device got connected
+        let state = state.connected(&7);
+        assert_eq!(state, ScanState::Connecting(1));
+
+        let state = state.connected(&1);
+        assert_eq!(state, ScanState::Verifying(1));
+
+        let state = state.verified("trainer", true);
+        assert_eq!(state, ScanState::Connected("trainer"));
+    }
+
+    #[test]
+    fn scan_state_goes_back_to_scanning_without_service() {
+        let state = TestState::Scanning.discovered(1).connected(&1);
+        assert!(state.is_candidate(&1));
+
+        let state = state.verified("headphones", false);
+        assert!(state.is_scanning());
+
+        // Failed connection
+        let state = state.discovered(2).connect_failed();
+        assert!(state.is_scanning());
+
+        // Candidate dropped the connection, unrelated disconnect does not matter
+        let state = state.discovered(3).connected(&3).disconnected(&7);
+        assert_eq!(state, ScanState::Verifying(3));
+        assert!(state.disconnected(&3).is_scanning());
+    }
+
+    #[test]
+    fn scan_without_matching_device_is_reported() {
+        let service = uuid_from_u16(0x1826);
+
+        assert!(matches!(
+            scan_exhausted(service, false),
+            BleError::DeviceNotFound
+        ));
+        assert!(matches!(
+            scan_exhausted(service, true),
+            BleError::ServiceMissing(uuid) if uuid == service
+        ));
+    }
+
+    #[test]
+    fn missing_local_name_falls_back_to_placeholder() {
+        let unnamed = PeripheralProperties {
+            local_name: None,
+            ..Default::default()
+        };
+        assert_eq!(peripheral_name(Some(&unnamed)), "(unknown)");
+        assert_eq!(peripheral_name(None), "(unknown)");
+
+        let named = PeripheralProperties {
+            local_name: Some("SUITO".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(peripheral_name(Some(&named)), "SUITO");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_succeeding_on_second_attempt_is_retried() {
+        let mut calls = 0;
+
+        let result = with_retries(READ_ATTEMPTS, READ_BACKOFF, || {
+            calls += 1;
+            let result = if calls == 1 {
+                Err(btleplug::Error::NotConnected)
+            } else {
+                Ok(vec![0x01])
+            };
+            async move { result }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), vec![0x01]);
+        assert_eq!(calls, 2);
+
+        let mut calls = 0;
+        let result: Result<(), _> = with_retries(READ_ATTEMPTS, READ_BACKOFF, || {
+            calls += 1;
+            async move { Err(btleplug::Error::NotConnected) }
+        })
+        .await;
+
+        assert!(matches!(result, Err(btleplug::Error::NotConnected)));
+        assert_eq!(calls, READ_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn scan_connects_to_the_device_with_service() {
+        let ftms = indoor_bike_data_defs::SERVICE_UUID;
+        let battery = uuid_from_u16(0x180F);
+
+        let imposter = Arc::new(MockPeripheral::new(battery, []).with_name("SUITO"));
+        let headphones = Arc::new(MockPeripheral::new(battery, []).with_name("BUDS"));
+        let trainer = Arc::new(MockPeripheral::new(ftms, []).with_name("SUITO"));
+
+        let central = MockCentral::new(
+            [
+                (1, imposter.clone()),
+                (2, headphones.clone()),
+                (3, trainer.clone()),
+            ],
+            vec![
+                ScanEvent::Discovered(1),
+                // Not looked at, while the candidate is being connected
+                ScanEvent::Discovered(3),
+                ScanEvent::Connected(1),
+                // Rejected already
+                ScanEvent::Discovered(1),
+                ScanEvent::Discovered(2),
+                ScanEvent::Discovered(3),
+                ScanEvent::Connected(3),
+            ],
+        );
+
+        let found = find_service(&central, ftms, "SUITO").await.unwrap();
+
+        assert!(Arc::ptr_eq(&found, &trainer));
+        assert!(trainer.is_connected().await.unwrap());
+        assert!(!imposter.is_connected().await.unwrap());
+        assert!(!headphones.is_connected().await.unwrap());
+        assert!(central.scan_filter().unwrap().services.contains(&ftms));
+    }
+
+    #[tokio::test]
+    async fn scan_ending_without_service_is_reported() {
+        let ftms = indoor_bike_data_defs::SERVICE_UUID;
+
+        let imposter = Arc::new(MockPeripheral::new(uuid_from_u16(0x180F), []).with_name("SUITO"));
+        let central = MockCentral::new(
+            [(1, imposter)],
+            vec![ScanEvent::Discovered(1), ScanEvent::Connected(1)],
+        );
+        assert!(matches!(
+            find_service(&central, ftms, "SUITO").await,
+            Err(BleError::ServiceMissing(uuid)) if uuid == ftms
+        ));
+
+        let central = MockCentral::new([], vec![]);
+        assert!(matches!(
+            find_service(&central, ftms, "SUITO").await,
+            Err(BleError::DeviceNotFound)
+        ));
+    }
+
+    #[test]
+    fn device_matching_after_update_is_connected() {
+        let ftms = indoor_bike_data_defs::SERVICE_UUID;
+        let power = uuid_from_u16(0x1818);
+
+        // Discovered without the name and services
+        let discovered = PeripheralProperties::default();
+        assert!(!is_candidate(Some(&discovered), ftms, "SUITO"));
+        assert!(!is_candidate(None, ftms, "SUITO"));
+
+        // DeviceUpdated completes the name
+        let updated = PeripheralProperties {
+            local_name: Some("SUITO".to_string()),
+            ..Default::default()
+        };
+        assert!(is_candidate(Some(&updated), ftms, "SUITO"));
+        assert!(!is_candidate(Some(&updated), ftms, "KICKR"));
+
+        // Power meters are recognized by the advertised service
+        let power_meter = PeripheralProperties {
+            services: vec![power],
+            ..Default::default()
+        };
+        assert!(is_candidate(Some(&power_meter), power, "SUITO"));
+        assert!(!is_candidate(Some(&updated), power, "SUITO"));
+    }
+}
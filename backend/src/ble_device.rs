@@ -0,0 +1,492 @@
+//! Thin abstraction over btleplug calls used by the clients, so they can run against
+//! an in-memory device in tests, without the hardware.
+use std::{collections::BTreeSet, fmt::Debug, hash::Hash, pin::Pin};
+
+use async_trait::async_trait;
+use btleplug::{
+    api::{
+        bleuuid::BleUuid, Central, CentralEvent, Characteristic, PeripheralProperties, ScanFilter,
+        Service, ValueNotification, WriteType,
+    },
+    platform::{Adapter, Peripheral, PeripheralId},
+    Result,
+};
+use futures::{future, Stream, StreamExt};
+
+pub type NotificationStream = Pin<Box<dyn Stream<Item = ValueNotification> + Send>>;
+pub type EventStream<Id> = Pin<Box<dyn Stream<Item = ScanEvent<Id>> + Send>>;
+
+/// Adapter events the scan reacts to
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanEvent<Id> {
+    /// Device got discovered, or its advertisement got updated
+    Discovered(Id),
+    Connected(Id),
+    Disconnected(Id),
+}
+
+/// Connected device exposing GATT characteristics
+#[async_trait]
+pub trait PeripheralLike: Send + Sync + 'static {
+    async fn properties(&self) -> Result<Option<PeripheralProperties>>;
+
+    async fn is_connected(&self) -> Result<bool>;
+
+    async fn connect(&self) -> Result<()>;
+
+    async fn discover_services(&self) -> Result<()>;
+
+    fn services(&self) -> BTreeSet<Service>;
+
+    fn characteristics(&self) -> BTreeSet<Characteristic>;
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>>;
+
+    async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<()>;
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<()>;
+
+    /// Stream of notifications from all subscribed characteristics
+    async fn notifications(&self) -> Result<NotificationStream>;
+
+    async fn disconnect(&self) -> Result<()>;
+}
+
+/// BLE adapter scanning for peripherals
+#[async_trait]
+pub trait CentralLike: Send + Sync {
+    type Id: Clone + Eq + Hash + Debug + Send + Sync;
+    type Peripheral: PeripheralLike;
+
+    async fn events(&self) -> Result<EventStream<Self::Id>>;
+
+    async fn start_scan(&self, filter: ScanFilter) -> Result<()>;
+
+    async fn peripheral(&self, id: &Self::Id) -> Result<Self::Peripheral>;
+}
+
+#[async_trait]
+impl PeripheralLike for Peripheral {
+    async fn properties(&self) -> Result<Option<PeripheralProperties>> {
+        btleplug::api::Peripheral::properties(self).await
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        btleplug::api::Peripheral::is_connected(self).await
+    }
+
+    async fn connect(&self) -> Result<()> {
+        btleplug::api::Peripheral::connect(self).await
+    }
+
+    async fn discover_services(&self) -> Result<()> {
+        btleplug::api::Peripheral::discover_services(self).await
+    }
+
+    fn services(&self) -> BTreeSet<Service> {
+        btleplug::api::Peripheral::services(self)
+    }
+
+    fn characteristics(&self) -> BTreeSet<Characteristic> {
+        btleplug::api::Peripheral::characteristics(self)
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
+        btleplug::api::Peripheral::read(self, characteristic).await
+    }
+
+    async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<()> {
+        btleplug::api::Peripheral::write(self, characteristic, data, write_type).await
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        btleplug::api::Peripheral::subscribe(self, characteristic).await
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream> {
+        btleplug::api::Peripheral::notifications(self).await
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        btleplug::api::Peripheral::disconnect(self).await
+    }
+}
+
+#[async_trait]
+impl CentralLike for Adapter {
+    type Id = PeripheralId;
+    type Peripheral = Peripheral;
+
+    async fn events(&self) -> Result<EventStream<PeripheralId>> {
+        let events = Central::events(self).await?;
+
+        Ok(Box::pin(
+            events.filter_map(|event| future::ready(scan_event(event))),
+        ))
+    }
+
+    async fn start_scan(&self, filter: ScanFilter) -> Result<()> {
+        Central::start_scan(self, filter).await
+    }
+
+    async fn peripheral(&self, id: &PeripheralId) -> Result<Peripheral> {
+        Central::peripheral(self, id).await
+    }
+}
+
+/// Advertisements are only logged, the scan does not need them
+fn scan_event(event: CentralEvent) -> Option<ScanEvent<PeripheralId>> {
+    match event {
+        // Name and services may be incomplete at discovery, on some platforms
+        // they are known only after the update, so both are evaluated the same way
+        CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id) => {
+            Some(ScanEvent::Discovered(id))
+        }
+        CentralEvent::DeviceConnected(id) => Some(ScanEvent::Connected(id)),
+        CentralEvent::DeviceDisconnected(id) => Some(ScanEvent::Disconnected(id)),
+        CentralEvent::ManufacturerDataAdvertisement {
+            id,
+            manufacturer_data,
+        } => {
+            trace!("ManufacturerDataAdvertisement: {id:?}, {manufacturer_data:?}");
+            None
+        }
+        CentralEvent::ServiceDataAdvertisement { id, service_data } => {
+            trace!("ServiceDataAdvertisement: {id:?}, {service_data:?}");
+            None
+        }
+        CentralEvent::ServicesAdvertisement { id, services } => {
+            let services: Vec<String> = services.into_iter().map(|s| s.to_short_string()).collect();
+            trace!("ServicesAdvertisement: {id:?}, {services:?}");
+            None
+        }
+    }
+}
+
+/// In-memory peripheral, serves canned characteristic values and scripted notifications
+#[cfg(test)]
+pub mod mock {
+    use std::{
+        collections::{BTreeSet, HashMap},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use async_trait::async_trait;
+    use btleplug::{
+        api::{
+            CharPropFlags, Characteristic, PeripheralProperties, ScanFilter, Service,
+            ValueNotification, WriteType,
+        },
+        Result,
+    };
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+    use uuid::Uuid;
+
+    use super::{CentralLike, EventStream, NotificationStream, PeripheralLike, ScanEvent};
+
+    /// Notifications sent in reaction to the write, like control point indication
+    type WriteReaction = Box<dyn Fn(Uuid, &[u8]) -> Vec<ValueNotification> + Send + Sync>;
+
+    pub struct MockPeripheral {
+        name: String,
+        service: Service,
+        connected: AtomicBool,
+        values: HashMap<Uuid, Vec<u8>>,
+        on_write: WriteReaction,
+        writes: Mutex<Vec<(Uuid, Vec<u8>)>>,
+        subscriptions: Mutex<Vec<Uuid>>,
+        notifications_tx: mpsc::UnboundedSender<ValueNotification>,
+        notifications_rx: Mutex<Option<mpsc::UnboundedReceiver<ValueNotification>>>,
+    }
+
+    impl MockPeripheral {
+        /// Peripheral with a single primary service, having given characteristics
+        pub fn new(
+            service_uuid: Uuid,
+            characteristics: impl IntoIterator<Item = (Uuid, CharPropFlags)>,
+        ) -> Self {
+            let characteristics = characteristics
+                .into_iter()
+                .map(|(uuid, properties)| Characteristic {
+                    uuid,
+                    service_uuid,
+                    properties,
+                })
+                .collect();
+
+            let (notifications_tx, notifications_rx) = mpsc::unbounded_channel();
+
+            Self {
+                name: "MOCK".to_string(),
+                service: Service {
+                    uuid: service_uuid,
+                    primary: true,
+                    characteristics,
+                },
+                connected: AtomicBool::new(false),
+                values: HashMap::new(),
+                on_write: Box::new(|_, _| vec![]),
+                writes: Mutex::new(vec![]),
+                subscriptions: Mutex::new(vec![]),
+                notifications_tx,
+                notifications_rx: Mutex::new(Some(notifications_rx)),
+            }
+        }
+
+        /// Name advertised by the peripheral
+        pub fn with_name(mut self, name: &str) -> Self {
+            self.name = name.to_string();
+            self
+        }
+
+        /// Value returned on read of given characteristic
+        pub fn with_value(mut self, uuid: Uuid, value: &[u8]) -> Self {
+            self.values.insert(uuid, value.to_vec());
+            self
+        }
+
+        /// Notifications returned by the reaction are sent after every write
+        pub fn with_write_reaction<F>(mut self, reaction: F) -> Self
+        where
+            F: Fn(Uuid, &[u8]) -> Vec<ValueNotification> + Send + Sync + 'static,
+        {
+            self.on_write = Box::new(reaction);
+            self
+        }
+
+        /// Sends the notification, it's queued until notifications are requested
+        pub fn notify(&self, uuid: Uuid, value: &[u8]) {
+            let _ = self.notifications_tx.send(ValueNotification {
+                uuid,
+                value: value.to_vec(),
+            });
+        }
+
+        /// Every write done so far, with the characteristic written
+        pub fn writes(&self) -> Vec<(Uuid, Vec<u8>)> {
+            self.writes.lock().unwrap().clone()
+        }
+
+        pub fn subscriptions(&self) -> Vec<Uuid> {
+            self.subscriptions.lock().unwrap().clone()
+        }
+
+        fn find(&self, characteristic: &Characteristic) -> Result<&Characteristic> {
+            self.service
+                .characteristics
+                .iter()
+                .find(|c| c.uuid == characteristic.uuid)
+                .ok_or_else(|| {
+                    btleplug::Error::NotSupported(format!(
+                        "no characteristic {}",
+                        characteristic.uuid
+                    ))
+                })
+        }
+    }
+
+    #[async_trait]
+    impl PeripheralLike for MockPeripheral {
+        async fn properties(&self) -> Result<Option<PeripheralProperties>> {
+            Ok(Some(PeripheralProperties {
+                local_name: Some(self.name.clone()),
+                services: vec![self.service.uuid],
+                ..Default::default()
+            }))
+        }
+
+        async fn is_connected(&self) -> Result<bool> {
+            Ok(self.connected.load(Ordering::SeqCst))
+        }
+
+        async fn connect(&self) -> Result<()> {
+            self.connected.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn discover_services(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn services(&self) -> BTreeSet<Service> {
+            BTreeSet::from([self.service.clone()])
+        }
+
+        fn characteristics(&self) -> BTreeSet<Characteristic> {
+            self.service.characteristics.clone()
+        }
+
+        async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
+            let characteristic = self.find(characteristic)?;
+
+            self.values
+                .get(&characteristic.uuid)
+                .cloned()
+                .ok_or_else(|| {
+                    btleplug::Error::NotSupported(format!(
+                        "{} is not readable",
+                        characteristic.uuid
+                    ))
+                })
+        }
+
+        async fn write(
+            &self,
+            characteristic: &Characteristic,
+            data: &[u8],
+            _write_type: WriteType,
+        ) -> Result<()> {
+            let uuid = self.find(characteristic)?.uuid;
+            self.writes.lock().unwrap().push((uuid, data.to_vec()));
+
+            for notification in (self.on_write)(uuid, data) {
+                let _ = self.notifications_tx.send(notification);
+            }
+
+            Ok(())
+        }
+
+        async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
+            let uuid = self.find(characteristic)?.uuid;
+            self.subscriptions.lock().unwrap().push(uuid);
+
+            Ok(())
+        }
+
+        /// Can be requested once, all notifications go to the single stream
+        async fn notifications(&self) -> Result<NotificationStream> {
+            let rx = self
+                .notifications_rx
+                .lock()
+                .unwrap()
+                .take()
+                .ok_or_else(|| {
+                    btleplug::Error::NotSupported("notifications already taken".to_string())
+                })?;
+
+            Ok(Box::pin(UnboundedReceiverStream::new(rx)))
+        }
+
+        async fn disconnect(&self) -> Result<()> {
+            self.connected.store(false, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// Lets the test inspect the mock, while the client owns it
+    #[async_trait]
+    impl<P: PeripheralLike> PeripheralLike for Arc<P> {
+        async fn properties(&self) -> Result<Option<PeripheralProperties>> {
+            self.as_ref().properties().await
+        }
+
+        async fn is_connected(&self) -> Result<bool> {
+            self.as_ref().is_connected().await
+        }
+
+        async fn connect(&self) -> Result<()> {
+            self.as_ref().connect().await
+        }
+
+        async fn discover_services(&self) -> Result<()> {
+            self.as_ref().discover_services().await
+        }
+
+        fn services(&self) -> BTreeSet<Service> {
+            self.as_ref().services()
+        }
+
+        fn characteristics(&self) -> BTreeSet<Characteristic> {
+            self.as_ref().characteristics()
+        }
+
+        async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
+            self.as_ref().read(characteristic).await
+        }
+
+        async fn write(
+            &self,
+            characteristic: &Characteristic,
+            data: &[u8],
+            write_type: WriteType,
+        ) -> Result<()> {
+            self.as_ref().write(characteristic, data, write_type).await
+        }
+
+        async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
+            self.as_ref().subscribe(characteristic).await
+        }
+
+        async fn notifications(&self) -> Result<NotificationStream> {
+            self.as_ref().notifications().await
+        }
+
+        async fn disconnect(&self) -> Result<()> {
+            self.as_ref().disconnect().await
+        }
+    }
+
+    /// Adapter with peripherals identified by numbers, replays scripted events once scan starts
+    pub struct MockCentral {
+        peripherals: HashMap<u32, Arc<MockPeripheral>>,
+        events: Mutex<Vec<ScanEvent<u32>>>,
+        scan_filter: Mutex<Option<ScanFilter>>,
+    }
+
+    impl MockCentral {
+        pub fn new(
+            peripherals: impl IntoIterator<Item = (u32, Arc<MockPeripheral>)>,
+            events: Vec<ScanEvent<u32>>,
+        ) -> Self {
+            Self {
+                peripherals: peripherals.into_iter().collect(),
+                events: Mutex::new(events),
+                scan_filter: Mutex::new(None),
+            }
+        }
+
+        /// Filter of the last scan started
+        pub fn scan_filter(&self) -> Option<ScanFilter> {
+            self.scan_filter.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl CentralLike for MockCentral {
+        type Id = u32;
+        type Peripheral = Arc<MockPeripheral>;
+
+        /// Stream ends once the scripted events are replayed
+        async fn events(&self) -> Result<EventStream<u32>> {
+            let events = std::mem::take(&mut *self.events.lock().unwrap());
+
+            Ok(Box::pin(futures::stream::iter(events)))
+        }
+
+        async fn start_scan(&self, filter: ScanFilter) -> Result<()> {
+            *self.scan_filter.lock().unwrap() = Some(filter);
+            Ok(())
+        }
+
+        async fn peripheral(&self, id: &u32) -> Result<Arc<MockPeripheral>> {
+            self.peripherals
+                .get(id)
+                .cloned()
+                .ok_or(btleplug::Error::DeviceNotFound)
+        }
+    }
+}
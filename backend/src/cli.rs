@@ -2,6 +2,7 @@ use clap::{ErrorKind, FromArgMatches, IntoApp, Parser, Subcommand};
 use std::{
     io::{self},
     thread,
+    time::Duration,
 };
 use tokio::sync::mpsc::Sender;
 
@@ -20,21 +21,80 @@ pub enum UserCommands {
     /// Prepare machine for new workout
     StartWorkout,
     SetResistance{resistance : u8},
+    /// Resistance in percent of the trainer range
+    SetResistancePercent {
+        percent: f64,
+    },
 
     SetTargetPower{power: i16},
+    /// Target speed in km/h, only for machines supporting speed target
+    SetTargetSpeed {
+        speed: f64,
+    },
+    /// Switch to simulation mode (ERG off), with given grade in percent
+    SetSimulation{grade: f64},
     /// Exits the application
     Exit,
+    /// Workout ran out of steps, application exits the same way as on Exit
+    #[clap(skip)]
+    WorkoutCompleted,
 }
 
 /// Commands to control flow of the workout
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum WorkoutCommands {
     Pause,
     Resume,
     SkipStep,
+    /// Hold current step (or current part of the interval) longer
+    ExtendStep(Duration),
+    /// Continue the workout from the beginning of given step, 1-based, both forward and backward
+    JumpToStep(usize),
+    /// Sets target power in manual mode, workout sets it on its own
+    SetTargetPower(i16),
+    /// Sets resistance in manual mode
+    SetResistance(u8),
+    /// Sets resistance in percent of the trainer range in manual mode
+    SetResistancePercent(f64),
+    /// Adjusts target power by given watts, during the workout it's layered on the workout target
+    NudgePower(i16),
+    /// Watts added to every target of the workout, replaces the nudges
+    SetPowerOffset(i16),
     Abort
 }
 
+/// How many watts target power is changed by on user request
+pub const NUDGE_POWER_BY: i16 = 5;
+
+/// Parses commands with an argument, typed by the user in the TUI, or sent over the websocket:
+/// step number to jump to, "P <watts>" to set target power, "R <level>" or "R <percent>%" to set resistance,
+/// "O <watts>" to offset workout targets, "+" or "-" to nudge target power by NUDGE_POWER_BY,
+/// "PAUSE" to pause, "RESUME" (or "START" for the workout started paused) to resume
+pub fn parse_workout_command(input: &str) -> Option<WorkoutCommands> {
+    let mut words = input.split_whitespace();
+
+    let command = match (words.next()?, words.next()) {
+        ("+", None) => WorkoutCommands::NudgePower(NUDGE_POWER_BY),
+        ("-", None) => WorkoutCommands::NudgePower(-NUDGE_POWER_BY),
+        ("PAUSE", None) => WorkoutCommands::Pause,
+        ("RESUME", None) | ("START", None) => WorkoutCommands::Resume,
+        (step_number, None) => WorkoutCommands::JumpToStep(step_number.parse().ok()?),
+        ("P", Some(power)) => WorkoutCommands::SetTargetPower(power.parse().ok()?),
+        ("R", Some(resistance)) => match resistance.strip_suffix('%') {
+            Some(percent) => WorkoutCommands::SetResistancePercent(percent.parse().ok()?),
+            None => WorkoutCommands::SetResistance(resistance.parse().ok()?),
+        },
+        ("O", Some(offset)) => WorkoutCommands::SetPowerOffset(offset.parse().ok()?),
+        _ => return None,
+    };
+
+    if words.next().is_some() {
+        return None;
+    }
+
+    Some(command)
+}
+
 /// Read stdin and use clap to parse user input to the CLIMessages enum
 pub fn control_cli(tx: Sender<UserCommands>) {
     // It's not recommended to handle user input using async.
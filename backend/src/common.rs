@@ -1,11 +1,86 @@
-use std::time::Duration;
+use std::{str::FromStr, time::Duration};
 
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use tokio::sync::broadcast::{self, error::RecvError};
 
+// Capacities of the channels. Broadcast channel keeps up to `capacity` messages for the slowest
+// receiver, once it falls further behind the oldest messages are dropped and the receiver lags.
+// Bigger capacity costs memory, since messages are kept until every receiver gets them,
+// but tolerates longer stalls of the receivers.
 
-pub fn duration_to_string(duration: &Duration) -> String {
-    const HOUR_IN_SECONDS: u64 = 3600;
-    const MINUTE_IN_SECONDS: u64 = 60;
+/// Bike data comes at the trainer rate, chatty trainers notify several times a second
+pub const BIKE_DATA_CHANNEL_CAPACITY: usize = 128;
+/// Workout state is sent once a second, and on every user action
+pub const WORKOUT_STATE_CHANNEL_CAPACITY: usize = 16;
+/// Commands, events and machine notifications are rare
+pub const CONTROL_CHANNEL_CAPACITY: usize = 16;
+
+const HOUR_IN_SECONDS: u64 = 3600;
+const MINUTE_IN_SECONDS: u64 = 60;
+const KM_IN_MILE: f64 = 1.609344;
+
+/// Units speed and distance are displayed in, internally both are always metric
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    #[default]
+    Metric,
+    Imperial,
+}
+
+impl FromStr for Units {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "metric" => Ok(Units::Metric),
+            "imperial" => Ok(Units::Imperial),
+            other => Err(anyhow!(
+                "Unknown units '{other}', expected 'metric' or 'imperial'"
+            )),
+        }
+    }
+}
+
+impl Units {
+    /// Speed given in km/h, like 32.5km/h or 20.2mph
+    pub fn speed(self, kmh: f64) -> String {
+        match self {
+            Units::Metric => format!("{kmh:.1}km/h"),
+            Units::Imperial => format!("{:.1}mph", kmh_to_mph(kmh)),
+        }
+    }
+
+    /// Distance given in meters, like 12.34km or 7.67mi
+    pub fn distance(self, meters: f64) -> String {
+        let km = meters / 1000.0;
+
+        match self {
+            Units::Metric => format!("{km:.2}km"),
+            Units::Imperial => format!("{:.2}mi", km_to_miles(km)),
+        }
+    }
+}
 
+pub fn km_to_miles(km: f64) -> f64 {
+    km / KM_IN_MILE
+}
+
+pub fn miles_to_km(miles: f64) -> f64 {
+    miles * KM_IN_MILE
+}
+
+pub fn kmh_to_mph(kmh: f64) -> f64 {
+    km_to_miles(kmh)
+}
+
+pub fn mph_to_kmh(mph: f64) -> f64 {
+    miles_to_km(mph)
+}
+
+/// Compact, human friendly form, like 1h 2m 3s
+pub fn duration_to_string(duration: &Duration) -> String {
     let secs = duration.as_secs();
 
     let hours = secs / HOUR_IN_SECONDS;
@@ -31,7 +106,269 @@ pub fn duration_to_string(duration: &Duration) -> String {
     res
 }
 
+/// Fixed width form HH:MM:SS, suitable for tables
+pub fn duration_to_hms(duration: &Duration) -> String {
+    let secs = duration.as_secs();
+
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / HOUR_IN_SECONDS,
+        secs % HOUR_IN_SECONDS / MINUTE_IN_SECONDS,
+        secs % MINUTE_IN_SECONDS
+    )
+}
+
+/// Parses duration given as:
+/// - plain seconds: 90
+/// - clock format: 1:30, 1:02:03
+/// - units: 90s, 1h5m, 1h 2m 3s (output of duration_to_string)
+pub fn parse_duration(input: &str) -> Result<Duration> {
+    let input = input.trim();
+
+    if input.is_empty() {
+        return Err(anyhow!("Empty duration"));
+    }
+
+    let secs = if input.contains(':') {
+        parse_clock(input)?
+    } else if input.chars().all(|c| c.is_ascii_digit()) {
+        input.parse()?
+    } else {
+        parse_units(input)?
+    };
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// [h:]m:s
+fn parse_clock(input: &str) -> Result<u64> {
+    let parts: Vec<&str> = input.split(':').collect();
+
+    if parts.len() > 3 {
+        return Err(anyhow!("Too many fields in duration '{input}'"));
+    }
+
+    let mut secs = 0;
+    for (idx, part) in parts.iter().enumerate() {
+        let value: u64 = part
+            .parse()
+            .with_context(|| format!("Invalid field '{part}' in duration '{input}'"))?;
+
+        // Only the leading field may overflow, 1:75 is not a valid time
+        if idx > 0 && value >= 60 {
+            return Err(anyhow!("Field '{part}' out of range in duration '{input}'"));
+        }
+
+        secs = secs * 60 + value;
+    }
+
+    Ok(secs)
+}
+
+/// Sequence of number followed by unit (h, m, s), each unit at most once, from largest to smallest
+fn parse_units(input: &str) -> Result<u64> {
+    let mut secs = 0;
+    let mut number = String::new();
+    // Multiplier of the last unit seen, enforces h -> m -> s order
+    let mut last_unit = u64::MAX;
+
+    for c in input.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        if c.is_whitespace() {
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            'h' => HOUR_IN_SECONDS,
+            'm' => MINUTE_IN_SECONDS,
+            's' => 1,
+            other => return Err(anyhow!("Unknown unit '{other}' in duration '{input}'")),
+        };
+
+        if number.is_empty() || unit >= last_unit {
+            return Err(anyhow!("Invalid duration '{input}'"));
+        }
+
+        secs += number.parse::<u64>()? * unit;
+        number.clear();
+        last_unit = unit;
+    }
+
+    if !number.is_empty() {
+        return Err(anyhow!(
+            "Missing unit after '{number}' in duration '{input}'"
+        ));
+    }
+
+    Ok(secs)
+}
+
+/// Receives next message, skipped ones are logged if receiver lags behind.
+/// Returns None once the channel is closed.
+pub async fn recv_lagging<T: Clone>(rx: &mut broadcast::Receiver<T>, name: &str) -> Option<T> {
+    loop {
+        match rx.recv().await {
+            Ok(message) => return Some(message),
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("{name} receiver lags, skipped {skipped} messages");
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
 pub fn get_power(ftp_base: f64, power_level: f64) -> i16 {
     (ftp_base * power_level).round() as i16
 }
 
+/// Gross efficiency of the rider, fraction of burned energy turned into work.
+/// At about 24% kJ of work and kcal burned are equal.
+pub const DEFAULT_EFFICIENCY: f64 = 0.24;
+
+const KJ_IN_KCAL: f64 = 4.184;
+
+/// Calories burned by the rider, for given work in kJ
+pub fn kj_to_kcal(work: f64, efficiency: f64) -> f64 {
+    work / KJ_IN_KCAL / efficiency
+}
+
+/// Work in kJ, for given calories burned by the rider
+pub fn kcal_to_kj(calories: f64, efficiency: f64) -> f64 {
+    calories * KJ_IN_KCAL * efficiency
+}
+
+/// Power in percent of FTP
+pub fn get_ftp_percent(ftp_base: f64, power: i16) -> f64 {
+    f64::from(power) * 100.0 / ftp_base
+}
+
+/// Serializes durations as whole seconds, a plain number is easier to consume in the browser
+/// than {secs, nanos} object serde gives by default
+pub mod whole_secs {
+    use std::time::Duration;
+
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs())
+    }
+
+    /// The same, for every duration of the sequence
+    pub fn serialize_all<S: Serializer>(
+        durations: &[Duration],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(durations.iter().map(Duration::as_secs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn work_is_converted_to_calories() {
+        assert_eq!(kj_to_kcal(1000.0, DEFAULT_EFFICIENCY).round(), 996.0);
+        assert_eq!(kj_to_kcal(0.0, DEFAULT_EFFICIENCY), 0.0);
+
+        // Less efficient rider burns more for the same work
+        assert!(kj_to_kcal(1000.0, 0.2) > kj_to_kcal(1000.0, DEFAULT_EFFICIENCY));
+    }
+
+    #[test]
+    fn parse_duration_works() {
+        let valid = [
+            ("90", 90),
+            ("0", 0),
+            ("1:30", 90),
+            ("01:02:03", 3723),
+            ("90:00", 5400),
+            ("90s", 90),
+            ("1h5m", 3900),
+            ("1h 2m 3s", 3723),
+            ("2M", 120),
+            (" 45s ", 45),
+        ];
+
+        for (input, expected) in valid {
+            assert_eq!(
+                parse_duration(input).unwrap(),
+                Duration::from_secs(expected),
+                "input '{}'",
+                input
+            );
+        }
+
+        let invalid = [
+            "", "abc", "1:75", "1:2:3:4", "1::2", "-5", "1.5", "5x", "10m5", "5s1h", "1h1h", "h",
+        ];
+
+        for input in invalid {
+            assert!(parse_duration(input).is_err(), "input '{}'", input);
+        }
+    }
+
+    #[test]
+    fn parse_duration_is_inverse_of_duration_to_string() {
+        for secs in [0, 59, 61, 3600, 3723] {
+            let duration = Duration::from_secs(secs);
+
+            assert_eq!(
+                parse_duration(&duration_to_string(&duration)).unwrap(),
+                duration
+            );
+        }
+    }
+
+    #[test]
+    fn duration_to_hms_works() {
+        assert_eq!(duration_to_hms(&Duration::from_secs(0)), "00:00:00");
+        assert_eq!(duration_to_hms(&Duration::from_secs(3723)), "01:02:03");
+        assert_eq!(duration_to_hms(&Duration::from_secs(36000)), "10:00:00");
+    }
+
+    #[test]
+    fn units_are_converted() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-3;
+
+        assert!(close(km_to_miles(1.609344), 1.0));
+        assert!(close(km_to_miles(42.195), 26.219));
+        assert!(close(miles_to_km(km_to_miles(100.0)), 100.0));
+
+        assert!(close(kmh_to_mph(100.0), 62.137));
+        assert!(close(mph_to_kmh(20.0), 32.187));
+
+        assert_eq!(Units::Metric.speed(32.5), "32.5km/h");
+        assert_eq!(Units::Imperial.speed(32.5), "20.2mph");
+        assert_eq!(Units::Metric.distance(12345.0), "12.35km");
+        assert_eq!(Units::Imperial.distance(16093.44), "10.00mi");
+        assert_eq!("imperial".parse::<Units>().unwrap(), Units::Imperial);
+    }
+
+    #[tokio::test]
+    async fn burst_of_bike_data_does_not_lag() {
+        let (tx, mut rx) = broadcast::channel(BIKE_DATA_CHANNEL_CAPACITY);
+
+        // Few seconds of a chatty trainer, while consumer is busy
+        for sample in 0..BIKE_DATA_CHANNEL_CAPACITY {
+            tx.send(sample).unwrap();
+        }
+
+        for sample in 0..BIKE_DATA_CHANNEL_CAPACITY {
+            assert_eq!(rx.recv().await, Ok(sample));
+        }
+
+        // Overflow drops the oldest samples only
+        for sample in 0..BIKE_DATA_CHANNEL_CAPACITY + 2 {
+            tx.send(sample).unwrap();
+        }
+        assert_eq!(recv_lagging(&mut rx, "bike data").await, Some(2));
+
+        drop(tx);
+        while recv_lagging(&mut rx, "bike data").await.is_some() {}
+    }
+}
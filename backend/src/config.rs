@@ -0,0 +1,352 @@
+//! Rider profile persisted in `~/.velomania/config.toml`, so it does not have to be passed every run.
+//! Command line flags take precedence over the config values.
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::common::Units;
+
+/// Sane FTP range in watts, anything outside is most likely a typo
+const FTP_RANGE: std::ops::RangeInclusive<f64> = 50.0..=600.0;
+/// Sane range of the power offset in watts
+const POWER_OFFSET_RANGE: std::ops::RangeInclusive<f64> = -200.0..=200.0;
+/// Sane range of the rider weight in kg
+const RIDER_WEIGHT_RANGE: std::ops::RangeInclusive<f64> = 30.0..=200.0;
+/// Sane range of the bike weight in kg
+const BIKE_WEIGHT_RANGE: std::ops::RangeInclusive<f64> = 3.0..=30.0;
+/// Sane range of the gross efficiency, as a fraction
+const EFFICIENCY_RANGE: std::ops::RangeInclusive<f64> = 0.1..=0.35;
+/// Only that many latest workouts are remembered
+pub const MAX_RECENT_WORKOUTS: usize = 10;
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub ftp_base: Option<f64>,
+    /// Name of the trainer advertised over BLE
+    pub device: Option<String>,
+    /// BLE adapter to use, like "hci1", first one is used if not set
+    pub adapter: Option<String>,
+    /// Watts added to the power measured by the trainer
+    pub power_offset: Option<f64>,
+    /// Units speed and distance are displayed in
+    pub units: Option<Units>,
+    /// In kg
+    pub rider_weight: Option<f64>,
+    /// In kg
+    pub bike_weight: Option<f64>,
+    /// Gross efficiency of the rider, for converting work to calories
+    pub efficiency: Option<f64>,
+    /// Workout library browsed by the UI
+    pub workout_dir: Option<PathBuf>,
+    /// Workouts run lately, the latest last. Maintained by the application
+    #[serde(default)]
+    pub recent: Vec<RecentWorkout>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecentWorkout {
+    pub path: PathBuf,
+    /// Seconds since the UNIX epoch
+    pub started: u64,
+    /// Workout went through all the steps, false if it was aborted (or is still running)
+    pub completed: bool,
+}
+
+impl RecentWorkout {
+    /// Workout started just now
+    pub fn started_now(path: PathBuf) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+
+        Self {
+            path,
+            started,
+            completed: false,
+        }
+    }
+}
+
+impl Config {
+    /// `~/.velomania/config.toml`, None if home directory is not known
+    pub fn default_path() -> Option<PathBuf> {
+        std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".velomania").join("config.toml"))
+    }
+
+    /// Loads the config from given path, or from the default one if it exists.
+    /// Missing default config is not an error, all values are just unset.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Self::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Cannot read config {}", path.display()))?;
+
+        let config = Self::from_toml_str(&content)
+            .with_context(|| format!("Invalid config {}", path.display()))?;
+
+        info!("Loaded config {}", path.display());
+
+        Ok(config)
+    }
+
+    pub fn from_toml_str(content: &str) -> Result<Self> {
+        let config: Config = toml::from_str(content)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Values set in overrides replace the config ones
+    pub fn merge(self, overrides: Config) -> Self {
+        Self {
+            ftp_base: overrides.ftp_base.or(self.ftp_base),
+            device: overrides.device.or(self.device),
+            adapter: overrides.adapter.or(self.adapter),
+            power_offset: overrides.power_offset.or(self.power_offset),
+            units: overrides.units.or(self.units),
+            rider_weight: overrides.rider_weight.or(self.rider_weight),
+            bike_weight: overrides.bike_weight.or(self.bike_weight),
+            efficiency: overrides.efficiency.or(self.efficiency),
+            workout_dir: overrides.workout_dir.or(self.workout_dir),
+            recent: self.recent,
+        }
+    }
+
+    /// Writes FTP to the config at given path, other values are kept, comments are dropped.
+    /// Config is created if it does not exist.
+    pub fn save_ftp_base(path: &Path, ftp_base: f64) -> Result<()> {
+        rewrite(path, |content| with_ftp_base(content, ftp_base))?;
+
+        info!("FTP {ftp_base} saved to {}", path.display());
+
+        Ok(())
+    }
+
+    /// Updates the list of recent workouts in the config at given path, the list is capped to
+    /// MAX_RECENT_WORKOUTS, the oldest are dropped. Returns the updated list.
+    pub fn update_recent(
+        path: &Path,
+        update: impl FnOnce(&mut Vec<RecentWorkout>),
+    ) -> Result<Vec<RecentWorkout>> {
+        let mut recent = vec![];
+
+        rewrite(path, |content| {
+            recent = Self::from_toml_str(content)?.recent;
+
+            update(&mut recent);
+            let excess = recent.len().saturating_sub(MAX_RECENT_WORKOUTS);
+            recent.drain(..excess);
+
+            with_recent(content, &recent)
+        })?;
+
+        Ok(recent)
+    }
+
+    /// Checks if values are within sane ranges
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ftp_base) = self.ftp_base {
+            ensure!(
+                FTP_RANGE.contains(&ftp_base),
+                "ftp_base {ftp_base} is out of range {FTP_RANGE:?}"
+            );
+        }
+
+        if let Some(power_offset) = self.power_offset {
+            ensure!(
+                POWER_OFFSET_RANGE.contains(&power_offset),
+                "power_offset {power_offset} is out of range {POWER_OFFSET_RANGE:?}"
+            );
+        }
+
+        if let Some(rider_weight) = self.rider_weight {
+            ensure!(
+                RIDER_WEIGHT_RANGE.contains(&rider_weight),
+                "rider_weight {rider_weight} is out of range {RIDER_WEIGHT_RANGE:?}"
+            );
+        }
+
+        if let Some(bike_weight) = self.bike_weight {
+            ensure!(
+                BIKE_WEIGHT_RANGE.contains(&bike_weight),
+                "bike_weight {bike_weight} is out of range {BIKE_WEIGHT_RANGE:?}"
+            );
+        }
+
+        if let Some(efficiency) = self.efficiency {
+            ensure!(
+                EFFICIENCY_RANGE.contains(&efficiency),
+                "efficiency {efficiency} is out of range {EFFICIENCY_RANGE:?}"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Applies the change to the content of the config, new content is validated before it's written.
+/// Missing config is treated as an empty one.
+fn rewrite(path: &Path, change: impl FnOnce(&str) -> Result<String>) -> Result<()> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Cannot read config {}", path.display())),
+    };
+
+    let content = change(&content)
+        .and_then(|content| Config::from_toml_str(&content).map(|_| content))
+        .with_context(|| format!("Invalid config {}", path.display()))?;
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, content).with_context(|| format!("Cannot write config {}", path.display()))
+}
+
+/// Sets ftp_base of the TOML config, other values are kept, comments and formatting are not
+fn with_ftp_base(content: &str, ftp_base: f64) -> Result<String> {
+    let mut config: toml::value::Table = toml::from_str(content)?;
+    config.insert("ftp_base".to_string(), toml::Value::Float(ftp_base));
+
+    // Value writes plain values ahead of the tables, as TOML requires
+    Ok(toml::to_string(&toml::Value::Table(config))?)
+}
+
+/// Replaces recent workouts of the TOML config. They are kept at the end of the config,
+/// as the array of tables, everything from the first one is dropped.
+fn with_recent(content: &str, recent: &[RecentWorkout]) -> Result<String> {
+    #[derive(Serialize)]
+    struct Recent<'a> {
+        recent: &'a [RecentWorkout],
+    }
+
+    let mut content: String = content
+        .lines()
+        .take_while(|line| line.trim() != "[[recent]]")
+        .map(|line| format!("{line}\n"))
+        .collect();
+
+    if !recent.is_empty() {
+        if !content.is_empty() && !content.ends_with("\n\n") {
+            content.push('\n');
+        }
+        content += &toml::to_string(&Recent { recent })?;
+    }
+
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_overrides_config() {
+        let config = Config::from_toml_str(
+            r#"
+            ftp_base = 250
+            device = "SUITO"
+            power_offset = -5.0
+            units = "imperial"
+            rider_weight = 72.5
+            "#,
+        )
+        .unwrap();
+
+        let cli = Config {
+            ftp_base: Some(260.0),
+            adapter: Some("hci1".to_string()),
+            bike_weight: Some(8.0),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.merge(cli),
+            Config {
+                ftp_base: Some(260.0),
+                device: Some("SUITO".to_string()),
+                adapter: Some("hci1".to_string()),
+                power_offset: Some(-5.0),
+                units: Some(Units::Imperial),
+                rider_weight: Some(72.5),
+                bike_weight: Some(8.0),
+                efficiency: None,
+                workout_dir: None,
+                recent: vec![],
+            }
+        );
+
+        assert!(Config::from_toml_str("ftp_base = 2500").is_err());
+        assert!(Config::from_toml_str("ftp = 250").is_err());
+        assert!(Config::from_toml_str("rider_weight = 7.25").is_err());
+    }
+
+    #[test]
+    fn ftp_base_is_replaced() {
+        let content = "# rider profile\nftp_base = 250 # watts\ndevice = \"SUITO\"\n";
+        assert_eq!(
+            with_ftp_base(content, 262.0).unwrap(),
+            "device = \"SUITO\"\nftp_base = 262.0\n"
+        );
+
+        assert_eq!(with_ftp_base("", 262.0).unwrap(), "ftp_base = 262.0\n");
+        assert_eq!(
+            with_ftp_base("ftp_base_old = 1\n", 262.0).unwrap(),
+            "ftp_base = 262.0\nftp_base_old = 1\n"
+        );
+
+        // Key of the same name in a table is not the rider's FTP
+        let content = "[[recent]]\npath = \"a.zwo\"\nftp_base = 200\n";
+        let config: toml::Value = toml::from_str(&with_ftp_base(content, 262.0).unwrap()).unwrap();
+        assert_eq!(config["ftp_base"].as_float(), Some(262.0));
+        assert_eq!(config["recent"][0]["ftp_base"].as_integer(), Some(200));
+
+        assert!(with_ftp_base("ftp_base = ", 262.0).is_err());
+    }
+
+    #[test]
+    fn recent_workouts_are_capped() {
+        let path =
+            std::env::temp_dir().join(format!("velomania_recent_{}.toml", std::process::id()));
+        std::fs::write(&path, "# rider profile\nftp_base = 250\n").unwrap();
+
+        for day in 0..MAX_RECENT_WORKOUTS + 2 {
+            let workout = RecentWorkout::started_now(PathBuf::from(format!("{day}.zwo")));
+            Config::update_recent(&path, |recent| recent.push(workout)).unwrap();
+        }
+        let recent = Config::update_recent(&path, |recent| {
+            recent.last_mut().unwrap().completed = true;
+        })
+        .unwrap();
+
+        let config = Config::load(Some(&path)).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.recent, recent);
+        assert_eq!(recent.len(), MAX_RECENT_WORKOUTS);
+        assert_eq!(recent[0].path, PathBuf::from("2.zwo"));
+        assert_eq!(
+            recent[MAX_RECENT_WORKOUTS - 1].path,
+            PathBuf::from("11.zwo")
+        );
+        assert!(recent[MAX_RECENT_WORKOUTS - 1].completed);
+        assert!(!recent[0].completed);
+
+        assert_eq!(config.ftp_base, Some(250.0));
+        assert!(content.starts_with("# rider profile\nftp_base = 250\n\n[[recent]]\n"));
+    }
+}
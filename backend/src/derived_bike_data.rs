@@ -0,0 +1,122 @@
+//! Fills in distance and energy for trainers that do not report them,
+//! by integrating instantaneous speed and power over time
+use tokio::{
+    sync::broadcast::{self, Receiver},
+    time::Instant,
+};
+
+use crate::common::{recv_lagging, BIKE_DATA_CHANNEL_CAPACITY};
+use crate::indoor_bike_data_defs::BikeData;
+
+/// Integrates samples, each sample is valid until the next one arrives
+#[derive(Debug, Default)]
+pub struct BikeDataIntegrator {
+    last: Option<(BikeData, Instant)>,
+    /// In meters
+    distance: f64,
+    /// In kJ
+    energy: f64,
+}
+
+impl BikeDataIntegrator {
+    /// Fills in missing distance and energy of the sample received at given time,
+    /// values reported by the trainer are passed as is
+    pub fn integrate(&mut self, mut bike_data: BikeData, at: Instant) -> BikeData {
+        if let Some((last, since)) = &self.last {
+            let secs = at.saturating_duration_since(*since).as_secs_f64();
+
+            if let Some(speed) = last.inst_speed {
+                self.distance += speed / 3.6 * secs;
+            }
+
+            if let Some(power) = last.inst_power {
+                self.energy += f64::from(power) * secs / 1000.0;
+            }
+        }
+
+        self.last = Some((bike_data.clone(), at));
+
+        if bike_data.tot_distance.is_none() {
+            bike_data.tot_distance = Some(self.distance.round() as u32);
+            bike_data.distance_derived = true;
+        }
+
+        if bike_data.tot_energy.is_none() {
+            bike_data.tot_energy = Some(self.energy);
+            bike_data.energy_derived = true;
+        }
+
+        bike_data
+    }
+}
+
+/// Returns stream of bike data with distance and energy always present
+pub fn integrate_bike_data(mut bike_rx: Receiver<BikeData>) -> Receiver<BikeData> {
+    let (integrated_tx, integrated_rx) = broadcast::channel(BIKE_DATA_CHANNEL_CAPACITY);
+
+    tokio::spawn(async move {
+        let mut integrator = BikeDataIntegrator::default();
+
+        while let Some(bike_data) = recv_lagging(&mut bike_rx, "Integrating bike data").await {
+            let bike_data = integrator.integrate(bike_data, Instant::now());
+
+            // Send may fail, if there is no receiver
+            let _ = integrated_tx.send(bike_data);
+        }
+
+        debug!("Integrating bike data leaves");
+    });
+
+    integrated_rx
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn constant_trace_is_integrated() {
+        let started = Instant::now();
+        let mut integrator = BikeDataIntegrator::default();
+
+        let sample = BikeData {
+            inst_power: Some(200),
+            inst_speed: Some(30.0),
+            ..Default::default()
+        };
+
+        let last = (0..=60)
+            .map(|second| {
+                integrator.integrate(sample.clone(), started + Duration::from_secs(second))
+            })
+            .last()
+            .unwrap();
+
+        // 200W for 60s is 12kJ, 30km/h for 60s is 500m
+        assert!((last.tot_energy.unwrap() - 12.0).abs() < 1e-9);
+        assert_eq!(last.tot_distance, Some(500));
+        assert!(last.energy_derived);
+        assert!(last.distance_derived);
+
+        // Distance reported by the trainer takes precedence
+        let reported = BikeData {
+            tot_distance: Some(1234),
+            ..Default::default()
+        };
+        let reported = integrator.integrate(reported, started + Duration::from_secs(61));
+        assert_eq!(reported.tot_distance, Some(1234));
+        assert!(!reported.distance_derived);
+        assert!(reported.energy_derived);
+
+        // So does the energy
+        let reported = BikeData {
+            tot_energy: Some(50.0),
+            ..Default::default()
+        };
+        let reported = integrator.integrate(reported, started + Duration::from_secs(62));
+        assert_eq!(reported.tot_energy, Some(50.0));
+        assert!(!reported.energy_derived);
+    }
+}
@@ -0,0 +1,53 @@
+//! Abstraction over the controlled trainer, keeps the control logic independent from the BLE stack
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::broadcast::Receiver;
+
+use crate::indoor_bike_data_defs::{
+    BikeData, ControlPointNotificationData, MachineStatusOpCode, StopOrPause,
+};
+
+/// Machine accepting control point requests, every request is acknowledged by the notification
+/// received from [`FitnessMachine::subscribe_for_control_point_notifications`].
+#[async_trait]
+pub trait FitnessMachine: Send + Sync {
+    /// Has to be granted, before any other control request is accepted
+    async fn request_control(&self) -> Result<()>;
+
+    /// Sets machine variables to default states, like target power,
+    /// time elapsed, inclination, etc.
+    async fn reset_status(&self) -> Result<()>;
+
+    /// Sets target power in ERG mode
+    async fn set_power(&self, power: i16) -> Result<()>;
+
+    /// Sets target speed in km/h, fails if machine does not support speed target
+    async fn set_speed(&self, speed: f64) -> Result<()>;
+
+    /// Sets resistance level in raw units of the trainer
+    async fn set_resistance(&self, resistance: u8) -> Result<()>;
+
+    /// Sets resistance as percent of the supported range, the same 0-100 scale fits every trainer
+    async fn set_resistance_percent(&self, percent: f64) -> Result<()>;
+
+    /// Switches machine to simulation mode, resistance follows given grade (in percent)
+    async fn set_simulation(&self, grade: f64) -> Result<()>;
+
+    /// Stops or pauses the training, machine stops applying target power/resistance
+    async fn stop_or_pause(&self, what: StopOrPause) -> Result<()>;
+
+    /// Reacts on machine status change, returns number of control point requests written
+    async fn handle_machine_status(&self, status: MachineStatusOpCode) -> Result<usize>;
+
+    async fn disconnect(&self) -> Result<()>;
+
+    /// Get rx endpoint for indoor bike data notifications
+    /// To unsub, simply drop rx
+    fn subscribe_for_indoor_bike_notifications(&self) -> Receiver<BikeData>;
+
+    fn subscribe_for_training_notifications(&self) -> Receiver<String>;
+
+    fn subscribe_for_machine_notifications(&self) -> Receiver<MachineStatusOpCode>;
+
+    fn subscribe_for_control_point_notifications(&self) -> Receiver<ControlPointNotificationData>;
+}
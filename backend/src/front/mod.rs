@@ -1 +1 @@
-pub mod tui;
\ No newline at end of file
+pub mod tui;
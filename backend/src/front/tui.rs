@@ -5,21 +5,28 @@ use std::{
     time::{Duration, Instant},
 };
 
-use termion::raw::IntoRawMode;
+use termion::{color, raw::IntoRawMode};
 use tokio::sync::broadcast::Receiver;
 
 use crate::{
-    common::{duration_to_string, get_power},
-    indoor_bike_data_defs::BikeData,
+    common::{duration_to_string, get_power, Units},
+    indoor_bike_data_defs::{BikeData, MachineStatusOpCode},
+    power_zones::NR_ZONES,
     workout_state::{IntervalState, WorkoutState},
     zwo_workout_file::WorkoutSteps,
 };
 
+/// Cadence within that many RPM from the target is fine
+const CADENCE_TOLERANCE: f64 = 5.0;
+/// Texts coming from the workout file are clamped to that many characters
+const MAX_TEXT_WIDTH: usize = 60;
+
 pub async fn show(
     mut workout_rx: Receiver<WorkoutState>,
     indoor_bike_notif: Option<Receiver<BikeData>>,
     training_notif: Option<Receiver<String>>,
-    machine_status_notif: Option<Receiver<String>>,
+    machine_status_notif: Option<Receiver<MachineStatusOpCode>>,
+    units: Units,
 ) {
     clear_all();
 
@@ -29,11 +36,11 @@ pub async fn show(
         loop {
             tokio::select! {
                 Ok(state) = workout_rx.recv() =>{
-                    handle_workout_state(state);
+                    handle_workout_state(state, units);
                     // TODO: handle workout finished
                 },
                 Ok(bike_data) = indoor_bike_notif.recv() => {
-                    handle_bike_data(bike_data);
+                    handle_bike_data(bike_data, units);
                 }
                 Ok(training_data) = training_notif.recv() => {
                     handle_training_data(training_data);
@@ -52,7 +59,7 @@ pub async fn show(
         loop {
             tokio::select! {
                 Ok(state) = workout_rx.recv() => {
-                    handle_workout_state(state);
+                    handle_workout_state(state, units);
                 }
                 else => {
                     warn!("None of the streams are available, leaving tui task");
@@ -63,9 +70,9 @@ pub async fn show(
     }
 }
 
-fn handle_workout_state(state: WorkoutState) {
+fn handle_workout_state(state: WorkoutState, units: Units) {
     let start_row = 1;
-    let nr_lines = 9;
+    let nr_lines = 10;
     clear(start_row, start_row + (nr_lines - 1));
 
     let next_step_duration = {
@@ -77,20 +84,26 @@ fn handle_workout_state(state: WorkoutState) {
     };
 
     let data_str =
-        format!("== WORKOUT STATE ==\n\rFTP base: {}\n\rcurrent power set: {}W\n\rworkout duration: {} elapsed {} to go {}\n\rstep: {}/{}\n\rcurrent step: {}\n\rstep duration {} elapsed {} to go {}\n\r{}next step: {} for {}\n\r",
-            state.ftp_base, state.current_power_set,
+        format!("== WORKOUT STATE {} ==\n\rFTP base: {}\n\rcurrent power set: {}W / {:.0}%{}{}{}\n\rworkout duration: {} elapsed {} to go {}\n\rstep: {}/{}\n\rcurrent step: {}\n\rstep duration {} elapsed {} to go {}\n\r{}next step: {} for {}\n\rzones: {}\n\r{}",
+            sanitize_for_terminal(&state.workout_name, MAX_TEXT_WIDTH),
+            state.ftp_base, state.current_power_set, state.current_power_ftp_percent,
+            display_power_offset(state.power_offset),
+            display_rest_floor(state.rest_power_floor_active),
+            display_cadence(state.target_cadence, state.cadence_delta),
             duration_to_string(&state.total_workout_duration),
             duration_to_string(&state.workout_elapsed),
-            duration_to_string(&state.total_workout_duration.saturating_sub(state.workout_elapsed)),
+            duration_to_string(&state.remaining),
             state.current_step_number,
             state.total_steps,
-            display_step(state.ftp_base, &Some(state.current_step.step)),
+            display_step(state.ftp_base, &Some(state.current_step.step), units),
             duration_to_string(&state.current_step.duration),
             duration_to_string(&state.current_step.elapsed),
             duration_to_string(&state.current_step.duration.saturating_sub(state.current_step.elapsed)),
-            display_interval(&state.current_interval),
-            display_step(state.ftp_base, &state.next_step),
+            display_interval(&state.current_interval, state.countdown),
+            display_step(state.ftp_base, &state.next_step, units),
             next_step_duration,
+            display_zones(&state.time_in_zones),
+            display_messages(&state.messages),
         );
 
     let stdout = stdout();
@@ -114,7 +127,7 @@ fn handle_training_data(data: String) {
     write!(
         stdout,
         "{}{} Training Data: {}{}",
-        termion::cursor::Goto(1, 21),
+        termion::cursor::Goto(1, 22),
         termion::clear::BeforeCursor,
         data,
         termion::cursor::Goto(1, 1),
@@ -124,13 +137,17 @@ fn handle_training_data(data: String) {
     stdout.flush().unwrap();
 }
 
-fn handle_bike_data(data: BikeData) {
-    let start_row = 10;
+fn handle_bike_data(data: BikeData, units: Units) {
+    let start_row = 11;
     let nr_lines = 11;
     clear(start_row, start_row + (nr_lines - 1));
 
-    let data_str = format!("== BIKE DATA==\n\rTIME: {:?} --> {:?}\n\rDISTANCE {:?}\n\r\n\rPOWER {:?}\n\rSPEED{:?}\n\rCADENCE {:?}\n\rAVG POWER {:?}\n\rAVG SPEED {:?}\n\rAVG CADENCE {:?}\n\rRESISTANCE {:?}",
-    data.elapsed_time, data.remaining_time, data.tot_distance, data.inst_power, data.inst_speed, data.inst_cadence, data.avg_power, data.avg_speed, data.avg_cadence, data.resistance_lvl);
+    let distance = data.tot_distance.map(|meters| units.distance(meters as f64));
+    let speed = data.inst_speed.map(|kmh| units.speed(kmh));
+    let avg_speed = data.avg_speed.map(|kmh| units.speed(kmh));
+
+    let data_str = format!("== BIKE DATA==\n\rTIME: {:?} --> {:?}\n\rDISTANCE {:?}{}\n\rENERGY {:?}{}\n\rPOWER {:?}{}\n\rSPEED{:?}\n\rCADENCE {:?}\n\rAVG POWER {:?}\n\rAVG SPEED {:?}\n\rAVG CADENCE {:?}\n\rRESISTANCE {:?}",
+    data.elapsed_time, data.remaining_time, distance, derived_marker(data.distance_derived), data.tot_energy, derived_marker(data.energy_derived), data.inst_power, estimated_marker(data.power_estimated), speed, data.inst_cadence, data.avg_power, avg_speed, data.avg_cadence, data.resistance_lvl);
     let stdout = stdout();
 
     let mut stdout = stdout.lock().into_raw_mode().unwrap();
@@ -146,8 +163,26 @@ fn handle_bike_data(data: BikeData) {
     stdout.flush().unwrap();
 }
 
-fn handle_machine_status_data(data: String) {
-    let start_row = 22;
+/// Marks values calculated by the application, instead of reported by the trainer
+fn derived_marker(derived: bool) -> &'static str {
+    if derived {
+        " (derived)"
+    } else {
+        ""
+    }
+}
+
+/// Marks values not measured by the trainer, but estimated from other values
+fn estimated_marker(estimated: bool) -> &'static str {
+    if estimated {
+        " (estimated)"
+    } else {
+        ""
+    }
+}
+
+fn handle_machine_status_data(data: MachineStatusOpCode) {
+    let start_row = 23;
     let nr_lines = 1;
     clear(start_row, start_row + (nr_lines - 1));
 
@@ -206,7 +241,7 @@ fn clear_all() {
     stdout.flush().unwrap();
 }
 
-pub fn display_step(ftp_base: f64, step: &Option<WorkoutSteps>) -> String {
+pub fn display_step(ftp_base: f64, step: &Option<WorkoutSteps>, units: Units) -> String {
     if let Some(step) = step {
         match step {
             WorkoutSteps::Warmup(s) => format!(
@@ -231,19 +266,25 @@ pub fn display_step(ftp_base: f64, step: &Option<WorkoutSteps>) -> String {
                 "Intervals: repeat {}, work {}W for {}, rest {}W for {}",
                 s.repeat,
                 get_power(ftp_base, s.on_power),
-                duration_to_string(&Duration::from_secs(s.on_duration)),
+                duration_to_string(&s.on_duration),
                 get_power(ftp_base, s.off_power),
-                duration_to_string(&Duration::from_secs(s.off_duration))
+                duration_to_string(&s.off_duration)
             ),
             WorkoutSteps::FreeRide(_) => "Free Ride".to_string(),
+            WorkoutSteps::SteadySpeed(s) => format!("Steady Speed: {}", units.speed(s.speed)),
         }
     } else {
         "None".to_string()
     }
 }
 
-pub fn display_interval(interval: &Option<IntervalState>) -> String {
-    if let Some(interval) = interval {
+pub fn display_interval(interval: &Option<IntervalState>, countdown: Option<u64>) -> String {
+    let countdown = match countdown {
+        Some(secs) => format!(">>> NEXT IN {secs} <<<\n\r"),
+        None => "".to_string(),
+    };
+
+    let interval = if let Some(interval) = interval {
         let interval_type = if interval.is_work_interval {
             "WORK"
         } else {
@@ -251,13 +292,145 @@ pub fn display_interval(interval: &Option<IntervalState>) -> String {
         };
 
         format!(
-            "interval #{} {} elapsed {}, to go {}\n\r",
+            "interval #{} {} elapsed {}, to go {}\n\r{} reps left: {} @ {}W / {} @ {}W\n\r",
             interval.repetition,
             interval_type,
             duration_to_string(&interval.elapsed),
-            duration_to_string(&interval.duration.saturating_sub(interval.elapsed))
+            duration_to_string(&interval.duration.saturating_sub(interval.elapsed)),
+            interval.remaining_reps,
+            duration_to_string(&interval.on_duration),
+            interval.on_power,
+            duration_to_string(&interval.off_duration),
+            interval.off_power,
         )
     } else {
         "".to_string()
+    };
+
+    interval + &countdown
+}
+
+/// Offset the user put on the workout targets, empty if there is none
+pub fn display_power_offset(offset: i16) -> String {
+    if offset == 0 {
+        return "".to_string();
+    }
+
+    format!(" ({offset:+}W offset)")
+}
+
+/// Marks the target raised to the rest power floor
+pub fn display_rest_floor(active: bool) -> String {
+    if active {
+        " (rest floor)".to_string()
+    } else {
+        "".to_string()
+    }
+}
+
+/// Target cadence, colored by how far the rider is from it: yellow too low, red too high
+pub fn display_cadence(target: Option<f64>, delta: Option<f64>) -> String {
+    let target = match target {
+        Some(target) => target,
+        None => return "".to_string(),
+    };
+
+    let feedback = match delta {
+        Some(delta) if delta < -CADENCE_TOLERANCE => format!(
+            "{}{:+.0}rpm PEDAL FASTER{}",
+            color::Fg(color::Yellow),
+            delta,
+            color::Fg(color::Reset)
+        ),
+        Some(delta) if delta > CADENCE_TOLERANCE => format!(
+            "{}{:+.0}rpm PEDAL SLOWER{}",
+            color::Fg(color::Red),
+            delta,
+            color::Fg(color::Reset)
+        ),
+        Some(delta) => format!(
+            "{}{:+.0}rpm OK{}",
+            color::Fg(color::Green),
+            delta,
+            color::Fg(color::Reset)
+        ),
+        None => "--".to_string(),
+    };
+
+    format!(", cadence target {target:.0}rpm {feedback}")
+}
+
+/// Time in each power zone as a bar, relative to the zone with the most time spent
+pub fn display_zones(time_in_zones: &[Duration; NR_ZONES]) -> String {
+    const BAR_WIDTH: u128 = 5;
+
+    let max = time_in_zones
+        .iter()
+        .max()
+        .unwrap_or(&Duration::ZERO)
+        .as_millis();
+
+    time_in_zones
+        .iter()
+        .enumerate()
+        .map(|(zone, time)| {
+            // Nothing is tracked yet, if max is 0
+            let filled = (time.as_millis() * BAR_WIDTH).checked_div(max).unwrap_or(0) as usize;
+
+            format!(
+                "Z{} [{:<width$}]",
+                zone + 1,
+                "#".repeat(filled),
+                width = BAR_WIDTH as usize
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Text events of the workout shown at the moment, one line each
+pub fn display_messages(messages: &[String]) -> String {
+    messages
+        .iter()
+        .map(|message| format!(">> {}\n\r", sanitize_for_terminal(message, MAX_TEXT_WIDTH)))
+        .collect()
+}
+
+/// Makes text coming from the workout file safe to print on the raw terminal.
+/// Control characters (newlines, escape sequences) would break the layout, they are replaced
+/// by spaces. Text is cut to the given number of characters.
+pub fn sanitize_for_terminal(text: &str, max_width: usize) -> String {
+    let words: Vec<_> = text
+        .split(|c: char| c.is_control() || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .collect();
+    let text = words.join(" ");
+
+    if text.chars().count() <= max_width {
+        return text;
+    }
+
+    let mut clamped: String = text.chars().take(max_width.saturating_sub(1)).collect();
+    clamped.push('…');
+    clamped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workout_texts_are_sanitized() {
+        let description = "Over-unders\n\rwith a \x1b[2J\x1b[31mtwist\t at the end";
+        assert_eq!(
+            sanitize_for_terminal(description, 80),
+            "Over-unders with a [2J [31mtwist at the end"
+        );
+
+        assert_eq!(sanitize_for_terminal("Żółw na rampie", 8), "Żółw na…");
+        assert_eq!(sanitize_for_terminal("Żółw", 4), "Żółw");
+
+        let messages = display_messages(&["line\none".to_string()]);
+        assert_eq!(messages, ">> line one\n\r");
     }
 }
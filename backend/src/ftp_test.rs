@@ -0,0 +1,165 @@
+//! Built-in FTP test protocols. Workout of the test is generated, FTP is estimated from the power
+//! recorded during it, targets of the test are relative to the current FTP.
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::zwo_workout_file::{WorkoutBuilder, WorkoutFile};
+
+const RAMP_STEP_SECS: u64 = 60;
+/// Enough steps to go well above any FTP, rider quits once target cannot be held
+const RAMP_STEPS: usize = 30;
+const RAMP_START: f64 = 0.5;
+const RAMP_INCREMENT: f64 = 0.05;
+/// FTP is that fraction of the best minute of the ramp
+const RAMP_FACTOR: f64 = 0.75;
+
+const TWENTY_MIN_SECS: u64 = 20 * 60;
+/// FTP is that fraction of the best 20 minutes
+const TWENTY_MIN_FACTOR: f64 = 0.95;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FtpTest {
+    /// Target goes up every minute, until the rider cannot hold it
+    Ramp,
+    /// 20 minutes all out, in free ride so the rider paces the effort
+    TwentyMin,
+}
+
+impl FromStr for FtpTest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ramp" => Ok(FtpTest::Ramp),
+            "20min" => Ok(FtpTest::TwentyMin),
+            other => Err(anyhow!(
+                "Unknown FTP test '{other}', expected 'ramp' or '20min'"
+            )),
+        }
+    }
+}
+
+impl FtpTest {
+    /// Workout of the test
+    pub fn workout(&self) -> WorkoutFile {
+        match self {
+            FtpTest::Ramp => {
+                let warmup_secs = 300;
+                let mut workout = WorkoutBuilder::new("Ramp FTP test")
+                    .description("Target goes up every minute, ride until you cannot hold it")
+                    .warmup(warmup_secs, 0.4, RAMP_START)
+                    .text_event(warmup_secs, "Ramp starts, hold on as long as you can");
+
+                for step in 0..RAMP_STEPS {
+                    let power = RAMP_START + RAMP_INCREMENT * step as f64;
+                    workout = workout.steady(RAMP_STEP_SECS, power);
+                }
+
+                workout.cooldown(600, RAMP_START, 0.4).build()
+            }
+            FtpTest::TwentyMin => {
+                let effort_starts = 600 + 300;
+
+                WorkoutBuilder::new("20 minute FTP test")
+                    .description("20 minutes all out, pace yourself to hold the power till the end")
+                    .warmup(600, 0.4, 0.7)
+                    .steady(300, 0.5)
+                    .free_ride(TWENTY_MIN_SECS)
+                    .text_event(effort_starts, "20 minutes all out starts now")
+                    .cooldown(600, 0.55, 0.4)
+                    .build()
+            }
+        }
+    }
+
+    /// Writes the workout of the test to the temporary directory, returns path of the file
+    pub async fn write_workout(&self) -> Result<PathBuf> {
+        let path = std::env::temp_dir().join(format!("velomania_ftp_test_{self:?}.zwo"));
+
+        tokio::fs::write(&path, self.workout().to_zwo_string()?)
+            .await
+            .with_context(|| format!("Cannot write FTP test workout {}", path.display()))?;
+
+        Ok(path)
+    }
+
+    /// FTP estimated from the power recorded every second of the test, None if the test was too short
+    pub fn estimate_ftp(&self, power_per_second: &[f64]) -> Option<f64> {
+        match self {
+            FtpTest::Ramp => {
+                best_average(power_per_second, RAMP_STEP_SECS as usize).map(|p| p * RAMP_FACTOR)
+            }
+            FtpTest::TwentyMin => best_average(power_per_second, TWENTY_MIN_SECS as usize)
+                .map(|p| p * TWENTY_MIN_FACTOR),
+        }
+    }
+}
+
+/// Best average power over given number of seconds
+fn best_average(power_per_second: &[f64], window: usize) -> Option<f64> {
+    if window == 0 || power_per_second.len() < window {
+        return None;
+    }
+
+    let mut sum: f64 = power_per_second[..window].iter().sum();
+    let mut best = sum;
+
+    for (entering, leaving) in power_per_second[window..].iter().zip(power_per_second) {
+        sum += entering - leaving;
+        best = best.max(sum);
+    }
+
+    Some(best / window as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zwo_workout_file::WorkoutSteps;
+
+    #[test]
+    fn ramp_goes_up_every_minute() {
+        let workout = FtpTest::Ramp.workout();
+
+        let ramp: Vec<_> = workout
+            .workout
+            .steps
+            .iter()
+            .filter_map(|step| match step {
+                WorkoutSteps::SteadyState(s) => Some(s),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(ramp.len(), RAMP_STEPS);
+        assert!(ramp
+            .iter()
+            .all(|step| step.duration.as_secs() == RAMP_STEP_SECS));
+        assert!(ramp.windows(2).all(|pair| pair[1].power > pair[0].power));
+        assert!(matches!(
+            workout.workout.steps.front(),
+            Some(WorkoutSteps::Warmup(_))
+        ));
+    }
+
+    #[test]
+    fn ftp_is_estimated_from_the_trace() {
+        // 10 minutes easy, a minute at 320W, then blown up
+        let mut trace = vec![150.0; 600];
+        trace.extend([320.0; 60]);
+        trace.extend([80.0; 30]);
+        assert_eq!(FtpTest::Ramp.estimate_ftp(&trace), Some(240.0));
+
+        // 20 minutes at 250W, with easy spinning around
+        let mut trace = vec![100.0; 300];
+        trace.extend(vec![250.0; 1200]);
+        trace.extend(vec![100.0; 300]);
+        assert_eq!(FtpTest::TwentyMin.estimate_ftp(&trace), Some(237.5));
+
+        // Not even 20 minutes ridden
+        assert_eq!(FtpTest::TwentyMin.estimate_ftp(&[250.0; 600]), None);
+
+        assert!("5min".parse::<FtpTest>().is_err());
+    }
+}
@@ -1,43 +1,206 @@
 //! Implementation of GATTS Fitness Machine of type Indoor Bike
 //! Refer to BLE GATTS Fitness Machine Profile documentation
-use std::pin::Pin;
+use std::{
+    convert::TryFrom,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
 
 use btleplug::{
-    api::{Characteristic, Peripheral as _, ValueNotification, WriteType},
+    api::{Characteristic, WriteType},
     platform::Peripheral,
 };
-use futures::{Stream, StreamExt};
+use futures::StreamExt;
 use num_traits::FromPrimitive;
+use serde::Serialize;
 
 use byteorder::{ByteOrder, LittleEndian};
-use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::{
+    broadcast::{Receiver, Sender},
+    watch,
+};
 use uuid::Uuid;
 
-use crate::ble_client::BleClient;
+use crate::ble_client::{peripheral_name, read_with_retries, BleClient, BleError};
+use crate::ble_device::{NotificationStream, PeripheralLike};
+use crate::common::{
+    kcal_to_kj, recv_lagging, BIKE_DATA_CHANNEL_CAPACITY, CONTROL_CHANNEL_CAPACITY,
+    DEFAULT_EFFICIENCY,
+};
+use crate::fitness_machine::FitnessMachine;
 use crate::indoor_bike_data_defs::{
     BikeData, BikeDataFlags, ControlPointNotificationData, ControlPointOpCode, ControlPointResult,
-    FitnessMachineFeatures, MachineStatusOpCode, Range, TargetSettingFeatures, BIKE_DATA_FLAGS_LEN,
-    CONTROL_POINT, FITNESS_MACHINE_FEATURES_LEN, INDOOR_BIKE_DATA, MACHINE_FEATURE, MACHINE_STATUS,
-    SERVICE_UUID, SUPPORTED_POWER_RANGE, SUPPORTED_RESISTANCE_LEVEL, TARGET_SETTING_FEATURES_LEN,
-    TRAINING_STATUS,
+    FitnessMachineFeatures, MachineStatusOpCode, PlausibilityBounds, PowerCalibration, Range,
+    StopOrPause, TargetSettingFeatures, BIKE_DATA_FLAGS_LEN, CONTROL_POINT,
+    FITNESS_MACHINE_FEATURES_LEN, INDOOR_BIKE_DATA, MACHINE_FEATURE, MACHINE_STATUS, SERVICE_UUID,
+    SIMULATION_CRR, SIMULATION_CW, SUPPORTED_INCLINATION_RANGE, SUPPORTED_POWER_RANGE,
+    SUPPORTED_RESISTANCE_LEVEL, TARGET_SETTING_FEATURES_LEN, TRAINING_STATUS,
 };
 use crate::scalar_converter::ScalarType;
 
 // TODO: it's getting messy, refactor
 
+/// How long to wait for the machine to grant the control, before giving up on the write
+const CONTROL_GRANT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Whether the application is still allowed to control the machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlStatus {
+    /// Machine revoked the control, target settings are ignored
+    Lost,
+    /// Control is requested again, last target is restored
+    Regained,
+}
+
+/// Tracks whether the machine accepts control writes. Control is held once RequestControl
+/// is acknowledged, and is gone once machine status reports it lost.
+#[derive(Debug)]
+pub struct ControlPermission {
+    granted_tx: watch::Sender<bool>,
+}
+
+impl Default for ControlPermission {
+    fn default() -> Self {
+        Self {
+            granted_tx: watch::channel(false).0,
+        }
+    }
+}
+
+impl ControlPermission {
+    pub fn is_granted(&self) -> bool {
+        *self.granted_tx.borrow()
+    }
+
+    pub fn on_control_point(&self, response: &ControlPointNotificationData) {
+        if matches!(response.request_op_code, ControlPointOpCode::RequestControl) {
+            let granted = matches!(response.request_status, ControlPointResult::Success);
+            debug!("Control {}", if granted { "granted" } else { "refused" });
+            self.granted_tx.send_replace(granted);
+        }
+    }
+
+    pub fn on_machine_status(&self, status: &MachineStatusOpCode) {
+        if *status == MachineStatusOpCode::ControlPermissionLost {
+            self.granted_tx.send_replace(false);
+        }
+    }
+
+    /// Returns once control is held, requests it first if needed.
+    /// Fails if request fails, or control is not granted in time.
+    pub async fn acquire<F, Fut>(&self, request: F, timeout: Duration) -> Result<()>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        if self.is_granted() {
+            return Ok(());
+        }
+
+        // Subscribe before the request, so the grant cannot be missed
+        let mut granted_rx = self.granted_tx.subscribe();
+
+        info!("Control is not held, requesting it");
+        request().await?;
+
+        let granted = tokio::time::timeout(timeout, granted_rx.wait_for(|granted| *granted))
+            .await
+            .is_ok_and(|granted| granted.is_ok());
+
+        if !granted {
+            return Err(anyhow!("Machine did not grant the control in {timeout:?}"));
+        }
+
+        Ok(())
+    }
+
+    /// Follows control point responses and machine status, until the machine is gone
+    async fn track(
+        self: Arc<Self>,
+        mut control_point_rx: Receiver<ControlPointNotificationData>,
+        mut machine_status_rx: Receiver<MachineStatusOpCode>,
+    ) {
+        loop {
+            tokio::select! {
+                // Status reporting the loss comes before the response to the request regaining the control
+                biased;
+                Ok(status) = machine_status_rx.recv() => self.on_machine_status(&status),
+                Ok(response) = control_point_rx.recv() => self.on_control_point(&response),
+                else => break,
+            }
+        }
+
+        debug!("Control permission tracking leaves");
+    }
+}
+
+/// What the machine supports, lets UI validate the input and hide unsupported controls
+#[derive(Debug, Clone, Serialize)]
+pub struct TrainerInfo {
+    pub power_range: Range<i16, u16>,
+    pub resistance_range: Range<f64>,
+    /// Grade in percent, None if machine does not report it
+    pub inclination_range: Option<Range<f64>>,
+    pub capabilities: Vec<TargetSettingFeatures>,
+}
+
+impl TrainerInfo {
+    /// Made up capabilities, used when running without the trainer
+    pub fn simulated() -> Self {
+        Self {
+            power_range: Range {
+                min: 0,
+                max: 2000,
+                step: 1,
+            },
+            resistance_range: Range {
+                min: 0.0,
+                max: 100.0,
+                step: 1.0,
+            },
+            inclination_range: Some(Range {
+                min: -20.0,
+                max: 20.0,
+                step: 0.1,
+            }),
+            capabilities: vec![
+                TargetSettingFeatures::Resistance,
+                TargetSettingFeatures::Power,
+                TargetSettingFeatures::IndoorBikeSimulation,
+            ],
+        }
+    }
+}
+
 /// Implementation of FitnessMachine GATTS profile for Indoor Bike
-pub struct IndoorBikeFitnessMachine {
-    client: Peripheral,
+pub struct IndoorBikeFitnessMachine<P: PeripheralLike = Peripheral> {
+    client: P,
     control_point: Characteristic,
     feature: Characteristic,
-    _resistance_range: Range<f64>,
+    resistance_range: Range<f64>,
     power_range: Range<i16, u16>,
+    /// Simulation grade is clamped to it, if machine reports it
+    inclination_range: Option<Range<f64>>,
+    /// Clamp requested power to power_range instead of rejecting it
+    clamp_power: bool,
+    clamping_reported: AtomicBool,
+    /// Last target setting request (power, resistance, speed or simulation), restored after control is regained
+    last_target_request: Mutex<Option<Vec<u8>>>,
     indoor_bike_tx: Sender<BikeData>,
     training_tx: Sender<String>,
-    machine_status_tx: Sender<String>,
+    machine_status_tx: Sender<MachineStatusOpCode>,
     control_point_tx: Sender<ControlPointNotificationData>,
+    control_status_tx: Sender<ControlStatus>,
+    control: Arc<ControlPermission>,
+    /// Machine is controlled by another app, nothing is written to the control point
+    observe_only: bool,
 }
 
 // TODO: this is very first implementation, that is not covering every possible indoor bike machine.
@@ -46,48 +209,85 @@ pub struct IndoorBikeFitnessMachine {
 impl IndoorBikeFitnessMachine {
     pub async fn new(ble: &BleClient) -> Result<IndoorBikeFitnessMachine> {
         info!("Creating Indoor Bike Fitness Machine...");
-        let res = ble.find_service(SERVICE_UUID).await?;
-
-        if res.is_some() {
-            // Client representing the device that exposes fitness machine profile
-            let client = res.unwrap();
-
-            // Get characteristic from the profile
-            let feature = get_characteristic(&client, MACHINE_FEATURE)
-                .ok_or_else(|| anyhow!("feature char not found!"))?;
-
-            let control_point = get_characteristic(&client, CONTROL_POINT)
-                .ok_or_else(|| anyhow!("control point char not found!"))?;
-
-            let (indoor_bike_tx, training_tx, machine_status_tx, control_point_tx) =
-                subscribe_to_characteristics(&client).await?;
-
-            let resistance_range = get_resistance_range(&client).await?;
-            info!("Supported resistance range {resistance_range:?}");
-
-            let power_range = get_power_range(&client).await?;
-            info!("Supported power range {power_range:?}");
-
-            let indoor_bike = IndoorBikeFitnessMachine {
-                client,
-                control_point,
-                feature,
-                _resistance_range: resistance_range,
-                power_range,
-                indoor_bike_tx,
-                training_tx,
-                machine_status_tx,
-                control_point_tx,
-            };
-
-            // TODO: we should wait for control point indication that this operation succeeded
-            // before doing any other writes
-            indoor_bike.request_control().await?;
-
-            Ok(indoor_bike)
-        } else {
-            Err(anyhow!("Fitness machine device not found"))
-        }
+        // Client representing the device that exposes fitness machine profile
+        let client = ble.find_service(SERVICE_UUID).await?;
+
+        Self::with_peripheral(client).await
+    }
+
+    /// Machine is only observed, see [`IndoorBikeFitnessMachine::observer_with_peripheral`]
+    pub async fn new_observer(ble: &BleClient) -> Result<IndoorBikeFitnessMachine> {
+        info!("Creating Indoor Bike Fitness Machine, observing only...");
+        let client = ble.find_service(SERVICE_UUID).await?;
+
+        Self::observer_with_peripheral(client).await
+    }
+}
+
+impl<P: PeripheralLike> IndoorBikeFitnessMachine<P> {
+    /// Builds the machine on top of already connected peripheral
+    pub async fn with_peripheral(client: P) -> Result<Self> {
+        Self::connect(client, false).await
+    }
+
+    /// Builds the machine, which is controlled by another app. Notifications are received as usual,
+    /// but the control is never requested, and control point writes are skipped.
+    pub async fn observer_with_peripheral(client: P) -> Result<Self> {
+        Self::connect(client, true).await
+    }
+
+    async fn connect(client: P, observe_only: bool) -> Result<Self> {
+        // Get characteristic from the profile
+        let feature = get_characteristic(&client, MACHINE_FEATURE)?;
+        let control_point = get_characteristic(&client, CONTROL_POINT)?;
+
+        let (indoor_bike_tx, training_tx, machine_status_tx, control_point_tx) =
+            subscribe_to_characteristics(&client).await?;
+
+        let resistance_range = get_resistance_range(&client).await?;
+        info!("Supported resistance range {resistance_range:?}");
+
+        let power_range = get_power_range(&client).await?;
+        info!("Supported power range {power_range:?}");
+
+        let inclination_range = get_inclination_range(&client).await?;
+        info!("Supported inclination range {inclination_range:?}");
+
+        let control = Arc::new(ControlPermission::default());
+        tokio::spawn(
+            control
+                .clone()
+                .track(control_point_tx.subscribe(), machine_status_tx.subscribe()),
+        );
+
+        let indoor_bike = IndoorBikeFitnessMachine {
+            client,
+            control_point,
+            feature,
+            resistance_range,
+            power_range,
+            inclination_range,
+            clamp_power: false,
+            clamping_reported: AtomicBool::new(false),
+            last_target_request: Mutex::new(None),
+            indoor_bike_tx,
+            training_tx,
+            machine_status_tx,
+            control_point_tx,
+            control_status_tx: tokio::sync::broadcast::channel(CONTROL_CHANNEL_CAPACITY).0,
+            control,
+            observe_only,
+        };
+
+        // Writes wait for the control anyway, do not fail if machine is slow to grant it
+        indoor_bike.ensure_control().await;
+
+        Ok(indoor_bike)
+    }
+
+    /// If enabled, power outside of supported range is clamped to it, otherwise setting it fails
+    pub fn set_clamp_power(&mut self, clamp_power: bool) {
+        self.clamp_power = clamp_power;
     }
 
     /// Enumerate accessible characteristics for Fitness profile
@@ -115,152 +315,426 @@ impl IndoorBikeFitnessMachine {
         Ok(())
     }
 
-    pub(crate) async fn disconnect(&self) -> Result<()> {
-        let name = self.client.properties().await?.unwrap().local_name.unwrap();
+    /// Get supported features for machine
+    pub async fn get_features(&self) -> Result<()> {
+        let (fitness_features, target_setting_features) = self.read_features().await?;
+
+        info!("Fitness features supported:");
+        for feature in fitness_features_list(fitness_features) {
+            info!(" {feature}");
+        }
+
+        info!("Target setting features supported:");
+        for feature in target_settings(target_setting_features) {
+            info!("  {feature}");
+        }
+
+        Ok(())
+    }
+
+    /// Supported ranges and targets of the machine
+    pub async fn trainer_info(&self) -> Result<TrainerInfo> {
+        let (_, target_setting_features) = self.read_features().await?;
+
+        Ok(TrainerInfo {
+            power_range: self.power_range.clone(),
+            resistance_range: self.resistance_range.clone(),
+            inclination_range: self.inclination_range.clone(),
+            capabilities: target_settings(target_setting_features),
+        })
+    }
+
+    /// Returns fitness machine features and target setting features bit fields
+    async fn read_features(&self) -> Result<(u32, u32), BleError> {
+        let raw = read_with_retries(&self.client, &self.feature).await?;
+
+        trace!("Feature raw response {raw:?}");
+
+        parse_features(&raw)
+    }
+
+    /// Get rx endpoint for control lost/regained events, useful for UI to warn the user
+    pub fn subscribe_for_control_status(&self) -> Receiver<ControlStatus> {
+        self.control_status_tx.subscribe()
+    }
+
+    /// Requests the control and waits for it, if it's not held already.
+    /// On failure write is done anyway, machine is going to reject it, which is acknowledged as usual.
+    async fn ensure_control(&self) {
+        if self.observe_only {
+            return;
+        }
+
+        if let Err(e) = self
+            .control
+            .acquire(|| self.request_control(), CONTROL_GRANT_TIMEOUT)
+            .await
+        {
+            warn!("{e:?}, writing anyway");
+        }
+    }
+
+    fn remember_target(&self, request: &[u8]) {
+        *self.last_target_request.lock().unwrap() = Some(request.to_vec());
+    }
+
+    async fn write_request(&self, request: &[u8]) -> Result<(), BleError> {
+        if self.observe_only {
+            debug!("Observing only, request {:#04x} is not written", request[0]);
+            return Ok(());
+        }
+
+        self.client
+            .write(&self.control_point, request, WriteType::WithResponse)
+            .await
+            .map_err(|source| BleError::WriteFailed {
+                op_code: request[0],
+                source,
+            })
+    }
+}
+
+#[async_trait]
+impl<P: PeripheralLike> FitnessMachine for IndoorBikeFitnessMachine<P> {
+    async fn disconnect(&self) -> Result<()> {
+        let name = peripheral_name(self.client.properties().await?.as_ref());
         info!("Disconnecting from {name}");
         self.client.disconnect().await?;
 
         Ok(())
     }
 
-    /// Get supported features for machine
-    pub async fn get_features(&self) -> Result<()> {
-        let raw = self.client.read(&self.feature).await?;
+    /// The control permission remains valid until the connection is terminated, the notification of the Fitness
+    /// Machine Status is sent with the value set to Control Permission Lost
+    async fn request_control(&self) -> Result<()> {
+        let data: [u8; 1] = [ControlPointOpCode::RequestControl as u8];
+        self.write_request(&data).await?;
+
+        Ok(())
+    }
+
+    /// Sets machine variables to default states, like target power,
+    /// time elapsed, inclination, etc.
+    async fn reset_status(&self) -> Result<()> {
+        let data: [u8; 1] = [ControlPointOpCode::Reset as u8];
+
+        match self.write_request(&data).await {
+            Ok(_) => debug!("Send reset status succeeded"),
+            Err(e) => error!("Failed to send reset command: '{e:?}', continuing"),
+        }
+
+        Ok(())
+    }
+
+    async fn set_power(&self, power: i16) -> Result<()> {
+        let power = limit_power(
+            &self.power_range,
+            self.clamp_power,
+            &self.clamping_reported,
+            power,
+        )?;
+
+        let data = set_power_request(power);
+        self.remember_target(&data);
+        self.ensure_control().await;
 
-        if raw.len() != 8 {
+        match self.write_request(&data).await {
+            Ok(_) => debug!("Set power succeeded"),
+            Err(e) => error!("Failed to set power: '{e:?}', continuing"),
+        }
+
+        Ok(())
+    }
+
+    /// Speed target is optional, machines supporting power target only reject it
+    async fn set_speed(&self, speed: f64) -> Result<()> {
+        let (_, target_setting_features) = self.read_features().await?;
+
+        if !target_settings(target_setting_features).contains(&TargetSettingFeatures::SpeedTarget) {
+            return Err(anyhow!("Machine does not support speed target"));
+        }
+
+        let data = set_speed_request(speed);
+        self.remember_target(&data);
+        self.ensure_control().await;
+
+        match self.write_request(&data).await {
+            Ok(_) => debug!("Set speed succeeded"),
+            Err(e) => error!("Failed to set speed: '{e:?}', continuing"),
+        }
+
+        Ok(())
+    }
+
+    /// Resistance level is in raw units of the trainer, its meaning depends on the trainer
+    async fn set_resistance(&self, resistance: u8) -> Result<()> {
+        let levels = resistance_levels(&self.resistance_range);
+
+        if !levels.in_range(f64::from(resistance)) {
             return Err(anyhow!(
-                "Invalid data received from feature characteristic {raw:?}"
+                "Resistance {resistance} outside valid range {}..={}",
+                levels.min,
+                levels.max
             ));
         }
 
-        trace!("Feature raw response {raw:?}");
-        let fitness_features = LittleEndian::read_u32(&raw[0..4]);
+        let data = set_resistance_request(resistance);
+        self.remember_target(&data);
+        self.ensure_control().await;
 
-        info!("Fitness features supported:");
-        for i in 0..FITNESS_MACHINE_FEATURES_LEN {
-            let feature = 1 << i;
-            if feature & fitness_features != 0 {
-                info!(" {:?}", FitnessMachineFeatures::from_u32(feature));
-            }
+        match self.write_request(&data).await {
+            Ok(_) => debug!("Set resistance succeeded"),
+            Err(e) => error!("Failed to set resistance: '{e:?}', continuing"),
         }
 
-        let target_setting_features = LittleEndian::read_u32(&raw[4..]);
+        Ok(())
+    }
+
+    /// Level is rounded to the nearest step of the range, and to the whole level the request carries
+    async fn set_resistance_percent(&self, percent: f64) -> Result<()> {
+        let level = resistance_levels(&self.resistance_range)
+            .at_percent(percent)
+            .round();
+        let level = u8::try_from(level as i64)
+            .map_err(|_| anyhow!("Resistance level {level} does not fit the request"))?;
 
-        info!("Target setting features supported:");
-        for i in 0..TARGET_SETTING_FEATURES_LEN {
-            let feature = 1 << i;
-            if feature & target_setting_features != 0 {
-                info!("  {:?}", TargetSettingFeatures::from_u32(feature));
+        self.set_resistance(level).await
+    }
+
+    /// Switches machine to simulation mode, resistance follows given grade (in percent)
+    /// instead of the target power. Setting target power switches machine back to ERG mode.
+    async fn set_simulation(&self, grade: f64) -> Result<()> {
+        let grade = match &self.inclination_range {
+            Some(range) if !range.in_range(grade) => {
+                let clamped = range.clamp(grade);
+                warn!("Grade {grade}% is outside of supported range {range:?}, using {clamped}%");
+                clamped
             }
+            _ => grade,
+        };
+
+        let data = simulation_request(grade);
+        self.remember_target(&data);
+        self.ensure_control().await;
+
+        match self.write_request(&data).await {
+            Ok(_) => debug!("Set simulation succeeded"),
+            Err(e) => error!("Failed to set simulation: '{e:?}', continuing"),
         }
-        // TODO: return struct?
+
         Ok(())
     }
 
+    /// Stops or pauses the training, machine stops applying target power/resistance.
+    /// Unlike other control writes, error is propagated, so caller knows the machine may be still loaded
+    async fn stop_or_pause(&self, what: StopOrPause) -> Result<()> {
+        let data = stop_or_pause_request(what);
+
+        self.write_request(&data).await?;
+
+        Ok(())
+    }
+
+    /// Reacts on machine status change. Once control permission is lost, all control writes are ignored
+    /// by the machine, so request control again, and restore last target.
+    /// Returns number of control point writes done, each of them is going to be acknowledged.
+    /// Control request is not counted, its acknowledgement goes to the control permission tracking.
+    async fn handle_machine_status(&self, status: MachineStatusOpCode) -> Result<usize> {
+        // Control belongs to another app
+        if self.observe_only {
+            return Ok(0);
+        }
+
+        let last_target_request = self.last_target_request.lock().unwrap().clone();
+        let requests = status_reaction(status, last_target_request);
+
+        if requests.is_empty() {
+            return Ok(0);
+        }
+
+        warn!("Control permission lost, requesting control again");
+        let _ = self.control_status_tx.send(ControlStatus::Lost);
+
+        for request in &requests {
+            self.write_request(request)
+                .await
+                .context("while regaining control")?;
+        }
+
+        info!("Control regained");
+        let _ = self.control_status_tx.send(ControlStatus::Regained);
+
+        Ok(requests
+            .iter()
+            .filter(|request| request[0] != ControlPointOpCode::RequestControl as u8)
+            .count())
+    }
+
     /// Get rx endpoint for status notifications
     /// To unsub, simply drop rx
-    pub fn subscribe_for_indoor_bike_notifications(&self) -> Receiver<BikeData> {
+    fn subscribe_for_indoor_bike_notifications(&self) -> Receiver<BikeData> {
         self.indoor_bike_tx.subscribe()
     }
 
-    pub fn subscribe_for_training_notifications(&self) -> Receiver<String> {
+    fn subscribe_for_training_notifications(&self) -> Receiver<String> {
         self.training_tx.subscribe()
     }
 
-    // TODO: let it be a string for now?
-    pub fn subscribe_for_machine_notifications(&self) -> Receiver<String> {
+    fn subscribe_for_machine_notifications(&self) -> Receiver<MachineStatusOpCode> {
         self.machine_status_tx.subscribe()
     }
 
-    pub fn subscribe_for_control_point_notifications(
-        &self,
-    ) -> Receiver<ControlPointNotificationData> {
+    fn subscribe_for_control_point_notifications(&self) -> Receiver<ControlPointNotificationData> {
         self.control_point_tx.subscribe()
     }
+}
 
-    pub async fn set_resistance(&self, _resistance: u8) -> Result<()> {
-        // if !self.resistance_range.in_range(resistance) {
-        //     return Err(anyhow!("Resistance {resistance} outside valid range {:?}", self.resistance_range));
-        // }
-        // let data: [u8; 1] = [ControlPoint::RequestControl as u8];
-        // self.client
-        //     .write(&self.control_point, &data, WriteType::WithResponse)
-        //     .await?;
-
-        // let data : [u8; 2] = [ControlPoint::SetTargetResistance as u8, resistance];
+/// Decodes fitness machine features bit field
+pub(crate) fn fitness_features_list(fitness_features: u32) -> Vec<FitnessMachineFeatures> {
+    (0..FITNESS_MACHINE_FEATURES_LEN)
+        .map(|i| 1 << i)
+        .filter(|feature| feature & fitness_features != 0)
+        .filter_map(FitnessMachineFeatures::from_u32)
+        .collect()
+}
 
-        // self.client
-        //     .write(&self.control_point, &data, WriteType::WithResponse)
-        //     .await?;
+/// Decodes target setting features bit field
+pub(crate) fn target_settings(target_setting_features: u32) -> Vec<TargetSettingFeatures> {
+    (0..TARGET_SETTING_FEATURES_LEN)
+        .map(|i| 1 << i)
+        .filter(|feature| feature & target_setting_features != 0)
+        .filter_map(TargetSettingFeatures::from_u32)
+        .collect()
+}
 
-        // Ok(())
+/// Applies calibration to the power measured by the machine, returns stream of calibrated bike data
+pub fn calibrate_bike_data(
+    mut bike_rx: Receiver<BikeData>,
+    calibration: PowerCalibration,
+) -> Receiver<BikeData> {
+    let (calibrated_tx, calibrated_rx) =
+        tokio::sync::broadcast::channel(BIKE_DATA_CHANNEL_CAPACITY);
 
-        todo!()
-    }
+    tokio::spawn(async move {
+        while let Some(mut bike_data) = recv_lagging(&mut bike_rx, "Calibrating bike data").await {
+            calibration.apply_to(&mut bike_data);
 
-    pub async fn set_power(&self, power: i16) -> Result<()> {
-        if !self.power_range.in_range(power) {
-            return Err(anyhow!(
-                "Resistance {power} outside valid range {:?}",
-                self.power_range
-            ));
+            // Send may fail, if there is no receiver
+            let _ = calibrated_tx.send(bike_data);
         }
 
-        let mut data: [u8; 3] = [ControlPointOpCode::SetTargetPower as u8, 0, 0];
+        debug!("Calibrating bike data leaves");
+    });
 
-        LittleEndian::write_i16(&mut data[1..], power);
+    calibrated_rx
+}
 
-        match self
-            .client
-            .write(&self.control_point, &data, WriteType::WithResponse)
-            .await
-            .context("while setting power")
-        {
-            Ok(_) => debug!("Set power succeeded"),
-            Err(e) => error!("Failed to set power: '{e:?}', continuing"),
-        }
+/// Turns control point indication into error, if the request was not successful
+pub fn check_response(response: &ControlPointNotificationData) -> Result<(), BleError> {
+    match response.request_status {
+        ControlPointResult::Success => Ok(()),
+        ref rejected => Err(BleError::ControlRejected(rejected.clone())),
+    }
+}
 
-        Ok(())
+/// Returns power that can be set on the machine.
+/// Out of range power is an error, unless clamping is enabled, then the closest valid value is used.
+/// Clamping is reported only once, workout would otherwise do it on every tick.
+fn limit_power(
+    power_range: &Range<i16, u16>,
+    clamp_power: bool,
+    clamping_reported: &AtomicBool,
+    power: i16,
+) -> Result<i16> {
+    if power_range.in_range(power) {
+        return Ok(power);
     }
 
-    /// Sets machine variables to default states, like target power,
-    /// time elapsed, inclination, etc.
-    pub async fn reset_status(&self) -> Result<()> {
-        let data: [u8; 1] = [ControlPointOpCode::Reset as u8];
+    if !clamp_power {
+        return Err(anyhow!("Power {power} outside valid range {power_range:?}"));
+    }
 
-        match self
-            .client
-            .write(&self.control_point, &data, WriteType::WithResponse)
-            .await
-            .context("while setting power")
-        {
-            Ok(_) => debug!("Send reset status succeeded"),
-            Err(e) => error!("Failed to send reset command: '{e:?}', continuing"),
-        }
+    let clamped = power_range.clamp(power);
 
-        Ok(())
+    if !clamping_reported.swap(true, Ordering::Relaxed) {
+        warn!("Power {power}W outside valid range {power_range:?}, clamping to {clamped}W");
     }
 
-    /// The control permission remains valid until the connection is terminated, the notification of the Fitness
-    /// Machine Status is sent with the value set to Control Permission Lost
-    async fn request_control(&self) -> Result<()> {
-        let data: [u8; 1] = [ControlPointOpCode::RequestControl as u8];
-        self.client
-            .write(&self.control_point, &data, WriteType::WithResponse)
-            .await
-            .context("while sending request control")?;
+    Ok(clamped)
+}
 
-        Ok(())
+fn set_power_request(power: i16) -> [u8; 3] {
+    let mut data: [u8; 3] = [ControlPointOpCode::SetTargetPower as u8, 0, 0];
+
+    LittleEndian::write_i16(&mut data[1..], power);
+
+    data
+}
+
+/// Resistance level, unitless
+/// DOCS: FTMS_v1.0 4.16.2.5
+fn set_resistance_request(resistance: u8) -> [u8; 2] {
+    [ControlPointOpCode::SetTargetResistance as u8, resistance]
+}
+
+/// Target speed in km/h, resolution 0.01 km/h
+/// DOCS: FTMS_v1.0 4.16.2.4
+fn set_speed_request(speed: f64) -> [u8; 3] {
+    let mut data: [u8; 3] = [ControlPointOpCode::SetTargetSpeed as u8, 0, 0];
+
+    LittleEndian::write_u16(&mut data[1..], (speed * 100.0).round() as u16);
+
+    data
+}
+
+/// Control point requests needed to react on given machine status
+fn status_reaction(
+    status: MachineStatusOpCode,
+    last_target_request: Option<Vec<u8>>,
+) -> Vec<Vec<u8>> {
+    match status {
+        MachineStatusOpCode::ControlPermissionLost => {
+            let mut requests = vec![vec![ControlPointOpCode::RequestControl as u8]];
+            requests.extend(last_target_request);
+
+            requests
+        }
+        _ => vec![],
     }
 }
 
+/// Indoor Bike Simulation parameters: wind speed, grade, crr, cw
+/// DOCS: FTMS_v1.0 4.16.2.18
+fn simulation_request(grade: f64) -> [u8; 7] {
+    let mut data = [
+        ControlPointOpCode::IndoorBikeSimulation as u8,
+        0,
+        0,
+        0,
+        0,
+        SIMULATION_CRR,
+        SIMULATION_CW,
+    ];
+
+    // No wind, resolution 0.001 m/s
+    LittleEndian::write_i16(&mut data[1..3], 0);
+    // Resolution 0.01%
+    LittleEndian::write_i16(&mut data[3..5], (grade * 100.0).round() as i16);
+
+    data
+}
+
+fn stop_or_pause_request(what: StopOrPause) -> [u8; 2] {
+    [ControlPointOpCode::StopOrPause as u8, what as u8]
+}
+
 /// Subscribe to all characteristics, and provide channels to access the data
 async fn subscribe_to_characteristics(
-    client: &Peripheral,
+    client: &impl PeripheralLike,
 ) -> Result<(
     Sender<BikeData>,
     Sender<String>,
-    Sender<String>,
+    Sender<MachineStatusOpCode>,
     Sender<ControlPointNotificationData>,
 )> {
     for characteristic_uuid in [
@@ -269,19 +743,33 @@ async fn subscribe_to_characteristics(
         MACHINE_STATUS,
         CONTROL_POINT,
     ] {
-        // TODO: now any of these is a fatal error, maybe don't be that picky
-        let characteristic = get_characteristic(client, characteristic_uuid)
-            .ok_or_else(|| anyhow!("{characteristic_uuid:? }char not found!"))?;
+        // Bike data and control point are needed to drive the workout, the rest is nice to have
+        let mandatory = matches!(characteristic_uuid, INDOOR_BIKE_DATA | CONTROL_POINT);
+
         // Enable listening on notification's
-        client.subscribe(&characteristic).await?;
+        let subscribed = match get_characteristic(client, characteristic_uuid) {
+            Ok(characteristic) => client
+                .subscribe(&characteristic)
+                .await
+                .map_err(anyhow::Error::from),
+            Err(e) => Err(e.into()),
+        };
+
+        match subscribed {
+            Ok(()) => {}
+            Err(e) if mandatory => return Err(e),
+            Err(e) => {
+                warn!("Optional characteristic {characteristic_uuid} not available, skipping: {e}")
+            }
+        }
     }
 
     // Create a broadcast channel for notification characteristic.
     // subscribers will receive rx endpoint of that channel
-    let (indoor_tx, _) = tokio::sync::broadcast::channel(16);
-    let (training_tx, _) = tokio::sync::broadcast::channel(16);
-    let (machine_status_tx, _) = tokio::sync::broadcast::channel(16);
-    let (control_point_tx, _) = tokio::sync::broadcast::channel(16);
+    let (indoor_tx, _) = tokio::sync::broadcast::channel(BIKE_DATA_CHANNEL_CAPACITY);
+    let (training_tx, _) = tokio::sync::broadcast::channel(CONTROL_CHANNEL_CAPACITY);
+    let (machine_status_tx, _) = tokio::sync::broadcast::channel(CONTROL_CHANNEL_CAPACITY);
+    let (control_point_tx, _) = tokio::sync::broadcast::channel(CONTROL_CHANNEL_CAPACITY);
 
     // Create a stream for incoming notifications
     let notifications = client.notifications().await?;
@@ -294,21 +782,49 @@ async fn subscribe_to_characteristics(
         training_tx.clone(),
         machine_status_tx.clone(),
         control_point_tx.clone(),
+        PlausibilityBounds::default(),
     ));
     Ok((indoor_tx, training_tx, machine_status_tx, control_point_tx))
 }
 
 /// Gets range of valid power setting, data format defined in GATT_Specification_Supplement_v5
-async fn get_power_range(client: &Peripheral) -> Result<Range<i16, u16>> {
-    let power = get_characteristic(client, SUPPORTED_POWER_RANGE)
-        .ok_or_else(|| anyhow!("supported power level char not found!"))?;
+async fn get_power_range(client: &impl PeripheralLike) -> Result<Range<i16, u16>, BleError> {
+    let power = get_characteristic(client, SUPPORTED_POWER_RANGE)?;
+
+    let raw = read_with_retries(client, &power).await?;
 
-    let raw = client.read(&power).await?;
+    parse_power_range(&raw)
+}
+
+/// Fitness machine features and target setting features, 32 bits each.
+/// Some machines pad the characteristic, only the first 8 bytes are used then.
+pub(crate) fn parse_features(raw: &[u8]) -> Result<(u32, u32), BleError> {
+    if raw.len() < 8 {
+        return Err(BleError::InvalidData {
+            uuid: MACHINE_FEATURE,
+            data: raw.to_vec(),
+        });
+    }
 
+    if raw.len() > 8 {
+        warn!(
+            "Feature characteristic has {} bytes instead of 8, extra bytes are ignored",
+            raw.len()
+        );
+    }
+
+    Ok((
+        LittleEndian::read_u32(&raw[0..4]),
+        LittleEndian::read_u32(&raw[4..8]),
+    ))
+}
+
+pub(crate) fn parse_power_range(raw: &[u8]) -> Result<Range<i16, u16>, BleError> {
     if raw.len() != 6 {
-        return Err(anyhow!(
-            "Invalid data format in supported power level char!"
-        ));
+        return Err(BleError::InvalidData {
+            uuid: SUPPORTED_POWER_RANGE,
+            data: raw.to_vec(),
+        });
     }
 
     let min = LittleEndian::read_i16(&raw[0..2]);
@@ -318,13 +834,62 @@ async fn get_power_range(client: &Peripheral) -> Result<Range<i16, u16>> {
     Ok(Range { min, max, step })
 }
 
+/// Reads supported inclination range, None if machine does not have the characteristic,
+/// it's mandatory only for machines supporting the inclination target
+async fn get_inclination_range(
+    client: &impl PeripheralLike,
+) -> Result<Option<Range<f64>>, BleError> {
+    let inclination = match get_characteristic(client, SUPPORTED_INCLINATION_RANGE) {
+        Ok(inclination) => inclination,
+        Err(BleError::CharacteristicMissing(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let raw = read_with_retries(client, &inclination).await?;
+
+    parse_inclination_range(&raw).map(Some)
+}
+
+/// Minimum and maximum as sint16, step as uint16, all in 0.1% of grade
+pub(crate) fn parse_inclination_range(raw: &[u8]) -> Result<Range<f64>, BleError> {
+    if raw.len() != 6 {
+        return Err(BleError::InvalidData {
+            uuid: SUPPORTED_INCLINATION_RANGE,
+            data: raw.to_vec(),
+        });
+    }
+
+    let min = LittleEndian::read_i16(&raw[0..2]);
+    let max = LittleEndian::read_i16(&raw[2..4]);
+    let step = LittleEndian::read_u16(&raw[4..6]);
+
+    Ok(Range {
+        min: f64::from(min) / 10.0,
+        max: f64::from(max) / 10.0,
+        step: f64::from(step) / 10.0,
+    })
+}
+
+/// Supported resistance range is decoded with that decimal exponent
+const RESISTANCE_DEC_EXP: i32 = 1;
+
 /// Reads supported resistance level
 /// field description in GATT_Specification_Supplement
-async fn get_resistance_range(client: &Peripheral) -> Result<Range<f64>> {
-    let resistance = get_characteristic(client, SUPPORTED_RESISTANCE_LEVEL)
-        .ok_or_else(|| anyhow!("supported resistance level char not found!"))?;
+async fn get_resistance_range(client: &impl PeripheralLike) -> Result<Range<f64>, BleError> {
+    let resistance = get_characteristic(client, SUPPORTED_RESISTANCE_LEVEL)?;
 
-    let raw = client.read(&resistance).await?;
+    let raw = read_with_retries(client, &resistance).await?;
+
+    parse_resistance_range(&raw)
+}
+
+pub(crate) fn parse_resistance_range(raw: &[u8]) -> Result<Range<f64>, BleError> {
+    if raw.len() != 6 {
+        return Err(BleError::InvalidData {
+            uuid: SUPPORTED_RESISTANCE_LEVEL,
+            data: raw.to_vec(),
+        });
+    }
 
     // TODO: docs claim there should be 3 u8's but that's not true :/
     let min = LittleEndian::read_i16(&raw[0..2]);
@@ -332,13 +897,9 @@ async fn get_resistance_range(client: &Peripheral) -> Result<Range<f64>> {
     // TODO: should be u16 probably
     let step = LittleEndian::read_i16(&raw[4..6]);
 
-    if raw.len() != 6 {
-        return Err(anyhow!(
-            "Invalid data format in supported resistance level char!"
-        ));
-    }
-
-    let conv = ScalarType::new().with_multiplier(1).with_dec_exp(1);
+    let conv = ScalarType::new()
+        .with_multiplier(1)
+        .with_dec_exp(RESISTANCE_DEC_EXP);
     Ok(Range {
         min: conv.to_scalar(min),
         max: conv.to_scalar(max),
@@ -346,12 +907,24 @@ async fn get_resistance_range(client: &Peripheral) -> Result<Range<f64>> {
     })
 }
 
+/// Supported resistance range in raw units, the ones the control point takes
+pub fn resistance_levels(resistance_range: &Range<f64>) -> Range<f64> {
+    let scale = 10.0f64.powi(RESISTANCE_DEC_EXP);
+
+    Range {
+        min: resistance_range.min / scale,
+        max: resistance_range.max / scale,
+        step: resistance_range.step / scale,
+    }
+}
+
 async fn handle_notifications(
-    mut notifications: Pin<Box<dyn Stream<Item = ValueNotification> + Send>>,
+    mut notifications: NotificationStream,
     indoor_tx: Sender<BikeData>,
     _training_tx: Sender<String>,
-    machine_status_tx: Sender<String>,
+    machine_status_tx: Sender<MachineStatusOpCode>,
     control_point_tx: Sender<ControlPointNotificationData>,
+    bounds: PlausibilityBounds,
 ) {
     // TODO: when it returns none?
     while let Some(data) = notifications.next().await {
@@ -360,14 +933,15 @@ async fn handle_notifications(
                 trace!("Got notification from MACHINE_STATUS: {:?}", data.value);
                 let status_update = handle_machine_status_notification(&data.value);
 
-                let _ = machine_status_tx.send(format!("{status_update:?}"));
+                let _ = machine_status_tx.send(status_update);
             }
             INDOOR_BIKE_DATA => {
                 trace!("Got notification from INDOOR_BIKE_DATA: {:?}", data.value);
-                let parsed_data = handle_bike_data_notification(&data.value);
-
-                // Send may fail, if there is no receiver
-                let _ = indoor_tx.send(parsed_data);
+                // Malformed frame is dropped, the next one is likely fine
+                if let Some(parsed_data) = handle_plausible_bike_data(&data.value, &bounds) {
+                    // Send may fail, if there is no receiver
+                    let _ = indoor_tx.send(parsed_data);
+                }
             }
             TRAINING_STATUS => {
                 trace!("Got notification from TRAINING_STATUS: {:?}", data.value);
@@ -406,14 +980,86 @@ fn handle_machine_status_notification(raw_data: &[u8]) -> MachineStatusOpCode {
     let op_code = raw_data[0];
 
     let parsed_op_code = MachineStatusOpCode::from_u8(op_code).unwrap();
-    debug!("Got Machine Status Notification with opcode {parsed_op_code:?}");
+    debug!("Got Machine Status Notification: {parsed_op_code}");
 
     parsed_op_code
 }
 
-/// Handle raw stream from notification into BikeData
-fn handle_bike_data_notification(raw_data: &[u8]) -> BikeData {
-    let flags = LittleEndian::read_u16(&raw_data[0..]);
+/// Instantaneous Speed has no flag of its own, bit 0 (More Data) tells whether the record is split
+/// into several notifications. Speed is present only when More Data is clear, that is in the last one.
+/// DOCS: FTMS_v1.0 4.9.1.1
+fn inst_speed_present(flags: u16) -> bool {
+    flags & BikeDataFlags::MoreData as u16 == 0
+}
+
+/// Other fields are present when their bit is set, returned in the order they follow in the record
+fn present_fields(flags: u16) -> impl Iterator<Item = BikeDataFlags> {
+    (1..BIKE_DATA_FLAGS_LEN)
+        .map(|i| 1 << i)
+        .filter(move |field| flags & field != 0)
+        .filter_map(BikeDataFlags::from_u16)
+}
+
+/// Bytes taken by the field in the Indoor Bike Data frame
+fn field_len(field: &BikeDataFlags) -> usize {
+    match field {
+        BikeDataFlags::MoreData => 0,
+        BikeDataFlags::ResistanceLvl | BikeDataFlags::HR | BikeDataFlags::MetabolicEquivalent => 1,
+        BikeDataFlags::AvgSpeed
+        | BikeDataFlags::InstCadence
+        | BikeDataFlags::AvgCadence
+        | BikeDataFlags::InstPower
+        | BikeDataFlags::AvgPower
+        | BikeDataFlags::ElapsedTime
+        | BikeDataFlags::RemainingTime => 2,
+        BikeDataFlags::TotDistance => 3,
+        BikeDataFlags::ExpendedEnergy => 5,
+    }
+}
+
+/// Length of the frame fields set in the flags take, flags and instantaneous speed included
+fn frame_len(flags: u16) -> usize {
+    let speed_len = if inst_speed_present(flags) { 2 } else { 0 };
+
+    2 + speed_len
+        + present_fields(flags)
+            .map(|field| field_len(&field))
+            .sum::<usize>()
+}
+
+/// Handle raw stream from notification into BikeData, values out of bounds are dropped.
+/// None if the frame is too short for the fields it flags
+fn handle_plausible_bike_data(raw_data: &[u8], bounds: &PlausibilityBounds) -> Option<BikeData> {
+    let mut bike_data = handle_bike_data_notification(raw_data)?;
+
+    let dropped = bounds.apply_to(&mut bike_data);
+    if !dropped.is_empty() {
+        warn!(
+            "Implausible {} in bike data frame {raw_data:?}, dropped",
+            dropped.join(", ")
+        );
+    }
+
+    Some(bike_data)
+}
+
+/// Handle raw stream from notification into BikeData, None if the frame is too short for the fields it flags
+fn handle_bike_data_notification(raw_data: &[u8]) -> Option<BikeData> {
+    let flags = match raw_data.get(0..2) {
+        Some(flags) => LittleEndian::read_u16(flags),
+        None => {
+            warn!("Bike data frame {raw_data:?} has no flags, dropped");
+            return None;
+        }
+    };
+
+    let expected_len = frame_len(flags);
+    if raw_data.len() < expected_len {
+        warn!(
+            "Bike data frame {raw_data:?} is truncated, fields need {expected_len} bytes, dropped"
+        );
+        return None;
+    }
 
     // Cursor pointing current position in raw_data
     // Start after flag field
@@ -421,32 +1067,21 @@ fn handle_bike_data_notification(raw_data: &[u8]) -> BikeData {
 
     let mut bike_data = BikeData::default();
 
-    // For inst speed logic is reversed, additionally this field contains 2 different things
-    // depending on value.
-    if flags & BikeDataFlags::MoreData as u16 == 1 {
-        // If set to 1, means there will be more data to come
-        // Happens when data does not fit into UTU
-        unimplemented!("More Data scenario is not yet implemented")
-    } else {
-        // If set to zero, it actually means field represents instantaneous speed
+    if inst_speed_present(flags) {
         let raw = LittleEndian::read_u16(&raw_data[cursor..]);
         // jump to another field
         cursor += 2;
 
         let conv = ScalarType::new().with_multiplier(1).with_dec_exp(-2);
         bike_data.inst_speed = Some(conv.to_scalar(raw));
+    } else {
+        // Record did not fit into the MTU, rest of it comes in the next notification.
+        // Fields present in this one are parsed as usual.
+        trace!("More Data is set, instantaneous speed is not present");
     }
 
-    // Check flags bit, if set then there is a value in the data stream corresponding to that field
-    for i in 1..BIKE_DATA_FLAGS_LEN {
-        let field_present: u16 = flags & (1 << i);
-
-        if field_present == 0 {
-            // Given field not present
-            continue;
-        }
-
-        match BikeDataFlags::from_u16(field_present).unwrap() {
+    for field in present_fields(flags) {
+        match field {
             BikeDataFlags::AvgSpeed => {
                 let raw = LittleEndian::read_u16(&raw_data[cursor..]);
                 cursor += 2;
@@ -505,28 +1140,623 @@ fn handle_bike_data_notification(raw_data: &[u8]) -> BikeData {
 
                 bike_data.remaining_time = Some(raw);
             }
-            BikeDataFlags::MoreData => unreachable!(),
+            BikeDataFlags::MoreData => unreachable!("More Data is not a field"),
             BikeDataFlags::MetabolicEquivalent => {
-                unimplemented!("parsing MetabolicEquivalent data not implemented")
+                let raw = raw_data[cursor];
+                cursor += 1;
+
+                let conv = ScalarType::new().with_multiplier(1).with_dec_exp(-1);
+                bike_data.metabolic_equivalent = Some(conv.to_scalar(raw));
+            }
+            BikeDataFlags::HR => {
+                let raw = raw_data[cursor];
+                cursor += 1;
+
+                bike_data.heart_rate = Some(raw);
             }
-            BikeDataFlags::HR => unimplemented!("parsing HR data not implemented"),
             BikeDataFlags::ExpendedEnergy => {
-                unimplemented!("parsing ExpendedEnergy data not implemented")
+                let total = LittleEndian::read_u16(&raw_data[cursor..]);
+                let per_hour = LittleEndian::read_u16(&raw_data[cursor + 2..]);
+                let per_minute = raw_data[cursor + 4];
+                cursor += 5;
+
+                // Machine reports kcal burned, work is derived with the efficiency machines assume.
+                // All bits set means the value is not available
+                if total != u16::MAX {
+                    bike_data.tot_energy = Some(kcal_to_kj(f64::from(total), DEFAULT_EFFICIENCY));
+                }
+                bike_data.energy_per_hour = Some(per_hour).filter(|&raw| raw != u16::MAX);
+                bike_data.energy_per_minute = Some(per_minute).filter(|&raw| raw != u8::MAX);
             }
         };
     }
 
     trace!("Parsed bike data {bike_data:#?}");
-    bike_data
+    Some(bike_data)
 }
 
 /// Helper function to find characteristic
-fn get_characteristic(client: &Peripheral, char_uuid: Uuid) -> Option<Characteristic> {
-    let mut found: Vec<_> = client
-        .characteristics()
+pub(crate) fn get_characteristic(
+    client: &impl PeripheralLike,
+    char_uuid: Uuid,
+) -> Result<Characteristic, BleError> {
+    find_characteristic(client.characteristics(), char_uuid)
+}
+
+fn find_characteristic(
+    characteristics: impl IntoIterator<Item = Characteristic>,
+    char_uuid: Uuid,
+) -> Result<Characteristic, BleError> {
+    let mut found: Vec<_> = characteristics
         .into_iter()
         .filter(|c| c.uuid == char_uuid)
         .collect();
 
-    found.pop()
+    found
+        .pop()
+        .ok_or(BleError::CharacteristicMissing(char_uuid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble_device::mock::MockPeripheral;
+    use btleplug::api::{CharPropFlags, ValueNotification};
+
+    fn power_range() -> Range<i16, u16> {
+        Range {
+            min: 0,
+            max: 800,
+            step: 1,
+        }
+    }
+
+    #[test]
+    fn out_of_range_power_is_rejected_by_default() {
+        let reported = AtomicBool::new(false);
+
+        assert_eq!(
+            limit_power(&power_range(), false, &reported, 250).unwrap(),
+            250
+        );
+        assert!(limit_power(&power_range(), false, &reported, 1000).is_err());
+        assert!(limit_power(&power_range(), false, &reported, -10).is_err());
+    }
+
+    #[test]
+    fn out_of_range_power_is_clamped() {
+        let reported = AtomicBool::new(false);
+
+        assert_eq!(
+            limit_power(&power_range(), true, &reported, 250).unwrap(),
+            250
+        );
+        assert!(!reported.load(Ordering::Relaxed));
+
+        assert_eq!(
+            limit_power(&power_range(), true, &reported, 1000).unwrap(),
+            800
+        );
+        assert!(reported.load(Ordering::Relaxed));
+
+        assert_eq!(
+            limit_power(&power_range(), true, &reported, -10).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn simulation_request_is_encoded() {
+        assert_eq!(simulation_request(0.0), [0x11, 0, 0, 0, 0, 40, 51]);
+        // 2.5% -> 250 -> 0x00FA
+        assert_eq!(simulation_request(2.5), [0x11, 0, 0, 0xFA, 0x00, 40, 51]);
+        // -1% -> -100 -> 0xFF9C
+        assert_eq!(simulation_request(-1.0), [0x11, 0, 0, 0x9C, 0xFF, 40, 51]);
+    }
+
+    #[test]
+    fn inclination_range_is_decoded() {
+        // -15.0% to 15.0%, step 0.1%
+        let range = parse_inclination_range(&[0x6a, 0xff, 0x96, 0x00, 0x01, 0x00]).unwrap();
+        assert_eq!(range.min, -15.0);
+        assert_eq!(range.max, 15.0);
+        assert_eq!(range.step, 0.1);
+
+        assert!(parse_inclination_range(&[0x6a, 0xff, 0x96, 0x00]).is_err());
+    }
+
+    #[tokio::test]
+    async fn grade_is_clamped_to_inclination_range() {
+        let trainer = Arc::new(mock_trainer());
+        let machine = IndoorBikeFitnessMachine::with_peripheral(trainer.clone())
+            .await
+            .unwrap();
+
+        machine.set_simulation(25.0).await.unwrap();
+        machine.set_simulation(-4.0).await.unwrap();
+
+        let writes: Vec<_> = trainer.writes().into_iter().skip(1).collect();
+        assert_eq!(
+            writes,
+            vec![
+                (CONTROL_POINT, simulation_request(20.0).to_vec()),
+                (CONTROL_POINT, simulation_request(-4.0).to_vec()),
+            ]
+        );
+
+        // Machines without inclination support do not limit the grade
+        let trainer = Arc::new(mock_trainer_without(&[SUPPORTED_INCLINATION_RANGE]));
+        let machine = IndoorBikeFitnessMachine::with_peripheral(trainer)
+            .await
+            .unwrap();
+        assert!(machine.inclination_range.is_none());
+    }
+
+    #[tokio::test]
+    async fn resistance_requests_control_like_other_targets() {
+        let trainer = Arc::new(mock_trainer());
+        let machine = IndoorBikeFitnessMachine::with_peripheral(trainer.clone())
+            .await
+            .unwrap();
+
+        // Another app took over, control is requested before the write
+        machine
+            .control
+            .on_machine_status(&MachineStatusOpCode::ControlPermissionLost);
+        machine.set_resistance(5).await.unwrap();
+
+        // Resistance target is restored once control is regained
+        let restored = machine
+            .handle_machine_status(MachineStatusOpCode::ControlPermissionLost)
+            .await
+            .unwrap();
+        assert_eq!(restored, 1);
+
+        let request_control = (
+            CONTROL_POINT,
+            vec![ControlPointOpCode::RequestControl as u8],
+        );
+        let set_resistance = (CONTROL_POINT, set_resistance_request(5).to_vec());
+        assert_eq!(
+            trainer.writes(),
+            vec![
+                request_control.clone(),
+                request_control.clone(),
+                set_resistance.clone(),
+                request_control,
+                set_resistance,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn resistance_is_set_in_percent_of_range() {
+        let trainer = Arc::new(mock_trainer());
+        let machine = IndoorBikeFitnessMachine::with_peripheral(trainer.clone())
+            .await
+            .unwrap();
+
+        // Raw levels 0..=10, decoded as 0..=100
+        assert_eq!(machine.resistance_range.max, 100.0);
+        machine.set_resistance_percent(50.0).await.unwrap();
+        machine.set_resistance_percent(120.0).await.unwrap();
+        machine.set_resistance(3).await.unwrap();
+        assert!(machine.set_resistance(11).await.is_err());
+
+        let writes: Vec<_> = trainer.writes().into_iter().skip(1).collect();
+        assert_eq!(
+            writes,
+            vec![
+                (CONTROL_POINT, vec![0x04, 5]),
+                (CONTROL_POINT, vec![0x04, 10]),
+                (CONTROL_POINT, vec![0x04, 3]),
+            ]
+        );
+
+        // Raw levels 0..=1000 do not fit the request
+        let trainer = Arc::new(
+            mock_trainer().with_value(SUPPORTED_RESISTANCE_LEVEL, &[0, 0, 0xe8, 0x03, 0x01, 0]),
+        );
+        let machine = IndoorBikeFitnessMachine::with_peripheral(trainer.clone())
+            .await
+            .unwrap();
+        machine.set_resistance_percent(25.0).await.unwrap();
+        assert!(machine.set_resistance_percent(26.0).await.is_err());
+        assert_eq!(
+            trainer.writes().last(),
+            Some(&(CONTROL_POINT, vec![0x04, 250]))
+        );
+    }
+
+    #[test]
+    fn speed_request_is_encoded() {
+        assert_eq!(set_speed_request(0.0), [0x02, 0, 0]);
+        // 30 km/h -> 3000 -> 0x0BB8
+        assert_eq!(set_speed_request(30.0), [0x02, 0xB8, 0x0B]);
+        // 12.345 km/h -> 1235 -> 0x04D3
+        assert_eq!(set_speed_request(12.345), [0x02, 0xD3, 0x04]);
+    }
+
+    #[test]
+    fn control_is_requested_after_permission_lost() {
+        let status = handle_machine_status_notification(&[0xFF]);
+        assert_eq!(status, MachineStatusOpCode::ControlPermissionLost);
+
+        let last_target = set_power_request(200).to_vec();
+        let requests = status_reaction(status, Some(last_target));
+
+        // RequestControl, followed by the last target power
+        assert_eq!(requests, vec![vec![0x00], vec![0x05, 200, 0]]);
+
+        // Nothing to restore yet
+        assert_eq!(status_reaction(status, None), vec![vec![0x00]]);
+    }
+
+    #[test]
+    fn other_statuses_need_no_reaction() {
+        let status = handle_machine_status_notification(&[0x08]);
+        assert_eq!(status, MachineStatusOpCode::TargetPowerChanged);
+
+        assert!(status_reaction(status, Some(set_power_request(200).to_vec())).is_empty());
+    }
+
+    #[test]
+    fn missing_characteristic_is_reported() {
+        let control_point = Characteristic {
+            uuid: CONTROL_POINT,
+            service_uuid: SERVICE_UUID,
+            properties: Default::default(),
+        };
+
+        assert_eq!(
+            find_characteristic(vec![control_point.clone()], CONTROL_POINT).unwrap(),
+            control_point
+        );
+        assert!(matches!(
+            find_characteristic(vec![control_point], MACHINE_FEATURE),
+            Err(BleError::CharacteristicMissing(uuid)) if uuid == MACHINE_FEATURE
+        ));
+    }
+
+    #[test]
+    fn malformed_power_range_is_reported() {
+        let range = parse_power_range(&[0, 0, 0x20, 0x03, 1, 0]).unwrap();
+        assert_eq!((range.min, range.max, range.step), (0, 800, 1));
+
+        assert!(matches!(
+            parse_power_range(&[0, 0, 0x20]),
+            Err(BleError::InvalidData { uuid, data })
+                if uuid == SUPPORTED_POWER_RANGE && data == vec![0, 0, 0x20]
+        ));
+    }
+
+    #[test]
+    fn padded_features_are_accepted() {
+        let padded = [0x02, 0x40, 0, 0, 0x0c, 0x20, 0, 0, 0xff];
+        assert_eq!(parse_features(&padded).unwrap(), (0x4002, 0x200c));
+
+        assert!(matches!(
+            parse_features(&padded[..7]),
+            Err(BleError::InvalidData { uuid, data })
+                if uuid == MACHINE_FEATURE && data.len() == 7
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_wait_until_control_is_granted() {
+        let control = Arc::new(ControlPermission::default());
+        let response = |request_status| ControlPointNotificationData {
+            request_op_code: ControlPointOpCode::RequestControl,
+            request_status,
+        };
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let request = {
+            let requests = requests.clone();
+            move || {
+                let requests = requests.clone();
+                async move {
+                    requests.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }
+            }
+        };
+
+        // Not granted yet, write is deferred until the machine acknowledges the request
+        let acquire = {
+            let control = control.clone();
+            let request = request.clone();
+            tokio::spawn(async move { control.acquire(request, CONTROL_GRANT_TIMEOUT).await })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!acquire.is_finished());
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        control.on_control_point(&response(ControlPointResult::Success));
+        acquire.await.unwrap().unwrap();
+        assert!(control.is_granted());
+
+        // Held, nothing is requested
+        control
+            .acquire(request.clone(), CONTROL_GRANT_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        // Lost, and not regained in time
+        control.on_machine_status(&MachineStatusOpCode::ControlPermissionLost);
+        assert!(!control.is_granted());
+        assert!(control
+            .acquire(request.clone(), CONTROL_GRANT_TIMEOUT)
+            .await
+            .is_err());
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+
+        // Regained
+        control.on_control_point(&response(ControlPointResult::Success));
+        control
+            .acquire(request.clone(), CONTROL_GRANT_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+    }
+
+    /// Trainer supporting power target, acknowledging every control point request
+    fn mock_trainer() -> MockPeripheral {
+        mock_trainer_without(&[])
+    }
+
+    /// Mock trainer, that does not expose given characteristics
+    fn mock_trainer_without(missing: &[Uuid]) -> MockPeripheral {
+        let notify = CharPropFlags::NOTIFY;
+        let characteristics = vec![
+            (MACHINE_FEATURE, CharPropFlags::READ),
+            (SUPPORTED_POWER_RANGE, CharPropFlags::READ),
+            (SUPPORTED_RESISTANCE_LEVEL, CharPropFlags::READ),
+            (SUPPORTED_INCLINATION_RANGE, CharPropFlags::READ),
+            (INDOOR_BIKE_DATA, notify),
+            (TRAINING_STATUS, notify),
+            (MACHINE_STATUS, notify),
+            (
+                CONTROL_POINT,
+                CharPropFlags::WRITE | CharPropFlags::INDICATE,
+            ),
+        ];
+
+        MockPeripheral::new(
+            SERVICE_UUID,
+            characteristics
+                .into_iter()
+                .filter(|(uuid, _)| !missing.contains(uuid)),
+        )
+        // Power target supported
+        .with_value(MACHINE_FEATURE, &[0, 0, 0, 0, 0x08, 0, 0, 0])
+        .with_value(SUPPORTED_POWER_RANGE, &[0, 0, 0x20, 0x03, 1, 0])
+        .with_value(SUPPORTED_RESISTANCE_LEVEL, &[0, 0, 0x0a, 0, 0x01, 0])
+        // -10% to 20%, step 0.5%
+        .with_value(SUPPORTED_INCLINATION_RANGE, &[0x9c, 0xff, 0xc8, 0, 0x05, 0])
+        .with_write_reaction(|uuid, request| match uuid {
+            CONTROL_POINT => vec![ValueNotification {
+                uuid: CONTROL_POINT,
+                value: vec![0x80, request[0], ControlPointResult::Success as u8],
+            }],
+            _ => vec![],
+        })
+    }
+
+    #[tokio::test]
+    async fn set_power_is_acknowledged_by_mock_trainer() {
+        let trainer = Arc::new(mock_trainer());
+        let machine = IndoorBikeFitnessMachine::with_peripheral(trainer.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            trainer.subscriptions(),
+            vec![
+                INDOOR_BIKE_DATA,
+                TRAINING_STATUS,
+                MACHINE_STATUS,
+                CONTROL_POINT
+            ]
+        );
+        assert_eq!(machine.power_range.max, 800);
+        assert_eq!(machine.resistance_range.max, 100.0);
+        assert_eq!(
+            machine.trainer_info().await.unwrap().capabilities,
+            vec![TargetSettingFeatures::Power]
+        );
+        assert!(machine.control.is_granted());
+
+        let mut acks = machine.subscribe_for_control_point_notifications();
+        machine.set_power(200).await.unwrap();
+
+        let ack = acks.recv().await.unwrap();
+        assert!(matches!(
+            ack.request_op_code,
+            ControlPointOpCode::SetTargetPower
+        ));
+        check_response(&ack).unwrap();
+
+        assert_eq!(
+            trainer.writes(),
+            vec![
+                (
+                    CONTROL_POINT,
+                    vec![ControlPointOpCode::RequestControl as u8]
+                ),
+                (CONTROL_POINT, set_power_request(200).to_vec()),
+            ]
+        );
+
+        // Scripted notification goes to subscribers
+        let mut bike_rx = machine.subscribe_for_indoor_bike_notifications();
+        trainer.notify(INDOOR_BIKE_DATA, &[0x40, 0, 0xe8, 0x03, 0xc8, 0]);
+        let bike_data = bike_rx.recv().await.unwrap();
+        assert_eq!(bike_data.inst_power, Some(200));
+        assert_eq!(bike_data.inst_speed, Some(10.0));
+    }
+
+    #[tokio::test]
+    async fn observer_does_not_write_to_control_point() {
+        let trainer = Arc::new(mock_trainer());
+        let machine = IndoorBikeFitnessMachine::observer_with_peripheral(trainer.clone())
+            .await
+            .unwrap();
+
+        machine.reset_status().await.unwrap();
+        machine.set_power(200).await.unwrap();
+        machine.stop_or_pause(StopOrPause::Stop).await.unwrap();
+        let writes = machine
+            .handle_machine_status(MachineStatusOpCode::ControlPermissionLost)
+            .await
+            .unwrap();
+
+        assert_eq!(writes, 0);
+        assert_eq!(trainer.writes(), vec![]);
+        assert!(!machine.control.is_granted());
+
+        // Data still flows
+        let mut bike_rx = machine.subscribe_for_indoor_bike_notifications();
+        trainer.notify(INDOOR_BIKE_DATA, &[0x40, 0, 0xe8, 0x03, 0xc8, 0]);
+        assert_eq!(bike_rx.recv().await.unwrap().inst_power, Some(200));
+    }
+
+    #[test]
+    fn speed_is_present_only_without_more_data() {
+        // More Data clear: speed 30.00km/h, cadence 90rpm, power 250W
+        let complete =
+            handle_bike_data_notification(&[0x44, 0x00, 0xb8, 0x0b, 0xb4, 0x00, 0xfa, 0x00])
+                .unwrap();
+        assert_eq!(complete.inst_speed, Some(30.0));
+        assert_eq!(complete.inst_cadence, Some(90.0));
+        assert_eq!(complete.inst_power, Some(250));
+
+        // More Data set: no speed, power follows the flags right away
+        let partial = handle_bike_data_notification(&[0x41, 0x00, 0xfa, 0x00]).unwrap();
+        assert_eq!(partial.inst_speed, None);
+        assert_eq!(partial.inst_power, Some(250));
+        assert_eq!(partial.inst_cadence, None);
+    }
+
+    #[test]
+    fn expended_energy_is_decoded() {
+        // Speed 30.00km/h, power 250W, energy 100kcal total, 600kcal/h, 10kcal/min, elapsed 60s
+        let frame = [
+            0x40, 0x09, 0xb8, 0x0b, 0xfa, 0x00, 0x64, 0x00, 0x58, 0x02, 0x0a, 0x3c, 0x00,
+        ];
+        let bike_data = handle_bike_data_notification(&frame).unwrap();
+        assert_eq!(bike_data.inst_power, Some(250));
+        // At the default efficiency kcal burned and kJ of work are about the same
+        assert!((bike_data.tot_energy.unwrap() - 100.416).abs() < 1e-9);
+        assert_eq!(bike_data.energy_per_hour, Some(600));
+        assert_eq!(bike_data.energy_per_minute, Some(10));
+        assert_eq!(bike_data.elapsed_time, Some(60));
+
+        // Total not available, the rest still is
+        let frame = [0x00, 0x01, 0xb8, 0x0b, 0xff, 0xff, 0x58, 0x02, 0xff];
+        let bike_data = handle_bike_data_notification(&frame).unwrap();
+        assert_eq!(bike_data.tot_energy, None);
+        assert_eq!(bike_data.energy_per_hour, Some(600));
+        assert_eq!(bike_data.energy_per_minute, None);
+    }
+
+    #[test]
+    fn heart_rate_and_metabolic_equivalent_are_decoded() {
+        // More Data set, power 250W, heart rate 140bpm, 8.5 METs, elapsed 60s
+        let frame = [0x41, 0x0e, 0xfa, 0x00, 0x8c, 0x55, 0x3c, 0x00];
+        let bike_data = handle_bike_data_notification(&frame).unwrap();
+        assert_eq!(bike_data.inst_power, Some(250));
+        assert_eq!(bike_data.heart_rate, Some(140));
+        assert_eq!(bike_data.metabolic_equivalent, Some(8.5));
+        assert_eq!(bike_data.elapsed_time, Some(60));
+    }
+
+    #[test]
+    fn truncated_frame_is_dropped() {
+        // Speed, cadence and power flagged, power is missing
+        let frame = [0x44, 0x00, 0xb8, 0x0b, 0xb4, 0x00];
+        assert!(handle_bike_data_notification(&frame).is_none());
+        // Expended energy cut in the middle
+        assert!(handle_bike_data_notification(&[0x00, 0x01, 0xb8, 0x0b, 0x64, 0x00]).is_none());
+        assert!(handle_bike_data_notification(&[0x44]).is_none());
+        assert!(handle_bike_data_notification(&[]).is_none());
+
+        assert_eq!(frame_len(0x0044), 8);
+        assert_eq!(frame_len(0x0e41), 8);
+    }
+
+    #[test]
+    fn implausible_values_are_dropped() {
+        // Speed 30.00km/h, cadence 90rpm, power 32000W
+        let frame = [0x44, 0x00, 0xb8, 0x0b, 0xb4, 0x00, 0x00, 0x7d];
+        let bike_data = handle_plausible_bike_data(&frame, &PlausibilityBounds::default()).unwrap();
+        assert_eq!(bike_data.inst_power, None);
+        assert_eq!(bike_data.inst_speed, Some(30.0));
+        assert_eq!(bike_data.inst_cadence, Some(90.0));
+
+        let mut bike_data = handle_bike_data_notification(&frame).unwrap();
+        let strict = PlausibilityBounds {
+            cadence: 0.0..=80.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            strict.apply_to(&mut bike_data),
+            vec!["inst_power", "inst_cadence"]
+        );
+        assert_eq!(bike_data.inst_cadence, None);
+    }
+
+    #[tokio::test]
+    async fn optional_characteristics_may_be_missing() {
+        let trainer = Arc::new(mock_trainer_without(&[TRAINING_STATUS, MACHINE_STATUS]));
+        IndoorBikeFitnessMachine::with_peripheral(trainer.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            trainer.subscriptions(),
+            vec![INDOOR_BIKE_DATA, CONTROL_POINT]
+        );
+
+        // Without bike data there is nothing to ride on
+        let trainer = Arc::new(mock_trainer_without(&[INDOOR_BIKE_DATA]));
+        assert!(IndoorBikeFitnessMachine::with_peripheral(trainer)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn rejected_request_is_reported() {
+        let mut response = ControlPointNotificationData {
+            request_op_code: ControlPointOpCode::SetTargetPower,
+            request_status: ControlPointResult::Success,
+        };
+        assert!(check_response(&response).is_ok());
+
+        response.request_status = ControlPointResult::ControlNotPermitted;
+        assert!(matches!(
+            check_response(&response),
+            Err(BleError::ControlRejected(
+                ControlPointResult::ControlNotPermitted
+            ))
+        ));
+    }
+
+    #[test]
+    fn target_setting_features_are_decoded() {
+        // Resistance, Power, IndoorBikeSimulation
+        let features = target_settings(1 << 2 | 1 << 3 | 1 << 13);
+
+        assert_eq!(
+            serde_json::to_value(&features).unwrap(),
+            serde_json::json!(["Resistance", "Power", "IndoorBikeSimulation"])
+        );
+    }
+
+    #[test]
+    fn stop_request_is_encoded() {
+        assert_eq!(stop_or_pause_request(StopOrPause::Stop), [0x08, 0x01]);
+        assert_eq!(stop_or_pause_request(StopOrPause::Pause), [0x08, 0x02]);
+    }
 }
@@ -2,7 +2,10 @@
 
 // Endpoints, aka Characteristics
 
+use std::{fmt, ops::RangeInclusive};
+
 use btleplug::api::bleuuid::uuid_from_u16;
+use serde::Serialize;
 use uuid::Uuid;
 
 /// GATTS Service UUID
@@ -18,6 +21,9 @@ pub const INDOOR_BIKE_DATA: Uuid = uuid_from_u16(0x2AD2);
 /// NOTIFY: something like, idle, warming up, low/high interval, fitness test, cool down, manual mode
 pub const TRAINING_STATUS: Uuid = uuid_from_u16(0x2AD3);
 
+/// READ: gets supported inclination range, present only if machine supports inclination
+pub const SUPPORTED_INCLINATION_RANGE: Uuid = uuid_from_u16(0x2AD5);
+
 /// READ: gets supported resistance level
 pub const SUPPORTED_RESISTANCE_LEVEL: Uuid = uuid_from_u16(0x2AD6);
 
@@ -53,7 +59,35 @@ pub enum FitnessMachineFeatures {
 }
 pub const FITNESS_MACHINE_FEATURES_LEN: u32 = 17;
 
-#[derive(Debug, FromPrimitive)]
+impl fmt::Display for FitnessMachineFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            FitnessMachineFeatures::AvgSpeed => "Average speed",
+            FitnessMachineFeatures::Cadence => "Cadence",
+            FitnessMachineFeatures::TotalDistance => "Total distance",
+            FitnessMachineFeatures::Inclination => "Inclination",
+            FitnessMachineFeatures::Elevation => "Elevation gain",
+            FitnessMachineFeatures::Pace => "Pace",
+            FitnessMachineFeatures::StepCount => "Step count",
+            FitnessMachineFeatures::Resistance => "Resistance level",
+            FitnessMachineFeatures::StrideCount => "Stride count",
+            FitnessMachineFeatures::ExpendedEnergy => "Expended energy",
+            FitnessMachineFeatures::HRMeasurement => "Heart rate measurement",
+            FitnessMachineFeatures::MetabolicEquivalent => "Metabolic equivalent",
+            FitnessMachineFeatures::ElapsedTime => "Elapsed time",
+            FitnessMachineFeatures::RemainingTime => "Remaining time",
+            FitnessMachineFeatures::PowerMeasurement => "Power measurement",
+            FitnessMachineFeatures::ForceOnBeltAndPowerOutputSupported => {
+                "Force on belt and power output"
+            }
+            FitnessMachineFeatures::UserDataRetention => "User data retention",
+        };
+
+        f.write_str(text)
+    }
+}
+
+#[derive(Debug, FromPrimitive, Clone, Serialize, PartialEq)]
 #[non_exhaustive]
 pub enum TargetSettingFeatures {
     SpeedTarget = 1 << 0,
@@ -76,6 +110,32 @@ pub enum TargetSettingFeatures {
 }
 pub const TARGET_SETTING_FEATURES_LEN: u32 = 17;
 
+impl fmt::Display for TargetSettingFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            TargetSettingFeatures::SpeedTarget => "Speed target",
+            TargetSettingFeatures::Inclination => "Inclination target",
+            TargetSettingFeatures::Resistance => "Resistance target",
+            TargetSettingFeatures::Power => "Power target (ERG mode)",
+            TargetSettingFeatures::HR => "Heart rate target",
+            TargetSettingFeatures::TargetedExpendedEnergyConfiguration => "Expended energy target",
+            TargetSettingFeatures::TargetedStepNumber => "Step number target",
+            TargetSettingFeatures::TargetedStrideNumber => "Stride number target",
+            TargetSettingFeatures::TargetedDistance => "Distance target",
+            TargetSettingFeatures::TargetedTrainingTime => "Training time target",
+            TargetSettingFeatures::TargetedTimeIn2HRZones => "Time in 2 heart rate zones target",
+            TargetSettingFeatures::TargetedTimeIn3HRZones => "Time in 3 heart rate zones target",
+            TargetSettingFeatures::TargetedTimeIn5HRZones => "Time in 5 heart rate zones target",
+            TargetSettingFeatures::IndoorBikeSimulation => "Indoor bike simulation (grade)",
+            TargetSettingFeatures::WheelCircumference => "Wheel circumference",
+            TargetSettingFeatures::SpinDownControl => "Spin down control",
+            TargetSettingFeatures::TargetedCadence => "Cadence target",
+        };
+
+        f.write_str(text)
+    }
+}
+
 /// Representation of data from Indoor Bike Data characteristic
 ///  BikeData has different fields present, depending on flag field
 #[derive(Debug, Default, Clone)]
@@ -84,17 +144,152 @@ pub struct BikeData {
     pub avg_speed: Option<f64>,
     pub inst_cadence: Option<f64>,
     pub avg_cadence: Option<f64>,
+    /// In meters
     pub tot_distance: Option<u32>,
+    /// In kJ
+    pub tot_energy: Option<f64>,
+    /// In kcal, as estimated by the machine
+    pub energy_per_hour: Option<u16>,
+    /// In kcal, as estimated by the machine
+    pub energy_per_minute: Option<u8>,
     pub resistance_lvl: Option<f64>,
     pub inst_power: Option<i16>,
     pub avg_power: Option<i16>,
     pub elapsed_time: Option<u16>,
     pub remaining_time: Option<u16>,
+    /// In bpm
+    pub heart_rate: Option<u8>,
+    /// In METs, resting rate is 1
+    pub metabolic_equivalent: Option<f64>,
+    /// Power as reported by the machine, before calibration, for debugging purposes
+    pub raw_inst_power: Option<i16>,
+    pub raw_avg_power: Option<i16>,
+    /// Trainer does not report the distance, it's calculated from the speed
+    pub distance_derived: bool,
+    /// Trainer does not report the energy, it's calculated from the power
+    pub energy_derived: bool,
+    /// Trainer does not measure the power, it's estimated from the speed
+    pub power_estimated: bool,
+}
+
+/// Correction of the power measured by the machine, scale is applied first, then offset.
+/// Affects measured values only, target power sent to the machine stays as is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerCalibration {
+    pub offset: f64,
+    pub scale: f64,
+}
+
+impl Default for PowerCalibration {
+    fn default() -> Self {
+        Self {
+            offset: 0.0,
+            scale: 1.0,
+        }
+    }
+}
+
+impl PowerCalibration {
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn apply(&self, power: i16) -> i16 {
+        (f64::from(power) * self.scale + self.offset).round() as i16
+    }
+
+    /// Calibrates measured power, keeping raw values aside
+    pub fn apply_to(&self, bike_data: &mut BikeData) {
+        bike_data.raw_inst_power = bike_data.inst_power;
+        bike_data.raw_avg_power = bike_data.avg_power;
+
+        bike_data.inst_power = bike_data.inst_power.map(|power| self.apply(power));
+        bike_data.avg_power = bike_data.avg_power.map(|power| self.apply(power));
+    }
+}
+
+/// Sane ranges of measured values, anything outside comes from a malformed frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlausibilityBounds {
+    /// In watts
+    pub power: RangeInclusive<i16>,
+    /// In RPM
+    pub cadence: RangeInclusive<f64>,
+    /// In km/h
+    pub speed: RangeInclusive<f64>,
+}
+
+impl Default for PlausibilityBounds {
+    fn default() -> Self {
+        Self {
+            power: 0..=2000,
+            cadence: 0.0..=250.0,
+            speed: 0.0..=120.0,
+        }
+    }
+}
+
+impl PlausibilityBounds {
+    /// Drops values out of bounds, returns names of dropped fields
+    pub fn apply_to(&self, bike_data: &mut BikeData) -> Vec<&'static str> {
+        let mut dropped = vec![];
+
+        drop_outside(
+            &mut bike_data.inst_power,
+            &self.power,
+            "inst_power",
+            &mut dropped,
+        );
+        drop_outside(
+            &mut bike_data.avg_power,
+            &self.power,
+            "avg_power",
+            &mut dropped,
+        );
+        drop_outside(
+            &mut bike_data.inst_cadence,
+            &self.cadence,
+            "inst_cadence",
+            &mut dropped,
+        );
+        drop_outside(
+            &mut bike_data.avg_cadence,
+            &self.cadence,
+            "avg_cadence",
+            &mut dropped,
+        );
+        drop_outside(
+            &mut bike_data.inst_speed,
+            &self.speed,
+            "inst_speed",
+            &mut dropped,
+        );
+        drop_outside(
+            &mut bike_data.avg_speed,
+            &self.speed,
+            "avg_speed",
+            &mut dropped,
+        );
+
+        dropped
+    }
+}
+
+fn drop_outside<T: PartialOrd>(
+    value: &mut Option<T>,
+    bounds: &RangeInclusive<T>,
+    name: &'static str,
+    dropped: &mut Vec<&'static str>,
+) {
+    if matches!(value, Some(v) if !bounds.contains(v)) {
+        *value = None;
+        dropped.push(name);
+    }
 }
 
 #[derive(Debug, FromPrimitive)]
 pub enum BikeDataFlags {
-    MoreData = 1 << 0, // a.k.a instantaneous speed, this is f*kd up
+    MoreData = 1 << 0, // when clear, Instantaneous Speed is present
     AvgSpeed = 1 << 1,
     InstCadence = 1 << 2,
     AvgCadence = 1 << 3,
@@ -111,7 +306,7 @@ pub enum BikeDataFlags {
 pub const BIKE_DATA_FLAGS_LEN: u16 = 13;
 
 /// Machine indicates about it's internal state change
-#[derive(Debug, FromPrimitive)]
+#[derive(Debug, FromPrimitive, Clone, Copy, PartialEq)]
 pub enum MachineStatusOpCode {
     Reserved0 = 0x0,
     Reset = 0x1,
@@ -138,14 +333,60 @@ pub enum MachineStatusOpCode {
     ControlPermissionLost = 0xFF,
 }
 
+impl fmt::Display for MachineStatusOpCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            MachineStatusOpCode::Reserved0 => "Reserved status",
+            MachineStatusOpCode::Reset => "Machine was reset",
+            MachineStatusOpCode::StoppedPausedByUser => "Stopped or paused by the user",
+            MachineStatusOpCode::StoppedBySafetyKey => "Stopped by the safety key",
+            MachineStatusOpCode::StartedResumedByUser => "Started or resumed by the user",
+            MachineStatusOpCode::TargetSpeedChanged => "Target speed changed",
+            MachineStatusOpCode::TargetInclineChanged => "Target incline changed",
+            MachineStatusOpCode::TargetResistanceChanged => "Target resistance changed",
+            MachineStatusOpCode::TargetPowerChanged => "Target power changed",
+            MachineStatusOpCode::TargetHRChanged => "Target heart rate changed",
+            MachineStatusOpCode::TargetedExpendedEnergyChanged => {
+                "Targeted expended energy changed"
+            }
+            MachineStatusOpCode::TargetedNumberOfStepsChanged => "Targeted number of steps changed",
+            MachineStatusOpCode::TargetedNumberOfStridesChanged => {
+                "Targeted number of strides changed"
+            }
+            MachineStatusOpCode::TargetedDistanceChanged => "Targeted distance changed",
+            MachineStatusOpCode::TargetedTrainingTimeChanged => "Targeted training time changed",
+            MachineStatusOpCode::TargetedTimeIn2HRZonesChanged => {
+                "Targeted time in 2 heart rate zones changed"
+            }
+            MachineStatusOpCode::TargetedTimeIn3HRZonesChanged => {
+                "Targeted time in 3 heart rate zones changed"
+            }
+            MachineStatusOpCode::TargetedTimeIn5HRZonesChanged => {
+                "Targeted time in 5 heart rate zones changed"
+            }
+            MachineStatusOpCode::IndoorBikeSimulationParametersChanged => {
+                "Simulation parameters changed"
+            }
+            MachineStatusOpCode::WheelCircumferenceChanged => "Wheel circumference changed",
+            MachineStatusOpCode::SpinDownStatus => "Spin down status",
+            MachineStatusOpCode::ControlPermissionLost => {
+                "Control permission lost, control has to be requested again"
+            }
+        };
+
+        f.write_str(text)
+    }
+}
+
 // TODO: added only those supported by SUITO
 /// Thing you can change using control point, followed by parameter
 /// DOCS: FTMS_v1.0 4.16.1, Table 4.15
-#[derive(Debug, FromPrimitive, Clone)]
+#[derive(Debug, FromPrimitive, Clone, PartialEq, Serialize)]
 pub enum ControlPointOpCode {
     RequestControl = 0x0,
     // Set machine fields to default, like elapsed time to 0, etc. sets training status to idle
     Reset = 0x1,
+    SetTargetSpeed = 0x2,
     SetTargetResistance = 0x4,
     SetTargetPower = 0x5,
     StartOrResume = 0x7,
@@ -155,9 +396,42 @@ pub enum ControlPointOpCode {
     SpinDownControl = 0x13,
 }
 
+impl fmt::Display for ControlPointOpCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            ControlPointOpCode::RequestControl => "Request control",
+            ControlPointOpCode::Reset => "Reset",
+            ControlPointOpCode::SetTargetSpeed => "Set target speed",
+            ControlPointOpCode::SetTargetResistance => "Set target resistance",
+            ControlPointOpCode::SetTargetPower => "Set target power",
+            ControlPointOpCode::StartOrResume => "Start or resume",
+            ControlPointOpCode::StopOrPause => "Stop or pause",
+            ControlPointOpCode::IndoorBikeSimulation => "Set simulation parameters",
+            ControlPointOpCode::WheelCircumference => "Set wheel circumference",
+            ControlPointOpCode::SpinDownControl => "Spin down control",
+        };
+
+        f.write_str(text)
+    }
+}
+
+/// Coefficient of rolling resistance used in simulation mode, resolution 0.0001
+pub const SIMULATION_CRR: u8 = 40;
+/// Wind resistance coefficient used in simulation mode, resolution 0.01 kg/m
+pub const SIMULATION_CW: u8 = 51;
+
+/// Parameter of StopOrPause op code
+/// DOCS: FTMS_v1.0 4.16.2.9
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopOrPause {
+    Stop = 0x1,
+    #[allow(dead_code)]
+    Pause = 0x2,
+}
+
 /// Control Point sends an indication as a response to the write request, with given status
 /// DOCS: FTMS_v1.0 4.16.1 Table 4.24
-#[derive(Debug, FromPrimitive, Clone)]
+#[derive(Debug, FromPrimitive, Clone, PartialEq, Serialize)]
 pub enum ControlPointResult {
     Reserved0 = 0x0,
     Success = 0x1,
@@ -168,15 +442,38 @@ pub enum ControlPointResult {
     // 0x06-0xff - reserved
 }
 
+impl fmt::Display for ControlPointResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            ControlPointResult::Reserved0 => "Reserved result",
+            ControlPointResult::Success => "Success",
+            ControlPointResult::OpCodeNotSupported => "Not supported by the machine",
+            ControlPointResult::InvalidParam => "Invalid parameter, likely out of supported range",
+            ControlPointResult::OperationFailed => "Operation failed",
+            ControlPointResult::ControlNotPermitted => {
+                "Control not permitted \u{2014} request control first"
+            }
+        };
+
+        f.write_str(text)
+    }
+}
+
 /// Data that is returned by control point indication
 /// It's a response to write request that happened prior that
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ControlPointNotificationData {
     pub request_op_code: ControlPointOpCode,
     pub request_status: ControlPointResult,
 }
+
+impl fmt::Display for ControlPointNotificationData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.request_op_code, self.request_status)
+    }
+}
 /// Struct holding supported range of values to set for given characteristic
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Range<T, S = T> {
     pub min: T,
     pub max: T,
@@ -190,3 +487,102 @@ where
         value >= self.min && value <= self.max
     }
 }
+
+impl<T, S> Range<T, S>
+where
+    T: PartialOrd + Copy,
+{
+    /// Closest value from the range
+    pub fn clamp(&self, value: T) -> T {
+        if value < self.min {
+            self.min
+        } else if value > self.max {
+            self.max
+        } else {
+            value
+        }
+    }
+}
+
+impl Range<f64> {
+    /// Value at given percent of the range, 0 is min, 100 is max, rounded to the nearest step
+    pub fn at_percent(&self, percent: f64) -> f64 {
+        let value = self.min + (self.max - self.min) * percent.clamp(0.0, 100.0) / 100.0;
+
+        if self.step <= 0.0 {
+            return value;
+        }
+
+        let steps = ((value - self.min) / self.step).round();
+        self.clamp(self.min + steps * self.step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_percent_is_rounded_to_step() {
+        let range = Range {
+            min: 10.0,
+            max: 30.0,
+            step: 0.5,
+        };
+
+        assert_eq!(range.at_percent(50.0), 20.0);
+        assert_eq!(range.at_percent(0.0), 10.0);
+        // 10 + 20 * 0.33 = 16.6, nearest step is 16.5
+        assert_eq!(range.at_percent(33.0), 16.5);
+        assert_eq!(range.at_percent(-5.0), 10.0);
+        assert_eq!(range.at_percent(150.0), 30.0);
+    }
+
+    #[test]
+    fn power_is_scaled_then_offset() {
+        let calibration = PowerCalibration {
+            offset: -10.0,
+            scale: 1.1,
+        };
+
+        // 200 * 1.1 - 10, not (200 - 10) * 1.1
+        assert_eq!(calibration.apply(200), 210);
+
+        let mut bike_data = BikeData {
+            inst_power: Some(200),
+            avg_power: Some(100),
+            ..Default::default()
+        };
+        calibration.apply_to(&mut bike_data);
+
+        assert_eq!(bike_data.inst_power, Some(210));
+        assert_eq!(bike_data.avg_power, Some(100));
+        assert_eq!(bike_data.raw_inst_power, Some(200));
+        assert_eq!(bike_data.raw_avg_power, Some(100));
+        assert!(PowerCalibration::default().is_identity());
+    }
+
+    #[test]
+    fn enums_are_displayed_for_humans() {
+        assert_eq!(
+            ControlPointResult::ControlNotPermitted.to_string(),
+            "Control not permitted \u{2014} request control first"
+        );
+        assert_eq!(
+            ControlPointNotificationData {
+                request_op_code: ControlPointOpCode::SetTargetPower,
+                request_status: ControlPointResult::InvalidParam,
+            }
+            .to_string(),
+            "Set target power: Invalid parameter, likely out of supported range"
+        );
+        assert_eq!(
+            MachineStatusOpCode::StoppedBySafetyKey.to_string(),
+            "Stopped by the safety key"
+        );
+        assert_eq!(
+            TargetSettingFeatures::Power.to_string(),
+            "Power target (ERG mode)"
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! Dump of the whole GATT profile of the trainer, helps to figure out why given device
+//! does not work with velomania.
+//! Note: btleplug does not expose descriptors, only services and characteristics are listed.
+use btleplug::api::CharPropFlags;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::ble_client::read_with_retries;
+use crate::ble_device::PeripheralLike;
+use crate::indoor_bike_client::{
+    fitness_features_list, parse_features, parse_inclination_range, parse_power_range,
+    parse_resistance_range, target_settings,
+};
+use crate::indoor_bike_data_defs::{
+    MACHINE_FEATURE, SUPPORTED_INCLINATION_RANGE, SUPPORTED_POWER_RANGE, SUPPORTED_RESISTANCE_LEVEL,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceDump {
+    pub uuid: Uuid,
+    pub primary: bool,
+    pub characteristics: Vec<CharacteristicDump>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CharacteristicDump {
+    pub uuid: Uuid,
+    pub properties: CharPropFlags,
+    /// Raw value, None if characteristic is not readable, or read failed
+    pub value: Option<Vec<u8>>,
+}
+
+impl CharacteristicDump {
+    /// Human readable value for known FTMS characteristics
+    pub fn decoded(&self) -> Option<String> {
+        let raw = self.value.as_ref()?;
+
+        let decoded = match self.uuid {
+            MACHINE_FEATURE => parse_features(raw).map(|(fitness, target)| {
+                format!(
+                    "fitness features: {:?}, target settings: {:?}",
+                    fitness_features_list(fitness),
+                    target_settings(target)
+                )
+            }),
+            SUPPORTED_POWER_RANGE => parse_power_range(raw).map(|range| format!("{range:?}")),
+            SUPPORTED_RESISTANCE_LEVEL => {
+                parse_resistance_range(raw).map(|range| format!("{range:?}"))
+            }
+            SUPPORTED_INCLINATION_RANGE => {
+                parse_inclination_range(raw).map(|range| format!("{range:?}"))
+            }
+            _ => return None,
+        };
+
+        Some(decoded.unwrap_or_else(|e| format!("cannot decode: {e}")))
+    }
+}
+
+/// Enumerates every service and characteristic of connected peripheral,
+/// readable characteristics are read.
+pub async fn dump_services(peripheral: &impl PeripheralLike) -> Vec<ServiceDump> {
+    let mut services = vec![];
+
+    for service in peripheral.services() {
+        let mut characteristics = vec![];
+
+        for characteristic in &service.characteristics {
+            let value = if characteristic.properties.contains(CharPropFlags::READ) {
+                match read_with_retries(peripheral, characteristic).await {
+                    Ok(value) => Some(value),
+                    Err(e) => {
+                        warn!("Cannot read {}: {e}", characteristic.uuid);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            characteristics.push(CharacteristicDump {
+                uuid: characteristic.uuid,
+                properties: characteristic.properties,
+                value,
+            });
+        }
+
+        services.push(ServiceDump {
+            uuid: service.uuid,
+            primary: service.primary,
+            characteristics,
+        });
+    }
+
+    services
+}
+
+fn hex(raw: &[u8]) -> String {
+    raw.iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Human readable form of the dump
+pub fn format_services(services: &[ServiceDump]) -> String {
+    let mut out = String::new();
+
+    for service in services {
+        let kind = if service.primary {
+            "primary"
+        } else {
+            "secondary"
+        };
+        out += &format!("Service {} ({kind})\n", service.uuid);
+
+        for characteristic in &service.characteristics {
+            out += &format!(
+                "    Characteristic {} {:?}\n",
+                characteristic.uuid, characteristic.properties
+            );
+
+            if let Some(value) = &characteristic.value {
+                out += &format!("        raw: [{}]\n", hex(value));
+            }
+
+            if let Some(decoded) = characteristic.decoded() {
+                out += &format!("        decoded: {decoded}\n");
+            }
+        }
+    }
+
+    out
+}
+
+/// Machine readable form of the dump
+pub fn services_to_json(services: &[ServiceDump]) -> Value {
+    services
+        .iter()
+        .map(|service| {
+            let characteristics: Vec<_> = service
+                .characteristics
+                .iter()
+                .map(|characteristic| {
+                    json!({
+                        "uuid": characteristic.uuid.to_string(),
+                        "properties": format!("{:?}", characteristic.properties),
+                        "value": characteristic.value,
+                        "decoded": characteristic.decoded(),
+                    })
+                })
+                .collect();
+
+            json!({
+                "uuid": service.uuid.to_string(),
+                "primary": service.primary,
+                "characteristics": characteristics,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indoor_bike_data_defs::{CONTROL_POINT, SERVICE_UUID};
+
+    #[test]
+    fn synthetic_service_is_formatted() {
+        let services = vec![ServiceDump {
+            uuid: SERVICE_UUID,
+            primary: true,
+            characteristics: vec![
+                CharacteristicDump {
+                    uuid: SUPPORTED_POWER_RANGE,
+                    properties: CharPropFlags::READ,
+                    value: Some(vec![0x00, 0x00, 0xe8, 0x03, 0x01, 0x00]),
+                },
+                CharacteristicDump {
+                    uuid: CONTROL_POINT,
+                    properties: CharPropFlags::WRITE | CharPropFlags::INDICATE,
+                    value: None,
+                },
+            ],
+        }];
+
+        let human = format_services(&services);
+        assert!(human.starts_with(&format!("Service {SERVICE_UUID} (primary)\n")));
+        assert!(human.contains("raw: [00 00 e8 03 01 00]"));
+        assert!(human.contains("decoded: Range { min: 0, max: 1000, step: 1 }"));
+        assert!(human.contains(&format!("Characteristic {CONTROL_POINT}")));
+
+        let json = services_to_json(&services);
+        let characteristics = &json[0]["characteristics"];
+        assert_eq!(json[0]["uuid"], SERVICE_UUID.to_string());
+        assert_eq!(characteristics[0]["value"], json!([0, 0, 232, 3, 1, 0]));
+        assert_eq!(characteristics[1]["value"], Value::Null);
+        assert_eq!(characteristics[1]["decoded"], Value::Null);
+    }
+}
@@ -0,0 +1,125 @@
+//! Workout engine driving FTMS indoor bikes, usable as a library.
+//!
+//! [`ZwoWorkout`] is a [`Stream`](futures::Stream) of [`UserCommands`], yielding a command whenever
+//! the trainer should change its target (power, simulation mode, etc.).
+//! Intended consumption pattern is:
+//! 1. construct the workout with [`ZwoWorkout::new`], it comes together with [`WorkoutStateActor`],
+//! 2. spawn the actor, in order to receive [`WorkoutState`] snapshots on a broadcast channel,
+//! 3. poll the stream, and feed the commands to your own trainer abstraction,
+//!    [`IndoorBikeFitnessMachine`] in case of a BLE FTMS device.
+//!
+//! Without the trainer (like the `--simulate` mode of the binary) commands are just consumed:
+//!
+//! ```
+//! use std::path::Path;
+//!
+//! use backend::{UserCommands, ZwoWorkout};
+//! use futures::StreamExt;
+//! use tokio::sync::broadcast;
+//!
+//! #[tokio::main]
+//! async fn main() -> anyhow::Result<()> {
+//!     let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("workouts/test.zwo");
+//!
+//!     let (mut workout, workout_state_actor) = ZwoWorkout::new(&path, 200.0).await?;
+//!     // Do not wait for the steps to finish
+//!     workout.set_fast_forward(true);
+//!
+//!     let (workout_state_tx, _workout_state_rx) = broadcast::channel(16);
+//!     let workout_state = tokio::spawn(workout_state_actor.run(workout_state_tx));
+//!
+//!     let mut power_set = vec![];
+//!     while let Some(command) = workout.next().await {
+//!         // Here the command would be sent to the trainer
+//!         if let UserCommands::SetTargetPower { power } = command {
+//!             power_set.push(power);
+//!         }
+//!     }
+//!
+//!     // Workout is done, state actor returns the final state
+//!     drop(workout);
+//!     let final_state = workout_state.await?;
+//!
+//!     assert_eq!(final_state.current_step_number, final_state.total_steps);
+//!     assert_eq!(power_set.first(), Some(&90));
+//!
+//!     Ok(())
+//! }
+//! ```
+use std::{path::PathBuf, sync::RwLock};
+
+use config::RecentWorkout;
+use indoor_bike_data_defs::ControlPointNotificationData;
+use ride_summary::RideSummary;
+use tokio::sync::{broadcast, mpsc};
+
+#[macro_use]
+extern crate num_derive;
+#[macro_use]
+extern crate log;
+
+mod bk_gatts_service;
+pub mod audio_cues;
+pub mod auto_pause;
+pub mod ble_client;
+pub mod ble_device;
+pub mod cli;
+pub mod common;
+pub mod config;
+pub mod derived_bike_data;
+pub mod fitness_machine;
+pub mod ftp_test;
+pub mod front;
+pub mod indoor_bike_client;
+pub mod indoor_bike_data_defs;
+pub mod inspect;
+pub mod logging;
+pub mod mock_workout;
+pub mod power_meter_client;
+pub mod power_model;
+pub mod power_zones;
+pub mod preview;
+pub mod progress;
+pub mod ride_summary;
+pub mod ride_workout;
+pub mod route;
+mod scalar_converter;
+pub mod trainer_control;
+pub mod watchdog;
+pub mod web_endpoints;
+pub mod workout_runner;
+pub mod workout_state;
+mod workout_state_ws;
+pub mod zwo_workout;
+pub mod zwo_workout_file;
+
+pub use cli::{UserCommands, WorkoutCommands};
+pub use fitness_machine::FitnessMachine;
+pub use indoor_bike_client::{IndoorBikeFitnessMachine, TrainerInfo};
+pub use indoor_bike_data_defs as ftms;
+pub use workout_state::{
+    WorkoutState, WorkoutStateActor, WorkoutStateChannel, WorkoutStatePublisher,
+};
+pub use zwo_workout::{Repeat, RestPowerFloor, WorkoutEvent, ZwoWorkout};
+pub use zwo_workout_file::{WorkoutFile, WorkoutInfo, WorkoutSteps};
+
+/// State shared with the HTTP server
+pub struct AppState {
+    /// Clients subscribe for the workout state here, the workout is the only publisher
+    pub workout_state: WorkoutStateChannel,
+    pub control_workout_tx: mpsc::Sender<WorkoutCommands>,
+    /// Metadata of currently loaded workout
+    pub workout_info: RwLock<Option<WorkoutInfo>>,
+    /// Capabilities of connected (or simulated) trainer
+    pub trainer_info: RwLock<Option<TrainerInfo>>,
+    /// Workout library browsed by the UI
+    pub workout_dir: Option<PathBuf>,
+    /// Workouts run lately, the latest last, for the UI to offer a quick pick
+    pub recent_workouts: RwLock<Vec<RecentWorkout>>,
+    /// Available once the workout is completed
+    pub ride_summary: RwLock<Option<RideSummary>>,
+    /// Transitions of the running workout, like step started, or workout completed
+    pub workout_events_tx: broadcast::Sender<WorkoutEvent>,
+    /// Trainer responses to control point requests, HTTP clients wait here for the result of their request
+    pub control_point_tx: broadcast::Sender<ControlPointNotificationData>,
+}
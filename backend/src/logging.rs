@@ -0,0 +1,100 @@
+//! Logger setup. Levels are set per module with RUST_LOG, like `backend=debug,btleplug=warn`.
+//! Console output stays human readable, unless JSON records are requested.
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use env_logger::{fmt::Target, Builder, Env};
+use log::Record;
+
+/// Initializes global logger, optionally writing one JSON record per line,
+/// and copying all logs to given file
+pub fn init_logging(json: bool, log_file: Option<&Path>) -> Result<()> {
+    let mut builder = Builder::from_env(Env::default());
+
+    if json {
+        builder.format(|buf, record| {
+            let line = json_record(record, buf.timestamp_millis());
+            writeln!(buf, "{}", line)
+        });
+    }
+
+    if let Some(path) = log_file {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Cannot open log file {}", path.display()))?;
+
+        builder.target(Target::Pipe(Box::new(Tee {
+            console: io::stderr(),
+            file,
+        })));
+    }
+
+    builder.try_init()?;
+
+    Ok(())
+}
+
+/// Serializes log record with its timestamp, module and level
+fn json_record(record: &Record, timestamp: impl Display) -> String {
+    serde_json::json!({
+        "timestamp": timestamp.to_string(),
+        "level": record.level().to_string(),
+        "module": record.module_path(),
+        "message": record.args().to_string(),
+    })
+    .to_string()
+}
+
+/// Writes logs to the console and the file
+struct Tee {
+    console: io::Stderr,
+    file: File,
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write_all(buf)?;
+        self.console.write_all(buf)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.console.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn json_record_is_parseable() {
+        let line = json_record(
+            &Record::builder()
+                .level(Level::Warn)
+                .module_path(Some("backend::zwo_workout"))
+                .args(format_args!("Step \"{}\" skipped", 3))
+                .build(),
+            "2022-12-24T12:00:00.000Z",
+        );
+        assert!(!line.contains('\n'));
+
+        let parsed: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed["timestamp"], "2022-12-24T12:00:00.000Z");
+        assert_eq!(parsed["level"], "WARN");
+        assert_eq!(parsed["module"], "backend::zwo_workout");
+        assert_eq!(parsed["message"], "Step \"3\" skipped");
+    }
+}
@@ -1,8 +1,7 @@
-#[macro_use]
-extern crate num_derive;
 use std::{
     fs::File,
     io::{self, BufReader},
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
     sync::RwLock,
     thread,
@@ -13,104 +12,552 @@ use actix_web::{middleware, App, HttpServer};
 use rustls::{Certificate, PrivateKey, ServerConfig};
 use rustls_pemfile::{certs, pkcs8_private_keys};
 use structopt::StructOpt;
-use workout_state::WorkoutState;
-use zwo_workout::ZwoWorkout;
 
-use crate::ble_client::BleClient;
-use anyhow::Result;
-use cli::{UserCommands, WorkoutCommands};
+use anyhow::{Context, Result};
+use backend::{
+    audio_cues::play_cues,
+    auto_pause::auto_pause,
+    ble_client::BleClient,
+    cli::parse_workout_command,
+    common::{
+        duration_to_string, parse_duration, Units, BIKE_DATA_CHANNEL_CAPACITY,
+        CONTROL_CHANNEL_CAPACITY, DEFAULT_EFFICIENCY, WORKOUT_STATE_CHANNEL_CAPACITY,
+    },
+    config::{Config, RecentWorkout},
+    derived_bike_data::integrate_bike_data,
+    ftp_test::FtpTest,
+    indoor_bike_client::calibrate_bike_data,
+    indoor_bike_data_defs::{self, BikeData, PowerCalibration},
+    inspect,
+    logging::init_logging,
+    mock_workout::mock_workout,
+    power_meter_client::{merge_with_bike_data, PowerMeterClient, PowerSource},
+    power_model::{estimate_power, PowerCurve, DEFAULT_BIKE_WEIGHT, DEFAULT_RIDER_WEIGHT},
+    power_zones::ZoneBounds,
+    preview::preview,
+    route::{replay_route, Route},
+    trainer_control::{
+        control_fit_machine, exit_requested, forward_control_point, send_trainer_command,
+    },
+    watchdog::watchdog,
+    web_endpoints,
+    workout_runner::{start_manual_mode, start_workout, WorkoutOptions},
+    AppState, FitnessMachine, IndoorBikeFitnessMachine, Repeat, RestPowerFloor, TrainerInfo,
+    UserCommands, WorkoutCommands, WorkoutFile, WorkoutState, WorkoutStateChannel, ZwoWorkout,
+};
+use btleplug::api::Peripheral as _;
 use futures::StreamExt;
-use indoor_bike_client::IndoorBikeFitnessMachine;
-use indoor_bike_data_defs::ControlPointResult;
 use signal_hook::consts::signal::*;
 use signal_hook_async_std::Signals;
-use tokio::{
-    sync::{broadcast, mpsc},
-    task,
-};
+use tokio::{sync::broadcast, task};
 
-mod bk_gatts_service;
-mod ble_client;
-mod cli;
-mod common;
-mod front;
-mod indoor_bike_client;
-mod indoor_bike_data_defs;
-mod scalar_converter;
-mod web_endpoints;
-mod workout_state;
-mod workout_state_ws;
-mod zwo_workout;
-mod zwo_workout_file;
 #[macro_use]
 extern crate log;
 
+/// How much time is added to the current step on user request
+const EXTEND_STEP_BY: Duration = Duration::from_secs(30);
+/// How long the workout is given on exit, to save the ride or the FTP test result
+const WORKOUT_EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the trainer and the workout are given to wind down, once the server stops
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(StructOpt)]
 struct Args {
-    /// Workout .zwo file
+    /// Workout .zwo file, without it target power is set by hand (manual mode)
     #[structopt(short, long, parse(from_os_str))]
-    workout: PathBuf,
-
+    workout: Option<PathBuf>,
+
+    /// GPX route, trainer in simulation mode follows its elevation profile as the distance is covered.
+    /// Target power is not set, the same as in manual mode
+    #[structopt(long, parse(from_os_str), conflicts_with = "workout")]
+    route: Option<PathBuf>,
+
+    /// Once the ride without a workout is aborted, save it as a workout template to given .zwo file,
+    /// power recorded every second is compacted into steps
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["workout", "ftp-test", "mock-workout"]
+    )]
+    save_ride: Option<PathBuf>,
+
+    /// Overrides ftp_base from the config, required if config does not set it
     #[structopt(short, long)]
-    ftp_base: f64,
+    ftp_base: Option<f64>,
+
+    /// Config file with the rider profile, ~/.velomania/config.toml is used if it exists
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Name of the trainer advertised over BLE, SUITO by default
+    #[structopt(long)]
+    device: Option<String>,
+
+    /// BLE adapter to use, like hci1, first one found by default
+    #[structopt(long)]
+    adapter: Option<String>,
+
+    /// Units speed and distance are displayed in: "metric" (default), or "imperial"
+    #[structopt(long)]
+    units: Option<Units>,
+
+    /// Directory with .zwo files, available for the UI to browse, overrides workout_dir of the config
+    #[structopt(long, parse(from_os_str))]
+    workout_dir: Option<PathBuf>,
+
+    /// Clamp target power to the range supported by the trainer, instead of rejecting it
+    #[structopt(long)]
+    clamp_power: bool,
+
+    /// Do not connect to the trainer, run the workout and the server only
+    #[structopt(long)]
+    simulate: bool,
+
+    /// Publish an endless synthetic workout state (and bike data) every second, for load testing
+    /// the server with many clients. Trainer is not connected, as with --simulate
+    #[structopt(long, conflicts_with_all = &["workout", "route", "ftp-test"])]
+    mock_workout: bool,
+
+    /// Run the workout instantly, without a trainer and the server, log all commands and the final state
+    #[structopt(long)]
+    fast_forward: bool,
+
+    /// Device measuring power and cadence: "trainer", or "power-meter" (Cycling Power Service),
+    /// trainer keeps handling the resistance in both cases
+    #[structopt(long, default_value = "trainer")]
+    power_source: PowerSource,
+
+    /// Watts added to the power measured by the trainer, applied after --power-scale.
+    /// Does not affect ERG targets, only measured values. 0 if config does not set it either
+    #[structopt(long, allow_hyphen_values = true)]
+    power_offset: Option<f64>,
+
+    /// Watts added to every target power of the workout, to ride it harder (or easier, if negative)
+    /// than planned. Unlike --power-offset, it changes ERG targets, not measured values
+    #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+    power_offset_watts: i16,
+
+    /// Lowest target of the rests, in watts (60W) or percent of FTP (30%).
+    /// Rest targets below it are raised, some trainers surge at very low ERG targets
+    #[structopt(long)]
+    rest_power_floor: Option<RestPowerFloor>,
+
+    /// Estimate power from the speed with given curve, if trainer does not measure the power:
+    /// "kinetic" (Kurt Kinetic fluid trainer), or "road" (generic flat road model)
+    #[structopt(long)]
+    power_curve: Option<PowerCurve>,
+
+    /// Rider weight in kg, for the road power curve
+    #[structopt(long)]
+    rider_weight: Option<f64>,
+
+    /// Bike weight in kg, for the road power curve
+    #[structopt(long)]
+    bike_weight: Option<f64>,
+
+    /// Gross efficiency of the rider, fraction of burned energy turned into work,
+    /// for calories estimate. 0.24 by default, which makes kcal about equal to kJ
+    #[structopt(long)]
+    efficiency: Option<f64>,
+
+    /// Factor the power measured by the trainer is multiplied by.
+    /// Does not affect ERG targets, only measured values
+    #[structopt(long, default_value = "1")]
+    power_scale: f64,
+
+    /// Upper bounds of power zones Z1-Z6, in percent of FTP
+    #[structopt(long, default_value = "55,75,90,105,120,150")]
+    power_zones: ZoneBounds,
+
+    /// Minimal time between target power writes, 0 disables the limit.
+    /// Some trainers cannot keep up with rapid writes
+    #[structopt(long, default_value = "1s", parse(try_from_str = parse_duration))]
+    min_write_interval: Duration,
+
+    /// How long to wait for the trainer to acknowledge a write, next command is sent anyway
+    /// once it passes. Some trainers do not respond at all
+    #[structopt(long, default_value = "5s", parse(try_from_str = parse_duration))]
+    ack_timeout: Duration,
+
+    /// Write every target to the trainer, instead of just the latest one of these queued
+    /// while waiting for the trainer to acknowledge the previous write
+    #[structopt(long)]
+    no_command_coalescing: bool,
+
+    /// Only observe the trainer, it's controlled by another app (like Zwift).
+    /// Nothing is written to the trainer, the workout drives the display only
+    #[structopt(long)]
+    no_control: bool,
+
+    /// Pause the workout, if cadence is 0 for given number of seconds, resume once rider pedals again.
+    /// 0 disables auto pause
+    #[structopt(long, default_value = "0")]
+    auto_pause_secs: u64,
+
+    /// Pause the workout, if trainer sends no bike data for given number of seconds. 0 disables the watchdog
+    #[structopt(long, default_value = "10")]
+    watchdog_secs: u64,
+
+    /// Origin allowed to access the server, like http://192.168.1.10:8080, can be repeated.
+    /// "*" allows any origin, by default only localhost origins (any port) are allowed
+    #[structopt(long)]
+    cors_origin: Vec<String>,
+
+    /// Address the server listens on, use 0.0.0.0 to make it reachable from other devices in the LAN
+    #[structopt(long, default_value = "127.0.0.1")]
+    bind_address: IpAddr,
+
+    #[structopt(long, default_value = "2137")]
+    port: u16,
+
+    /// Log one JSON record per line, instead of human readable text
+    #[structopt(long)]
+    log_json: bool,
+
+    /// Copy logs to given file, console output stays as is
+    #[structopt(long, parse(from_os_str))]
+    log_file: Option<PathBuf>,
+
+    /// Log a concise line with the workout progress, for runs without any UI
+    #[structopt(long)]
+    progress: bool,
+
+    /// How often the progress is logged
+    #[structopt(long, default_value = "30s", parse(try_from_str = parse_duration))]
+    progress_interval: Duration,
+
+    /// Beep when step, or interval part is about to end and when it starts,
+    /// sound requires the "audio" feature
+    #[structopt(long)]
+    audio: bool,
+
+    /// Change the target of warmup, ramp and cooldown only when it differs by more than 1W,
+    /// instead of every second, so the trainer ramps smoothly
+    #[structopt(long)]
+    smooth_ramps: bool,
+
+    /// Load the workout, but start it once resumed ("RESUME", or "START" command),
+    /// so the rider can get on the bike first
+    #[structopt(long)]
+    start_paused: bool,
+
+    /// Refuse workouts meant for other sport than the bike, instead of warning about them
+    #[structopt(long)]
+    strict: bool,
+
+    /// Ride FTP test instead of a workout: "ramp" (target goes up every minute until you quit),
+    /// or "20min" (20 minutes all out). Estimated FTP is logged at the end
+    #[structopt(long, conflicts_with_all = &["workout", "route"])]
+    ftp_test: Option<FtpTest>,
+
+    /// Write FTP estimated by the --ftp-test to the config
+    #[structopt(long, requires = "ftp-test")]
+    save_ftp: bool,
+
+    /// Number of times the workout is executed, or "infinite", it starts over once completed.
+    /// Ride summary covers all the loops
+    #[structopt(long, default_value = "1")]
+    repeat: Repeat,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
 }
 
-struct AppState {
-    workout_state_tx: RwLock<Option<broadcast::Sender<WorkoutState>>>,
-    control_workout_tx: mpsc::Sender<WorkoutCommands>,
+#[derive(StructOpt)]
+enum Command {
+    /// Connect to the trainer, print all its services and characteristics, with values of readable ones
+    Inspect {
+        /// Print JSON, instead of human readable text
+        #[structopt(long)]
+        json: bool,
+    },
+    /// List workouts run lately, the latest last
+    Recent,
+    /// Print steps of the workout with the planned training load, without connecting to the trainer
+    Preview {
+        #[structopt(parse(from_os_str))]
+        workout: PathBuf,
+        /// FTP the targets are relative to, FTP from the config by default
+        #[structopt(long)]
+        ftp: Option<f64>,
+    },
+}
+
+impl Args {
+    fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+
+    /// Values given in the command line, they take precedence over the config file
+    fn config_overrides(&self) -> Config {
+        Config {
+            ftp_base: self.ftp_base,
+            device: self.device.clone(),
+            adapter: self.adapter.clone(),
+            power_offset: self.power_offset,
+            units: self.units,
+            rider_weight: self.rider_weight,
+            bike_weight: self.bike_weight,
+            efficiency: self.efficiency,
+            workout_dir: self.workout_dir.clone(),
+            recent: vec![],
+        }
+    }
+
+    fn workout_options(&self, ftp_base: f64, config: &Config) -> WorkoutOptions {
+        WorkoutOptions {
+            ftp_base,
+            zone_bounds: self.power_zones,
+            smooth_ramps: self.smooth_ramps,
+            repeat: self.repeat,
+            power_offset_watts: self.power_offset_watts,
+            rest_power_floor: self.rest_power_floor,
+            start_paused: self.start_paused,
+            strict: self.strict,
+            units: config.units.unwrap_or_default(),
+            efficiency: config.efficiency.unwrap_or(DEFAULT_EFFICIENCY),
+            ftp_test: self.ftp_test,
+            save_ftp_to: if self.save_ftp {
+                self.config.clone().or_else(Config::default_path)
+            } else {
+                None
+            },
+            recent_to: self.config.clone().or_else(Config::default_path),
+            progress_every: self.progress.then_some(self.progress_interval),
+        }
+    }
 }
 
 // TODO: why not tokio::main?
 #[actix_web::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    let mut opt = Args::from_args();
+
+    init_logging(opt.log_json, opt.log_file.as_deref())?;
 
-    let connect_to_trainer = true;
+    let config = Config::load(opt.config.as_deref())?.merge(opt.config_overrides());
+    config.validate()?;
 
-    let opt = Args::from_args();
+    match opt.cmd {
+        Some(Command::Inspect { json }) => return inspect_trainer(&config, json).await,
+        Some(Command::Recent) => {
+            print_recent_workouts(&config.recent);
+            return Ok(());
+        }
+        Some(Command::Preview { ref workout, ftp }) => {
+            let ftp_base = ftp
+                .or(config.ftp_base)
+                .context("FTP is not known, pass --ftp or set ftp_base in the config")?;
+            let workout = WorkoutFile::new(workout).await?;
+
+            print!(
+                "{}",
+                preview(&workout, ftp_base, config.units.unwrap_or_default())
+            );
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let ftp_base = config
+        .ftp_base
+        .context("FTP is not known, pass --ftp-base or set ftp_base in the config")?;
+
+    if let Some(ftp_test) = opt.ftp_test {
+        info!("Riding {ftp_test:?} FTP test, targets are relative to FTP {ftp_base}W");
+        opt.workout = Some(ftp_test.write_workout().await?);
+    }
+
+    if opt.fast_forward {
+        let workout = opt
+            .workout
+            .as_deref()
+            .context("--fast-forward requires a --workout")?;
+        fast_forward_workout(workout, ftp_base).await?;
+        return Ok(());
+    }
 
     // Channel used by workout task to broadcast power value to be set - received by control_fit_machine, but also by frontend
-    let (trainer_commands_tx, _command_rx) = tokio::sync::broadcast::channel(16);
-    let (workout_state_tx, _rx) = tokio::sync::broadcast::channel(16);
+    let (trainer_commands_tx, _command_rx) =
+        tokio::sync::broadcast::channel(CONTROL_CHANNEL_CAPACITY);
 
     // Channel used to control workout, skip step, pause
-    let (control_workout_tx, control_workout_rx) = tokio::sync::mpsc::channel(16);
+    let (control_workout_tx, control_workout_rx) =
+        tokio::sync::mpsc::channel(CONTROL_CHANNEL_CAPACITY);
 
     let app_state = actix_web::web::Data::new(AppState {
-        workout_state_tx: RwLock::new(Some(workout_state_tx)),
+        workout_state: WorkoutStateChannel::new(WORKOUT_STATE_CHANNEL_CAPACITY),
         control_workout_tx,
+        workout_info: RwLock::new(None),
+        trainer_info: RwLock::new(None),
+        workout_dir: config.workout_dir.clone(),
+        recent_workouts: RwLock::new(config.recent.clone()),
+        ride_summary: RwLock::new(None),
+        workout_events_tx: broadcast::channel(CONTROL_CHANNEL_CAPACITY).0,
+        control_point_tx: broadcast::channel(CONTROL_CHANNEL_CAPACITY).0,
     });
 
     register_signal_handler(trainer_commands_tx.clone());
 
-    let (fit, bike_notifications, training_notifications, machine_status_notifications) = {
-        if connect_to_trainer {
-            let fit = connect_to_fit().await?;
-            let bike_notifications = fit.subscribe_for_indoor_bike_notifications();
+    // Mock workout feeds the bike data instead of the trainer
+    let mut mock_bike_tx = None;
+
+    let (
+        fit,
+        power_meter,
+        bike_notifications,
+        training_notifications,
+        machine_status_notifications,
+    ) = {
+        if !opt.simulate && !opt.mock_workout {
+            // Trainer and power meter are found with the same adapter
+            let ble = BleClient::with_adapter(config.adapter.as_deref())
+                .await?
+                .with_device_name(config.device.clone());
+
+            let mut fit = connect_to_fit(&ble, opt.no_control).await?;
+            fit.set_clamp_power(opt.clamp_power);
+
+            *app_state.trainer_info.write().unwrap() = Some(fit.trainer_info().await?);
+            tokio::spawn(forward_control_point(
+                fit.subscribe_for_control_point_notifications(),
+                app_state.control_point_tx.clone(),
+            ));
+
+            let mut bike_notifications = fit.subscribe_for_indoor_bike_notifications();
+
+            let calibration = PowerCalibration {
+                offset: config.power_offset.unwrap_or(0.0),
+                scale: opt.power_scale,
+            };
+            if !calibration.is_identity() {
+                info!("Measured power is calibrated with {calibration:?}");
+                bike_notifications = calibrate_bike_data(bike_notifications, calibration);
+            }
+
             let training_notifications = fit.subscribe_for_training_notifications();
             let machine_status_notifications = fit.subscribe_for_machine_notifications();
 
+            let power_meter = match opt.power_source {
+                PowerSource::Trainer => None,
+                PowerSource::PowerMeter => {
+                    let power_meter = connect_to_power_meter(&ble).await?;
+
+                    bike_notifications = merge_with_bike_data(
+                        bike_notifications,
+                        power_meter.subscribe_for_power_notifications(),
+                    );
+
+                    Some(power_meter)
+                }
+            };
+
+            if let Some(curve) = opt.power_curve {
+                let curve = curve.with_mass(
+                    config.rider_weight.unwrap_or(DEFAULT_RIDER_WEIGHT)
+                        + config.bike_weight.unwrap_or(DEFAULT_BIKE_WEIGHT),
+                );
+                info!("Power is estimated from the speed with {curve:?} curve, if not measured");
+                bike_notifications = estimate_power(bike_notifications, curve);
+            }
+
+            bike_notifications = integrate_bike_data(bike_notifications);
+
             (
                 Some(fit),
+                power_meter,
                 Some(bike_notifications),
                 Some(training_notifications),
                 Some(machine_status_notifications),
             )
+        } else if opt.mock_workout {
+            *app_state.trainer_info.write().unwrap() = Some(TrainerInfo::simulated());
+
+            let (bike_tx, bike_notifications) = broadcast::channel(BIKE_DATA_CHANNEL_CAPACITY);
+            mock_bike_tx = Some(bike_tx);
+
+            (None, None, Some(bike_notifications), None, None)
         } else {
+            *app_state.trainer_info.write().unwrap() = Some(TrainerInfo::simulated());
+
             // TODO: create fake data in the future
-            (None, None, None, None)
+            (None, None, None, None, None)
         }
     };
 
+    if let (Some(bike_notifications), true) = (&bike_notifications, opt.auto_pause_secs > 0) {
+        tokio::spawn(auto_pause(
+            bike_notifications.resubscribe(),
+            app_state.control_workout_tx.clone(),
+            Duration::from_secs(opt.auto_pause_secs),
+        ));
+    }
+
+    if let (Some(bike_notifications), true) = (&bike_notifications, opt.watchdog_secs > 0) {
+        tokio::spawn(watchdog(
+            bike_notifications.resubscribe(),
+            app_state.control_workout_tx.clone(),
+            app_state.workout_events_tx.clone(),
+            Duration::from_secs(opt.watchdog_secs),
+        ));
+    }
+
+    if let Some(route) = opt.route.as_deref() {
+        let route = Route::new(route).await?;
+
+        match &bike_notifications {
+            Some(bike_notifications) => {
+                tokio::spawn(replay_route(
+                    route,
+                    bike_notifications.resubscribe(),
+                    trainer_commands_tx.clone(),
+                ));
+            }
+            None => warn!("Route replay requires distance reported by the trainer, route ignored"),
+        }
+    }
+
+    if opt.audio {
+        if !cfg!(feature = "audio") {
+            warn!("Built without the \"audio\" feature, cues are only logged");
+        }
+
+        let workout_state_rx = app_state.workout_state.subscribe().unwrap();
+        tokio::spawn(play_cues(
+            app_state.workout_events_tx.subscribe(),
+            workout_state_rx,
+        ));
+    }
+
     // Start workout task, will broadcast next steps
-    let workout_join_handle = start_workout(
-        trainer_commands_tx.clone(),
-        app_state.clone(),
-        control_workout_rx,
-        opt.workout.as_path(),
-        opt.ftp_base,
-    )
-    .await?;
+    let mut workout_join_handle = match opt.workout.as_deref() {
+        _ if opt.mock_workout => start_mock_workout(
+            app_state.clone(),
+            trainer_commands_tx.subscribe(),
+            control_workout_rx,
+            ftp_base,
+            mock_bike_tx,
+        ),
+        Some(workout) => {
+            start_workout(
+                trainer_commands_tx.clone(),
+                app_state.clone(),
+                control_workout_rx,
+                bike_notifications,
+                workout,
+                opt.workout_options(ftp_base, &config),
+            )
+            .await?
+        }
+        None => start_manual_mode(
+            trainer_commands_tx.clone(),
+            app_state.clone(),
+            control_workout_rx,
+            ftp_base,
+            bike_notifications,
+            opt.save_ride.clone(),
+        ),
+    };
 
     handle_user_input(app_state.control_workout_tx.clone());
 
@@ -120,25 +567,62 @@ async fn main() -> Result<()> {
     //     bike_notifications,
     //     training_notifications,
     //     machine_status_notifications,
+    //     config.units.unwrap_or_default(),
     // ));
 
-    tokio::spawn(async move {
-        if let Some(fit) = fit {
-            control_fit_machine(fit, trainer_commands_tx.subscribe())
+    let min_write_interval = opt.min_write_interval;
+    let ack_timeout = opt.ack_timeout;
+    let coalesce = !opt.no_command_coalescing;
+    let no_control = opt.no_control;
+    let exit_tx = trainer_commands_tx.clone();
+    let control_join_handle = tokio::spawn(async move {
+        match fit {
+            Some(fit) if !no_control => {
+                if let Err(e) = control_fit_machine(
+                    fit,
+                    trainer_commands_tx.subscribe(),
+                    min_write_interval,
+                    coalesce,
+                    ack_timeout,
+                )
                 .await
-                .unwrap();
-        } else {
-            // Listen for sigterm
-            let mut rx = trainer_commands_tx.subscribe();
-            while let Ok(message) = rx.recv().await {
-                if let UserCommands::Exit = message {
-                    info!("Exit!");
-                    break;
+                {
+                    error!("Control task failed: {e:?}");
+                }
+            }
+            fit => {
+                // Listen for sigterm
+                let mut rx = trainer_commands_tx.subscribe();
+                while let Ok(message) = rx.recv().await {
+                    if matches!(message, UserCommands::Exit | UserCommands::WorkoutCompleted) {
+                        info!("Exit!");
+                        break;
+                    }
+                }
+
+                // Observed trainer is left as it is, another app controls it
+                if let Some(fit) = fit {
+                    if let Err(e) = fit.disconnect().await {
+                        warn!("Failed to disconnect the trainer: {e:?}");
+                    }
                 }
             }
         };
 
-        workout_join_handle.abort();
+        if let Some(power_meter) = power_meter {
+            if let Err(e) = power_meter.disconnect().await {
+                warn!("Failed to disconnect power meter: {e:?}");
+            }
+        }
+
+        // Workout got the exit too, let it save its results, but do not wait forever
+        if tokio::time::timeout(WORKOUT_EXIT_TIMEOUT, &mut workout_join_handle)
+            .await
+            .is_err()
+        {
+            warn!("Workout did not finish in {WORKOUT_EXIT_TIMEOUT:?}, aborting it");
+            workout_join_handle.abort();
+        }
         // tui_join_handle.abort();
     });
 
@@ -147,23 +631,49 @@ async fn main() -> Result<()> {
     // there is an issue opened for it for quite some time
     let _tls_conf = load_rustls_config();
 
-    HttpServer::new(move || {
+    let cors_origins = opt.cors_origin.clone();
+    let socket_addr = opt.socket_addr();
+
+    let server = HttpServer::new(move || {
         // HttpServer accepts an application factory rather than an application instance.
         // An HttpServer constructs an application instance for EACH thread.
         // Therefore, application data must be constructed multiple times.
         // If you want to share data between different threads,
         // a shareable object should be used, e.g. Send + Sync.
         App::new()
+            .wrap(web_endpoints::cors(&cors_origins))
             .wrap(middleware::Logger::default())
             .app_data(app_state.clone())
             .service(web_endpoints::workout_state_handle)
+            .service(web_endpoints::events_handle)
+            .service(web_endpoints::workout_info_handle)
+            .service(web_endpoints::trainer_info_handle)
+            .service(web_endpoints::workouts_handle)
+            .service(web_endpoints::recent_handle)
+            .service(web_endpoints::summary_handle)
+            .service(web_endpoints::health_handle)
+            .service(web_endpoints::ready_handle)
+            .service(web_endpoints::control_power_handle)
+            .service(web_endpoints::control_resistance_handle)
             .service(web_endpoints::web_socket_handle)
     })
     // TODO: wss does not work for some reason
-    // .bind_rustls(("127.0.0.1", 2137), tls_conf)?
-    .bind(("127.0.0.1", 2137))?
-    .run()
-    .await?;
+    // .bind_rustls(socket_addr, tls_conf)?
+    .bind(socket_addr)?;
+
+    info!("Serving on http://{socket_addr}, websocket on ws://{socket_addr}/ws");
+
+    server.run().await?;
+
+    // Server stops on signals on its own, stop the trainer and the workout the same way.
+    // No one listens if they are done already
+    let _ = exit_tx.send(UserCommands::Exit);
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, control_join_handle)
+        .await
+        .is_err()
+    {
+        warn!("Trainer did not stop in {SHUTDOWN_TIMEOUT:?}");
+    }
 
     Ok(())
 }
@@ -199,147 +709,52 @@ fn load_rustls_config() -> rustls::ServerConfig {
     config.with_single_cert(cert_chain, keys.remove(0)).unwrap()
 }
 
-/// Reads ZWO file, and sends commands according to it
-async fn start_workout(
-    trainer_commands_tx: tokio::sync::broadcast::Sender<UserCommands>,
-    app_state: actix_web::web::Data<AppState>,
-    mut control_workout_rx: tokio::sync::mpsc::Receiver<WorkoutCommands>,
-    workout: &Path,
-    ftp_base: f64,
-) -> Result<tokio::task::JoinHandle<()>> {
-    let mut workout = ZwoWorkout::new(workout, ftp_base).await?;
-
-    let handle = tokio::spawn(async move {
-        debug!("spawning workout task");
-
-        let propagate_workout_state = tokio::time::interval(Duration::from_secs(1));
-        tokio::pin!(propagate_workout_state);
+/// Executes whole workout without waiting, returns number of commands it produced and the final state
+async fn fast_forward_workout(workout: &Path, ftp_base: f64) -> Result<(usize, WorkoutState)> {
+    let (mut workout, workout_state_actor) = ZwoWorkout::new(workout, ftp_base).await?;
+    workout.set_fast_forward(true);
 
-        let workout_state_tx = {
-            let guard = app_state.workout_state_tx.read().unwrap();
+    let (workout_state_tx, _) = broadcast::channel(WORKOUT_STATE_CHANNEL_CAPACITY);
+    let workout_state_handle = tokio::spawn(workout_state_actor.run(workout_state_tx));
 
-            guard.as_ref().cloned().unwrap()
-        };
-
-        trainer_commands_tx
-            .send(UserCommands::StartWorkout)
-            .unwrap();
-
-        loop {
-            tokio::select! {
-                workout_step = workout.next() => {
-                    // Next step is available
-                    match workout_step {
-                        Some(command) => {
-                            debug!("Got command from workout: {command:?}");
-                            debug!("workout {}/{}",
-                                workout.workout_state.current_step_number,
-                                workout.workout_state.total_steps);
-
-                            debug!("workout {:?}", workout.current_step);
-                            trainer_commands_tx.send(command).unwrap();
-                        }
-                        None => {
-                            debug!("No more steps in workout, workout task exits");
-                            trainer_commands_tx.send(UserCommands::Exit).unwrap();
+    let mut nr_commands = 0;
+    while let Some(command) = workout.next().await {
+        nr_commands += 1;
+        info!("#{nr_commands} {command:?}");
+    }
 
-                            break;
-                        },
-                    }
-                }
-                // TODO: this is a workaround, ideally there would be:
-                //
-                // Some(workout_state) = workout.workout_state.next() => {
-                //     workout_state_tx.send(workout.workout_state.clone()).unwrap();
-                // }
-                // But BC complains that mut borrow is already held on workout,
-                // figure something out here
-                // TODO: Arc? Gets immutable borrow Nope, RefCell? Nope will panic during runtime
-                // Mutex? Will deadlock
-                // Do subscribe to the channel from the workout state?
-                // Move workout state as separate actor, let workout communicate to state via channel
-                // to update it
-                _ = propagate_workout_state.tick() => {
-                    debug!("Broadcast workout state {}/{}",
-                        workout.workout_state.current_step_number,
-                        workout.workout_state.total_steps);
-
-                    workout.workout_state.update_ts();
-                    workout_state_tx.send(workout.workout_state.clone()).unwrap();
-                }
-                Some(control)  = control_workout_rx.recv() => {
-                    match control {
-                        WorkoutCommands::Pause=> workout.pause(),
-                        WorkoutCommands::Resume=> todo!(),
-                        WorkoutCommands::SkipStep=> workout.skip_step(),
-                        WorkoutCommands::Abort => {
-                            trainer_commands_tx.send(UserCommands::Exit).unwrap();
-                            break;
-                        },
-                    }
-                }
-            }
-        }
+    // Dropping the workout stops the state actor
+    drop(workout);
+    let final_state = workout_state_handle.await?;
 
-        {
-            // Workout completed, drop workout_state_tx, so all receivers will close
-            // TODO: note if someone will clone workout_state_tx (which is possible - broadcast channel allows that)
-            // that will break the whole idea - streams would not be closed until all tx instances are not dropped
-            let mut guard = app_state.workout_state_tx.write().unwrap();
-            let _ = guard.take();
-        }
-    });
+    info!("Workout fast forwarded, {nr_commands} commands, final state {final_state:#?}");
 
-    Ok(handle)
+    Ok((nr_commands, final_state))
 }
 
-/// Gets the commands (may be ZWO workout, or user input), and passes them to the fitness machine
-async fn control_fit_machine(
-    fit: IndoorBikeFitnessMachine,
-    mut rx: broadcast::Receiver<UserCommands>,
-) -> Result<()> {
-    // Cannot set return type of async block, async closures are unstable
-
-    fit.dump_service_info().await?;
-    fit.get_features().await?;
-
-    // TODO: Use select?
-    // let _status_notifications = fit.subscribe_for_status_notifications();
-
-    let mut cp_notifications = fit.subscribe_for_control_point_notifications();
+/// Synthetic workout, for load testing the server, runs until user aborts, or the application exits
+fn start_mock_workout(
+    app_state: actix_web::web::Data<AppState>,
+    mut trainer_commands_rx: broadcast::Receiver<UserCommands>,
+    control_workout_rx: tokio::sync::mpsc::Receiver<WorkoutCommands>,
+    ftp_base: f64,
+    bike_tx: Option<broadcast::Sender<BikeData>>,
+) -> tokio::task::JoinHandle<()> {
+    info!("Starting mock workout");
 
-    while let Ok(message) = rx.recv().await {
-        match message {
-            UserCommands::Exit => {
-                info!("Control task exits");
-                break;
-            }
-            UserCommands::SetResistance { resistance } => {
-                fit.set_resistance(resistance).await?;
-            }
-            UserCommands::SetTargetPower { power } => {
-                fit.set_power(power).await?;
-            }
-            UserCommands::StartWorkout => {
-                fit.reset_status().await?;
-            }
-        }
+    let workout_state_tx = app_state
+        .workout_state
+        .publisher()
+        .unwrap_or_else(|| broadcast::channel(1).0.into());
 
-        // Wait for CP notification response for above write request
-        let resp = cp_notifications.recv().await?;
-        match resp.request_status {
-            ControlPointResult::Success => {
-                debug!("Got ACK for request {resp:?}");
-            }
-            _ => {
-                error!("Received NACK for request: {resp:?}");
-            }
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = mock_workout(ftp_base, workout_state_tx, bike_tx, control_workout_rx) => {}
+            _ = exit_requested(&mut trainer_commands_rx) => {}
         }
-    }
-
-    fit.disconnect().await?;
 
-    Ok(())
+        app_state.workout_state.close();
+    })
 }
 
 fn register_signal_handler(tx: tokio::sync::broadcast::Sender<UserCommands>) {
@@ -351,22 +766,79 @@ fn register_signal_handler(tx: tokio::sync::broadcast::Sender<UserCommands>) {
         match signals.next().await {
             Some(sig) => {
                 warn!("Got signal {sig}");
-                tx.send(UserCommands::Exit).unwrap();
+                send_trainer_command(&tx, UserCommands::Exit);
             }
             None => unreachable!("Signals stream closed?"),
         }
     });
 }
 
-async fn connect_to_fit() -> Result<IndoorBikeFitnessMachine> {
-    let ble = BleClient::new().await;
+async fn connect_to_fit(ble: &BleClient, observe_only: bool) -> Result<IndoorBikeFitnessMachine> {
     // ble.connect_to_bc().await.unwrap();
 
-    let fit = IndoorBikeFitnessMachine::new(&ble).await?;
+    let fit = if observe_only {
+        IndoorBikeFitnessMachine::new_observer(ble).await?
+    } else {
+        IndoorBikeFitnessMachine::new(ble).await?
+    };
+
+    fit.dump_service_info().await?;
+    fit.get_features().await?;
 
     Ok(fit)
 }
 
+fn print_recent_workouts(recent: &[RecentWorkout]) {
+    let now = RecentWorkout::started_now(PathBuf::new()).started;
+
+    for workout in recent {
+        let ago = Duration::from_secs(now.saturating_sub(workout.started) / 60 * 60);
+        let status = if workout.completed {
+            "completed"
+        } else {
+            "aborted"
+        };
+
+        println!(
+            "{:>16} ago  {status:<9}  {}",
+            duration_to_string(&ago),
+            workout.path.display()
+        );
+    }
+}
+
+/// Prints GATT profile of the trainer
+async fn inspect_trainer(config: &Config, json: bool) -> Result<()> {
+    let ble = BleClient::with_adapter(config.adapter.as_deref())
+        .await?
+        .with_device_name(config.device.clone());
+
+    let peripheral = ble
+        .find_service(indoor_bike_data_defs::SERVICE_UUID)
+        .await?;
+
+    let services = inspect::dump_services(&peripheral).await;
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&inspect::services_to_json(&services))?
+        );
+    } else {
+        print!("{}", inspect::format_services(&services));
+    }
+
+    peripheral.disconnect().await?;
+
+    Ok(())
+}
+
+async fn connect_to_power_meter(ble: &BleClient) -> Result<PowerMeterClient> {
+    let power_meter = PowerMeterClient::new(ble).await?;
+
+    Ok(power_meter)
+}
+
 pub fn handle_user_input(tx: tokio::sync::mpsc::Sender<WorkoutCommands>) {
     // It's not recommended to handle user input using async.
     // Spawn dedicated thread instead.
@@ -380,7 +852,7 @@ pub fn handle_user_input(tx: tokio::sync::mpsc::Sender<WorkoutCommands>) {
 
             if let Err(e) = res {
                 error!("Got error while reading stdin {e}, exiting");
-                tx.blocking_send(WorkoutCommands::Abort).unwrap();
+                let _ = tx.blocking_send(WorkoutCommands::Abort);
                 break;
             }
 
@@ -388,17 +860,128 @@ pub fn handle_user_input(tx: tokio::sync::mpsc::Sender<WorkoutCommands>) {
 
             match input.as_str() {
                 "S" => {
-                    tx.blocking_send(WorkoutCommands::SkipStep).unwrap();
+                    if tx.blocking_send(WorkoutCommands::SkipStep).is_err() {
+                        warn!("Workout is not running anymore");
+                        break;
+                    }
                 }
-                "Q" => {
-                    let _ = tx.blocking_send(WorkoutCommands::Abort);
-                    break;
+                "E" => {
+                    if tx
+                        .blocking_send(WorkoutCommands::ExtendStep(EXTEND_STEP_BY))
+                        .is_err()
+                    {
+                        warn!("Workout is not running anymore");
+                        break;
+                    }
                 }
-                other => {
-                    warn!("Unexpected user input {other}");
+                "Q" => {
+                    // Abort during the final cooldown needs confirmation, keep reading
+                    if tx.blocking_send(WorkoutCommands::Abort).is_err() {
+                        break;
+                    }
                 }
+                // Commands with an argument
+                other => match parse_workout_command(other) {
+                    Some(command) => {
+                        if tx.blocking_send(command).is_err() {
+                            warn!("Workout is not running anymore");
+                            break;
+                        }
+                    }
+                    None => warn!("Unexpected user input {other}"),
+                },
             }
         }
         info!("Waiting for user input leaves");
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bind_address_and_port_are_parsed() {
+        let args = Args::from_iter_safe(["backend", "-w", "test.zwo", "-f", "200"]).unwrap();
+        assert_eq!(args.socket_addr(), "127.0.0.1:2137".parse().unwrap());
+
+        let args = Args::from_iter_safe([
+            "backend",
+            "-w",
+            "test.zwo",
+            "-f",
+            "200",
+            "--bind-address",
+            "0.0.0.0",
+            "--port",
+            "8080",
+        ])
+        .unwrap();
+        assert_eq!(args.socket_addr(), "0.0.0.0:8080".parse().unwrap());
+
+        assert!(Args::from_iter_safe([
+            "backend",
+            "-w",
+            "test.zwo",
+            "-f",
+            "200",
+            "--bind-address",
+            "localhost:80",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn workout_is_optional() {
+        let args = Args::from_iter_safe(["backend", "-f", "200"]).unwrap();
+        assert!(args.workout.is_none());
+        assert_eq!(args.ftp_base, Some(200.0));
+    }
+
+    #[test]
+    fn ride_is_saved_in_manual_mode_only() {
+        assert!(Args::from_iter_safe(["backend", "-f", "200", "--save-ride", "ride.zwo"]).is_ok());
+
+        for conflicting in [
+            &["-w", "test.zwo"][..],
+            &["--ftp-test", "ramp"],
+            &["--mock-workout"],
+        ] {
+            let args = ["backend", "-f", "200", "--save-ride", "ride.zwo"]
+                .iter()
+                .chain(conflicting);
+            assert!(Args::from_iter_safe(args).is_err(), "{:?}", conflicting);
+        }
+    }
+
+    #[test]
+    fn mock_workout_runs_alone() {
+        assert!(Args::from_iter_safe(["backend", "-f", "200", "--mock-workout"]).is_ok());
+
+        for conflicting in [
+            &["-w", "test.zwo"][..],
+            &["--route", "route.gpx"],
+            &["--ftp-test", "ramp"],
+        ] {
+            let args = ["backend", "-f", "200", "--mock-workout"]
+                .iter()
+                .chain(conflicting);
+            assert!(Args::from_iter_safe(args).is_err(), "{:?}", conflicting);
+        }
+    }
+
+    fn test_workout() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts/test.zwo")
+    }
+
+    #[tokio::test]
+    async fn fast_forward_executes_whole_workout() {
+        let (nr_commands, final_state) =
+            fast_forward_workout(&test_workout(), 200.0).await.unwrap();
+
+        // Warmup 5 + 6 steady states + 5 intervals on/off + Cooldown 5 + FreeRide + Ramp 5
+        assert_eq!(nr_commands, 5 + 6 + 10 + 5 + 1 + 5);
+        assert_eq!(final_state.current_step_number, final_state.total_steps);
+        assert_eq!(final_state.current_step_number, 11);
+    }
+}
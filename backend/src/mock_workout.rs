@@ -0,0 +1,109 @@
+//! Endless synthetic workout, for load testing the server with many clients,
+//! without a trainer or a workout file.
+use std::time::Duration;
+
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    cli::WorkoutCommands, common::get_power, indoor_bike_data_defs::BikeData,
+    workout_state::WorkoutStatePublisher, WorkoutState,
+};
+
+/// State is published that often, the same as by the real workout
+const MOCK_PERIOD: Duration = Duration::from_secs(1);
+/// Target changes every minute, going through these fractions of FTP over and over again
+const MOCK_LEVELS: [f64; 4] = [0.5, 0.75, 1.0, 0.6];
+const MOCK_LEVEL_SECS: u64 = 60;
+const MOCK_CADENCE: f64 = 90.0;
+const MOCK_SPEED: f64 = 30.0;
+
+/// Publishes workout state every second, until aborted. If bike data sender is given,
+/// a sample riding exactly the target is sent along with each state.
+pub async fn mock_workout(
+    ftp_base: f64,
+    workout_state_tx: WorkoutStatePublisher,
+    bike_tx: Option<broadcast::Sender<BikeData>>,
+    mut control_workout_rx: mpsc::Receiver<WorkoutCommands>,
+) {
+    let mut state = WorkoutState::manual(ftp_base);
+    state.workout_name = "Mock workout".to_string();
+
+    let mut publish = tokio::time::interval(MOCK_PERIOD);
+
+    loop {
+        tokio::select! {
+            _ = publish.tick() => {
+                state.update_ts();
+
+                let level = mock_level(state.workout_elapsed);
+                let power = get_power(ftp_base, level);
+                state.set_power(power, Some(level));
+
+                if workout_state_tx.send(state.clone()).is_err() {
+                    trace!("No one listens for mock workout state");
+                }
+
+                if let Some(bike_tx) = &bike_tx {
+                    // Send may fail, if there is no receiver
+                    let _ = bike_tx.send(BikeData {
+                        inst_power: Some(power),
+                        inst_cadence: Some(MOCK_CADENCE),
+                        inst_speed: Some(MOCK_SPEED),
+                        ..Default::default()
+                    });
+                }
+            }
+            control = control_workout_rx.recv() => match control {
+                Some(WorkoutCommands::Abort) | None => break,
+                Some(other) => debug!("{other:?} is ignored by the mock workout"),
+            }
+        }
+    }
+
+    debug!("Mock workout leaves");
+}
+
+/// Power level of the mock workout at given time
+fn mock_level(elapsed: Duration) -> f64 {
+    let minute = (elapsed.as_secs() / MOCK_LEVEL_SECS) as usize;
+
+    MOCK_LEVELS[minute % MOCK_LEVELS.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn mock_workout_clock_goes_forward() {
+        let (workout_state_tx, mut workout_state_rx) = broadcast::channel(16);
+        let (bike_tx, mut bike_rx) = broadcast::channel(16);
+        let (control_workout_tx, control_workout_rx) = mpsc::channel(1);
+
+        let mock = tokio::spawn(mock_workout(
+            200.0,
+            workout_state_tx.into(),
+            Some(bike_tx),
+            control_workout_rx,
+        ));
+
+        let mut elapsed = vec![];
+        for _ in 0..3 {
+            let state = workout_state_rx.recv().await.unwrap();
+            assert_eq!(state.current_power_set, 100);
+            elapsed.push(state.workout_elapsed);
+        }
+        assert!(elapsed.windows(2).all(|pair| pair[1] > pair[0]));
+        assert_eq!(bike_rx.recv().await.unwrap().inst_power, Some(100));
+
+        // Target moves on after a minute
+        assert_eq!(mock_level(Duration::from_secs(61)), 0.75);
+        assert_eq!(mock_level(Duration::from_secs(241)), 0.5);
+
+        control_workout_tx
+            .send(WorkoutCommands::Abort)
+            .await
+            .unwrap();
+        mock.await.unwrap();
+    }
+}
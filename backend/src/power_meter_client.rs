@@ -0,0 +1,323 @@
+//! Client of the standalone power meter, exposing GATTS Cycling Power Service
+//! Refer to BLE GATTS Cycling Power Profile documentation
+use std::{pin::Pin, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use btleplug::{
+    api::{bleuuid::uuid_from_u16, Peripheral as _, ValueNotification},
+    platform::Peripheral,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use futures::{Stream, StreamExt};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use uuid::Uuid;
+
+use crate::{
+    ble_client::BleClient, common::BIKE_DATA_CHANNEL_CAPACITY,
+    indoor_bike_client::get_characteristic, indoor_bike_data_defs::BikeData,
+    scalar_converter::ScalarType,
+};
+
+/// GATTS Service UUID
+pub const SERVICE_UUID: Uuid = uuid_from_u16(0x1818);
+
+/// NOTIFY, gets instantaneous power, and optionally pedal balance, torque, wheel and crank revolutions
+pub const CYCLING_POWER_MEASUREMENT: Uuid = uuid_from_u16(0x2A63);
+
+/// Fields present in the Cycling Power Measurement, only those preceding crank revolution data
+/// are listed, nothing after them is parsed.
+/// DOCS: CPS_v1.1 3.2.1, GATT_Specification_Supplement 3.59
+#[derive(Debug)]
+pub enum CyclingPowerFlags {
+    PedalPowerBalance = 1 << 0,
+    AccumulatedTorque = 1 << 2,
+    WheelRevolutionData = 1 << 4,
+    CrankRevolutionData = 1 << 5,
+}
+
+/// Decoded Cycling Power Measurement
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PowerMeasurement {
+    pub inst_power: i16,
+    /// Percentage of the power coming from the reference (usually left) pedal
+    pub pedal_balance: Option<f64>,
+    /// Derived from two consecutive crank revolution readings
+    pub inst_cadence: Option<f64>,
+}
+
+/// Which device provides power and cadence readings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerSource {
+    Trainer,
+    PowerMeter,
+}
+
+impl FromStr for PowerSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "trainer" => Ok(PowerSource::Trainer),
+            "power-meter" => Ok(PowerSource::PowerMeter),
+            other => Err(anyhow!(
+                "Unknown power source '{other}', expected 'trainer' or 'power-meter'"
+            )),
+        }
+    }
+}
+
+pub struct PowerMeterClient {
+    client: Peripheral,
+    power_tx: Sender<PowerMeasurement>,
+}
+
+impl PowerMeterClient {
+    pub async fn new(ble: &BleClient) -> Result<PowerMeterClient> {
+        info!("Creating Power Meter...");
+        let client = ble.find_service(SERVICE_UUID).await?;
+
+        let measurement = get_characteristic(&client, CYCLING_POWER_MEASUREMENT)?;
+        client.subscribe(&measurement).await?;
+
+        let (power_tx, _) = broadcast::channel(BIKE_DATA_CHANNEL_CAPACITY);
+
+        let notifications = client.notifications().await?;
+        tokio::spawn(handle_notifications(notifications, power_tx.clone()));
+
+        Ok(PowerMeterClient { client, power_tx })
+    }
+
+    /// Get rx endpoint for power measurements
+    /// To unsub, simply drop rx
+    pub fn subscribe_for_power_notifications(&self) -> Receiver<PowerMeasurement> {
+        self.power_tx.subscribe()
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        info!("Disconnecting from power meter");
+        self.client.disconnect().await?;
+
+        Ok(())
+    }
+}
+
+/// Replaces power and cadence reported by the trainer with the latest power meter readings,
+/// returns stream of merged bike data
+pub fn merge_with_bike_data(
+    mut bike_rx: Receiver<BikeData>,
+    mut power_rx: Receiver<PowerMeasurement>,
+) -> Receiver<BikeData> {
+    let (merged_tx, merged_rx) = broadcast::channel(BIKE_DATA_CHANNEL_CAPACITY);
+
+    tokio::spawn(async move {
+        let mut latest = None;
+
+        loop {
+            tokio::select! {
+                Ok(measurement) = power_rx.recv() => latest = Some(measurement),
+                Ok(bike_data) = bike_rx.recv() => {
+                    let bike_data = match &latest {
+                        Some(measurement) => merge_power(bike_data, measurement),
+                        None => bike_data,
+                    };
+
+                    // Send may fail, if there is no receiver
+                    let _ = merged_tx.send(bike_data);
+                }
+                else => break,
+            }
+        }
+
+        debug!("Merging power meter data leaves");
+    });
+
+    merged_rx
+}
+
+fn merge_power(mut bike_data: BikeData, measurement: &PowerMeasurement) -> BikeData {
+    bike_data.inst_power = Some(measurement.inst_power);
+
+    if measurement.inst_cadence.is_some() {
+        bike_data.inst_cadence = measurement.inst_cadence;
+    }
+
+    bike_data
+}
+
+async fn handle_notifications(
+    mut notifications: Pin<Box<dyn Stream<Item = ValueNotification> + Send>>,
+    power_tx: Sender<PowerMeasurement>,
+) {
+    let mut decoder = PowerMeasurementDecoder::default();
+
+    while let Some(data) = notifications.next().await {
+        if data.uuid != CYCLING_POWER_MEASUREMENT {
+            warn!(
+                "Got unhandled notification from uuid {}, value {:?}",
+                data.uuid, data.value
+            );
+            continue;
+        }
+
+        trace!(
+            "Got notification from CYCLING_POWER_MEASUREMENT: {:?}",
+            data.value
+        );
+        match decoder.decode(&data.value) {
+            // Send may fail, if there is no receiver
+            Ok(measurement) => {
+                let _ = power_tx.send(measurement);
+            }
+            Err(e) => warn!("Failed to decode power measurement: {e}"),
+        }
+    }
+}
+
+/// Cadence is not sent directly, it needs to be derived from consecutive crank revolution readings
+#[derive(Debug, Default)]
+struct PowerMeasurementDecoder {
+    /// Cumulative crank revolutions and last crank event time (1/1024s) from the previous measurement
+    last_crank: Option<(u16, u16)>,
+}
+
+impl PowerMeasurementDecoder {
+    fn decode(&mut self, raw_data: &[u8]) -> Result<PowerMeasurement> {
+        if raw_data.len() < 4 {
+            return Err(anyhow!("Power measurement too short {raw_data:?}"));
+        }
+
+        let flags = LittleEndian::read_u16(&raw_data[0..]);
+        let mut measurement = PowerMeasurement {
+            inst_power: LittleEndian::read_i16(&raw_data[2..]),
+            ..Default::default()
+        };
+
+        // Cursor pointing current position in raw_data, start after power field
+        let mut cursor = 4;
+        let field = |cursor: usize, len: usize| {
+            raw_data
+                .get(cursor..cursor + len)
+                .ok_or_else(|| anyhow!("Power measurement truncated {raw_data:?}"))
+        };
+
+        if flags & CyclingPowerFlags::PedalPowerBalance as u16 != 0 {
+            let raw = field(cursor, 1)?[0];
+            cursor += 1;
+
+            let conv = ScalarType::new().with_multiplier(1).with_bin_exp(-1);
+            measurement.pedal_balance = Some(conv.to_scalar(raw));
+        }
+
+        if flags & CyclingPowerFlags::AccumulatedTorque as u16 != 0 {
+            cursor += 2;
+        }
+
+        if flags & CyclingPowerFlags::WheelRevolutionData as u16 != 0 {
+            // Cumulative wheel revolutions u32, last wheel event time u16
+            cursor += 6;
+        }
+
+        if flags & CyclingPowerFlags::CrankRevolutionData as u16 != 0 {
+            let raw = field(cursor, 4)?;
+            let revolutions = LittleEndian::read_u16(&raw[0..]);
+            let event_time = LittleEndian::read_u16(&raw[2..]);
+
+            measurement.inst_cadence = self.cadence(revolutions, event_time);
+        }
+
+        Ok(measurement)
+    }
+
+    /// Returns cadence in rpm, if crank moved since previous measurement
+    fn cadence(&mut self, revolutions: u16, event_time: u16) -> Option<f64> {
+        let previous = self.last_crank.replace((revolutions, event_time));
+        let (last_revolutions, last_event_time) = previous?;
+
+        // Both counters roll over
+        let revolutions = revolutions.wrapping_sub(last_revolutions);
+        let elapsed = event_time.wrapping_sub(last_event_time);
+
+        if elapsed == 0 {
+            return None;
+        }
+
+        Some(f64::from(revolutions) * 60.0 * 1024.0 / f64::from(elapsed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_and_balance_are_decoded() {
+        let mut decoder = PowerMeasurementDecoder::default();
+
+        // Flags: pedal power balance, power 250W, balance 101 * 0.5 = 50.5%
+        let measurement = decoder.decode(&[0x01, 0x00, 0xFA, 0x00, 101]).unwrap();
+
+        assert_eq!(
+            measurement,
+            PowerMeasurement {
+                inst_power: 250,
+                pedal_balance: Some(50.5),
+                inst_cadence: None,
+            }
+        );
+    }
+
+    #[test]
+    fn cadence_is_derived_from_crank_revolutions() {
+        let mut decoder = PowerMeasurementDecoder::default();
+
+        // Flags: wheel and crank revolution data, power 200W
+        // wheel revs 100, wheel time 0, crank revs 0xFFFF, crank time 0xFF00
+        let first = [
+            0x30, 0x00, 0xC8, 0x00, 100, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0x00, 0xFF,
+        ];
+        // One crank revolution later, counters rolled over, 0.5s elapsed (512/1024)
+        let second = [
+            0x30, 0x00, 0xC8, 0x00, 101, 0, 0, 0, 0, 0, 0x00, 0x00, 0x00, 0x01,
+        ];
+
+        let measurement = decoder.decode(&first).unwrap();
+        assert_eq!(measurement.inst_power, 200);
+        assert_eq!(measurement.inst_cadence, None);
+
+        let measurement = decoder.decode(&second).unwrap();
+        assert_eq!(measurement.inst_cadence, Some(120.0));
+    }
+
+    #[test]
+    fn truncated_measurement_is_rejected() {
+        let mut decoder = PowerMeasurementDecoder::default();
+
+        assert!(decoder.decode(&[0x00, 0x00, 0xFA]).is_err());
+        // Crank revolution data announced, but missing
+        assert!(decoder.decode(&[0x20, 0x00, 0xFA, 0x00]).is_err());
+    }
+
+    #[test]
+    fn power_meter_overrides_trainer_readings() {
+        let bike_data = BikeData {
+            inst_power: Some(180),
+            inst_cadence: Some(85.0),
+            inst_speed: Some(30.0),
+            ..Default::default()
+        };
+
+        let merged = merge_power(
+            bike_data,
+            &PowerMeasurement {
+                inst_power: 195,
+                pedal_balance: None,
+                inst_cadence: None,
+            },
+        );
+
+        assert_eq!(merged.inst_power, Some(195));
+        // Power meter does not report cadence, keep the trainer one
+        assert_eq!(merged.inst_cadence, Some(85.0));
+        assert_eq!(merged.inst_speed, Some(30.0));
+    }
+}
@@ -0,0 +1,143 @@
+//! Estimates power from the speed, for trainers that measure the speed only (wheel-on, fluid, magnetic).
+//! Trainer resistance at given speed is fixed, so power follows the curve of the trainer.
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::broadcast::{self, Receiver};
+
+use crate::common::{recv_lagging, BIKE_DATA_CHANNEL_CAPACITY};
+use crate::indoor_bike_data_defs::BikeData;
+
+const KMH_IN_MPH: f64 = 1.609344;
+const KMH_IN_MS: f64 = 3.6;
+
+/// Flat road model: on the hoods, tarmac.
+/// Rider and bike weights are used if not given
+pub const DEFAULT_RIDER_WEIGHT: f64 = 76.0;
+pub const DEFAULT_BIKE_WEIGHT: f64 = 9.0;
+const ROAD_CRR: f64 = 0.004;
+const ROAD_CDA: f64 = 0.32;
+const AIR_DENSITY: f64 = 1.225;
+const GRAVITY: f64 = 9.81;
+
+/// Speed to power curves
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerCurve {
+    /// Kurt Kinetic fluid trainer, formula published by the vendor
+    Kinetic,
+    /// Riding on the flat road, generic cubic curve for trainers without a known curve.
+    /// Mass of the rider and the bike in kg
+    Road { mass: f64 },
+}
+
+impl FromStr for PowerCurve {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kinetic" => Ok(PowerCurve::Kinetic),
+            "road" => Ok(PowerCurve::Road {
+                mass: DEFAULT_RIDER_WEIGHT + DEFAULT_BIKE_WEIGHT,
+            }),
+            other => Err(anyhow!(
+                "Unknown power curve '{other}', expected 'kinetic' or 'road'"
+            )),
+        }
+    }
+}
+
+impl PowerCurve {
+    /// Uses given mass of the rider and the bike, if the curve depends on it
+    pub fn with_mass(self, mass: f64) -> Self {
+        match self {
+            PowerCurve::Road { .. } => PowerCurve::Road { mass },
+            other => other,
+        }
+    }
+
+    /// Power in watts needed to hold given speed in km/h
+    pub fn estimate(&self, speed: f64) -> i16 {
+        let speed = speed.max(0.0);
+
+        let power = match self {
+            PowerCurve::Kinetic => {
+                let mph = speed / KMH_IN_MPH;
+                5.244820 * mph + 0.019168 * mph.powi(3)
+            }
+            PowerCurve::Road { mass } => {
+                let ms = speed / KMH_IN_MS;
+                ROAD_CRR * mass * GRAVITY * ms + 0.5 * AIR_DENSITY * ROAD_CDA * ms.powi(3)
+            }
+        };
+
+        power.round().min(f64::from(i16::MAX)) as i16
+    }
+
+    /// Fills in power of the sample, if trainer did not measure it, measured power is kept as is
+    pub fn apply_to(&self, bike_data: &mut BikeData) {
+        if bike_data.inst_power.is_some() {
+            return;
+        }
+
+        if let Some(speed) = bike_data.inst_speed {
+            bike_data.inst_power = Some(self.estimate(speed));
+            bike_data.power_estimated = true;
+        }
+    }
+}
+
+/// Returns stream of bike data with power estimated from the speed, where it's missing
+pub fn estimate_power(mut bike_rx: Receiver<BikeData>, curve: PowerCurve) -> Receiver<BikeData> {
+    let (estimated_tx, estimated_rx) = broadcast::channel(BIKE_DATA_CHANNEL_CAPACITY);
+
+    tokio::spawn(async move {
+        while let Some(mut bike_data) = recv_lagging(&mut bike_rx, "Estimating power").await {
+            curve.apply_to(&mut bike_data);
+
+            // Send may fail, if there is no receiver
+            let _ = estimated_tx.send(bike_data);
+        }
+
+        debug!("Estimating power leaves");
+    });
+
+    estimated_rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_is_estimated_from_speed() {
+        // 20mph: 5.24482 * 20 + 0.019168 * 20^3
+        assert_eq!(PowerCurve::Kinetic.estimate(20.0 * KMH_IN_MPH), 258);
+        // 10m/s: 0.004 * 85 * 9.81 * 10 + 0.5 * 1.225 * 0.32 * 10^3
+        let road: PowerCurve = "road".parse().unwrap();
+        assert_eq!(road.estimate(36.0), 229);
+        assert_eq!(road.estimate(0.0), 0);
+        // Heavier rider rolls harder: 0.004 * 100 * 9.81 * 10 + 196
+        assert_eq!(road.with_mass(100.0).estimate(36.0), 235);
+        assert_eq!(PowerCurve::Kinetic.with_mass(100.0), PowerCurve::Kinetic);
+
+        let mut bike_data = BikeData {
+            inst_speed: Some(36.0),
+            ..Default::default()
+        };
+        road.apply_to(&mut bike_data);
+        assert_eq!(bike_data.inst_power, Some(229));
+        assert!(bike_data.power_estimated);
+
+        // Measured power takes precedence
+        let mut bike_data = BikeData {
+            inst_speed: Some(36.0),
+            inst_power: Some(180),
+            ..Default::default()
+        };
+        road.apply_to(&mut bike_data);
+        assert_eq!(bike_data.inst_power, Some(180));
+        assert!(!bike_data.power_estimated);
+
+        assert!("rollers".parse::<PowerCurve>().is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! Training zones, based on the percentage of FTP
+use std::{convert::TryInto, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Result};
+
+pub const NR_ZONES: usize = 7;
+
+/// Upper bounds of zones Z1-Z6 in percent of FTP, Z7 has no upper bound
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZoneBounds(pub [f64; NR_ZONES - 1]);
+
+impl Default for ZoneBounds {
+    /// Coggan power zones
+    fn default() -> Self {
+        Self([55.0, 75.0, 90.0, 105.0, 120.0, 150.0])
+    }
+}
+
+/// Comma separated percents of FTP, like 55,75,90,105,120,150
+impl FromStr for ZoneBounds {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bounds = s
+            .split(',')
+            .map(|bound| {
+                bound
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|e| anyhow!("Invalid zone bound '{bound}': {e}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let bounds: [f64; NR_ZONES - 1] = bounds.try_into().map_err(|bounds: Vec<f64>| {
+            anyhow!(
+                "Expected {} zone bounds, got {}",
+                NR_ZONES - 1,
+                bounds.len()
+            )
+        })?;
+
+        if bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(anyhow!("Zone bounds have to be ascending, got {bounds:?}"));
+        }
+
+        Ok(Self(bounds))
+    }
+}
+
+/// Tracks cumulative time spent in each zone
+#[derive(Debug, Clone)]
+pub struct PowerZones {
+    /// Upper bounds of zones Z1-Z6 in watts
+    upper_bounds: [f64; NR_ZONES - 1],
+    time_in_zones: [Duration; NR_ZONES],
+}
+
+impl PowerZones {
+    pub fn new(ftp_base: f64, bounds: ZoneBounds) -> Self {
+        Self {
+            upper_bounds: bounds.0.map(|percent| ftp_base * percent / 100.0),
+            time_in_zones: Default::default(),
+        }
+    }
+
+    /// Index of the zone, 0 for Z1
+    pub fn zone(&self, power: f64) -> usize {
+        self.upper_bounds
+            .iter()
+            .position(|bound| power < *bound)
+            .unwrap_or(NR_ZONES - 1)
+    }
+
+    /// Accounts given power held for given time
+    pub fn add(&mut self, power: f64, duration: Duration) {
+        let zone = self.zone(power);
+        self.time_in_zones[zone] += duration;
+    }
+
+    /// Time spent in each zone, Z1 first
+    pub fn time_in_zones(&self) -> [Duration; NR_ZONES] {
+        self.time_in_zones
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_trace_is_bucketed() {
+        let mut zones = PowerZones::new(200.0, ZoneBounds::default());
+
+        // Each sample lasts a second, Z1 < 110W, Z2 < 150W, Z3 < 180W, Z4 < 210W, Z5 < 240W, Z6 < 300W
+        let trace = [0, 109, 110, 150, 179, 180, 209, 210, 240, 299, 300, 1000];
+        for power in trace {
+            zones.add(f64::from(power), Duration::from_secs(1));
+        }
+
+        let expected = [2, 1, 2, 2, 1, 2, 2].map(Duration::from_secs);
+        assert_eq!(zones.time_in_zones(), expected);
+    }
+
+    #[test]
+    fn zone_bounds_can_be_overridden() {
+        let bounds: ZoneBounds = "50, 60, 70, 80, 90, 100".parse().unwrap();
+        let zones = PowerZones::new(200.0, bounds);
+
+        assert_eq!(zones.zone(99.0), 0);
+        assert_eq!(zones.zone(100.0), 1);
+        assert_eq!(zones.zone(199.0), 5);
+        assert_eq!(zones.zone(200.0), 6);
+
+        assert!("55,75,90".parse::<ZoneBounds>().is_err());
+        assert!("55,75,90,105,150,120".parse::<ZoneBounds>().is_err());
+        assert!("55,75,90,105,120,abc".parse::<ZoneBounds>().is_err());
+    }
+}
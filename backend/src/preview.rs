@@ -0,0 +1,137 @@
+//! Plan of the workout, printed without the trainer, to check the file before the ride.
+//! Metrics are these of riding every target exactly, free rides and speed steps count as 0W.
+use std::time::Duration;
+
+use crate::{
+    common::{duration_to_string, Units},
+    front::tui::display_step,
+    ride_summary::normalized_power,
+    zwo_workout_file::{WorkoutFile, WorkoutSteps},
+};
+
+const HOUR_IN_SECONDS: f64 = 3600.0;
+
+/// Training load of the workout ridden as planned
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannedMetrics {
+    pub duration: Duration,
+    pub normalized_power: f64,
+    /// Normalized power relative to FTP
+    pub intensity_factor: f64,
+    /// Training stress score, an hour at FTP is 100
+    pub tss: f64,
+}
+
+/// Target power of every second of the workout, in watts
+pub fn planned_power(workout: &WorkoutFile, ftp_base: f64) -> Vec<f64> {
+    let mut power_per_second = vec![];
+
+    for step in &workout.workout.steps {
+        let mut step = step.clone();
+
+        while let Some(power_duration) = step.advance() {
+            let secs = power_duration.duration.as_secs_f64().round() as usize;
+            let power = ftp_base * power_duration.power_level;
+
+            power_per_second.extend(std::iter::repeat_n(power, secs));
+        }
+    }
+
+    power_per_second
+}
+
+pub fn planned_metrics(workout: &WorkoutFile, ftp_base: f64) -> PlannedMetrics {
+    let power_per_second = planned_power(workout, ftp_base);
+
+    let average = if power_per_second.is_empty() {
+        0.0
+    } else {
+        power_per_second.iter().sum::<f64>() / power_per_second.len() as f64
+    };
+    let normalized_power = normalized_power(&power_per_second).unwrap_or(average);
+    let intensity_factor = normalized_power / ftp_base;
+    let duration = workout.total_workout_duration;
+
+    PlannedMetrics {
+        duration,
+        normalized_power,
+        intensity_factor,
+        tss: duration.as_secs_f64() / HOUR_IN_SECONDS * intensity_factor.powi(2) * 100.0,
+    }
+}
+
+/// Table of the steps, followed by the totals
+pub fn preview(workout: &WorkoutFile, ftp_base: f64, units: Units) -> String {
+    let mut preview = format!("{}, FTP {ftp_base}W\n", workout.name.trim());
+    preview += &format!("{:>3}  {:>12}  {:>9}  step\n", "#", "duration", "%FTP");
+
+    for (number, step) in workout.workout.steps.iter().enumerate() {
+        preview += &format!(
+            "{:>3}  {:>12}  {:>9}  {}\n",
+            number + 1,
+            duration_to_string(&step.get_step_duration()),
+            ftp_percent(step),
+            display_step(ftp_base, &Some(step.clone()), units)
+        );
+    }
+
+    let metrics = planned_metrics(workout, ftp_base);
+    preview += &format!(
+        "total {}, NP {:.0}W, IF {:.2}, TSS {:.0}\n",
+        duration_to_string(&metrics.duration),
+        metrics.normalized_power,
+        metrics.intensity_factor,
+        metrics.tss
+    );
+
+    preview
+}
+
+/// Power of the step in percent of FTP, ranges go from the start to the end of the step
+fn ftp_percent(step: &WorkoutSteps) -> String {
+    let percent = |level: f64| format!("{:.0}", level * 100.0);
+
+    match step {
+        WorkoutSteps::Warmup(s) => format!("{}-{}%", percent(s.power_low), percent(s.power_high)),
+        WorkoutSteps::Ramp(s) => format!("{}-{}%", percent(s.power_low), percent(s.power_high)),
+        WorkoutSteps::Cooldown(s) => {
+            format!("{}-{}%", percent(s.power_low), percent(s.power_high))
+        }
+        WorkoutSteps::SteadyState(s) => format!("{}%", percent(s.power)),
+        WorkoutSteps::IntervalsT(s) => format!("{}/{}%", percent(s.on_power), percent(s.off_power)),
+        WorkoutSteps::FreeRide(_) | WorkoutSteps::SteadySpeed(_) => "--".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zwo_workout_file::WorkoutBuilder;
+
+    #[test]
+    fn workout_plan_is_previewed() {
+        let workout = WorkoutBuilder::new("Preview")
+            .warmup(300, 0.5, 0.75)
+            .intervals(3, 60, 60, 1.2, 0.5)
+            .free_ride(120)
+            .build();
+
+        assert_eq!(
+            preview(&workout, 200.0, Units::Metric),
+            concat!(
+                "Preview, FTP 200W\n",
+                "  #      duration       %FTP  step\n",
+                "  1         5m 0s     50-75%  Warmup: 100W -> 150W\n",
+                "  2         6m 0s    120/50%  Intervals: repeat 3, work 240W for 1m 0s, rest 100W for 1m 0s\n",
+                "  3         2m 0s         --  Free Ride\n",
+                "total 13m 0s, NP 168W, IF 0.84, TSS 15\n",
+            )
+        );
+
+        // An hour at FTP
+        let metrics = planned_metrics(&WorkoutBuilder::new("FTP").steady(3600, 1.0).build(), 250.0);
+        assert_eq!(metrics.normalized_power, 250.0);
+        assert_eq!(metrics.intensity_factor, 1.0);
+        assert_eq!(metrics.tss, 100.0);
+    }
+}
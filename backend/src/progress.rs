@@ -0,0 +1,112 @@
+//! Concise progress of the workout logged periodically, for headless runs without any UI
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::broadcast::{error::RecvError, Receiver},
+    time::Instant,
+};
+
+use crate::{ride_summary::RideSummaryAccumulator, workout_state::WorkoutState};
+
+/// Logs progress of the workout every given period, until the workout is done.
+/// Normalized power comes from the ride summary, it's shown only if there is bike data.
+pub async fn log_progress(
+    mut state_rx: Receiver<WorkoutState>,
+    ride_summary: Arc<Mutex<RideSummaryAccumulator>>,
+    every: Duration,
+) {
+    let mut latest = None;
+    let mut log = tokio::time::interval(every);
+
+    loop {
+        tokio::select! {
+            state = state_rx.recv() => match state {
+                Ok(state) => latest = Some(state),
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            },
+            _ = log.tick() => {
+                if let Some(state) = &latest {
+                    let normalized_power = {
+                        let mut ride_summary = ride_summary.lock().unwrap();
+                        let summary = ride_summary.summary(Instant::now());
+                        // Nothing to normalize, without power from the trainer
+                        (!ride_summary.power_per_second().is_empty())
+                            .then_some(summary.normalized_power)
+                    };
+
+                    info!("{}", format_progress(state, normalized_power));
+                }
+            }
+        }
+    }
+
+    debug!("Progress logging leaves");
+}
+
+/// "Step 3/12 SteadyState 210W — 04:32 elapsed / 58:20 total — NP 198W"
+pub fn format_progress(state: &WorkoutState, normalized_power: Option<f64>) -> String {
+    let normalized_power = match normalized_power {
+        Some(power) => format!("{power:.0}W"),
+        None => "--".to_string(),
+    };
+
+    format!(
+        "Step {}/{} {} {}W{} — {} elapsed / {} total — NP {}",
+        state.current_step_number,
+        state.total_steps,
+        state.current_step.step.name(),
+        state.current_power_set,
+        if state.paused { " (paused)" } else { "" },
+        clock(&state.workout_elapsed),
+        clock(&state.total_workout_duration),
+        normalized_power,
+    )
+}
+
+/// mm:ss, or h:mm:ss for an hour and more
+fn clock(duration: &Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+    if hours > 0 {
+        format!("{hours}:{mins:02}:{secs:02}")
+    } else {
+        format!("{mins:02}:{secs:02}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::zwo_workout_file::WorkoutFile;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn progress_line_is_formatted() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts/test.zwo");
+        let workout = WorkoutFile::new(&path).await.unwrap();
+
+        let mut state = WorkoutState::new(&workout, 200.0);
+        state.current_power_set = 210;
+        state.workout_elapsed = Duration::from_secs(4 * 60 + 32);
+        state.total_workout_duration = Duration::from_secs(58 * 60 + 20);
+
+        assert_eq!(
+            format_progress(&state, Some(198.4)),
+            "Step 1/11 Warmup 210W — 04:32 elapsed / 58:20 total — NP 198W"
+        );
+
+        state.paused = true;
+        state.total_workout_duration = Duration::from_secs(3723);
+        assert_eq!(
+            format_progress(&state, None),
+            "Step 1/11 Warmup 210W (paused) — 04:32 elapsed / 1:02:03 total — NP --"
+        );
+    }
+}
@@ -0,0 +1,397 @@
+//! Aggregated statistics of the ride, computed from the bike data received during the workout
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::{
+    sync::broadcast::{error::RecvError, Receiver},
+    time::Instant,
+};
+
+use crate::{
+    common::{kj_to_kcal, whole_secs, DEFAULT_EFFICIENCY},
+    indoor_bike_data_defs::BikeData,
+    power_zones::{PowerZones, NR_ZONES},
+    zwo_workout::WorkoutEvent,
+};
+
+/// Window of the rolling average used to calculate normalized power
+const NP_WINDOW_SECS: usize = 30;
+
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct RideSummary {
+    #[serde(with = "whole_secs")]
+    pub duration: Duration,
+    pub avg_power: f64,
+    pub normalized_power: f64,
+    pub avg_cadence: f64,
+    /// In km/h
+    pub avg_speed: f64,
+    /// In km
+    pub distance: f64,
+    /// Work done by the rider in kJ
+    pub energy: f64,
+    /// Estimated calories burned, in kcal
+    pub calories: f64,
+    /// Time spent in each power zone, Z1 first
+    #[serde(serialize_with = "whole_secs::serialize_all")]
+    pub time_in_zones: [Duration; NR_ZONES],
+}
+
+/// Collects bike data samples, each sample is valid until the next one arrives
+#[derive(Debug)]
+pub struct RideSummaryAccumulator {
+    power_zones: PowerZones,
+    started: Instant,
+    last: Option<(BikeData, Instant)>,
+    /// Set while the ride is paused, paused time does not count
+    paused_since: Option<Instant>,
+    paused: Duration,
+    /// Power resampled to 1 second resolution, for normalized power
+    power_per_second: Vec<f64>,
+    /// Part of the second not yet put in power_per_second
+    second_carry: f64,
+    energy: f64,
+    distance: f64,
+    power_secs: f64,
+    cadence_sum: f64,
+    cadence_secs: f64,
+    speed_sum: f64,
+    speed_secs: f64,
+    /// Gross efficiency of the rider, for converting work to calories
+    efficiency: f64,
+}
+
+impl RideSummaryAccumulator {
+    pub fn new(power_zones: PowerZones, started: Instant) -> Self {
+        Self {
+            power_zones,
+            started,
+            last: None,
+            paused_since: None,
+            paused: Duration::ZERO,
+            power_per_second: vec![],
+            second_carry: 0.0,
+            energy: 0.0,
+            distance: 0.0,
+            power_secs: 0.0,
+            cadence_sum: 0.0,
+            cadence_secs: 0.0,
+            speed_sum: 0.0,
+            speed_secs: 0.0,
+            efficiency: DEFAULT_EFFICIENCY,
+        }
+    }
+
+    pub fn with_efficiency(mut self, efficiency: f64) -> Self {
+        self.efficiency = efficiency;
+        self
+    }
+
+    /// Adds the sample received at given time
+    pub fn add(&mut self, bike_data: BikeData, at: Instant) {
+        self.close_last_sample(at);
+        self.last = Some((bike_data, at));
+    }
+
+    /// Samples are not accounted until the ride is resumed
+    pub fn pause(&mut self, at: Instant) {
+        if self.paused_since.is_none() {
+            self.close_last_sample(at);
+            self.paused_since = Some(at);
+        }
+    }
+
+    pub fn resume(&mut self, at: Instant) {
+        if let Some(paused_since) = self.paused_since.take() {
+            self.paused += at.saturating_duration_since(paused_since);
+
+            // The last sample is valid from now on, not since it arrived during the pause
+            if let Some((_, since)) = self.last.as_mut() {
+                *since = at;
+            }
+        }
+    }
+
+    /// Summary of the ride up to given time
+    pub fn summary(&mut self, at: Instant) -> RideSummary {
+        self.close_last_sample(at);
+
+        let paused = self.paused
+            + self
+                .paused_since
+                .map_or(Duration::ZERO, |since| at.saturating_duration_since(since));
+
+        RideSummary {
+            duration: at
+                .saturating_duration_since(self.started)
+                .saturating_sub(paused),
+            avg_power: average(self.energy, self.power_secs),
+            normalized_power: normalized_power(&self.power_per_second)
+                .unwrap_or_else(|| average(self.energy, self.power_secs)),
+            avg_cadence: average(self.cadence_sum, self.cadence_secs),
+            avg_speed: average(self.speed_sum, self.speed_secs),
+            distance: self.distance,
+            energy: self.energy / 1000.0,
+            calories: kj_to_kcal(self.energy / 1000.0, self.efficiency),
+            time_in_zones: self.power_zones.time_in_zones(),
+        }
+    }
+
+    /// Power resampled to 1 second resolution, up to the last summary
+    pub fn power_per_second(&self) -> &[f64] {
+        &self.power_per_second
+    }
+
+    /// Accounts the last sample for the time until given instant, nothing is accounted while paused
+    fn close_last_sample(&mut self, at: Instant) {
+        if self.paused_since.is_some() {
+            return;
+        }
+
+        let (bike_data, since) = match self.last.as_mut() {
+            Some((bike_data, since)) => (bike_data.clone(), since),
+            None => return,
+        };
+
+        let elapsed = at.saturating_duration_since(*since);
+        *since = at;
+
+        let secs = elapsed.as_secs_f64();
+
+        if let Some(power) = bike_data.inst_power {
+            let power = f64::from(power);
+            self.energy += power * secs;
+            self.power_secs += secs;
+            self.power_zones.add(power, elapsed);
+
+            self.second_carry += secs;
+            while self.second_carry >= 1.0 {
+                self.power_per_second.push(power);
+                self.second_carry -= 1.0;
+            }
+        }
+
+        if let Some(cadence) = bike_data.inst_cadence {
+            self.cadence_sum += cadence * secs;
+            self.cadence_secs += secs;
+        }
+
+        if let Some(speed) = bike_data.inst_speed {
+            self.speed_sum += speed * secs;
+            self.speed_secs += secs;
+            self.distance += speed * secs / 3600.0;
+        }
+    }
+}
+
+/// Fourth root of the mean of 4th powers of 30s rolling average power.
+/// None for rides shorter than the window, average power is the closest there.
+pub fn normalized_power(power_per_second: &[f64]) -> Option<f64> {
+    if power_per_second.len() < NP_WINDOW_SECS {
+        return None;
+    }
+
+    let rolling: Vec<f64> = power_per_second
+        .windows(NP_WINDOW_SECS)
+        .map(|window| window.iter().sum::<f64>() / NP_WINDOW_SECS as f64)
+        .collect();
+
+    let mean = rolling.iter().map(|power| power.powi(4)).sum::<f64>() / rolling.len() as f64;
+
+    Some(mean.powf(0.25))
+}
+
+fn average(sum: f64, secs: f64) -> f64 {
+    if secs > 0.0 {
+        sum / secs
+    } else {
+        0.0
+    }
+}
+
+/// Feeds the accumulator with bike data, until the stream closes.
+/// Ride is paused and resumed together with the workout.
+pub async fn accumulate(
+    accumulator: Arc<Mutex<RideSummaryAccumulator>>,
+    mut bike_rx: Receiver<BikeData>,
+    mut events_rx: Receiver<WorkoutEvent>,
+) {
+    let mut events_open = true;
+
+    loop {
+        tokio::select! {
+            bike_data = bike_rx.recv() => match bike_data {
+                Ok(bike_data) => accumulator.lock().unwrap().add(bike_data, Instant::now()),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Ride summary lags behind bike data, skipped {skipped} samples");
+                }
+                Err(RecvError::Closed) => break,
+            },
+            event = events_rx.recv(), if events_open => match event {
+                Ok(WorkoutEvent::Paused) => accumulator.lock().unwrap().pause(Instant::now()),
+                Ok(WorkoutEvent::Resumed) => accumulator.lock().unwrap().resume(Instant::now()),
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Ride summary skipped {skipped} workout events");
+                }
+                Err(RecvError::Closed) => events_open = false,
+            },
+        }
+    }
+
+    debug!("Ride summary stops accumulating");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::power_zones::ZoneBounds;
+
+    use super::*;
+
+    fn new_accumulator(ftp_base: f64, started: Instant) -> RideSummaryAccumulator {
+        RideSummaryAccumulator::new(PowerZones::new(ftp_base, ZoneBounds::default()), started)
+    }
+
+    fn sample(power: i16, cadence: f64, speed: f64) -> BikeData {
+        BikeData {
+            inst_power: Some(power),
+            inst_cadence: Some(cadence),
+            inst_speed: Some(speed),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn averages_are_time_weighted() {
+        let started = Instant::now();
+        let mut accumulator = new_accumulator(250.0, started);
+
+        // 60s at 100W, 90rpm, 30km/h, then 120s at 250W, 60rpm, 36km/h
+        accumulator.add(sample(100, 90.0, 30.0), started);
+        accumulator.add(sample(250, 60.0, 36.0), started + Duration::from_secs(60));
+
+        let summary = accumulator.summary(started + Duration::from_secs(180));
+
+        assert_eq!(summary.duration, Duration::from_secs(180));
+        assert_eq!(summary.avg_power, 200.0);
+        assert_eq!(summary.avg_cadence, 70.0);
+        assert_eq!(summary.avg_speed, 34.0);
+        assert!((summary.distance - 1.7).abs() < 1e-9);
+        assert_eq!(summary.energy, 36.0);
+        assert_eq!(summary.calories.round(), 36.0);
+
+        // 100W is Z1 (below 55% of FTP), 250W is Z4 (between 90% and 105%)
+        let mut time_in_zones = [Duration::ZERO; NR_ZONES];
+        time_in_zones[0] = Duration::from_secs(60);
+        time_in_zones[3] = Duration::from_secs(120);
+        assert_eq!(summary.time_in_zones, time_in_zones);
+
+        // Variable power weighs more than the average
+        assert!(summary.normalized_power > summary.avg_power);
+    }
+
+    #[test]
+    fn steady_power_is_normalized_power() {
+        let started = Instant::now();
+        let mut accumulator = new_accumulator(200.0, started);
+
+        for second in 0..60 {
+            accumulator.add(
+                sample(170, 90.0, 30.0),
+                started + Duration::from_secs(second),
+            );
+        }
+
+        let summary = accumulator.summary(started + Duration::from_secs(60));
+
+        assert_eq!(summary.normalized_power, 170.0);
+        assert_eq!(summary.time_in_zones[2], Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bike_data_stream_is_accumulated() {
+        let started = Instant::now();
+        let accumulator = Arc::new(Mutex::new(new_accumulator(200.0, started)));
+
+        let (bike_tx, bike_rx) = tokio::sync::broadcast::channel(16);
+        let (events_tx, events_rx) = tokio::sync::broadcast::channel(16);
+        let task = tokio::spawn(accumulate(accumulator.clone(), bike_rx, events_rx));
+
+        for power in [150, 150, 250, 250] {
+            bike_tx.send(sample(power, 80.0, 30.0)).unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        // Paused for 10s, trainer keeps reporting the coasting rider
+        events_tx.send(WorkoutEvent::Paused).unwrap();
+        for _ in 0..10 {
+            bike_tx.send(sample(0, 0.0, 0.0)).unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        events_tx.send(WorkoutEvent::Resumed).unwrap();
+        tokio::task::yield_now().await;
+
+        drop(events_tx);
+        bike_tx.send(sample(200, 80.0, 30.0)).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        drop(bike_tx);
+        task.await.unwrap();
+
+        let summary = accumulator.lock().unwrap().summary(Instant::now());
+        assert_eq!(summary.duration, Duration::from_secs(5));
+        assert_eq!(summary.avg_power, 200.0);
+        assert_eq!(summary.avg_cadence, 80.0);
+        assert_eq!(summary.energy, 1.0);
+    }
+
+    #[test]
+    fn paused_time_is_not_accounted() {
+        let started = Instant::now();
+        let mut accumulator = new_accumulator(250.0, started);
+        let at = |secs| started + Duration::from_secs(secs);
+
+        accumulator.add(sample(100, 90.0, 30.0), at(0));
+        accumulator.pause(at(60));
+        // Arrives during the pause, counts only once resumed
+        accumulator.add(sample(250, 60.0, 36.0), at(90));
+
+        // Duration is known while paused already
+        assert_eq!(
+            accumulator.summary(at(120)).duration,
+            Duration::from_secs(60)
+        );
+
+        accumulator.resume(at(300));
+        let summary = accumulator.summary(at(420));
+
+        assert_eq!(summary.duration, Duration::from_secs(180));
+        assert_eq!(summary.avg_power, 200.0);
+        assert_eq!(summary.energy, 36.0);
+        assert!((summary.distance - 1.7).abs() < 1e-9);
+
+        let mut time_in_zones = [Duration::ZERO; NR_ZONES];
+        time_in_zones[0] = Duration::from_secs(60);
+        time_in_zones[3] = Duration::from_secs(120);
+        assert_eq!(summary.time_in_zones, time_in_zones);
+    }
+
+    #[test]
+    fn no_data_gives_empty_summary() {
+        let started = Instant::now();
+        let mut accumulator = new_accumulator(200.0, started);
+
+        let summary = accumulator.summary(started + Duration::from_secs(10));
+
+        assert_eq!(
+            summary,
+            RideSummary {
+                duration: Duration::from_secs(10),
+                ..Default::default()
+            }
+        );
+    }
+}
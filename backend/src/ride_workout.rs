@@ -0,0 +1,182 @@
+//! Ride captured as a workout template. Power recorded every second is compacted into steps:
+//! seconds of the same power make a steady state, stairs of equal height and length make a ramp.
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::zwo_workout_file::{WorkoutBuilder, WorkoutFile};
+
+/// Power is rounded to that many watts, so the measurement noise does not break the steps
+const POWER_RESOLUTION_WATTS: f64 = 5.0;
+/// Shorter steps are merged into the previous one
+const MIN_STEP_SECS: u64 = 5;
+/// Least number of stairs making a ramp
+const MIN_RAMP_STAIRS: usize = 3;
+/// Longer runs are steps of their own, even if they go up evenly
+const MAX_STAIR_SECS: u64 = 10;
+
+/// Seconds of the same rounded power
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Run {
+    watts: f64,
+    secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    Steady { watts: f64, secs: u64 },
+    Ramp { from: f64, to: f64, secs: u64 },
+}
+
+/// Workout repeating the ride, targets are relative to given FTP
+pub fn workout_from_ride(name: &str, power_per_second: &[f64], ftp_base: f64) -> WorkoutFile {
+    let mut workout = WorkoutBuilder::new(name).description("Recorded ride");
+
+    for step in compact(&runs(power_per_second)) {
+        workout = match step {
+            Step::Steady { watts, secs } => workout.steady(secs, watts / ftp_base),
+            Step::Ramp { from, to, secs } => workout.ramp(secs, from / ftp_base, to / ftp_base),
+        };
+    }
+
+    workout.build()
+}
+
+/// Writes the ride as ZWO workout, named after the file
+pub async fn save_ride_as_workout(
+    path: &Path,
+    power_per_second: &[f64],
+    ftp_base: f64,
+) -> Result<()> {
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "Recorded ride".to_string());
+    let workout = workout_from_ride(&name, power_per_second, ftp_base);
+
+    tokio::fs::write(path, workout.to_zwo_string()?)
+        .await
+        .with_context(|| format!("Cannot write ride workout {}", path.display()))?;
+
+    info!(
+        "Ride saved as workout {}, {} steps",
+        path.display(),
+        workout.workout.steps.len()
+    );
+
+    Ok(())
+}
+
+fn runs(power_per_second: &[f64]) -> Vec<Run> {
+    let mut runs: Vec<Run> = vec![];
+
+    for power in power_per_second {
+        let watts = (power / POWER_RESOLUTION_WATTS).round() * POWER_RESOLUTION_WATTS;
+
+        match runs.last_mut() {
+            Some(run) if run.watts == watts => run.secs += 1,
+            _ => runs.push(Run { watts, secs: 1 }),
+        }
+    }
+
+    runs
+}
+
+fn compact(runs: &[Run]) -> Vec<Step> {
+    let mut steps = vec![];
+    let mut next = 0;
+
+    while next < runs.len() {
+        let stairs = ramp_stairs(&runs[next..]);
+
+        if stairs >= MIN_RAMP_STAIRS {
+            let ramp = &runs[next..next + stairs];
+            steps.push(Step::Ramp {
+                from: ramp[0].watts,
+                to: ramp[stairs - 1].watts,
+                secs: ramp.iter().map(|run| run.secs).sum(),
+            });
+            next += stairs;
+        } else {
+            push_steady(&mut steps, runs[next]);
+            next += 1;
+        }
+    }
+
+    steps
+}
+
+/// Number of runs going up (or down) by the same watts, lasting about the same time
+fn ramp_stairs(runs: &[Run]) -> usize {
+    let (first, second) = match runs {
+        [first, second, ..] => (first, second),
+        _ => return runs.len(),
+    };
+
+    if first.secs > MAX_STAIR_SECS {
+        return 1;
+    }
+
+    let height = second.watts - first.watts;
+    let is_stair = |run: &Run| run.secs.abs_diff(first.secs) <= 1;
+
+    1 + runs
+        .windows(2)
+        .take_while(|pair| pair[1].watts - pair[0].watts == height && is_stair(&pair[1]))
+        .count()
+}
+
+/// Short runs extend the previous step, the same power as the previous step continues it
+fn push_steady(steps: &mut Vec<Step>, run: Run) {
+    match steps.last_mut() {
+        Some(Step::Steady { watts, secs }) if *watts == run.watts => *secs += run.secs,
+        Some(Step::Steady { secs, .. }) | Some(Step::Ramp { secs, .. })
+            if run.secs < MIN_STEP_SECS =>
+        {
+            *secs += run.secs
+        }
+        _ => steps.push(Step::Steady {
+            watts: run.watts,
+            secs: run.secs,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ride_is_compacted_into_steps() {
+        let mut trace = vec![100.0; 60];
+        // Stairs of 5W every 5 seconds
+        for watts in (105..=195).step_by(5) {
+            trace.extend([f64::from(watts); 5]);
+        }
+        // Noisy 200W, with a short surge in the middle
+        for second in 0..120 {
+            trace.push(if second % 2 == 0 { 198.0 } else { 202.0 });
+            if second == 60 {
+                trace.extend([250.0; 2]);
+            }
+        }
+        trace.extend(vec![100.0; 60]);
+        // Minute long steps going up evenly are not a ramp
+        trace.extend(vec![150.0; 60]);
+        trace.extend(vec![200.0; 60]);
+
+        let workout = workout_from_ride("Ride", &trace, 200.0);
+        let parsed = WorkoutFile::from_zwo_str(&workout.to_zwo_string().unwrap()).unwrap();
+
+        let expected = WorkoutBuilder::new("Ride")
+            .steady(60, 0.5)
+            .ramp(95, 0.525, 0.975)
+            .steady(122, 1.0)
+            .steady(60, 0.5)
+            .steady(60, 0.75)
+            .steady(60, 1.0)
+            .build();
+        assert_eq!(parsed.workout.steps, expected.workout.steps);
+        assert_eq!(parsed.total_workout_duration.as_secs(), trace.len() as u64);
+    }
+}
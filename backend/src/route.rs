@@ -0,0 +1,244 @@
+//! Replays elevation profile of a GPX route, trainer resistance follows the grade
+//! at the distance rider has covered
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tokio::{io::AsyncReadExt, sync::broadcast};
+
+use crate::{cli::UserCommands, indoor_bike_data_defs::BikeData};
+
+/// Mean Earth radius in meters
+const EARTH_RADIUS: f64 = 6_371_000.0;
+/// GPS elevation is noisy, steeper grades are cut off, in percent
+const MAX_GRADE: f64 = 20.0;
+/// Grade is not written to the trainer, until it changes at least that much, in percent
+const GRADE_STEP: f64 = 0.1;
+
+// GPX schema, only the parts needed for the elevation profile
+#[derive(Debug, Deserialize)]
+struct Gpx {
+    #[serde(default)]
+    trk: Vec<Track>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Track {
+    #[serde(default)]
+    trkseg: Vec<TrackSegment>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TrackSegment {
+    #[serde(default)]
+    trkpt: Vec<TrackPoint>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TrackPoint {
+    lat: f64,
+    lon: f64,
+    ele: Option<f64>,
+}
+
+/// Grade in percent, valid from given distance in meters, until the next point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RoutePoint {
+    pub distance: f64,
+    pub grade: f64,
+}
+
+#[derive(Debug)]
+pub struct Route {
+    points: Vec<RoutePoint>,
+    /// Total length of the route in meters
+    length: f64,
+}
+
+impl Route {
+    pub async fn new(route_path: &Path) -> Result<Self> {
+        let mut file = tokio::fs::File::open(route_path).await?;
+
+        let mut content = String::new();
+        let _read = file
+            .read_to_string(&mut content)
+            .await
+            .context("Reading gpx to String failed")?;
+
+        let route = Self::from_gpx_str(&content)
+            .with_context(|| format!("Invalid route {}", route_path.display()))?;
+
+        info!(
+            "Loaded {}, {:.1}km long",
+            route_path.display(),
+            route.length / 1000.0
+        );
+
+        Ok(route)
+    }
+
+    /// Parses content of the GPX file, all track points are joined into a single profile.
+    /// Points without elevation are skipped.
+    pub fn from_gpx_str(content: &str) -> Result<Self> {
+        let gpx: Gpx =
+            serde_xml_rs::from_str(content).context("Parsing xml string to Gpx struct failed")?;
+
+        let track_points: Vec<_> = gpx
+            .trk
+            .iter()
+            .flat_map(|track| &track.trkseg)
+            .flat_map(|segment| &segment.trkpt)
+            .filter_map(|point| point.ele.map(|ele| (point, ele)))
+            .collect();
+
+        let mut points = vec![];
+        let mut length = 0.0;
+
+        for pair in track_points.windows(2) {
+            let ((from, from_ele), (to, to_ele)) = (pair[0], pair[1]);
+            let distance = haversine(from, to);
+
+            // Duplicated points carry no grade
+            if distance < f64::EPSILON {
+                continue;
+            }
+
+            let grade = ((to_ele - from_ele) / distance * 100.0).clamp(-MAX_GRADE, MAX_GRADE);
+            points.push(RoutePoint {
+                distance: length,
+                grade,
+            });
+
+            length += distance;
+        }
+
+        anyhow::ensure!(
+            !points.is_empty(),
+            "Route needs at least two track points with elevation"
+        );
+
+        Ok(Self { points, length })
+    }
+
+    pub fn points(&self) -> &[RoutePoint] {
+        &self.points
+    }
+
+    pub fn length(&self) -> f64 {
+        self.length
+    }
+
+    /// Grade at given distance in meters, None once the route is over
+    pub fn grade_at(&self, distance: f64) -> Option<f64> {
+        if distance >= self.length {
+            return None;
+        }
+
+        // Points are sorted by the distance
+        let idx = self
+            .points
+            .partition_point(|point| point.distance <= distance)
+            .saturating_sub(1);
+
+        Some(self.points[idx].grade)
+    }
+}
+
+/// Distance in meters between two points on the Earth surface
+fn haversine(from: &TrackPoint, to: &TrackPoint) -> f64 {
+    let (lat1, lat2) = (from.lat.to_radians(), to.lat.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (to.lon - from.lon).to_radians();
+
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+/// Sends simulation grade matching distance reported by the trainer, until route is over,
+/// or bike data is gone. Flat road is set at the end of the route.
+pub async fn replay_route(
+    route: Route,
+    mut bike_rx: broadcast::Receiver<BikeData>,
+    trainer_commands_tx: broadcast::Sender<UserCommands>,
+) {
+    let mut grade_set: Option<f64> = None;
+
+    loop {
+        let bike_data = match bike_rx.recv().await {
+            Ok(bike_data) => bike_data,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                trace!("Route replay skipped {skipped} bike data samples");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        // Not every notification carries the distance
+        let distance = match bike_data.tot_distance {
+            Some(distance) => distance as f64,
+            None => continue,
+        };
+
+        let grade = match route.grade_at(distance) {
+            Some(grade) => grade,
+            None => {
+                info!("Route finished at {distance}m");
+                let _ = trainer_commands_tx.send(UserCommands::SetSimulation { grade: 0.0 });
+                break;
+            }
+        };
+
+        if grade_set.is_some_and(|set| (set - grade).abs() < GRADE_STEP) {
+            continue;
+        }
+
+        debug!("Route grade {grade:.1}% at {distance}m");
+        if trainer_commands_tx
+            .send(UserCommands::SetSimulation { grade })
+            .is_err()
+        {
+            break;
+        }
+        grade_set = Some(grade);
+    }
+
+    debug!("Route replay leaves");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elevation_profile_is_converted_to_grades() {
+        // Points are 0.001 degree apart along the meridian, ~111.2m each,
+        // climbing 5%, flat, then descending 2%
+        let gpx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="test" xmlns="http://www.topografix.com/GPX/1/1">
+  <metadata><name>Test climb</name></metadata>
+  <trk>
+    <name>Test climb</name>
+    <trkseg>
+      <trkpt lat="0.000" lon="20.0"><ele>100.0</ele></trkpt>
+      <trkpt lat="0.001" lon="20.0"><ele>105.56</ele></trkpt>
+      <trkpt lat="0.002" lon="20.0"><ele>105.56</ele></trkpt>
+      <trkpt lat="0.002" lon="20.0"><ele>105.56</ele></trkpt>
+      <trkpt lat="0.003" lon="20.0"><ele>103.336</ele></trkpt>
+    </trkseg>
+  </trk>
+</gpx>"#;
+
+        let route = Route::from_gpx_str(gpx).unwrap();
+
+        assert_eq!(route.points().len(), 3);
+        assert!((route.length() - 333.6).abs() < 0.1, "{}", route.length());
+
+        let grade = |distance| route.grade_at(distance).unwrap();
+        assert!((grade(0.0) - 5.0).abs() < 0.01);
+        assert!((grade(111.0) - 5.0).abs() < 0.01);
+        assert!(grade(112.0).abs() < 0.01);
+        assert!((grade(300.0) + 2.0).abs() < 0.01);
+        assert_eq!(route.grade_at(334.0), None);
+    }
+}
@@ -0,0 +1,727 @@
+//! Control loop passing the commands to the fitness machine, one request at a time
+use std::{collections::VecDeque, time::Duration};
+
+use anyhow::{Context, Result};
+use tokio::{
+    sync::broadcast::{self, error::TryRecvError},
+    time::Instant,
+};
+
+use crate::{
+    common::recv_lagging,
+    indoor_bike_client::check_response,
+    indoor_bike_data_defs::{ControlPointNotificationData, ControlPointOpCode, StopOrPause},
+    FitnessMachine, UserCommands,
+};
+
+/// How long to wait for the trainer to acknowledge stop request on exit
+const STOP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Passes trainer responses to the HTTP clients waiting for them
+pub async fn forward_control_point(
+    mut cp_notifications: broadcast::Receiver<ControlPointNotificationData>,
+    control_point_tx: broadcast::Sender<ControlPointNotificationData>,
+) {
+    while let Some(response) = recv_lagging(&mut cp_notifications, "Control point").await {
+        // Send fails if no one waits for the response
+        let _ = control_point_tx.send(response);
+    }
+}
+
+/// Broadcast with no receivers is not fatal, the command is only logged as dropped
+pub fn send_trainer_command(
+    trainer_commands_tx: &broadcast::Sender<UserCommands>,
+    command: UserCommands,
+) {
+    if let Err(e) = trainer_commands_tx.send(command) {
+        warn!("No one listens for trainer commands, dropping {:?}", e.0);
+    }
+}
+
+/// Resolves once the application is told to exit (like on SIGINT), other commands are skipped
+pub async fn exit_requested(trainer_commands_rx: &mut broadcast::Receiver<UserCommands>) {
+    while let Some(command) = recv_lagging(trainer_commands_rx, "Trainer commands").await {
+        if matches!(command, UserCommands::Exit) {
+            return;
+        }
+    }
+}
+
+/// Commands waiting for the trainer. If coalescing, target replaces the same kind of target
+/// queued right before it, commands changing state of the trainer are kept in order.
+#[derive(Debug)]
+struct CommandQueue {
+    commands: VecDeque<UserCommands>,
+    coalesce: bool,
+}
+
+impl CommandQueue {
+    fn new(coalesce: bool) -> Self {
+        Self {
+            commands: VecDeque::new(),
+            coalesce,
+        }
+    }
+
+    fn push(&mut self, command: UserCommands) {
+        if self.coalesce {
+            if let Some(last) = self.commands.back_mut() {
+                if is_same_target(last, &command) {
+                    debug!("{last:?} superseded by {command:?}");
+                    *last = command;
+                    return;
+                }
+            }
+        }
+
+        self.commands.push_back(command);
+    }
+
+    fn pop(&mut self) -> Option<UserCommands> {
+        self.commands.pop_front()
+    }
+
+    /// Takes commands sent in the meantime, without waiting for more
+    fn fill(&mut self, rx: &mut broadcast::Receiver<UserCommands>) {
+        loop {
+            match rx.try_recv() {
+                Ok(command) => self.push(command),
+                Err(TryRecvError::Lagged(skipped)) => {
+                    warn!("Control task lagged, {skipped} commands dropped")
+                }
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Setpoints of the same kind, the latter makes the former pointless
+fn is_same_target(first: &UserCommands, second: &UserCommands) -> bool {
+    matches!(
+        (first, second),
+        (
+            UserCommands::SetTargetPower { .. },
+            UserCommands::SetTargetPower { .. }
+        ) | (
+            UserCommands::SetResistance { .. } | UserCommands::SetResistancePercent { .. },
+            UserCommands::SetResistance { .. } | UserCommands::SetResistancePercent { .. }
+        ) | (
+            UserCommands::SetTargetSpeed { .. },
+            UserCommands::SetTargetSpeed { .. }
+        ) | (
+            UserCommands::SetSimulation { .. },
+            UserCommands::SetSimulation { .. }
+        )
+    )
+}
+
+/// Gets the commands (may be ZWO workout, or user input), and passes them to the fitness machine.
+/// Target power writes are at least min_write_interval apart, unchanged target within that time is not written.
+/// Commands coming while waiting for the ACK are queued, and coalesced if asked to.
+pub async fn control_fit_machine(
+    fit: impl FitnessMachine,
+    mut rx: broadcast::Receiver<UserCommands>,
+    min_write_interval: Duration,
+    coalesce: bool,
+    ack_timeout: Duration,
+) -> Result<()> {
+    // Cannot set return type of async block, async closures are unstable
+
+    // TODO: Use select?
+    // let _status_notifications = fit.subscribe_for_status_notifications();
+
+    let mut cp_notifications = fit.subscribe_for_control_point_notifications();
+    let mut machine_status = fit.subscribe_for_machine_notifications();
+    let mut last_power_write: Option<(i16, Instant)> = None;
+    let mut queue = CommandQueue::new(coalesce);
+
+    loop {
+        let message = match queue.pop() {
+            Some(message) => message,
+            None => tokio::select! {
+                message = rx.recv() => match message {
+                    Ok(message) => message,
+                    Err(_) => break,
+                },
+                Ok(status) = machine_status.recv() => {
+                    let writes = fit.handle_machine_status(status).await?;
+
+                    for _ in 0..writes {
+                        wait_for_ack(&mut cp_notifications, ack_timeout).await?;
+                    }
+
+                    continue;
+                }
+            },
+        };
+
+        match message {
+            UserCommands::Exit | UserCommands::WorkoutCompleted => {
+                if let UserCommands::WorkoutCompleted = message {
+                    info!("Workout completed");
+                }
+                info!("Control task exits, stopping the trainer");
+
+                // Do not leave the flywheel loaded in ERG mode, but also do not hang forever
+                // if control point is unresponsive
+                match tokio::time::timeout(STOP_TIMEOUT, stop_trainer(&fit, &mut cp_notifications))
+                    .await
+                {
+                    Ok(Ok(())) => info!("Trainer stopped"),
+                    Ok(Err(e)) => warn!("Failed to stop the trainer: {e:?}"),
+                    Err(_) => warn!("Trainer did not acknowledge stop request in {STOP_TIMEOUT:?}"),
+                }
+
+                break;
+            }
+            UserCommands::SetResistance { resistance } => {
+                // Nothing was written if the level is outside of the trainer range
+                if let Err(e) = fit.set_resistance(resistance).await {
+                    warn!("Resistance {resistance} rejected: {e}");
+                    continue;
+                }
+            }
+            UserCommands::SetResistancePercent { percent } => {
+                // Nothing was written if the level does not fit the request
+                if let Err(e) = fit.set_resistance_percent(percent).await {
+                    warn!("Resistance {percent}% rejected: {e}");
+                    continue;
+                }
+            }
+            UserCommands::SetTargetPower { power } => {
+                if let Some((last_power, written_at)) = last_power_write {
+                    let since = written_at.elapsed();
+
+                    if since < min_write_interval {
+                        if last_power == power {
+                            info!(
+                                "Power target {power}W coalesced, same was written {since:?} ago"
+                            );
+                            continue;
+                        }
+
+                        tokio::time::sleep(min_write_interval - since).await;
+                    }
+                }
+
+                // Nothing was written if the target is outside of the trainer range, and not clamped
+                if let Err(e) = fit.set_power(power).await {
+                    warn!("Power target {power}W rejected: {e}");
+                    continue;
+                }
+                last_power_write = Some((power, Instant::now()));
+            }
+            UserCommands::SetTargetSpeed { speed } => {
+                // Nothing was written if machine does not support the speed target
+                if let Err(e) = fit.set_speed(speed).await {
+                    warn!("Speed target {speed}km/h rejected: {e}");
+                    continue;
+                }
+            }
+            UserCommands::SetSimulation { grade } => {
+                fit.set_simulation(grade).await?;
+            }
+            UserCommands::StartWorkout => {
+                fit.reset_status().await?;
+            }
+        }
+
+        // Wait for CP notification response for above write request
+        wait_for_ack(&mut cp_notifications, ack_timeout).await?;
+
+        // Commands sent while waiting for the ACK
+        queue.fill(&mut rx);
+    }
+
+    fit.disconnect().await?;
+
+    Ok(())
+}
+
+/// Next CP notification response. Responses to control requests are skipped,
+/// the machine tracks the control permission with them, no one else waits for them.
+async fn recv_response(
+    cp_notifications: &mut broadcast::Receiver<ControlPointNotificationData>,
+) -> Result<ControlPointNotificationData> {
+    loop {
+        let resp = cp_notifications.recv().await?;
+
+        if !matches!(resp.request_op_code, ControlPointOpCode::RequestControl) {
+            return Ok(resp);
+        }
+    }
+}
+
+/// Waits for CP notification response for the write request, NACK is only logged.
+/// Missing response is logged too, trainer is not going to block the next writes.
+async fn wait_for_ack(
+    cp_notifications: &mut broadcast::Receiver<ControlPointNotificationData>,
+    timeout: Duration,
+) -> Result<()> {
+    let resp = match tokio::time::timeout(timeout, recv_response(cp_notifications)).await {
+        Ok(resp) => resp?,
+        Err(_) => {
+            warn!("Trainer did not respond to the request in {timeout:?}, moving on");
+            return Ok(());
+        }
+    };
+
+    match check_response(&resp) {
+        Ok(()) => debug!("Got ACK for request {resp}"),
+        Err(e) => error!("Received NACK for request {}: {e}", resp.request_op_code),
+    }
+
+    Ok(())
+}
+
+/// Sends stop request and waits for it's ACK
+async fn stop_trainer(
+    fit: &impl FitnessMachine,
+    cp_notifications: &mut broadcast::Receiver<ControlPointNotificationData>,
+) -> Result<()> {
+    fit.stop_or_pause(StopOrPause::Stop).await?;
+
+    let resp = recv_response(cp_notifications).await?;
+    check_response(&resp).context("stop request")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        cli::parse_workout_command,
+        indoor_bike_data_defs::{BikeData, ControlPointResult, MachineStatusOpCode},
+        WorkoutCommands,
+    };
+
+    const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+    const MOCK_MAX_RESISTANCE: u8 = 10;
+    const MOCK_MAX_POWER: i16 = 1000;
+
+    #[derive(Debug, PartialEq)]
+    enum MockCall {
+        RequestControl,
+        ResetStatus,
+        SetPower(i16),
+        SetSpeed(f64),
+        SetResistance(u8),
+        SetResistancePercent(f64),
+        SetSimulation(f64),
+        StopOrPause(StopOrPause),
+        Disconnect,
+    }
+
+    /// Records every call, acknowledging requests is up to the test
+    struct MockFitnessMachine {
+        calls_tx: mpsc::UnboundedSender<MockCall>,
+        machine_status_tx: broadcast::Sender<MachineStatusOpCode>,
+        control_point_tx: broadcast::Sender<ControlPointNotificationData>,
+    }
+
+    impl MockFitnessMachine {
+        fn new() -> (Self, mpsc::UnboundedReceiver<MockCall>) {
+            let (calls_tx, calls_rx) = mpsc::unbounded_channel();
+
+            let fit = Self {
+                calls_tx,
+                machine_status_tx: broadcast::channel(16).0,
+                control_point_tx: broadcast::channel(16).0,
+            };
+
+            (fit, calls_rx)
+        }
+
+        fn record(&self, call: MockCall) -> Result<()> {
+            self.calls_tx.send(call)?;
+
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl FitnessMachine for MockFitnessMachine {
+        async fn request_control(&self) -> Result<()> {
+            self.record(MockCall::RequestControl)
+        }
+
+        async fn reset_status(&self) -> Result<()> {
+            self.record(MockCall::ResetStatus)
+        }
+
+        /// Targets above MOCK_MAX_POWER are rejected, like the real machine without clamping does
+        async fn set_power(&self, power: i16) -> Result<()> {
+            self.record(MockCall::SetPower(power))?;
+
+            if power > MOCK_MAX_POWER {
+                return Err(anyhow::anyhow!("Power {power} outside valid range"));
+            }
+
+            Ok(())
+        }
+
+        async fn set_speed(&self, speed: f64) -> Result<()> {
+            self.record(MockCall::SetSpeed(speed))
+        }
+
+        /// Levels above MOCK_MAX_RESISTANCE are rejected, like the real machine does
+        async fn set_resistance(&self, resistance: u8) -> Result<()> {
+            self.record(MockCall::SetResistance(resistance))?;
+
+            if resistance > MOCK_MAX_RESISTANCE {
+                return Err(anyhow::anyhow!(
+                    "Resistance {resistance} outside valid range"
+                ));
+            }
+
+            Ok(())
+        }
+
+        async fn set_resistance_percent(&self, percent: f64) -> Result<()> {
+            self.record(MockCall::SetResistancePercent(percent))
+        }
+
+        async fn set_simulation(&self, grade: f64) -> Result<()> {
+            self.record(MockCall::SetSimulation(grade))
+        }
+
+        async fn stop_or_pause(&self, what: StopOrPause) -> Result<()> {
+            self.record(MockCall::StopOrPause(what))
+        }
+
+        async fn handle_machine_status(&self, _status: MachineStatusOpCode) -> Result<usize> {
+            Ok(0)
+        }
+
+        async fn disconnect(&self) -> Result<()> {
+            self.record(MockCall::Disconnect)
+        }
+
+        fn subscribe_for_indoor_bike_notifications(&self) -> broadcast::Receiver<BikeData> {
+            broadcast::channel(1).1
+        }
+
+        fn subscribe_for_training_notifications(&self) -> broadcast::Receiver<String> {
+            broadcast::channel(1).1
+        }
+
+        fn subscribe_for_machine_notifications(&self) -> broadcast::Receiver<MachineStatusOpCode> {
+            self.machine_status_tx.subscribe()
+        }
+
+        fn subscribe_for_control_point_notifications(
+            &self,
+        ) -> broadcast::Receiver<ControlPointNotificationData> {
+            self.control_point_tx.subscribe()
+        }
+    }
+
+    fn ack(request_op_code: ControlPointOpCode) -> ControlPointNotificationData {
+        ControlPointNotificationData {
+            request_op_code,
+            request_status: ControlPointResult::Success,
+        }
+    }
+
+    #[tokio::test]
+    async fn control_loop_waits_for_ack_before_next_request() {
+        let (fit, mut calls_rx) = MockFitnessMachine::new();
+        let control_point_tx = fit.control_point_tx.clone();
+        let (commands_tx, commands_rx) = broadcast::channel(16);
+
+        let control = tokio::spawn(control_fit_machine(
+            fit,
+            commands_rx,
+            Duration::ZERO,
+            true,
+            ACK_TIMEOUT,
+        ));
+
+        commands_tx
+            .send(UserCommands::SetTargetPower { power: 200 })
+            .unwrap();
+        commands_tx
+            .send(UserCommands::SetTargetPower { power: 250 })
+            .unwrap();
+        assert_eq!(calls_rx.recv().await, Some(MockCall::SetPower(200)));
+
+        // Second request is not sent, until the first one is acknowledged
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), calls_rx.recv())
+                .await
+                .is_err()
+        );
+
+        control_point_tx
+            .send(ack(ControlPointOpCode::SetTargetPower))
+            .unwrap();
+        assert_eq!(calls_rx.recv().await, Some(MockCall::SetPower(250)));
+        control_point_tx
+            .send(ack(ControlPointOpCode::SetTargetPower))
+            .unwrap();
+
+        // Exit stops the trainer, and disconnects once stop is acknowledged
+        commands_tx.send(UserCommands::Exit).unwrap();
+        assert_eq!(
+            calls_rx.recv().await,
+            Some(MockCall::StopOrPause(StopOrPause::Stop))
+        );
+        control_point_tx
+            .send(ack(ControlPointOpCode::StopOrPause))
+            .unwrap();
+        assert_eq!(calls_rx.recv().await, Some(MockCall::Disconnect));
+
+        control.await.unwrap().unwrap();
+        assert_eq!(calls_rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn resistance_is_set_as_level_or_percent() {
+        assert_eq!(
+            parse_workout_command("R 5"),
+            Some(WorkoutCommands::SetResistance(5))
+        );
+        assert_eq!(
+            parse_workout_command("R 40%"),
+            Some(WorkoutCommands::SetResistancePercent(40.0))
+        );
+        assert_eq!(parse_workout_command("R high%"), None);
+
+        let (fit, mut calls_rx) = MockFitnessMachine::new();
+        let control_point_tx = fit.control_point_tx.clone();
+        let (commands_tx, commands_rx) = broadcast::channel(16);
+
+        let control = tokio::spawn(control_fit_machine(
+            fit,
+            commands_rx,
+            Duration::ZERO,
+            true,
+            ACK_TIMEOUT,
+        ));
+
+        // Rejected level does not end the control task, and is not waited for
+        commands_tx
+            .send(UserCommands::SetResistance { resistance: 200 })
+            .unwrap();
+        assert_eq!(calls_rx.recv().await, Some(MockCall::SetResistance(200)));
+        commands_tx
+            .send(UserCommands::SetResistance { resistance: 3 })
+            .unwrap();
+        assert_eq!(calls_rx.recv().await, Some(MockCall::SetResistance(3)));
+        control_point_tx
+            .send(ack(ControlPointOpCode::SetTargetResistance))
+            .unwrap();
+
+        commands_tx
+            .send(UserCommands::SetResistancePercent { percent: 40.0 })
+            .unwrap();
+        assert_eq!(
+            calls_rx.recv().await,
+            Some(MockCall::SetResistancePercent(40.0))
+        );
+        control_point_tx
+            .send(ack(ControlPointOpCode::SetTargetResistance))
+            .unwrap();
+
+        commands_tx.send(UserCommands::Exit).unwrap();
+        assert_eq!(
+            calls_rx.recv().await,
+            Some(MockCall::StopOrPause(StopOrPause::Stop))
+        );
+        control_point_tx
+            .send(ack(ControlPointOpCode::StopOrPause))
+            .unwrap();
+
+        control.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn unchanged_power_target_is_coalesced() {
+        let (fit, mut calls_rx) = MockFitnessMachine::new();
+        let control_point_tx = fit.control_point_tx.clone();
+        let (commands_tx, commands_rx) = broadcast::channel(16);
+
+        let control = tokio::spawn(control_fit_machine(
+            fit,
+            commands_rx,
+            Duration::from_secs(60),
+            true,
+            ACK_TIMEOUT,
+        ));
+
+        for _ in 0..3 {
+            commands_tx
+                .send(UserCommands::SetTargetPower { power: 200 })
+                .unwrap();
+        }
+        assert_eq!(calls_rx.recv().await, Some(MockCall::SetPower(200)));
+        control_point_tx
+            .send(ack(ControlPointOpCode::SetTargetPower))
+            .unwrap();
+
+        // Remaining targets are not written, next call is the stop request
+        commands_tx.send(UserCommands::Exit).unwrap();
+        assert_eq!(
+            calls_rx.recv().await,
+            Some(MockCall::StopOrPause(StopOrPause::Stop))
+        );
+        control_point_tx
+            .send(ack(ControlPointOpCode::StopOrPause))
+            .unwrap();
+
+        control.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn targets_queued_behind_ack_are_coalesced() {
+        let (fit, mut calls_rx) = MockFitnessMachine::new();
+        let control_point_tx = fit.control_point_tx.clone();
+        let (commands_tx, commands_rx) = broadcast::channel(16);
+
+        let control = tokio::spawn(control_fit_machine(
+            fit,
+            commands_rx,
+            Duration::ZERO,
+            true,
+            ACK_TIMEOUT,
+        ));
+
+        commands_tx.send(UserCommands::StartWorkout).unwrap();
+        assert_eq!(calls_rx.recv().await, Some(MockCall::ResetStatus));
+
+        // Trainer is slow to acknowledge, rider keeps changing the target
+        for power in [200, 220, 250] {
+            commands_tx
+                .send(UserCommands::SetTargetPower { power })
+                .unwrap();
+        }
+        control_point_tx
+            .send(ack(ControlPointOpCode::Reset))
+            .unwrap();
+
+        assert_eq!(calls_rx.recv().await, Some(MockCall::SetPower(250)));
+        control_point_tx
+            .send(ack(ControlPointOpCode::SetTargetPower))
+            .unwrap();
+
+        commands_tx.send(UserCommands::Exit).unwrap();
+        assert_eq!(
+            calls_rx.recv().await,
+            Some(MockCall::StopOrPause(StopOrPause::Stop))
+        );
+        control_point_tx
+            .send(ack(ControlPointOpCode::StopOrPause))
+            .unwrap();
+
+        control.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_ack_does_not_block_next_target() {
+        let (fit, mut calls_rx) = MockFitnessMachine::new();
+        let control_point_tx = fit.control_point_tx.clone();
+        let (commands_tx, commands_rx) = broadcast::channel(16);
+
+        let control = tokio::spawn(control_fit_machine(
+            fit,
+            commands_rx,
+            Duration::ZERO,
+            true,
+            ACK_TIMEOUT,
+        ));
+
+        // Trainer never responds to the target writes
+        commands_tx
+            .send(UserCommands::SetTargetPower { power: 200 })
+            .unwrap();
+        assert_eq!(calls_rx.recv().await, Some(MockCall::SetPower(200)));
+
+        let started = Instant::now();
+        commands_tx
+            .send(UserCommands::SetTargetPower { power: 250 })
+            .unwrap();
+        assert_eq!(calls_rx.recv().await, Some(MockCall::SetPower(250)));
+        assert_eq!(started.elapsed(), ACK_TIMEOUT);
+
+        commands_tx.send(UserCommands::Exit).unwrap();
+        assert_eq!(
+            calls_rx.recv().await,
+            Some(MockCall::StopOrPause(StopOrPause::Stop))
+        );
+        control_point_tx
+            .send(ack(ControlPointOpCode::StopOrPause))
+            .unwrap();
+
+        control.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exit_stops_the_trainer_even_if_it_does_not_respond() {
+        let (fit, mut calls_rx) = MockFitnessMachine::new();
+        let (commands_tx, commands_rx) = broadcast::channel(16);
+
+        let control = tokio::spawn(control_fit_machine(
+            fit,
+            commands_rx,
+            Duration::ZERO,
+            true,
+            ACK_TIMEOUT,
+        ));
+
+        // Stop request is never acknowledged, trainer gets disconnected anyway
+        let started = Instant::now();
+        commands_tx.send(UserCommands::Exit).unwrap();
+        assert_eq!(
+            calls_rx.recv().await,
+            Some(MockCall::StopOrPause(StopOrPause::Stop))
+        );
+        assert_eq!(calls_rx.recv().await, Some(MockCall::Disconnect));
+        assert_eq!(started.elapsed(), STOP_TIMEOUT);
+
+        control.await.unwrap().unwrap();
+        assert_eq!(calls_rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn rejected_power_target_does_not_end_control() {
+        let (fit, mut calls_rx) = MockFitnessMachine::new();
+        let control_point_tx = fit.control_point_tx.clone();
+        let (commands_tx, commands_rx) = broadcast::channel(16);
+
+        let control = tokio::spawn(control_fit_machine(
+            fit,
+            commands_rx,
+            Duration::ZERO,
+            true,
+            ACK_TIMEOUT,
+        ));
+
+        // Rejected target is not waited for, the next one is written
+        commands_tx
+            .send(UserCommands::SetTargetPower { power: 1500 })
+            .unwrap();
+        assert_eq!(calls_rx.recv().await, Some(MockCall::SetPower(1500)));
+        commands_tx
+            .send(UserCommands::SetTargetPower { power: 200 })
+            .unwrap();
+        assert_eq!(calls_rx.recv().await, Some(MockCall::SetPower(200)));
+        control_point_tx
+            .send(ack(ControlPointOpCode::SetTargetPower))
+            .unwrap();
+
+        commands_tx.send(UserCommands::Exit).unwrap();
+        assert_eq!(
+            calls_rx.recv().await,
+            Some(MockCall::StopOrPause(StopOrPause::Stop))
+        );
+        control_point_tx
+            .send(ack(ControlPointOpCode::StopOrPause))
+            .unwrap();
+
+        control.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+//! Pauses the workout once the trainer goes silent, so target power is not commanded into the void.
+//! There is no reconnect yet, rider resumes the workout once the trainer is back.
+use std::time::Duration;
+
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    cli::WorkoutCommands, common::recv_lagging, indoor_bike_data_defs::BikeData,
+    zwo_workout::WorkoutEvent,
+};
+
+/// Sends Pause, if there is no bike data for given time, and tells the UI about it.
+/// Runs until bike data or the workout is gone.
+pub async fn watchdog(
+    mut bike_rx: broadcast::Receiver<BikeData>,
+    control_workout_tx: mpsc::Sender<WorkoutCommands>,
+    workout_events_tx: broadcast::Sender<WorkoutEvent>,
+    timeout: Duration,
+) {
+    let mut silent = false;
+
+    loop {
+        match tokio::time::timeout(timeout, recv_lagging(&mut bike_rx, "Watchdog")).await {
+            Ok(Some(_)) => {
+                if silent {
+                    silent = false;
+                    info!("Trainer sends bike data again, resume the workout when ready");
+                    let _ = workout_events_tx.send(WorkoutEvent::TrainerBack);
+                }
+            }
+            Ok(None) => break,
+            Err(_) if silent => {}
+            Err(_) => {
+                silent = true;
+                error!("No bike data from the trainer for {timeout:?}, pausing the workout");
+                let _ = workout_events_tx.send(WorkoutEvent::TrainerSilent);
+
+                if control_workout_tx
+                    .send(WorkoutCommands::Pause)
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }
+    }
+
+    debug!("Watchdog leaves");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn workout_is_paused_when_trainer_goes_silent() {
+        let (bike_tx, bike_rx) = broadcast::channel(16);
+        let (control_workout_tx, mut control_workout_rx) = mpsc::channel(16);
+        let (events_tx, mut events_rx) = broadcast::channel(16);
+
+        tokio::spawn(watchdog(
+            bike_rx,
+            control_workout_tx,
+            events_tx,
+            Duration::from_millis(2500),
+        ));
+
+        // One sample per second, then trainer stalls
+        let mut commands = vec![];
+        for second in 0..10 {
+            if second < 3 {
+                bike_tx.send(BikeData::default()).unwrap();
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+
+            while let Ok(command) = control_workout_rx.try_recv() {
+                commands.push((second, command));
+            }
+        }
+
+        // Paused once, 2.5 seconds after the last sample
+        assert_eq!(commands, vec![(4, WorkoutCommands::Pause)]);
+        assert_eq!(events_rx.try_recv(), Ok(WorkoutEvent::TrainerSilent));
+        assert!(events_rx.try_recv().is_err());
+
+        // Trainer is back, workout is not resumed on its own
+        bike_tx.send(BikeData::default()).unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(events_rx.try_recv(), Ok(WorkoutEvent::TrainerBack));
+        assert!(control_workout_rx.try_recv().is_err());
+    }
+}
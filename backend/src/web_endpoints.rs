@@ -1,38 +1,389 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use crate::{workout_state_ws::WebSocketActor, AppState};
+use crate::{
+    cli::WorkoutCommands,
+    indoor_bike_client::{check_response, resistance_levels},
+    indoor_bike_data_defs::{ControlPointNotificationData, ControlPointOpCode},
+    workout_state_ws::WebSocketActor,
+    zwo_workout_file::list_workouts,
+    AppState,
+};
+use actix_cors::Cors;
 use actix_web::{
     get,
+    http::header::HeaderValue,
+    post,
     web::{self, Data},
-    Error, HttpRequest, HttpResponse, Responder,
+    Error, HttpRequest, HttpResponse,
 };
 use actix_web_actors::ws;
 use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, IntervalStream};
+
+/// How long control request waits for the trainer to acknowledge it
+const ACK_TIMEOUT: Duration = Duration::from_secs(3);
+/// Comment sent to the idle event stream, so proxies do not close it
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Frontend may be served from other origin than the server, allow it to fetch the data.
+/// Without explicit origins any localhost origin is allowed, which is handy for development.
+/// Applies to the websocket too, browsers send the Origin header with the upgrade request.
+pub fn cors(origins: &[String]) -> Cors {
+    let cors = if origins.is_empty() {
+        Cors::default().allowed_origin_fn(|origin, _| is_localhost_origin(origin))
+    } else if origins.iter().any(|origin| origin == "*") {
+        Cors::default().allow_any_origin()
+    } else {
+        origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+    };
+
+    cors.allowed_methods(["GET", "POST"])
+        .allow_any_header()
+        .max_age(3600)
+}
 
-use tokio_stream::wrappers::BroadcastStream;
+fn is_localhost_origin(origin: &HeaderValue) -> bool {
+    let host = origin
+        .to_str()
+        .unwrap_or_default()
+        .split("://")
+        .nth(1)
+        .unwrap_or_default();
+
+    // Strip the port
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => host,
+    };
+
+    matches!(host, "localhost" | "127.0.0.1" | "[::1]")
+}
+
+/// Reconnecting client tells with `since` the step number it has seen last
+#[derive(Debug, Deserialize)]
+struct WorkoutStateQuery {
+    since: Option<usize>,
+}
 
 /// This is a stream endpoint, one line contains one workout state
 /// In HTTP/1 it uses header <transfer-encoding: chunked
 /// IN HTTP/2 uses DATA frames
-
+/// Slow client does not get disconnected, instead it gets {"lagged":N} line, telling how many states were missed
+/// Client gets the current state right away, instead of waiting for the next one, then the stream continues
+/// with live states. Reconnecting client (with `since` param) does not miss anything since it dropped this way,
+/// once the workout is done, it gets the final state.
 #[get("/workout_state")]
-async fn workout_state_handle(app_state: Data<AppState>) -> HttpResponse {
-    let guard = app_state.workout_state_tx.read().unwrap();
+async fn workout_state_handle(
+    app_state: Data<AppState>,
+    query: web::Query<WorkoutStateQuery>,
+) -> HttpResponse {
+    // Subscribe first, state published in the meantime is sent twice rather than lost
+    let workout_state_rx = app_state.workout_state.subscribe();
 
-    if let Some(workout_state) = guard.as_ref() {
-        let stream = BroadcastStream::new(workout_state.subscribe());
+    let catch_up = match (query.since, &workout_state_rx) {
+        (Some(since), _) => {
+            let latest = app_state.workout_state.latest();
+            if let Some(state) = &latest {
+                debug!(
+                    "Client resumes at step {}, it has seen step {since}",
+                    state.current_step_number
+                );
+            }
+            latest
+        }
+        (None, Some(_)) => app_state.workout_state.latest(),
+        // Workout is done, there is nothing to stream
+        (None, None) => None,
+    };
 
-        let stream = stream.map(|element| {
-            let state = element?;
-            let serialized = format!("{}\n", serde_json::to_string(&state)?);
-            anyhow::Ok(actix_web::web::Bytes::from(serialized))
+    if workout_state_rx.is_none() && catch_up.is_none() {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let live = futures::stream::iter(workout_state_rx.map(BroadcastStream::new)).flatten();
+    let stream = futures::stream::iter(catch_up.map(Ok))
+        .chain(live)
+        .map(|element| {
+            let serialized = match element {
+                Ok(state) => serde_json::to_string(&state)?,
+                Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                    warn!("Workout state stream lagged, skipped {skipped} states");
+                    serde_json::json!({ "lagged": skipped }).to_string()
+                }
+            };
+            anyhow::Ok(actix_web::web::Bytes::from(format!("{serialized}\n")))
         });
 
-        HttpResponse::Ok()
-            // .content_type("application/json")
-            .streaming(stream)
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(stream)
+}
+
+/// Server-sent events alternative to the /workout_state stream, one `data:` frame carries one workout state.
+/// Lagging client gets {"lagged":N} frame, idle stream gets keep-alive comments.
+/// Stream is dropped once the client disconnects, or ends once the workout is done.
+#[get("/events")]
+async fn events_handle(app_state: Data<AppState>) -> HttpResponse {
+    let workout_state_rx = match app_state.workout_state.subscribe() {
+        Some(workout_state_rx) => workout_state_rx,
+        None => return HttpResponse::BadRequest().finish(),
+    };
+
+    let states = BroadcastStream::new(workout_state_rx).map(|element| {
+        let serialized = match element {
+            Ok(state) => serde_json::to_string(&state)?,
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                warn!("Event stream lagged, skipped {skipped} states");
+                serde_json::json!({ "lagged": skipped }).to_string()
+            }
+        };
+        anyhow::Ok(Some(format!("data: {serialized}\n\n")))
+    });
+
+    let start = tokio::time::Instant::now() + SSE_KEEP_ALIVE;
+    let keep_alive = IntervalStream::new(tokio::time::interval_at(start, SSE_KEEP_ALIVE))
+        .map(|_| anyhow::Ok(Some(": keep-alive\n\n".to_string())));
+
+    // Keep-alive never ends, stop once the states are over
+    let stream =
+        futures::stream::select(states.chain(futures::stream::iter([Ok(None)])), keep_alive)
+            .take_while(|frame| futures::future::ready(!matches!(frame, Ok(None))))
+            .map(|frame| frame.map(|frame| actix_web::web::Bytes::from(frame.unwrap_or_default())));
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream)
+}
+
+/// Static metadata of the loaded workout, available without waiting for the workout state
+#[get("/workout_info")]
+async fn workout_info_handle(app_state: Data<AppState>) -> HttpResponse {
+    let guard = app_state.workout_info.read().unwrap();
+
+    if let Some(workout_info) = guard.as_ref() {
+        HttpResponse::Ok().json(workout_info)
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+/// Workouts available in the workout directory
+#[get("/workouts")]
+async fn workouts_handle(app_state: Data<AppState>) -> HttpResponse {
+    if let Some(workout_dir) = app_state.workout_dir.as_ref() {
+        HttpResponse::Ok().json(list_workouts(workout_dir).await)
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+/// Workouts run lately, the latest last
+#[get("/recent")]
+async fn recent_handle(app_state: Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(&*app_state.recent_workouts.read().unwrap())
+}
+
+/// Supported ranges and targets of the trainer
+#[get("/trainer_info")]
+async fn trainer_info_handle(app_state: Data<AppState>) -> HttpResponse {
+    let guard = app_state.trainer_info.read().unwrap();
+
+    if let Some(trainer_info) = guard.as_ref() {
+        HttpResponse::Ok().json(trainer_info)
+    } else {
+        HttpResponse::ServiceUnavailable().finish()
+    }
+}
+
+/// Summary of the ride, available once the workout is completed
+#[get("/summary")]
+async fn summary_handle(app_state: Data<AppState>) -> HttpResponse {
+    let guard = app_state.ride_summary.read().unwrap();
+
+    if let Some(summary) = guard.as_ref() {
+        HttpResponse::Ok().json(summary)
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+/// Connection state of the backend, reported by health and readiness checks
+#[derive(Debug, Serialize)]
+struct BackendStatus {
+    /// Trainer is connected, or simulated
+    trainer_connected: bool,
+    workout_loaded: bool,
+    /// Workout is loaded and not completed yet
+    workout_running: bool,
+    /// Number of clients receiving the workout state
+    subscribers: usize,
+}
+
+impl BackendStatus {
+    fn new(app_state: &AppState) -> Self {
+        let workout_loaded = app_state.workout_info.read().unwrap().is_some();
+
+        Self {
+            trainer_connected: app_state.trainer_info.read().unwrap().is_some(),
+            workout_loaded,
+            workout_running: workout_loaded && app_state.workout_state.is_open(),
+            subscribers: app_state.workout_state.receiver_count(),
+        }
+    }
+}
+
+/// Liveness check, responds as soon as the server is up
+#[get("/health")]
+async fn health_handle(app_state: Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(BackendStatus::new(&app_state))
+}
+
+/// Readiness check, ready once the trainer is connected (or simulated)
+#[get("/ready")]
+async fn ready_handle(app_state: Data<AppState>) -> HttpResponse {
+    let status = BackendStatus::new(&app_state);
+
+    if status.trainer_connected {
+        HttpResponse::Ok().json(status)
     } else {
-        HttpResponse::BadRequest().finish()
+        HttpResponse::ServiceUnavailable().json(status)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PowerRequest {
+    watts: i16,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ResistanceRequest {
+    /// Level in raw units of the trainer
+    Level { level: u8 },
+    /// Percent of the trainer range, the same scale for every trainer
+    Percent { percent: f64 },
+}
+
+/// Sets target power in manual mode, responds with the trainer acknowledgement.
+/// Power outside of the trainer range is rejected with 422
+#[post("/control/power")]
+async fn control_power_handle(
+    app_state: Data<AppState>,
+    request: web::Json<PowerRequest>,
+) -> HttpResponse {
+    let watts = request.watts;
+    let power_range = match app_state.trainer_info.read().unwrap().as_ref() {
+        Some(trainer_info) => trainer_info.power_range.clone(),
+        None => return HttpResponse::ServiceUnavailable().finish(),
+    };
+
+    if !power_range.in_range(watts) {
+        return unprocessable(format!(
+            "Power {watts}W outside valid range {}..={}W",
+            power_range.min, power_range.max
+        ));
+    }
+
+    control_request(
+        &app_state,
+        WorkoutCommands::SetTargetPower(watts),
+        ControlPointOpCode::SetTargetPower,
+    )
+    .await
+}
+
+/// Sets resistance level, or percent of the trainer range, in manual mode,
+/// responds with the trainer acknowledgement. Level outside of the trainer range is rejected with 422
+#[post("/control/resistance")]
+async fn control_resistance_handle(
+    app_state: Data<AppState>,
+    request: web::Json<ResistanceRequest>,
+) -> HttpResponse {
+    // Level is written as it is, so it's checked in raw units of the trainer
+    let levels = match app_state.trainer_info.read().unwrap().as_ref() {
+        Some(trainer_info) => resistance_levels(&trainer_info.resistance_range),
+        None => return HttpResponse::ServiceUnavailable().finish(),
+    };
+
+    let command = match request.into_inner() {
+        ResistanceRequest::Level { level } => {
+            if !levels.in_range(f64::from(level)) {
+                return unprocessable(format!(
+                    "Resistance {level} outside valid range {}..={}",
+                    levels.min, levels.max
+                ));
+            }
+
+            WorkoutCommands::SetResistance(level)
+        }
+        ResistanceRequest::Percent { percent } => {
+            if !(0.0..=100.0).contains(&percent) {
+                return unprocessable(format!(
+                    "Resistance {percent}% outside valid range 0..=100%"
+                ));
+            }
+
+            WorkoutCommands::SetResistancePercent(percent)
+        }
+    };
+
+    control_request(&app_state, command, ControlPointOpCode::SetTargetResistance).await
+}
+
+fn unprocessable(error: String) -> HttpResponse {
+    HttpResponse::UnprocessableEntity().json(serde_json::json!({ "error": error }))
+}
+
+/// Passes the command to manual mode and waits for the trainer to acknowledge the request.
+/// Without the trainer (simulated) nothing acknowledges it, 504 is returned after ACK_TIMEOUT.
+async fn control_request(
+    app_state: &AppState,
+    command: WorkoutCommands,
+    op_code: ControlPointOpCode,
+) -> HttpResponse {
+    // Workout sets the target on its own
+    if app_state.workout_info.read().unwrap().is_some() {
+        return HttpResponse::Conflict().json(serde_json::json!({
+            "error": format!("{command:?} is available in manual mode only")
+        }));
+    }
+
+    // Subscribe first, so the acknowledgement cannot be missed
+    let mut acks = app_state.control_point_tx.subscribe();
+
+    if app_state.control_workout_tx.send(command).await.is_err() {
+        return HttpResponse::ServiceUnavailable().finish();
+    }
+
+    match tokio::time::timeout(ACK_TIMEOUT, wait_for_ack(&mut acks, op_code)).await {
+        Ok(Some(ack)) => match check_response(&ack) {
+            Ok(()) => HttpResponse::Ok().json(ack),
+            Err(e) => HttpResponse::UnprocessableEntity().json(serde_json::json!({
+                "error": e.to_string(),
+                "ack": ack,
+            })),
+        },
+        Ok(None) | Err(_) => HttpResponse::GatewayTimeout().finish(),
+    }
+}
+
+/// Acknowledgement of the request with given op code, None if trainer is gone
+async fn wait_for_ack(
+    acks: &mut broadcast::Receiver<ControlPointNotificationData>,
+    op_code: ControlPointOpCode,
+) -> Option<ControlPointNotificationData> {
+    loop {
+        match acks.recv().await {
+            Ok(ack) if ack.request_op_code == op_code => return Some(ack),
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return None,
+        }
     }
 }
 
@@ -44,14 +395,11 @@ async fn web_socket_handle(
     stream: web::Payload,
     app_state: Data<AppState>,
 ) -> Result<HttpResponse, Error> {
-    let guard = app_state.workout_state_tx.read().unwrap();
-
-    if let Some(workout_state) = guard.as_ref() {
-        let workout_state_rx = workout_state.subscribe();
-
+    if let Some(workout_state_rx) = app_state.workout_state.subscribe() {
         let actor = WebSocketActor {
             workout_state_rx,
             control_workout_tx: app_state.control_workout_tx.clone(),
+            app_state: app_state.clone(),
             hb: Instant::now(),
         };
 
@@ -62,3 +410,657 @@ async fn web_socket_handle(
         Ok(HttpResponse::BadRequest().finish())
     }
 }
+
+/// State with nothing loaded and no one listening, tests override the fields they need
+#[cfg(test)]
+pub(crate) fn new_app_state() -> AppState {
+    use std::sync::RwLock;
+
+    let (control_workout_tx, _) = tokio::sync::mpsc::channel(16);
+
+    AppState {
+        workout_state: crate::WorkoutStateChannel::closed(),
+        control_workout_tx,
+        workout_info: RwLock::new(None),
+        trainer_info: RwLock::new(None),
+        workout_dir: None,
+        recent_workouts: RwLock::new(vec![]),
+        ride_summary: RwLock::new(None),
+        workout_events_tx: broadcast::channel(16).0,
+        control_point_tx: broadcast::channel(16).0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::RwLock};
+
+    use actix_web::{test, App};
+    use tokio::sync::mpsc;
+
+    use crate::{
+        indoor_bike_client::TrainerInfo,
+        power_zones::NR_ZONES,
+        ride_summary::RideSummary,
+        workout_state::{WorkoutState, WorkoutStateChannel},
+        zwo_workout_file::WorkoutFile,
+    };
+
+    use super::*;
+
+    fn app_state() -> Data<AppState> {
+        Data::new(new_app_state())
+    }
+
+    /// State of the test workout, as it is loaded and running
+    async fn running_workout(capacity: usize) -> (WorkoutState, Data<AppState>) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts/test.zwo");
+        let workout = WorkoutFile::new(&path).await.unwrap();
+
+        let app_state = Data::new(AppState {
+            workout_state: WorkoutStateChannel::new(capacity),
+            workout_info: RwLock::new(Some(workout.info())),
+            ..new_app_state()
+        });
+
+        (WorkoutState::new(&workout, 200.0), app_state)
+    }
+
+    #[actix_web::test]
+    async fn lagging_workout_state_stream_survives() {
+        let (state, app_state) = running_workout(2).await;
+        let workout_state_tx = app_state.workout_state.publisher().unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(workout_state_handle),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/workout_state").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "application/x-ndjson"
+        );
+
+        // Client did not read anything yet, overflow the channel
+        for power in 0..5 {
+            let mut state = state.clone();
+            state.current_power_set = power;
+            workout_state_tx.send(state).unwrap();
+        }
+        // Close the stream
+        drop(workout_state_tx);
+        app_state.workout_state.close();
+
+        let body = test::read_body(resp).await;
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        // Oldest states are gone, stream continues with the ones that are still buffered
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], serde_json::json!({ "lagged": 3 }));
+        assert_eq!(lines[1]["current_power_set"], 3);
+        assert_eq!(lines[2]["current_power_set"], 4);
+    }
+
+    #[actix_web::test]
+    async fn new_client_gets_current_state_first() {
+        let (mut state, app_state) = running_workout(16).await;
+        let workout_state_tx = app_state.workout_state.publisher().unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(workout_state_handle),
+        )
+        .await;
+
+        // Broadcast before the client connects
+        state.current_power_set = 150;
+        let _ = workout_state_tx.send(state.clone());
+
+        let req = test::TestRequest::get().uri("/workout_state").to_request();
+        let resp = test::call_service(&app, req).await;
+
+        state.current_power_set = 160;
+        workout_state_tx.send(state.clone()).unwrap();
+        drop(workout_state_tx);
+        app_state.workout_state.close();
+
+        let body = test::read_body(resp).await;
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["current_power_set"], 150);
+        assert_eq!(lines[1]["current_power_set"], 160);
+    }
+
+    #[actix_web::test]
+    async fn reconnecting_client_gets_current_state_first() {
+        let (mut state, app_state) = running_workout(16).await;
+        let workout_state_tx = app_state.workout_state.publisher().unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(workout_state_handle),
+        )
+        .await;
+
+        // Client dropped at step 2, workout went on
+        state.current_step_number = 3;
+        let _ = workout_state_tx.send(state.clone());
+
+        let req = test::TestRequest::get()
+            .uri("/workout_state?since=2")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        state.current_step_number = 4;
+        workout_state_tx.send(state.clone()).unwrap();
+        drop(workout_state_tx);
+        app_state.workout_state.close();
+
+        let body = test::read_body(resp).await;
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["current_step_number"], 3);
+        assert_eq!(lines[1]["current_step_number"], 4);
+
+        // Once the workout is done, reconnecting client gets the final state only
+        let req = test::TestRequest::get()
+            .uri("/workout_state?since=4")
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let lines: Vec<&str> = std::str::from_utf8(&body).unwrap().lines().collect();
+        assert_eq!(lines.len(), 1);
+
+        let req = test::TestRequest::get().uri("/workout_state").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn events_are_framed_as_server_sent_events() {
+        let (mut state, app_state) = running_workout(16).await;
+        let workout_state_tx = app_state.workout_state.publisher().unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(events_handle),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/events").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "text/event-stream"
+        );
+
+        state.current_power_set = 150;
+        workout_state_tx.send(state).unwrap();
+        drop(workout_state_tx);
+        app_state.workout_state.close();
+
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        let frame = body.strip_suffix("\n\n").unwrap();
+        let json = frame.strip_prefix("data: ").unwrap();
+        assert!(!json.contains('\n'));
+
+        let state: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(state["current_power_set"], 150);
+    }
+
+    #[actix_web::test]
+    async fn web_socket_is_closed_on_client_request() {
+        let (_, app_state) = running_workout(16).await;
+
+        let app =
+            test::init_service(App::new().app_data(app_state).service(web_socket_handle)).await;
+
+        // Masked close frame without the payload, as sent by the browser, followed by binary frame
+        let close_frame = [0x88, 0x80, 0x00, 0x00, 0x00, 0x00];
+        let binary_frame = [0x82, 0x81, 0x00, 0x00, 0x00, 0x00, 0x2A];
+        let req = test::TestRequest::get()
+            .uri("/ws")
+            .insert_header(("upgrade", "websocket"))
+            .insert_header(("connection", "upgrade"))
+            .insert_header(("sec-websocket-version", "13"))
+            .insert_header(("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="))
+            .set_payload([&binary_frame[..], &close_frame[..]].concat())
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            actix_web::http::StatusCode::SWITCHING_PROTOCOLS
+        );
+
+        // Actor stops, so the stream ends, last frame is the close frame
+        let body = tokio::time::timeout(std::time::Duration::from_secs(1), test::read_body(resp))
+            .await
+            .unwrap();
+        assert_eq!(&body[body.len() - 2..], &[0x88, 0x00]);
+    }
+
+    #[actix_web::test]
+    async fn workout_info_is_served() {
+        let path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts/12wk_ftp_base/week1/1.zwo");
+        let workout = WorkoutFile::new(&path).await.unwrap();
+
+        let app_state = app_state();
+        *app_state.workout_info.write().unwrap() = Some(workout.info());
+
+        let app =
+            test::init_service(App::new().app_data(app_state).service(workout_info_handle)).await;
+
+        let req = test::TestRequest::get().uri("/workout_info").to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(info["author"], "Marco Pinotti");
+        assert_eq!(info["name"], "Day 1");
+        assert_eq!(info["description"], "Foundation");
+        assert_eq!(info["sport_type"], "bike");
+        assert_eq!(info["total_workout_duration"], 3000);
+        assert_eq!(info["total_steps"], 3);
+    }
+
+    #[actix_web::test]
+    async fn workout_info_without_workout_is_not_found() {
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state())
+                .service(workout_info_handle),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/workout_info").to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn trainer_info_is_served() {
+        let app_state = app_state();
+        *app_state.trainer_info.write().unwrap() = Some(TrainerInfo::simulated());
+
+        let app =
+            test::init_service(App::new().app_data(app_state).service(trainer_info_handle)).await;
+
+        let req = test::TestRequest::get().uri("/trainer_info").to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(
+            info["power_range"],
+            serde_json::json!({"min": 0, "max": 2000, "step": 1})
+        );
+        assert_eq!(info["resistance_range"]["max"], 100.0);
+        assert_eq!(
+            info["capabilities"],
+            serde_json::json!(["Resistance", "Power", "IndoorBikeSimulation"])
+        );
+    }
+
+    #[actix_web::test]
+    async fn trainer_info_without_trainer_is_unavailable() {
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state())
+                .service(trainer_info_handle),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/trainer_info").to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(
+            resp.status(),
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[actix_web::test]
+    async fn backend_is_not_ready_without_trainer() {
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state())
+                .service(health_handle)
+                .service(ready_handle),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/health").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/ready").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let status: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            status,
+            serde_json::json!({
+                "trainer_connected": false,
+                "workout_loaded": false,
+                "workout_running": false,
+                "subscribers": 0,
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn backend_is_ready_with_trainer() {
+        let (_, app_state) = running_workout(1).await;
+        *app_state.trainer_info.write().unwrap() = Some(TrainerInfo::simulated());
+        let _workout_state_rx = app_state.workout_state.subscribe().unwrap();
+
+        let app = test::init_service(App::new().app_data(app_state).service(ready_handle)).await;
+
+        let req = test::TestRequest::get().uri("/ready").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        let status: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            status,
+            serde_json::json!({
+                "trainer_connected": true,
+                "workout_loaded": true,
+                "workout_running": true,
+                "subscribers": 1,
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn localhost_origin_is_allowed_by_default() {
+        let app = test::init_service(
+            App::new()
+                .wrap(cors(&[]))
+                .app_data(app_state())
+                .service(health_handle),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/health")
+            .insert_header(("origin", "http://localhost:8080"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("access-control-allow-origin").unwrap(),
+            "http://localhost:8080"
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/health")
+            .insert_header(("origin", "http://example.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        // Browser blocks the response, if the header is not there
+        assert!(resp.headers().get("access-control-allow-origin").is_none());
+    }
+
+    #[actix_web::test]
+    async fn web_socket_can_be_opened_from_configured_origin() {
+        let (_, app_state) = running_workout(16).await;
+
+        let app = test::init_service(
+            App::new()
+                .wrap(cors(&["http://192.168.1.10:8080".to_string()]))
+                .app_data(app_state)
+                .service(web_socket_handle),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/ws")
+            .insert_header(("origin", "http://192.168.1.10:8080"))
+            .insert_header(("upgrade", "websocket"))
+            .insert_header(("connection", "upgrade"))
+            .insert_header(("sec-websocket-version", "13"))
+            .insert_header(("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(
+            resp.status(),
+            actix_web::http::StatusCode::SWITCHING_PROTOCOLS
+        );
+        assert_eq!(
+            resp.headers().get("access-control-allow-origin").unwrap(),
+            "http://192.168.1.10:8080"
+        );
+    }
+
+    #[actix_web::test]
+    async fn summary_is_served_once_workout_is_completed() {
+        let app_state = app_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state.clone())
+                .service(summary_handle),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/summary").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+
+        let mut time_in_zones = [Duration::ZERO; NR_ZONES];
+        time_in_zones[1] = Duration::from_millis(60_400);
+        *app_state.ride_summary.write().unwrap() = Some(RideSummary {
+            duration: Duration::from_millis(90_700),
+            avg_power: 180.0,
+            time_in_zones,
+            ..Default::default()
+        });
+
+        let req = test::TestRequest::get().uri("/summary").to_request();
+        let summary: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(summary["avg_power"], 180.0);
+        // Durations are whole seconds
+        assert_eq!(summary["duration"], 90);
+        assert_eq!(
+            summary["time_in_zones"],
+            serde_json::json!([0, 60, 0, 0, 0, 0, 0])
+        );
+    }
+
+    #[actix_web::test]
+    async fn workouts_are_listed() {
+        let workout_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts");
+        let nr_workouts = walkdir::WalkDir::new(&workout_dir)
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("zwo".as_ref()))
+            .count();
+
+        let app_state = AppState {
+            workout_dir: Some(workout_dir),
+            ..new_app_state()
+        };
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(app_state))
+                .service(workouts_handle),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/workouts").to_request();
+        let workouts: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(workouts.len(), nr_workouts);
+
+        let day_1 = workouts
+            .iter()
+            .find(|workout| workout["path"] == "12wk_ftp_base/week1/1.zwo")
+            .unwrap();
+        assert_eq!(day_1["name"], "Day 1");
+        assert_eq!(day_1["author"], "Marco Pinotti");
+        assert_eq!(day_1["duration"], 3000);
+    }
+
+    #[actix_web::test]
+    async fn target_power_is_set_and_acknowledged() {
+        let (control_workout_tx, mut control_workout_rx) = mpsc::channel(16);
+        let app_state = Data::new(AppState {
+            control_workout_tx,
+            trainer_info: RwLock::new(Some(TrainerInfo::simulated())),
+            ..new_app_state()
+        });
+
+        // Manual mode passing the target to the trainer, which acknowledges it
+        let control_point_tx = app_state.control_point_tx.clone();
+        let manual_mode = tokio::spawn(async move {
+            let command = control_workout_rx.recv().await.unwrap();
+            control_point_tx
+                .send(ControlPointNotificationData {
+                    request_op_code: ControlPointOpCode::SetTargetPower,
+                    request_status: crate::indoor_bike_data_defs::ControlPointResult::Success,
+                })
+                .unwrap();
+            command
+        });
+
+        let app =
+            test::init_service(App::new().app_data(app_state).service(control_power_handle)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/control/power")
+            .set_json(serde_json::json!({ "watts": 210 }))
+            .to_request();
+        let ack: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(ack["request_op_code"], "SetTargetPower");
+        assert_eq!(ack["request_status"], "Success");
+        assert_eq!(
+            manual_mode.await.unwrap(),
+            WorkoutCommands::SetTargetPower(210)
+        );
+    }
+
+    #[actix_web::test]
+    async fn resistance_percent_is_set_and_acknowledged() {
+        let (control_workout_tx, mut control_workout_rx) = mpsc::channel(16);
+        let app_state = Data::new(AppState {
+            control_workout_tx,
+            trainer_info: RwLock::new(Some(TrainerInfo::simulated())),
+            ..new_app_state()
+        });
+
+        let control_point_tx = app_state.control_point_tx.clone();
+        let manual_mode = tokio::spawn(async move {
+            let command = control_workout_rx.recv().await.unwrap();
+            control_point_tx
+                .send(ControlPointNotificationData {
+                    request_op_code: ControlPointOpCode::SetTargetResistance,
+                    request_status: crate::indoor_bike_data_defs::ControlPointResult::Success,
+                })
+                .unwrap();
+            command
+        });
+
+        let app = test::init_service(
+            App::new()
+                .app_data(app_state)
+                .service(control_resistance_handle),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/control/resistance")
+            .set_json(serde_json::json!({ "percent": 40 }))
+            .to_request();
+        let ack: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(ack["request_op_code"], "SetTargetResistance");
+        assert_eq!(
+            manual_mode.await.unwrap(),
+            WorkoutCommands::SetResistancePercent(40.0)
+        );
+    }
+
+    #[actix_web::test]
+    async fn out_of_range_target_is_rejected() {
+        let (control_workout_tx, mut control_workout_rx) = mpsc::channel(16);
+        let app_state = AppState {
+            control_workout_tx,
+            trainer_info: RwLock::new(Some(TrainerInfo::simulated())),
+            ..new_app_state()
+        };
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(app_state))
+                .service(control_power_handle)
+                .service(control_resistance_handle),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/control/power")
+            .set_json(serde_json::json!({ "watts": 2500 }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            actix_web::http::StatusCode::UNPROCESSABLE_ENTITY
+        );
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "Power 2500W outside valid range 0..=2000W");
+
+        // Simulated range 0..=100 is 0..=10 in raw levels
+        let req = test::TestRequest::post()
+            .uri("/control/resistance")
+            .set_json(serde_json::json!({ "level": 50 }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            actix_web::http::StatusCode::UNPROCESSABLE_ENTITY
+        );
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "Resistance 50 outside valid range 0..=10");
+
+        let req = test::TestRequest::post()
+            .uri("/control/resistance")
+            .set_json(serde_json::json!({ "percent": 150 }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            actix_web::http::StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        // Nothing reached the trainer
+        assert!(control_workout_rx.try_recv().is_err());
+    }
+}
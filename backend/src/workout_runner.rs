@@ -0,0 +1,834 @@
+//! Workout and manual mode tasks, turning the workout, or the rider input, into trainer commands
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use tokio::{sync::broadcast, time::Instant};
+
+use crate::{
+    common::Units,
+    config::{Config, RecentWorkout},
+    ftp_test::FtpTest,
+    indoor_bike_data_defs::{BikeData, Range},
+    power_zones::{PowerZones, ZoneBounds},
+    progress::log_progress,
+    ride_summary::{self, RideSummaryAccumulator},
+    ride_workout::save_ride_as_workout,
+    trainer_control::{exit_requested, send_trainer_command},
+    AppState, Repeat, RestPowerFloor, TrainerInfo, UserCommands, WorkoutCommands, WorkoutState,
+    ZwoWorkout,
+};
+
+/// Command line options affecting the workout execution
+pub struct WorkoutOptions {
+    pub ftp_base: f64,
+    pub zone_bounds: ZoneBounds,
+    pub smooth_ramps: bool,
+    pub repeat: Repeat,
+    pub power_offset_watts: i16,
+    pub rest_power_floor: Option<RestPowerFloor>,
+    pub start_paused: bool,
+    pub strict: bool,
+    pub units: Units,
+    /// Gross efficiency of the rider, for calories estimate
+    pub efficiency: f64,
+    /// Workout is the FTP test, FTP is estimated at its end
+    pub ftp_test: Option<FtpTest>,
+    /// Config the estimated FTP is written to
+    pub save_ftp_to: Option<PathBuf>,
+    /// Config the workout is recorded to, as a recent one
+    pub recent_to: Option<PathBuf>,
+    /// Period of the progress logging, no logging if not set
+    pub progress_every: Option<Duration>,
+}
+
+/// Reads ZWO file, and sends commands according to it
+pub async fn start_workout(
+    trainer_commands_tx: tokio::sync::broadcast::Sender<UserCommands>,
+    app_state: actix_web::web::Data<AppState>,
+    mut control_workout_rx: tokio::sync::mpsc::Receiver<WorkoutCommands>,
+    bike_rx: Option<broadcast::Receiver<BikeData>>,
+    workout: &Path,
+    options: WorkoutOptions,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let WorkoutOptions {
+        ftp_base,
+        zone_bounds,
+        smooth_ramps,
+        repeat,
+        power_offset_watts,
+        rest_power_floor,
+        start_paused,
+        strict,
+        units,
+        efficiency,
+        ftp_test,
+        save_ftp_to,
+        recent_to,
+        progress_every,
+    } = options;
+
+    let workout_path = std::fs::canonicalize(workout).unwrap_or_else(|_| workout.to_path_buf());
+    let (workout, mut workout_state_actor) = ZwoWorkout::new(workout, ftp_base).await?;
+    workout.workout_info().check_sport_type(strict)?;
+
+    update_recent_workouts(&app_state, recent_to.as_deref(), |recent| {
+        recent.push(RecentWorkout::started_now(workout_path))
+    });
+    let mut workout = workout.with_events(app_state.workout_events_tx.clone());
+    // Subscribed ahead of start_paused, the ride summary has to see the pause
+    let ride_events_rx = app_state.workout_events_tx.subscribe();
+    workout.set_smooth_ramps(smooth_ramps);
+    workout.set_repeat(repeat);
+    if power_offset_watts != 0 {
+        workout.set_power_offset(power_offset_watts, &power_range(&app_state));
+    }
+    if let Some(floor) = rest_power_floor {
+        workout.set_rest_power_floor(floor);
+    }
+    if start_paused {
+        workout.start_paused();
+    }
+
+    let power_zones = PowerZones::new(ftp_base, zone_bounds);
+    let ride_summary = Arc::new(Mutex::new(
+        RideSummaryAccumulator::new(power_zones.clone(), Instant::now())
+            .with_efficiency(efficiency),
+    ));
+
+    if let Some(bike_rx) = bike_rx {
+        tokio::spawn(ride_summary::accumulate(
+            ride_summary.clone(),
+            bike_rx.resubscribe(),
+            ride_events_rx,
+        ));
+        workout_state_actor = workout_state_actor
+            .with_bike_data(bike_rx, power_zones)
+            .with_efficiency(efficiency);
+    }
+
+    *app_state.workout_info.write().unwrap() = Some(workout.workout_info().clone());
+
+    let workout_state_tx = app_state
+        .workout_state
+        .publisher()
+        .context("Workout state is already published")?;
+
+    if let (Some(every), Some(workout_state_rx)) =
+        (progress_every, app_state.workout_state.subscribe())
+    {
+        tokio::spawn(log_progress(workout_state_rx, ride_summary.clone(), every));
+    }
+
+    // Workout state lives in a separate task, workout only sends updates to it
+    tokio::spawn(workout_state_actor.run(workout_state_tx));
+
+    let mut trainer_commands_rx = trainer_commands_tx.subscribe();
+    let handle = tokio::spawn(async move {
+        debug!("spawning workout task");
+
+        // Broadcast with no receivers is not fatal, it happens when frontend disconnects,
+        // so errors from sending are just logged
+        send_trainer_command(&trainer_commands_tx, UserCommands::StartWorkout);
+
+        let mut abort_lock = AbortLock::default();
+
+        loop {
+            tokio::select! {
+                workout_step = workout.next() => {
+                    // Next step is available
+                    match workout_step {
+                        Some(command) => {
+                            debug!("Got command from workout: {command:?}");
+                            debug!("workout {:?}", workout.current_step);
+                            send_trainer_command(&trainer_commands_tx, command);
+                        }
+                        None => {
+                            debug!("No more steps in workout, workout task exits");
+
+                            let summary = ride_summary.lock().unwrap().summary(Instant::now());
+                            info!("Workout completed, summary {summary:#?}");
+                            info!(
+                                "Distance {}, average speed {}",
+                                units.distance(summary.distance * 1000.0),
+                                units.speed(summary.avg_speed)
+                            );
+                            *app_state.ride_summary.write().unwrap() = Some(summary);
+
+                            update_recent_workouts(&app_state, recent_to.as_deref(), |recent| {
+                                if let Some(workout) = recent.last_mut() {
+                                    workout.completed = true;
+                                }
+                            });
+
+                            send_trainer_command(&trainer_commands_tx, UserCommands::WorkoutCompleted);
+
+                            break;
+                        },
+                    }
+                }
+                Some(control)  = control_workout_rx.recv() => {
+                    match control {
+                        WorkoutCommands::Pause=> workout.pause(),
+                        WorkoutCommands::Resume=> workout.resume(),
+                        WorkoutCommands::SkipStep=> workout.skip_step(),
+                        WorkoutCommands::ExtendStep(by) => workout.extend_step(by),
+                        WorkoutCommands::JumpToStep(step_number) => {
+                            if let Err(e) = workout.jump_to_step(step_number) {
+                                warn!("{e}");
+                            }
+                        }
+                        WorkoutCommands::NudgePower(by) => {
+                            if let Some(command) = workout.nudge_power(by, &power_range(&app_state)) {
+                                send_trainer_command(&trainer_commands_tx, command);
+                            }
+                        }
+                        WorkoutCommands::SetPowerOffset(offset) => {
+                            if let Some(command) = workout.set_power_offset(offset, &power_range(&app_state)) {
+                                send_trainer_command(&trainer_commands_tx, command);
+                            }
+                        }
+                        WorkoutCommands::SetTargetPower(_)
+                        | WorkoutCommands::SetResistance(_)
+                        | WorkoutCommands::SetResistancePercent(_) => {
+                            warn!("{control:?} is available in manual mode only");
+                        }
+                        WorkoutCommands::Abort => {
+                            if !abort_lock.abort(workout.is_in_final_cooldown(), Instant::now()) {
+                                warn!("Workout is almost done, abort again within {ABORT_CONFIRM_WINDOW:?} to abort it");
+                                continue;
+                            }
+
+                            send_trainer_command(&trainer_commands_tx, UserCommands::Exit);
+                            break;
+                        },
+                    }
+                }
+                _ = exit_requested(&mut trainer_commands_rx) => {
+                    debug!("Application exits, workout task exits");
+                    break;
+                }
+            }
+        }
+
+        // FTP test ends either way, ramp test usually by the rider quitting it
+        if let Some(ftp_test) = ftp_test {
+            report_ftp_test(ftp_test, &ride_summary, save_ftp_to.as_deref());
+        }
+
+        // Workout completed, all workout state streams end once the workout state task exits
+        // (it owns the publisher, and exits together with the workout)
+        app_state.workout_state.close();
+    });
+
+    Ok(handle)
+}
+
+/// Logs FTP estimated from the power recorded during the test, and writes it to the config if asked to
+fn report_ftp_test(
+    ftp_test: FtpTest,
+    ride_summary: &Mutex<RideSummaryAccumulator>,
+    save_to: Option<&Path>,
+) {
+    let estimate = {
+        let mut ride_summary = ride_summary.lock().unwrap();
+        // Accounts the last sample
+        ride_summary.summary(Instant::now());
+        ftp_test.estimate_ftp(ride_summary.power_per_second())
+    };
+
+    let ftp = match estimate {
+        Some(ftp) => ftp.round(),
+        None => {
+            warn!("{ftp_test:?} FTP test was too short to estimate FTP");
+            return;
+        }
+    };
+
+    info!("{ftp_test:?} FTP test done, estimated FTP {ftp}W");
+
+    if let Some(path) = save_to {
+        if let Err(e) = Config::save_ftp_base(path, ftp) {
+            error!("Failed to save FTP: {e:?}");
+        }
+    }
+}
+
+/// Abort during the final cooldown is confirmed by another one within that time
+const ABORT_CONFIRM_WINDOW: Duration = Duration::from_secs(2);
+
+/// Abort during the final cooldown has to be repeated, so the rider does not lose
+/// almost completed workout by accident
+#[derive(Debug, Default)]
+struct AbortLock {
+    requested_at: Option<Instant>,
+}
+
+impl AbortLock {
+    /// Tells if the workout is to be aborted, locked abort is ignored unless it's a confirmation
+    fn abort(&mut self, locked: bool, now: Instant) -> bool {
+        if !locked {
+            return true;
+        }
+
+        match self.requested_at.take() {
+            Some(requested_at) if now.duration_since(requested_at) <= ABORT_CONFIRM_WINDOW => true,
+            _ => {
+                self.requested_at = Some(now);
+                false
+            }
+        }
+    }
+}
+
+/// Ride without a workout, target power and resistance are passed from the user to the trainer,
+/// workout state is broadcasted every second, until user aborts, or the application exits.
+/// Ride is saved as a workout to given file, if there is bike data to record it from
+pub fn start_manual_mode(
+    trainer_commands_tx: broadcast::Sender<UserCommands>,
+    app_state: actix_web::web::Data<AppState>,
+    mut control_workout_rx: tokio::sync::mpsc::Receiver<WorkoutCommands>,
+    ftp_base: f64,
+    bike_rx: Option<broadcast::Receiver<BikeData>>,
+    save_ride_to: Option<PathBuf>,
+) -> tokio::task::JoinHandle<()> {
+    info!("No workout given, starting manual mode");
+
+    let recording = match (save_ride_to, bike_rx) {
+        (Some(path), Some(bike_rx)) => {
+            let ride = Arc::new(Mutex::new(RideSummaryAccumulator::new(
+                PowerZones::new(ftp_base, ZoneBounds::default()),
+                Instant::now(),
+            )));
+            tokio::spawn(ride_summary::accumulate(
+                ride.clone(),
+                bike_rx,
+                app_state.workout_events_tx.subscribe(),
+            ));
+
+            Some((path, ride))
+        }
+        (Some(_), None) => {
+            warn!("Ride cannot be saved without bike data from the trainer");
+            None
+        }
+        (None, _) => None,
+    };
+
+    // Without the publisher state is not seen by anyone, but manual mode still works
+    let workout_state_tx = app_state
+        .workout_state
+        .publisher()
+        .unwrap_or_else(|| broadcast::channel(1).0.into());
+    let mut trainer_commands_rx = trainer_commands_tx.subscribe();
+
+    tokio::spawn(async move {
+        let mut state = WorkoutState::manual(ftp_base);
+        let mut propagate_workout_state = tokio::time::interval(Duration::from_secs(1));
+
+        send_trainer_command(&trainer_commands_tx, UserCommands::StartWorkout);
+
+        let exiting = loop {
+            tokio::select! {
+                _ = propagate_workout_state.tick() => {
+                    state.update_ts();
+                    if workout_state_tx.send(state.clone()).is_err() {
+                        trace!("No one listens for workout state");
+                    }
+                }
+                control = control_workout_rx.recv() => match control {
+                    Some(WorkoutCommands::SetTargetPower(power)) => {
+                        let power = power_range(&app_state).clamp(power);
+                        state.set_power(power, None);
+                        let command = UserCommands::SetTargetPower { power };
+                        send_trainer_command(&trainer_commands_tx, command);
+                    }
+                    Some(WorkoutCommands::NudgePower(by)) => {
+                        let power = power_range(&app_state)
+                            .clamp(state.current_power_set.saturating_add(by));
+                        state.set_power(power, None);
+                        let command = UserCommands::SetTargetPower { power };
+                        send_trainer_command(&trainer_commands_tx, command);
+                    }
+                    Some(WorkoutCommands::SetResistance(resistance)) => {
+                        let command = UserCommands::SetResistance { resistance };
+                        send_trainer_command(&trainer_commands_tx, command);
+                    }
+                    Some(WorkoutCommands::SetResistancePercent(percent)) => {
+                        let command = UserCommands::SetResistancePercent { percent };
+                        send_trainer_command(&trainer_commands_tx, command);
+                    }
+                    Some(WorkoutCommands::Abort) | None => break false,
+                    Some(other) => warn!("{other:?} is not available in manual mode"),
+                },
+                _ = exit_requested(&mut trainer_commands_rx) => break true,
+            }
+        };
+
+        // Saved before the exit, application waits for this task only for a while
+        if let Some((path, ride)) = recording {
+            let power_per_second = {
+                let mut ride = ride.lock().unwrap();
+                // Accounts the last sample
+                ride.summary(Instant::now());
+                ride.power_per_second().to_vec()
+            };
+
+            if let Err(e) = save_ride_as_workout(&path, &power_per_second, ftp_base).await {
+                error!("Failed to save the ride: {e:?}");
+            }
+        }
+        if !exiting {
+            send_trainer_command(&trainer_commands_tx, UserCommands::Exit);
+        }
+
+        // Close the workout state streams, the same way finished workout does
+        drop(workout_state_tx);
+        app_state.workout_state.close();
+    })
+}
+
+/// Power range of the trainer, any sane power is accepted if trainer is not known yet
+pub fn power_range(app_state: &AppState) -> Range<i16, u16> {
+    app_state
+        .trainer_info
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(TrainerInfo::simulated)
+        .power_range
+}
+
+/// Updates recent workouts in the config, and the ones served to the UI.
+/// Failure is not fatal, the workout goes on.
+pub fn update_recent_workouts(
+    app_state: &AppState,
+    config: Option<&Path>,
+    update: impl FnOnce(&mut Vec<RecentWorkout>),
+) {
+    let config = match config {
+        Some(config) => config,
+        None => return,
+    };
+
+    match Config::update_recent(config, update) {
+        Ok(recent) => *app_state.recent_workouts.write().unwrap() = recent,
+        Err(e) => warn!("Cannot update recent workouts: {e:#}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use super::*;
+    use crate::{
+        cli::parse_workout_command, common::DEFAULT_EFFICIENCY, config::MAX_RECENT_WORKOUTS,
+        web_endpoints::new_app_state, WorkoutStateChannel,
+    };
+
+    fn test_workout() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts/test.zwo")
+    }
+
+    #[tokio::test]
+    async fn workout_task_survives_without_receivers() {
+        let (trainer_commands_tx, trainer_commands_rx) = broadcast::channel(16);
+        let (control_workout_tx, control_workout_rx) = tokio::sync::mpsc::channel(16);
+
+        // Nobody listens, every broadcast fails
+        drop(trainer_commands_rx);
+
+        let app_state = actix_web::web::Data::new(AppState {
+            workout_state: WorkoutStateChannel::new(16),
+            control_workout_tx,
+            ..new_app_state()
+        });
+
+        let handle = start_workout(
+            trainer_commands_tx,
+            app_state,
+            control_workout_rx,
+            None,
+            &test_workout(),
+            WorkoutOptions {
+                ftp_base: 200.0,
+                zone_bounds: ZoneBounds::default(),
+                smooth_ramps: false,
+                repeat: Repeat::default(),
+                power_offset_watts: 0,
+                rest_power_floor: None,
+                start_paused: false,
+                strict: false,
+                units: Units::default(),
+                efficiency: DEFAULT_EFFICIENCY,
+                ftp_test: None,
+                save_ftp_to: None,
+                recent_to: None,
+                progress_every: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!handle.is_finished());
+
+        handle.abort();
+        assert!(handle.await.unwrap_err().is_cancelled());
+    }
+
+    /// Runs the test workout, aborted right away if asked to, returns the last trainer command
+    async fn last_workout_command(abort: bool, recent_to: Option<&Path>) -> UserCommands {
+        let (trainer_commands_tx, mut trainer_commands_rx) = broadcast::channel(1024);
+        let (control_workout_tx, control_workout_rx) = tokio::sync::mpsc::channel(16);
+
+        let app_state = actix_web::web::Data::new(AppState {
+            workout_state: WorkoutStateChannel::new(16),
+            control_workout_tx: control_workout_tx.clone(),
+            ..new_app_state()
+        });
+
+        let handle = start_workout(
+            trainer_commands_tx,
+            app_state,
+            control_workout_rx,
+            None,
+            &test_workout(),
+            WorkoutOptions {
+                ftp_base: 200.0,
+                zone_bounds: ZoneBounds::default(),
+                smooth_ramps: false,
+                repeat: Repeat::default(),
+                power_offset_watts: 0,
+                rest_power_floor: None,
+                start_paused: false,
+                strict: false,
+                units: Units::default(),
+                efficiency: DEFAULT_EFFICIENCY,
+                ftp_test: None,
+                save_ftp_to: None,
+                recent_to: recent_to.map(Path::to_path_buf),
+                progress_every: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        if abort {
+            control_workout_tx
+                .send(WorkoutCommands::Abort)
+                .await
+                .unwrap();
+        }
+        handle.await.unwrap();
+
+        let mut last = None;
+        while let Ok(command) = trainer_commands_rx.try_recv() {
+            last = Some(command);
+        }
+        last.unwrap()
+    }
+
+    #[test]
+    fn abort_in_final_cooldown_needs_confirmation() {
+        let start = Instant::now();
+
+        let mut lock = AbortLock::default();
+        assert!(lock.abort(false, start));
+
+        // Single abort is ignored, another one within the window aborts
+        assert!(!lock.abort(true, start));
+        assert!(lock.abort(true, start + Duration::from_secs(1)));
+
+        // Too late, it counts as the first abort again
+        let mut lock = AbortLock::default();
+        assert!(!lock.abort(true, start));
+        assert!(!lock.abort(true, start + Duration::from_secs(3)));
+        assert!(lock.abort(true, start + Duration::from_secs(4)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn completed_workout_is_told_apart_from_abort() {
+        assert!(matches!(
+            last_workout_command(false, None).await,
+            UserCommands::WorkoutCompleted
+        ));
+        assert!(matches!(
+            last_workout_command(true, None).await,
+            UserCommands::Exit
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn workouts_run_are_recorded_as_recent() {
+        let config =
+            std::env::temp_dir().join(format!("velomania_main_{}.toml", std::process::id()));
+        let _ = std::fs::remove_file(&config);
+
+        last_workout_command(false, Some(&config)).await;
+        for _ in 1..MAX_RECENT_WORKOUTS {
+            last_workout_command(true, Some(&config)).await;
+        }
+
+        let recent = Config::load(Some(&config)).unwrap().recent;
+        assert_eq!(recent.len(), MAX_RECENT_WORKOUTS);
+        assert!(recent[0].completed);
+        assert!(!recent[1].completed);
+        assert_eq!(recent[0].path, test_workout().canonicalize().unwrap());
+
+        // The oldest one goes away
+        last_workout_command(true, Some(&config)).await;
+        let recent = Config::load(Some(&config)).unwrap().recent;
+        std::fs::remove_file(&config).unwrap();
+
+        assert_eq!(recent.len(), MAX_RECENT_WORKOUTS);
+        assert!(recent.iter().all(|workout| !workout.completed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn manual_mode_passes_power_to_the_trainer() {
+        let (trainer_commands_tx, mut trainer_commands_rx) = broadcast::channel(16);
+        let (control_workout_tx, control_workout_rx) = tokio::sync::mpsc::channel(16);
+
+        let app_state = actix_web::web::Data::new(AppState {
+            workout_state: WorkoutStateChannel::new(16),
+            control_workout_tx: control_workout_tx.clone(),
+            ..new_app_state()
+        });
+        let mut workout_state_rx = app_state.workout_state.subscribe().unwrap();
+
+        let handle = start_manual_mode(
+            trainer_commands_tx,
+            app_state.clone(),
+            control_workout_rx,
+            200.0,
+            None,
+            None,
+        );
+
+        let state = workout_state_rx.recv().await.unwrap();
+        assert!(state.manual);
+        assert_eq!(state.total_steps, 0);
+
+        let command = parse_workout_command("P 180").unwrap();
+        control_workout_tx.send(command).await.unwrap();
+
+        assert!(matches!(
+            trainer_commands_rx.recv().await.unwrap(),
+            UserCommands::StartWorkout
+        ));
+        assert!(matches!(
+            trainer_commands_rx.recv().await.unwrap(),
+            UserCommands::SetTargetPower { power: 180 }
+        ));
+
+        let state = workout_state_rx.recv().await.unwrap();
+        assert_eq!(state.current_power_set, 180);
+
+        let command = parse_workout_command("R 3").unwrap();
+        control_workout_tx.send(command).await.unwrap();
+        assert!(matches!(
+            trainer_commands_rx.recv().await.unwrap(),
+            UserCommands::SetResistance { resistance: 3 }
+        ));
+
+        // Steps are not there to skip
+        control_workout_tx
+            .send(WorkoutCommands::SkipStep)
+            .await
+            .unwrap();
+        control_workout_tx
+            .send(WorkoutCommands::Abort)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            trainer_commands_rx.recv().await.unwrap(),
+            UserCommands::Exit
+        ));
+        handle.await.unwrap();
+        assert!(!app_state.workout_state.is_open());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn manual_target_is_clamped_to_power_range() {
+        let (trainer_commands_tx, mut trainer_commands_rx) = broadcast::channel(16);
+        let (control_workout_tx, control_workout_rx) = tokio::sync::mpsc::channel(16);
+
+        let mut trainer_info = TrainerInfo::simulated();
+        trainer_info.power_range = Range {
+            min: 100,
+            max: 300,
+            step: 1,
+        };
+
+        let app_state = actix_web::web::Data::new(AppState {
+            workout_state: WorkoutStateChannel::new(16),
+            control_workout_tx: control_workout_tx.clone(),
+            trainer_info: RwLock::new(Some(trainer_info)),
+            ..new_app_state()
+        });
+
+        let handle = start_manual_mode(
+            trainer_commands_tx,
+            app_state,
+            control_workout_rx,
+            200.0,
+            None,
+            None,
+        );
+
+        assert!(matches!(
+            trainer_commands_rx.recv().await.unwrap(),
+            UserCommands::StartWorkout
+        ));
+
+        let inputs = [
+            ("P 298", 298),
+            ("+", 300),
+            ("+", 300),
+            ("P 102", 102),
+            ("-", 100),
+            ("-", 100),
+            ("+", 105),
+            ("P 5000", 300),
+            ("P -20", 100),
+        ];
+
+        for (input, expected) in inputs {
+            let command = parse_workout_command(input).unwrap();
+            control_workout_tx.send(command).await.unwrap();
+
+            match trainer_commands_rx.recv().await.unwrap() {
+                UserCommands::SetTargetPower { power } => assert_eq!(power, expected, "{input}"),
+                other => panic!("Unexpected command {:?}", other),
+            }
+        }
+
+        // Nudge as large as it gets does not overflow
+        control_workout_tx
+            .send(WorkoutCommands::SetTargetPower(300))
+            .await
+            .unwrap();
+        control_workout_tx
+            .send(WorkoutCommands::NudgePower(i16::MAX))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                trainer_commands_rx.recv().await.unwrap(),
+                UserCommands::SetTargetPower { power: 300 }
+            ));
+        }
+
+        control_workout_tx
+            .send(WorkoutCommands::Abort)
+            .await
+            .unwrap();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn manual_ride_is_saved_on_exit() {
+        let (trainer_commands_tx, _trainer_commands_rx) = broadcast::channel(16);
+        let (control_workout_tx, control_workout_rx) = tokio::sync::mpsc::channel(16);
+        let (_bike_tx, bike_rx) = broadcast::channel(16);
+
+        let path = std::env::temp_dir().join(format!("velomania_ride_{}.zwo", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let app_state = actix_web::web::Data::new(AppState {
+            workout_state: WorkoutStateChannel::new(16),
+            control_workout_tx,
+            ..new_app_state()
+        });
+
+        let handle = start_manual_mode(
+            trainer_commands_tx.clone(),
+            app_state,
+            control_workout_rx,
+            200.0,
+            Some(bike_rx),
+            Some(path.clone()),
+        );
+
+        // Like on SIGINT, the ride is not aborted by the user
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        trainer_commands_tx.send(UserCommands::Exit).unwrap();
+        handle.await.unwrap();
+
+        let saved = std::fs::read_to_string(&path);
+        let _ = std::fs::remove_file(&path);
+        assert!(saved.unwrap().contains("<workout_file>"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ftp_test_is_reported_on_exit() {
+        let (trainer_commands_tx, _trainer_commands_rx) = broadcast::channel(16);
+        let (control_workout_tx, control_workout_rx) = tokio::sync::mpsc::channel(16);
+        let (bike_tx, bike_rx) = broadcast::channel(16);
+
+        let config =
+            std::env::temp_dir().join(format!("velomania_ftp_{}.toml", std::process::id()));
+        std::fs::write(&config, "ftp_base = 200.0\n").unwrap();
+
+        let app_state = actix_web::web::Data::new(AppState {
+            workout_state: WorkoutStateChannel::new(16),
+            control_workout_tx,
+            ..new_app_state()
+        });
+
+        let handle = start_workout(
+            trainer_commands_tx.clone(),
+            app_state,
+            control_workout_rx,
+            Some(bike_rx),
+            &FtpTest::Ramp.write_workout().await.unwrap(),
+            WorkoutOptions {
+                ftp_base: 200.0,
+                zone_bounds: ZoneBounds::default(),
+                smooth_ramps: false,
+                repeat: Repeat::default(),
+                power_offset_watts: 0,
+                rest_power_floor: None,
+                start_paused: false,
+                strict: false,
+                units: Units::default(),
+                efficiency: DEFAULT_EFFICIENCY,
+                ftp_test: Some(FtpTest::Ramp),
+                save_ftp_to: Some(config.clone()),
+                recent_to: None,
+                progress_every: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        // Rider holds 300W for 90s, then quits with Ctrl-C
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        bike_tx
+            .send(BikeData {
+                inst_power: Some(300),
+                ..Default::default()
+            })
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(90)).await;
+        trainer_commands_tx.send(UserCommands::Exit).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let saved = Config::load(Some(&config)).unwrap().ftp_base;
+        std::fs::remove_file(&config).unwrap();
+        assert_eq!(
+            saved,
+            Some(FtpTest::Ramp.estimate_ftp(&[300.0; 60]).unwrap().round())
+        );
+    }
+}
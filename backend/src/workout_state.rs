@@ -1,55 +1,235 @@
-use std::{time::Duration};
-
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
 
 use serde::Serialize;
-use tokio::time::Instant;
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::Instant,
+};
 
 use crate::{
-    zwo_workout_file::{WorkoutFile, WorkoutSteps},
+    common::{get_ftp_percent, get_power, kj_to_kcal, whole_secs, DEFAULT_EFFICIENCY},
+    indoor_bike_data_defs::BikeData,
+    power_zones::{PowerZones, NR_ZONES},
+    zwo_workout_file::{FreeRide, TextEvent, WorkoutFile, WorkoutSteps},
 };
 
+/// How many seconds before the next interval, or step, countdown starts
+const COUNTDOWN_FROM: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone, Serialize)]
 pub struct StepState {
+    #[serde(with = "whole_secs")]
     pub duration: Duration,
     pub step: WorkoutSteps,
+    #[serde(with = "whole_secs")]
     pub elapsed: Duration,
     #[serde(skip)]
     started: Instant,
 }
 
+/// Instant the step started at is not compared, equal states may be created at different moments
+impl PartialEq for StepState {
+    fn eq(&self, other: &Self) -> bool {
+        // Destructured, so new fields are not forgotten
+        let Self {
+            duration,
+            step,
+            elapsed,
+            started: _,
+        } = self;
+
+        duration == &other.duration && step == &other.step && elapsed == &other.elapsed
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct IntervalState {
     pub repetition: usize,
     pub is_work_interval: bool,
+    #[serde(with = "whole_secs")]
     pub elapsed: Duration,
+    #[serde(with = "whole_secs")]
     pub duration: Duration,
+    /// Repetitions left, including the current one
+    pub remaining_reps: u64,
+    /// Work target in watts
+    pub on_power: i16,
+    #[serde(with = "whole_secs")]
+    pub on_duration: Duration,
+    /// Rest target in watts
+    pub off_power: i16,
+    #[serde(with = "whole_secs")]
+    pub off_duration: Duration,
     #[serde(skip)]
     started: Instant,
 }
 
+/// Instant the interval started at is not compared, like for the StepState
+impl PartialEq for IntervalState {
+    fn eq(&self, other: &Self) -> bool {
+        // Destructured, so new fields are not forgotten
+        let Self {
+            repetition,
+            is_work_interval,
+            elapsed,
+            duration,
+            remaining_reps,
+            on_power,
+            on_duration,
+            off_power,
+            off_duration,
+            started: _,
+        } = self;
+
+        repetition == &other.repetition
+            && is_work_interval == &other.is_work_interval
+            && elapsed == &other.elapsed
+            && duration == &other.duration
+            && remaining_reps == &other.remaining_reps
+            && on_power == &other.on_power
+            && on_duration == &other.on_duration
+            && off_power == &other.off_power
+            && off_duration == &other.off_duration
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct WorkoutState {
+    /// Name of the workout as given in the file, empty for the manual ride
+    pub workout_name: String,
     pub total_steps: usize,
     pub current_step_number: usize,
 
+    #[serde(with = "whole_secs")]
     pub total_workout_duration: Duration,
 
     pub next_step: Option<WorkoutSteps>,
 
     pub current_power_set: i16,
+    /// Current target power in percent of FTP
+    pub current_power_ftp_percent: f64,
+    /// Power level of the workout step the target comes from, fraction of FTP.
+    /// None if target is set by hand
+    pub current_power_level: Option<f64>,
+    /// Watts the user added to every target of the workout
+    pub power_offset: i16,
+    /// Target is the rest power floor, instead of the power of the workout step
+    pub rest_power_floor_active: bool,
     pub ftp_base: f64,
 
     pub current_step: StepState,
     pub current_interval: Option<IntervalState>,
+    #[serde(with = "whole_secs")]
     pub workout_elapsed: Duration,
+    /// Time left to the end of the workout
+    #[serde(with = "whole_secs")]
+    pub remaining: Duration,
+    /// Fraction of the workout done, in range 0.0..=1.0
+    pub progress: f32,
+    /// Time spent in each power zone, Z1 first, tracked only if bike data is available
+    #[serde(serialize_with = "whole_secs::serialize_all")]
+    pub time_in_zones: [Duration; NR_ZONES],
+    /// Estimated calories burned in kcal, tracked only if bike data is available
+    pub calories: f64,
+    /// Workout clock is stopped
+    pub paused: bool,
+    /// All steps are done, it's the last state of the workout
+    pub finished: bool,
+    /// No workout is loaded, target power is set by the user
+    pub manual: bool,
+    /// Seconds to the next interval, or step, present only shortly before it starts
+    pub countdown: Option<u64>,
+    /// Cadence the current step (or interval part) asks for, in RPM
+    pub target_cadence: Option<f64>,
+    /// Actual cadence minus the target, negative means rider should pedal faster
+    pub cadence_delta: Option<f64>,
+    /// Text events of the workout being shown at the moment
+    pub messages: Vec<String>,
+    /// Text events of the workout, sorted by the time offset
+    #[serde(skip)]
+    text_events: Vec<TextEvent>,
+    /// Number of text events already fired, each fires once, even when user jumps back
+    #[serde(skip)]
+    text_events_fired: usize,
     #[serde(skip)]
     workout_started: Instant,
+    #[serde(skip)]
+    paused_at: Option<Instant>,
 }
 
+/// Instants the clocks refer to are not compared, elapsed times are
+impl PartialEq for WorkoutState {
+    fn eq(&self, other: &Self) -> bool {
+        // Destructured, so new fields are not forgotten
+        let Self {
+            workout_name,
+            total_steps,
+            current_step_number,
+            total_workout_duration,
+            next_step,
+            current_power_set,
+            current_power_ftp_percent,
+            current_power_level,
+            power_offset,
+            rest_power_floor_active,
+            ftp_base,
+            current_step,
+            current_interval,
+            workout_elapsed,
+            remaining,
+            progress,
+            time_in_zones,
+            calories,
+            paused,
+            finished,
+            manual,
+            countdown,
+            target_cadence,
+            cadence_delta,
+            messages,
+            text_events,
+            text_events_fired,
+            workout_started: _,
+            paused_at: _,
+        } = self;
 
+        workout_name == &other.workout_name
+            && total_steps == &other.total_steps
+            && current_step_number == &other.current_step_number
+            && total_workout_duration == &other.total_workout_duration
+            && next_step == &other.next_step
+            && current_power_set == &other.current_power_set
+            && current_power_ftp_percent == &other.current_power_ftp_percent
+            && current_power_level == &other.current_power_level
+            && power_offset == &other.power_offset
+            && rest_power_floor_active == &other.rest_power_floor_active
+            && ftp_base == &other.ftp_base
+            && current_step == &other.current_step
+            && current_interval == &other.current_interval
+            && workout_elapsed == &other.workout_elapsed
+            && remaining == &other.remaining
+            && progress == &other.progress
+            && time_in_zones == &other.time_in_zones
+            && calories == &other.calories
+            && paused == &other.paused
+            && finished == &other.finished
+            && manual == &other.manual
+            && countdown == &other.countdown
+            && target_cadence == &other.target_cadence
+            && cadence_delta == &other.cadence_delta
+            && messages == &other.messages
+            && text_events == &other.text_events
+            && text_events_fired == &other.text_events_fired
+    }
+}
 
 impl WorkoutState {
-
     pub(crate) fn new(workout: &WorkoutFile, ftp_base: f64) -> Self {
         let total_workout_duration = workout.total_workout_duration;
 
@@ -71,6 +251,7 @@ impl WorkoutState {
 
         let next_step = workout.workout.steps.get(1).cloned();
         Self {
+            workout_name: workout.name.clone(),
             total_steps,
             total_workout_duration,
             // Note it's 1-based for human readability!
@@ -79,41 +260,245 @@ impl WorkoutState {
             next_step,
             current_interval: None,
             current_power_set: 0,
+            current_power_ftp_percent: 0.0,
+            current_power_level: None,
+            power_offset: 0,
+            rest_power_floor_active: false,
             ftp_base,
             workout_elapsed: Duration::from_secs(0),
+            remaining: total_workout_duration,
+            progress: 0.0,
+            time_in_zones: Default::default(),
+            calories: 0.0,
+            paused: false,
+            finished: false,
+            manual: false,
+            countdown: None,
+            target_cadence: None,
+            cadence_delta: None,
+            messages: vec![],
+            text_events: workout.workout.text_events.clone(),
+            text_events_fired: 0,
+            workout_started: Instant::now(),
+            paused_at: None,
+        }
+    }
+
+    /// State of the ride without a workout, there are no steps, just a free ride lasting until user quits
+    pub fn manual(ftp_base: f64) -> Self {
+        let step = WorkoutSteps::FreeRide(FreeRide {
+            duration: Duration::ZERO,
+            flat_road: 1.0,
+        });
+
+        Self {
+            workout_name: String::new(),
+            total_steps: 0,
+            current_step_number: 0,
+            total_workout_duration: Duration::ZERO,
+            next_step: None,
+            current_power_set: 0,
+            current_power_ftp_percent: 0.0,
+            current_power_level: None,
+            power_offset: 0,
+            rest_power_floor_active: false,
+            ftp_base,
+            current_step: StepState {
+                duration: Duration::ZERO,
+                step,
+                elapsed: Duration::ZERO,
+                started: Instant::now(),
+            },
+            current_interval: None,
+            workout_elapsed: Duration::ZERO,
+            remaining: Duration::ZERO,
+            progress: 0.0,
+            time_in_zones: Default::default(),
+            calories: 0.0,
+            paused: false,
+            finished: false,
+            manual: true,
+            countdown: None,
+            target_cadence: None,
+            cadence_delta: None,
+            messages: vec![],
+            text_events: vec![],
+            text_events_fired: 0,
             workout_started: Instant::now(),
+            paused_at: None,
+        }
+    }
+
+    pub(crate) fn apply(&mut self, update: WorkoutStateUpdate) {
+        match update {
+            WorkoutStateUpdate::StepAdvanced(step) => self.handle_step_advance(&step),
+            WorkoutStateUpdate::NextStep { step, next_step } => {
+                self.handle_next_step(step, next_step)
+            }
+            WorkoutStateUpdate::Skip => {
+                // Remaining time is calculated from elapsed, make it fresh
+                self.update_ts();
+                self.handle_skip_step();
+            }
+            WorkoutStateUpdate::JumpToStep {
+                step_number,
+                step,
+                next_step,
+                remaining,
+            } => {
+                // Total duration is calculated from elapsed, make it fresh
+                self.update_ts();
+                self.handle_jump_to_step(step_number, step, next_step, remaining);
+            }
+            WorkoutStateUpdate::Extend(by) => self.handle_extend_step(by),
+            WorkoutStateUpdate::PowerSet { power, level } => self.set_power(power, level),
+            WorkoutStateUpdate::PowerOffset(offset) => self.power_offset = offset,
+            WorkoutStateUpdate::RestPowerFloor(active) => self.rest_power_floor_active = active,
+            WorkoutStateUpdate::Pause => self.handle_pause(),
+            WorkoutStateUpdate::Resume => self.handle_resume(),
+            WorkoutStateUpdate::Tick => self.update_ts(),
+            WorkoutStateUpdate::Finished => {
+                self.update_ts();
+                self.finished = true;
+                self.countdown = None;
+            }
         }
     }
 
+    /// Sets target power, level is the fraction of FTP given by the workout step, if any
+    pub fn set_power(&mut self, power: i16, level: Option<f64>) {
+        self.current_power_set = power;
+        self.current_power_ftp_percent = get_ftp_percent(self.ftp_base, power);
+        self.current_power_level = level;
+    }
+
     /// Sets workout step that is currently executed, together with workout state update
-    pub fn handle_next_step(&mut self, workout: &WorkoutFile) {
-        if let Some(next) = workout.workout.steps.front() {
-            self.current_step.step = next.clone();
+    pub fn handle_next_step(&mut self, step: WorkoutSteps, next_step: Option<WorkoutSteps>) {
+        self.current_step.step = step;
 
-            self.current_step.duration = self.current_step.step.get_step_duration();
-            self.current_step_number += 1;
+        self.current_step.duration = self.current_step.step.get_step_duration();
+        self.current_step_number += 1;
 
-            self.current_step.elapsed = Duration::from_secs(0);
-            self.current_step.started = Instant::now();
+        self.current_step.elapsed = Duration::from_secs(0);
+        self.current_step.started = Instant::now();
 
-            // Clear interval info if step is not interval
-            match self.current_step.step {
-                WorkoutSteps::IntervalsT(_) => (),
-                _ => self.current_interval = None,
-            }
+        // Interval info belongs to the previous step, new intervals set it on the first advance
+        self.current_interval = None;
 
-            self.next_step = workout.workout.steps.get(1).cloned();
-        }
+        self.next_step = next_step;
+        self.update_countdown();
     }
 
     pub fn update_ts(&mut self) {
-        let instant = Instant::now();
+        // Clock does not move while paused
+        let instant = self.paused_at.unwrap_or_else(Instant::now);
         self.current_step.elapsed = instant - self.current_step.started;
         self.workout_elapsed = instant - self.workout_started;
 
         if let Some(ref mut interval_state) = self.current_interval {
             interval_state.elapsed = instant - interval_state.started;
         }
+
+        self.update_progress();
+        self.update_messages();
+    }
+
+    /// Fires text events the workout clock went past, keeps the ones still shown
+    fn update_messages(&mut self) {
+        while let Some(text_event) = self.text_events.get(self.text_events_fired) {
+            if text_event.timeoffset > self.workout_elapsed {
+                break;
+            }
+
+            info!("Workout message: {}", text_event.message);
+            self.text_events_fired += 1;
+        }
+
+        let elapsed = self.workout_elapsed;
+        self.messages = self.text_events[..self.text_events_fired]
+            .iter()
+            .filter(|text_event| elapsed < text_event.timeoffset + text_event.duration)
+            .map(|text_event| text_event.message.clone())
+            .collect();
+    }
+
+    /// Time left to the end of the current interval part, or the step if it's not an interval
+    pub fn segment_remaining(&self) -> Duration {
+        match (&self.current_step.step, &self.current_interval) {
+            (WorkoutSteps::IntervalsT(_), Some(interval)) => {
+                interval.duration.saturating_sub(interval.elapsed)
+            }
+            _ => self
+                .current_step
+                .duration
+                .saturating_sub(self.current_step.elapsed),
+        }
+    }
+
+    /// Compares actual cadence with the one the current step, or interval part, asks for
+    pub(crate) fn update_cadence(&mut self, cadence: Option<f64>) {
+        self.target_cadence = match &self.current_step.step {
+            WorkoutSteps::SteadyState(steady) => steady.cadence,
+            WorkoutSteps::IntervalsT(intervals) => {
+                let is_rest = self
+                    .current_interval
+                    .as_ref()
+                    .is_some_and(|interval| !interval.is_work_interval);
+
+                if is_rest {
+                    intervals.cadence_resting
+                } else {
+                    intervals.cadence
+                }
+            }
+            _ => None,
+        };
+
+        self.cadence_delta = match (cadence, self.target_cadence) {
+            (Some(actual), Some(target)) => Some(actual - target),
+            _ => None,
+        };
+    }
+
+    /// Counts down during the end of the rest, when work interval follows, or before the next step
+    fn update_countdown(&mut self) {
+        let is_rest = self
+            .current_interval
+            .as_ref()
+            .is_some_and(|interval| !interval.is_work_interval);
+
+        let left = if is_rest {
+            self.segment_remaining()
+        } else if self.next_step.is_some() {
+            self.current_step
+                .duration
+                .saturating_sub(self.current_step.elapsed)
+        } else {
+            Duration::ZERO
+        };
+
+        self.countdown = if !left.is_zero() && left <= COUNTDOWN_FROM {
+            // Rounded up, so countdown does not show 0 while the segment is still going
+            Some(left.as_secs() + u64::from(left.subsec_nanos() > 0))
+        } else {
+            None
+        };
+    }
+
+    /// Derives remaining time and progress from elapsed time and (possibly shortened) total duration
+    fn update_progress(&mut self) {
+        self.remaining = self
+            .total_workout_duration
+            .saturating_sub(self.workout_elapsed);
+
+        self.progress = if self.total_workout_duration.is_zero() {
+            1.0
+        } else {
+            (self.workout_elapsed.as_secs_f32() / self.total_workout_duration.as_secs_f32())
+                .min(1.0)
+        };
+
+        self.update_countdown();
     }
 
     pub(crate) fn handle_step_advance(&mut self, current_step: &WorkoutSteps) {
@@ -128,22 +513,980 @@ impl WorkoutState {
                 is_work_interval: interval.is_work_interval(),
                 repetition: interval.current_interval / 2 + 1,
                 elapsed: Duration::from_secs(0),
-                duration: Duration::from_secs(interval_duration),
+                duration: interval_duration,
+                // Repeat counts down as the pairs are done
+                remaining_reps: interval.repeat,
+                on_power: get_power(self.ftp_base, interval.on_power),
+                on_duration: interval.on_duration,
+                off_power: get_power(self.ftp_base, interval.off_power),
+                off_duration: interval.off_duration,
                 started: Instant::now(),
             })
         }
     }
 
+    pub(crate) fn handle_pause(&mut self) {
+        if self.paused_at.is_some() {
+            return;
+        }
+
+        self.update_ts();
+        self.paused_at = Some(Instant::now());
+        self.paused = true;
+    }
+
+    /// Time spent in pause is not counted, all clocks are moved forward by it
+    pub(crate) fn handle_resume(&mut self) {
+        let paused_at = match self.paused_at.take() {
+            Some(paused_at) => paused_at,
+            None => return,
+        };
+
+        let paused_for = Instant::now().saturating_duration_since(paused_at);
+
+        self.workout_started += paused_for;
+        self.current_step.started += paused_for;
+        if let Some(interval) = &mut self.current_interval {
+            interval.started += paused_for;
+        }
+
+        self.paused = false;
+        self.update_ts();
+    }
+
+    /// Elapsed time stays as is, the workout is going to last for the duration of the remaining steps
+    pub(crate) fn handle_jump_to_step(
+        &mut self,
+        step_number: usize,
+        step: WorkoutSteps,
+        next_step: Option<WorkoutSteps>,
+        remaining: Duration,
+    ) {
+        self.handle_next_step(step, next_step);
+        self.current_step_number = step_number;
+        self.current_interval = None;
+        self.total_workout_duration = self.workout_elapsed + remaining;
+
+        self.update_progress();
+    }
+
+    pub(crate) fn handle_extend_step(&mut self, by: Duration) {
+        if let Some(interval) = &mut self.current_interval {
+            interval.duration += by;
+        }
+
+        self.current_step.duration += by;
+        self.total_workout_duration += by;
+
+        self.update_progress();
+    }
+
     pub(crate) fn handle_skip_step(&mut self) {
-        let remaining_time = {
-            if let Some(interval) = &self.current_interval {
-                interval.duration.saturating_sub(interval.elapsed)
-            } else {
-                self.current_step
-                    .duration
-                    .saturating_sub(self.current_step.elapsed)
+        let remaining_time = match (&self.current_step.step, &self.current_interval) {
+            (WorkoutSteps::IntervalsT(step), Some(interval)) => {
+                let remaining = interval.duration.saturating_sub(interval.elapsed);
+
+                // Whole on+off pair is skipped
+                if interval.is_work_interval {
+                    remaining + step.off_duration
+                } else {
+                    remaining
+                }
+            }
+            // Intervals not started yet, the workout drops the first pair only
+            (WorkoutSteps::IntervalsT(step), None) if step.repeat > 0 => {
+                step.on_duration + step.off_duration
             }
+            _ => self
+                .current_step
+                .duration
+                .saturating_sub(self.current_step.elapsed),
         };
         self.total_workout_duration = self.total_workout_duration.saturating_sub(remaining_time);
+
+        self.update_progress();
+    }
+}
+
+/// Mutations of the workout state, sent by the workout to the WorkoutStateActor
+#[derive(Debug)]
+pub enum WorkoutStateUpdate {
+    /// Current step is about to be advanced (next part of the interval, next second of the ramp, etc.)
+    StepAdvanced(WorkoutSteps),
+    /// Current step is exhausted, next one from the workout is executed
+    NextStep {
+        step: WorkoutSteps,
+        next_step: Option<WorkoutSteps>,
+    },
+    /// User skipped current step
+    Skip,
+    /// User jumped to given step (1-based), remaining is duration of the rest of the workout, including the step
+    JumpToStep {
+        step_number: usize,
+        step: WorkoutSteps,
+        next_step: Option<WorkoutSteps>,
+        remaining: Duration,
+    },
+    /// User extended current step (or current part of the interval)
+    Extend(Duration),
+    /// New target power is set, level is the fraction of FTP from the workout step
+    PowerSet { power: i16, level: Option<f64> },
+    /// Watts added to the workout targets changed
+    PowerOffset(i16),
+    /// Next target is raised to the rest power floor, or not
+    RestPowerFloor(bool),
+    /// Workout clock stops
+    Pause,
+    /// Workout clock continues
+    Resume,
+    /// Refresh elapsed times
+    Tick,
+    /// Workout went through all the steps
+    Finished,
+}
+
+impl WorkoutStateUpdate {
+    /// Step or interval part has changed
+    fn is_step_boundary(&self) -> bool {
+        matches!(
+            self,
+            WorkoutStateUpdate::NextStep { .. }
+                | WorkoutStateUpdate::Skip
+                | WorkoutStateUpdate::JumpToStep { .. }
+                | WorkoutStateUpdate::StepAdvanced(WorkoutSteps::IntervalsT(_))
+        )
+    }
+}
+
+/// Owns the WorkoutState, applies updates coming from the workout, and broadcasts snapshots of the state
+pub struct WorkoutStateActor {
+    state: WorkoutState,
+    updates_rx: mpsc::UnboundedReceiver<WorkoutStateUpdate>,
+    bike_rx: Option<broadcast::Receiver<BikeData>>,
+    power_zones: Option<PowerZones>,
+    latest_power: Option<i16>,
+    latest_cadence: Option<f64>,
+    power_accounted: Instant,
+    /// Work done by the rider in kJ
+    work: f64,
+    efficiency: f64,
+}
+
+impl WorkoutStateActor {
+    pub(crate) fn new(
+        state: WorkoutState,
+        updates_rx: mpsc::UnboundedReceiver<WorkoutStateUpdate>,
+    ) -> Self {
+        Self {
+            state,
+            updates_rx,
+            bike_rx: None,
+            power_zones: None,
+            latest_power: None,
+            latest_cadence: None,
+            power_accounted: Instant::now(),
+            work: 0.0,
+            efficiency: DEFAULT_EFFICIENCY,
+        }
+    }
+
+    /// Tracks time spent in power zones, power measured by the machine is bucketed every second.
+    /// Measured cadence is compared with the target cadence of the step.
+    pub fn with_bike_data(
+        mut self,
+        bike_rx: broadcast::Receiver<BikeData>,
+        power_zones: PowerZones,
+    ) -> Self {
+        self.bike_rx = Some(bike_rx);
+        self.power_zones = Some(power_zones);
+        self
+    }
+
+    /// Gross efficiency of the rider, for converting work to calories
+    pub fn with_efficiency(mut self, efficiency: f64) -> Self {
+        self.efficiency = efficiency;
+        self
+    }
+
+    /// Runs until workout drops the updates sender, broadcasts the state every second,
+    /// and additionally right after step changes, so UI does not show stale step.
+    /// Returns the final state.
+    pub async fn run(mut self, workout_state_tx: impl Into<WorkoutStatePublisher>) -> WorkoutState {
+        debug!("spawning workout state task");
+        let workout_state_tx = workout_state_tx.into();
+
+        let mut propagate_workout_state = tokio::time::interval(Duration::from_secs(1));
+        let mut step_changed = false;
+
+        loop {
+            tokio::select! {
+                update = self.updates_rx.recv() => {
+                    match update {
+                        Some(update) => {
+                            step_changed |= update.is_step_boundary();
+
+                            // Target power of the new step is the last update sent on step change,
+                            // broadcast when state is complete. Final state is sent right away,
+                            // so it's buffered for the clients before the channel closes.
+                            let finished = matches!(update, WorkoutStateUpdate::Finished);
+                            let flush = matches!(update, WorkoutStateUpdate::PowerSet { .. });
+
+                            // Power up to the pause counts, power during the pause does not
+                            if matches!(update, WorkoutStateUpdate::Pause | WorkoutStateUpdate::Resume) {
+                                self.account_power();
+                            }
+
+                            self.state.apply(update);
+
+                            if finished || (flush && step_changed) {
+                                step_changed = false;
+                                self.state.apply(WorkoutStateUpdate::Tick);
+                                self.broadcast(&workout_state_tx);
+                            }
+                        }
+                        None => {
+                            debug!("Workout is gone, workout state task exits");
+                            break;
+                        }
+                    }
+                }
+                Some(bike_data) = next_bike_data(&mut self.bike_rx) => {
+                    self.latest_power = bike_data.inst_power;
+                    self.latest_cadence = bike_data.inst_cadence;
+                }
+                _ = propagate_workout_state.tick() => {
+                    self.account_power();
+                    self.state.apply(WorkoutStateUpdate::Tick);
+                    self.broadcast(&workout_state_tx);
+                }
+            }
+        }
+
+        self.state
+    }
+
+    /// Puts the latest power to its zone, for the time since it was last accounted.
+    /// Time in pause is skipped.
+    fn account_power(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.power_accounted);
+        self.power_accounted = now;
+
+        if self.state.paused {
+            return;
+        }
+
+        if let (Some(power_zones), Some(power)) = (self.power_zones.as_mut(), self.latest_power) {
+            power_zones.add(f64::from(power), elapsed);
+            self.state.time_in_zones = power_zones.time_in_zones();
+
+            self.work += f64::from(power) * elapsed.as_secs_f64() / 1000.0;
+            self.state.calories = kj_to_kcal(self.work, self.efficiency);
+        }
+    }
+
+    fn broadcast(&mut self, workout_state_tx: &WorkoutStatePublisher) {
+        self.state.update_cadence(self.latest_cadence);
+
+        debug!(
+            "Broadcast workout state {}/{}",
+            self.state.current_step_number, self.state.total_steps
+        );
+
+        if workout_state_tx.send(self.state.clone()).is_err() {
+            trace!("No one listens for workout state");
+        }
+    }
+}
+
+/// Broadcast of the workout state shared with the clients (HTTP stream, websocket, etc.).
+/// Clients only subscribe, the one and only sender is handed out to the workout. Streams of all
+/// subscribers end once the workout closes the channel and drops its sender, any additional sender
+/// would keep the streams open forever.
+pub struct WorkoutStateChannel {
+    tx: RwLock<Option<broadcast::Sender<WorkoutState>>>,
+    publisher_taken: AtomicBool,
+    /// The last state sent by the publisher, kept after the workout is done
+    latest: Arc<RwLock<Option<WorkoutState>>>,
+}
+
+impl WorkoutStateChannel {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+
+        Self {
+            tx: RwLock::new(Some(tx)),
+            publisher_taken: AtomicBool::new(false),
+            latest: Default::default(),
+        }
+    }
+
+    /// Channel without a workout, there is nothing to subscribe to
+    pub fn closed() -> Self {
+        Self {
+            tx: RwLock::new(None),
+            publisher_taken: AtomicBool::new(true),
+            latest: Default::default(),
+        }
+    }
+
+    /// Sender for the workout, given only once
+    pub fn publisher(&self) -> Option<WorkoutStatePublisher> {
+        if self.publisher_taken.swap(true, Ordering::SeqCst) {
+            error!("Workout state publisher is already taken");
+            return None;
+        }
+
+        let tx = self.tx.read().unwrap().clone()?;
+
+        Some(WorkoutStatePublisher {
+            tx,
+            latest: Some(self.latest.clone()),
+        })
+    }
+
+    /// The last state published, if any
+    pub fn latest(&self) -> Option<WorkoutState> {
+        self.latest.read().unwrap().clone()
+    }
+
+    /// Stream of the workout states, None if workout is already done
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<WorkoutState>> {
+        self.tx
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(broadcast::Sender::subscribe)
+    }
+
+    /// Number of clients subscribed for the workout state
+    pub fn receiver_count(&self) -> usize {
+        self.tx
+            .read()
+            .unwrap()
+            .as_ref()
+            .map_or(0, broadcast::Sender::receiver_count)
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.tx.read().unwrap().is_some()
+    }
+
+    /// Workout is done, streams end once the publisher is dropped as well
+    pub fn close(&self) {
+        let _ = self.tx.write().unwrap().take();
+    }
+}
+
+/// Sending side of the workout state broadcast, optionally remembering the last state sent
+#[derive(Debug, Clone)]
+pub struct WorkoutStatePublisher {
+    tx: broadcast::Sender<WorkoutState>,
+    latest: Option<Arc<RwLock<Option<WorkoutState>>>>,
+}
+
+impl WorkoutStatePublisher {
+    /// Fails if there are no receivers, the state is remembered anyway.
+    /// The state given back in the error is boxed, it's too large to be returned by value
+    pub fn send(
+        &self,
+        state: WorkoutState,
+    ) -> Result<usize, Box<broadcast::error::SendError<WorkoutState>>> {
+        if let Some(latest) = &self.latest {
+            *latest.write().unwrap() = Some(state.clone());
+        }
+
+        self.tx.send(state).map_err(Box::new)
+    }
+}
+
+impl From<broadcast::Sender<WorkoutState>> for WorkoutStatePublisher {
+    fn from(tx: broadcast::Sender<WorkoutState>) -> Self {
+        Self { tx, latest: None }
+    }
+}
+
+/// Waits for the next bike data, never resolves if there is no bike data source, or it's gone
+async fn next_bike_data(bike_rx: &mut Option<broadcast::Receiver<BikeData>>) -> Option<BikeData> {
+    loop {
+        let rx = match bike_rx.as_mut() {
+            Some(rx) => rx,
+            None => return futures::future::pending().await,
+        };
+
+        match rx.recv().await {
+            Ok(bike_data) => return Some(bike_data),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                trace!("Workout state skipped {skipped} bike data samples");
+            }
+            Err(broadcast::error::RecvError::Closed) => {
+                debug!("Bike data is gone, power zones are not tracked anymore");
+                *bike_rx = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::zwo_workout_file::WorkoutBuilder;
+
+    async fn test_workout() -> WorkoutFile {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts/test.zwo");
+
+        WorkoutFile::new(&path).await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn actor_handles_next_step_and_skip() {
+        let workout = test_workout().await;
+        let total_duration = workout.total_workout_duration;
+
+        let (updates_tx, updates_rx) = mpsc::unbounded_channel();
+        let (workout_state_tx, mut workout_state_rx) = broadcast::channel(16);
+
+        let actor = WorkoutStateActor::new(WorkoutState::new(&workout, 200.0), updates_rx);
+        let handle = tokio::spawn(actor.run(workout_state_tx));
+
+        // Initial state is broadcasted right away
+        let state = workout_state_rx.recv().await.unwrap();
+        assert_eq!(state.current_step_number, 1);
+
+        // Second step is SteadyState lasting 3 seconds
+        updates_tx
+            .send(WorkoutStateUpdate::NextStep {
+                step: workout.workout.steps[1].clone(),
+                next_step: workout.workout.steps.get(2).cloned(),
+            })
+            .unwrap();
+
+        let state = workout_state_rx.recv().await.unwrap();
+        assert_eq!(state.current_step_number, 2);
+        assert_eq!(state.current_step.step, workout.workout.steps[1]);
+        assert_eq!(state.current_step.elapsed, Duration::from_secs(1));
+
+        // Skip in the middle of the step, 2 seconds were remaining
+        updates_tx.send(WorkoutStateUpdate::Skip).unwrap();
+
+        let state = workout_state_rx.recv().await.unwrap();
+        assert_eq!(
+            state.total_workout_duration,
+            total_duration - Duration::from_secs(2)
+        );
+
+        // Workout is gone, actor exits and closes the stream
+        drop(updates_tx);
+        handle.await.unwrap();
+        assert!(workout_state_rx.recv().await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn text_events_fire_once_across_steps() {
+        let zwo = WorkoutBuilder::new("Messages")
+            .steady(60, 0.5)
+            .steady(60, 0.6)
+            .steady(60, 0.7)
+            .text_event(120, "Last one")
+            .text_event(0, "Here we go")
+            .text_event(65, "Second step")
+            .text_event(65, "Second step")
+            .build()
+            .to_zwo_string()
+            .unwrap();
+        let workout = WorkoutFile::from_zwo_str(&zwo).unwrap();
+        assert_eq!(workout.workout.steps.len(), 3);
+        assert_eq!(workout.workout.text_events.len(), 3);
+
+        let mut state = WorkoutState::new(&workout, 200.0);
+        state.apply(WorkoutStateUpdate::Tick);
+        assert_eq!(state.messages, vec!["Here we go"]);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        state.apply(WorkoutStateUpdate::Tick);
+        assert!(state.messages.is_empty());
+
+        tokio::time::advance(Duration::from_secs(50)).await;
+        state.apply(WorkoutStateUpdate::NextStep {
+            step: workout.workout.steps[1].clone(),
+            next_step: workout.workout.steps.get(2).cloned(),
+        });
+        tokio::time::advance(Duration::from_secs(6)).await;
+        state.apply(WorkoutStateUpdate::Tick);
+        assert_eq!(state.messages, vec!["Second step"]);
+
+        tokio::time::advance(Duration::from_secs(55)).await;
+        state.apply(WorkoutStateUpdate::NextStep {
+            step: workout.workout.steps[2].clone(),
+            next_step: None,
+        });
+        state.apply(WorkoutStateUpdate::Tick);
+        assert_eq!(state.messages, vec!["Last one"]);
+        assert_eq!(state.text_events_fired, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skip_moves_progress_forward() {
+        let workout = test_workout().await;
+        let mut state = WorkoutState::new(&workout, 200.0);
+        assert_eq!(state.progress, 0.0);
+        assert_eq!(state.remaining, Duration::from_secs(46));
+
+        // 4 seconds into the 5 seconds long warmup
+        tokio::time::advance(Duration::from_secs(4)).await;
+        state.apply(WorkoutStateUpdate::Tick);
+        let progress_before_skip = state.progress;
+        assert_eq!(state.remaining, Duration::from_secs(42));
+        assert!((progress_before_skip - 4.0 / 46.0).abs() < f32::EPSILON);
+
+        // Remaining second of the warmup is dropped from the workout
+        state.apply(WorkoutStateUpdate::Skip);
+        assert_eq!(state.total_workout_duration, Duration::from_secs(45));
+        assert_eq!(state.remaining, Duration::from_secs(41));
+        assert!((state.progress - 4.0 / 45.0).abs() < f32::EPSILON);
+        assert!(state.progress > progress_before_skip);
+
+        // Time keeps flowing, progress does not go back, nor above 1.0
+        tokio::time::advance(Duration::from_secs(60)).await;
+        state.apply(WorkoutStateUpdate::Tick);
+        assert_eq!(state.progress, 1.0);
+        assert_eq!(state.remaining, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn extend_prolongs_interval_part_and_step() {
+        let workout = test_workout().await;
+        let mut state = WorkoutState::new(&workout, 200.0);
+
+        // IntervalsT: repeat 5, on 1s, off 2s
+        let intervals = workout.workout.steps[7].clone();
+        state.apply(WorkoutStateUpdate::NextStep {
+            step: intervals.clone(),
+            next_step: None,
+        });
+        state.apply(WorkoutStateUpdate::StepAdvanced(intervals));
+
+        state.apply(WorkoutStateUpdate::Extend(Duration::from_secs(30)));
+
+        let interval = state.current_interval.as_ref().unwrap();
+        assert_eq!(interval.duration, Duration::from_secs(31));
+        assert_eq!(state.current_step.duration, Duration::from_secs(45));
+        assert_eq!(state.total_workout_duration, Duration::from_secs(76));
+        assert_eq!(state.remaining, Duration::from_secs(76));
+    }
+
+    /// State right after given part of the IntervalsT from test workout has started
+    async fn state_in_intervals(part: usize) -> WorkoutState {
+        let workout = test_workout().await;
+        let mut state = WorkoutState::new(&workout, 200.0);
+
+        // IntervalsT: repeat 5, on 1s, off 2s
+        let mut intervals = workout.workout.steps[7].clone();
+        state.apply(WorkoutStateUpdate::NextStep {
+            step: intervals.clone(),
+            next_step: None,
+        });
+
+        for _ in 0..part {
+            intervals.advance();
+        }
+        state.apply(WorkoutStateUpdate::StepAdvanced(intervals));
+
+        state
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn durations_are_serialized_as_whole_seconds() {
+        let mut state = state_in_intervals(1).await;
+        tokio::time::advance(Duration::from_millis(1500)).await;
+        state.update_ts();
+        state.time_in_zones[1] = Duration::from_millis(2700);
+
+        let json = serde_json::to_value(&state).unwrap();
+
+        assert_eq!(json["total_workout_duration"], 46);
+        assert_eq!(json["workout_elapsed"], 1);
+        assert_eq!(json["remaining"], state.remaining.as_secs());
+        assert_eq!(json["current_step"]["duration"], 15);
+        assert_eq!(json["current_step"]["elapsed"], 1);
+        assert_eq!(json["current_interval"]["elapsed"], 1);
+        assert_eq!(json["current_interval"]["on_duration"], 1);
+        assert_eq!(json["current_interval"]["off_duration"], 2);
+        assert_eq!(
+            json["time_in_zones"],
+            serde_json::json!([0, 2, 0, 0, 0, 0, 0])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn interval_preview_counts_remaining_reps() {
+        // Work of the third repetition, two are done
+        let state = state_in_intervals(4).await;
+        let interval = state.current_interval.as_ref().unwrap();
+
+        assert_eq!(interval.repetition, 3);
+        assert_eq!(interval.remaining_reps, 3);
+        assert_eq!(interval.on_power, 140);
+        assert_eq!(interval.on_duration, Duration::from_secs(1));
+        assert_eq!(interval.off_power, 104);
+        assert_eq!(interval.off_duration, Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skip_during_steady_state_drops_its_remaining_time() {
+        let workout = test_workout().await;
+        let mut state = WorkoutState::new(&workout, 200.0);
+
+        // SteadyState lasting 3 seconds, 1 second in
+        state.apply(WorkoutStateUpdate::NextStep {
+            step: workout.workout.steps[1].clone(),
+            next_step: workout.workout.steps.get(2).cloned(),
+        });
+        tokio::time::advance(Duration::from_secs(1)).await;
+        state.apply(WorkoutStateUpdate::Skip);
+
+        assert_eq!(state.total_workout_duration, Duration::from_secs(44));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skip_at_step_boundary_drops_nothing() {
+        let workout = test_workout().await;
+        let mut state = WorkoutState::new(&workout, 200.0);
+
+        state.apply(WorkoutStateUpdate::NextStep {
+            step: workout.workout.steps[1].clone(),
+            next_step: workout.workout.steps.get(2).cloned(),
+        });
+        tokio::time::advance(Duration::from_secs(3)).await;
+        state.apply(WorkoutStateUpdate::Skip);
+        assert_eq!(state.total_workout_duration, Duration::from_secs(46));
+
+        // Step took longer than planned, nothing is left to drop
+        tokio::time::advance(Duration::from_secs(2)).await;
+        state.apply(WorkoutStateUpdate::Skip);
+        assert_eq!(state.total_workout_duration, Duration::from_secs(46));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skip_of_fresh_intervals_drops_the_first_pair() {
+        let workout = test_workout().await;
+        let mut state = state_in_intervals(3).await;
+
+        // Intervals follow intervals, interval info of the previous step is not used
+        state.apply(WorkoutStateUpdate::NextStep {
+            step: workout.workout.steps[7].clone(),
+            next_step: None,
+        });
+        assert!(state.current_interval.is_none());
+        state.apply(WorkoutStateUpdate::Skip);
+
+        // Work 1s and rest 2s are gone
+        assert_eq!(state.total_workout_duration, Duration::from_secs(43));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skip_during_work_interval_drops_the_pair() {
+        let mut state = state_in_intervals(2).await;
+        assert_eq!(state.current_interval.as_ref().unwrap().repetition, 2);
+
+        state.apply(WorkoutStateUpdate::Skip);
+
+        // Work 1s and rest 2s are gone
+        assert_eq!(state.total_workout_duration, Duration::from_secs(43));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skip_during_rest_interval_drops_the_rest() {
+        let mut state = state_in_intervals(3).await;
+        let interval = state.current_interval.as_ref().unwrap();
+        assert!(!interval.is_work_interval);
+        assert_eq!(interval.repetition, 2);
+
+        // Half a second of the rest is done
+        tokio::time::advance(Duration::from_millis(500)).await;
+        state.apply(WorkoutStateUpdate::Skip);
+
+        assert_eq!(state.total_workout_duration, Duration::from_millis(44_500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pause_stops_workout_clock() {
+        let workout = test_workout().await;
+        let mut state = WorkoutState::new(&workout, 200.0);
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        state.apply(WorkoutStateUpdate::Pause);
+        assert!(state.paused);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        state.apply(WorkoutStateUpdate::Tick);
+        assert_eq!(state.workout_elapsed, Duration::from_secs(2));
+
+        state.apply(WorkoutStateUpdate::Resume);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        state.apply(WorkoutStateUpdate::Tick);
+
+        assert!(!state.paused);
+        assert_eq!(state.workout_elapsed, Duration::from_secs(3));
+        assert_eq!(state.current_step.elapsed, Duration::from_secs(3));
+        assert_eq!(
+            state.remaining,
+            state.total_workout_duration - Duration::from_secs(3)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn states_are_compared_without_instants() {
+        let workout = test_workout().await;
+
+        // Same transitions, at the same time from the start, lead to the same state
+        let ride = |workout| async move {
+            let mut state = WorkoutState::new(workout, 200.0);
+            tokio::time::advance(Duration::from_secs(2)).await;
+            state.apply(WorkoutStateUpdate::Skip);
+            state.apply(WorkoutStateUpdate::PowerSet {
+                power: 176,
+                level: Some(0.88),
+            });
+            state
+        };
+
+        let state = ride(&workout).await;
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let mut later = ride(&workout).await;
+
+        assert_eq!(state, later);
+        assert_eq!(state.current_step, later.current_step);
+
+        later.apply(WorkoutStateUpdate::Pause);
+        assert_ne!(state, later);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pause_does_not_inflate_interval_elapsed() {
+        let mut state = state_in_intervals(1).await;
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        state.apply(WorkoutStateUpdate::Pause);
+        tokio::time::advance(Duration::from_secs(60)).await;
+        state.apply(WorkoutStateUpdate::Resume);
+        tokio::time::advance(Duration::from_millis(500)).await;
+        state.apply(WorkoutStateUpdate::Tick);
+
+        let interval = state.current_interval.as_ref().unwrap();
+        assert_eq!(interval.elapsed, Duration::from_secs(1));
+        assert_eq!(state.current_step.elapsed, Duration::from_secs(1));
+        assert_eq!(state.workout_elapsed, Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn time_in_power_zones_is_tracked() {
+        let workout = test_workout().await;
+
+        let (updates_tx, updates_rx) = mpsc::unbounded_channel();
+        let (workout_state_tx, mut workout_state_rx) = broadcast::channel(16);
+        let (bike_tx, bike_rx) = broadcast::channel(16);
+
+        let actor = WorkoutStateActor::new(WorkoutState::new(&workout, 200.0), updates_rx)
+            .with_bike_data(bike_rx, PowerZones::new(200.0, Default::default()));
+        tokio::spawn(actor.run(workout_state_tx));
+
+        let bike_data = |power| BikeData {
+            inst_power: Some(power),
+            ..Default::default()
+        };
+
+        let _initial = workout_state_rx.recv().await.unwrap();
+
+        // One second in Z1, then two seconds in Z6
+        bike_tx.send(bike_data(100)).unwrap();
+        let _ = workout_state_rx.recv().await.unwrap();
+        bike_tx.send(bike_data(250)).unwrap();
+        let _ = workout_state_rx.recv().await.unwrap();
+        let state = workout_state_rx.recv().await.unwrap();
+
+        let expected = [1, 0, 0, 0, 0, 2, 0].map(Duration::from_secs);
+        assert_eq!(state.time_in_zones, expected);
+
+        // 0.6kJ of work
+        assert!((state.calories - kj_to_kcal(0.6, DEFAULT_EFFICIENCY)).abs() < 1e-9);
+
+        // Nothing is accounted while paused
+        updates_tx.send(WorkoutStateUpdate::Pause).unwrap();
+        let _ = workout_state_rx.recv().await.unwrap();
+        let state = workout_state_rx.recv().await.unwrap();
+        assert_eq!(state.time_in_zones, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn step_change_is_broadcasted_immediately() {
+        let workout = test_workout().await;
+
+        let (updates_tx, updates_rx) = mpsc::unbounded_channel();
+        let (workout_state_tx, mut workout_state_rx) = broadcast::channel(16);
+
+        let actor = WorkoutStateActor::new(WorkoutState::new(&workout, 200.0), updates_rx);
+        tokio::spawn(actor.run(workout_state_tx));
+
+        let _initial = workout_state_rx.recv().await.unwrap();
+        let started = Instant::now();
+
+        // Same sequence as ZwoWorkout sends when step is exhausted
+        updates_tx
+            .send(WorkoutStateUpdate::NextStep {
+                step: workout.workout.steps[1].clone(),
+                next_step: workout.workout.steps.get(2).cloned(),
+            })
+            .unwrap();
+        updates_tx
+            .send(WorkoutStateUpdate::StepAdvanced(
+                workout.workout.steps[1].clone(),
+            ))
+            .unwrap();
+        updates_tx
+            .send(WorkoutStateUpdate::PowerSet {
+                power: 176,
+                level: Some(0.88),
+            })
+            .unwrap();
+
+        let state = workout_state_rx.recv().await.unwrap();
+
+        // Did not wait for the next tick
+        assert_eq!(started.elapsed(), Duration::from_secs(0));
+        assert_eq!(state.current_step_number, 2);
+        assert_eq!(state.current_power_set, 176);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn countdown_precedes_step_and_work_interval() {
+        let workout = WorkoutBuilder::new("Countdown")
+            .steady(10, 0.5)
+            .intervals(2, 30, 10, 1.0, 0.5)
+            .build();
+        let steps: Vec<_> = workout.workout.steps.iter().cloned().collect();
+        let mut state = WorkoutState::new(&workout, 200.0);
+
+        let advance = |secs: f64| tokio::time::advance(Duration::from_secs_f64(secs));
+
+        advance(4.0).await;
+        state.update_ts();
+        assert_eq!(state.countdown, None);
+
+        advance(2.0).await;
+        state.update_ts();
+        assert_eq!(state.countdown, Some(4));
+
+        advance(3.5).await;
+        state.update_ts();
+        assert_eq!(state.countdown, Some(1));
+
+        // Work part of the intervals does not count down, it's followed by the rest
+        state.handle_next_step(steps[1].clone(), None);
+        let mut intervals = steps[1].clone();
+        state.handle_step_advance(&intervals);
+        assert_eq!(state.countdown, None);
+
+        advance(28.0).await;
+        state.update_ts();
+        assert_eq!(state.countdown, None);
+
+        // Rest part counts down to the next work part
+        intervals.advance();
+        advance(2.0).await;
+        state.handle_step_advance(&intervals);
+        state.update_ts();
+        assert_eq!(state.countdown, None);
+
+        advance(7.0).await;
+        state.update_ts();
+        assert_eq!(state.countdown, Some(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn all_subscribers_get_the_same_states_and_close() {
+        let workout = test_workout().await;
+        let channel = WorkoutStateChannel::new(16);
+
+        let receivers: Vec<_> = (0..3).map(|_| channel.subscribe().unwrap()).collect();
+        assert_eq!(channel.receiver_count(), 3);
+
+        let (updates_tx, updates_rx) = mpsc::unbounded_channel();
+        let actor = WorkoutStateActor::new(WorkoutState::new(&workout, 200.0), updates_rx);
+        let handle = tokio::spawn(actor.run(channel.publisher().unwrap()));
+
+        // There is only one publisher
+        assert!(channel.publisher().is_none());
+
+        updates_tx
+            .send(WorkoutStateUpdate::NextStep {
+                step: workout.workout.steps[1].clone(),
+                next_step: workout.workout.steps.get(2).cloned(),
+            })
+            .unwrap();
+        updates_tx
+            .send(WorkoutStateUpdate::PowerSet {
+                power: 150,
+                level: Some(0.75),
+            })
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        // Workout is done
+        drop(updates_tx);
+        handle.await.unwrap();
+        channel.close();
+        assert!(channel.subscribe().is_none());
+
+        let mut received = vec![];
+        for mut rx in receivers {
+            let mut states = vec![];
+            loop {
+                match rx.recv().await {
+                    Ok(state) => states.push(serde_json::to_string(&state).unwrap()),
+                    Err(broadcast::error::RecvError::Closed) => break,
+                    Err(e) => panic!("Unexpected error {}", e),
+                }
+            }
+            received.push(states);
+        }
+
+        assert!(received[0].len() > 1);
+        assert!(received.iter().all(|states| *states == received[0]));
+    }
+
+    #[tokio::test]
+    async fn cadence_is_compared_with_target() {
+        let workout = WorkoutFile::from_zwo_str(
+            r#"<workout_file><author>a</author><name>n</name><description>d</description>
+            <sportType>bike</sportType><workout>
+            <SteadyState Duration="60" Power="0.6" Cadence="90"/>
+            <IntervalsT Repeat="2" OnDuration="30" OffDuration="30" OnPower="1.0" OffPower="0.5"
+                Cadence="100" CadenceResting="80"/>
+            </workout></workout_file>"#,
+        )
+        .unwrap();
+        let mut state = WorkoutState::new(&workout, 200.0);
+
+        state.update_cadence(Some(84.0));
+        assert_eq!(state.target_cadence, Some(90.0));
+        assert_eq!(state.cadence_delta, Some(-6.0));
+
+        // Cadence is not measured
+        state.update_cadence(None);
+        assert_eq!(state.target_cadence, Some(90.0));
+        assert_eq!(state.cadence_delta, None);
+
+        // Rest part of the intervals has its own target
+        let mut intervals = workout.workout.steps[1].clone();
+        intervals.advance();
+        state.handle_next_step(intervals.clone(), None);
+        state.handle_step_advance(&intervals);
+
+        state.update_cadence(Some(95.0));
+        assert_eq!(state.target_cadence, Some(80.0));
+        assert_eq!(state.cadence_delta, Some(15.0));
     }
 }
@@ -1,4 +1,5 @@
 use actix::prelude::*;
+use actix_web::web::Data;
 use actix_web_actors::ws;
 use futures::StreamExt;
 use tokio::sync::{broadcast, mpsc};
@@ -6,7 +7,11 @@ use tokio_stream::wrappers::BroadcastStream;
 
 use std::time::{Duration, Instant};
 
-use crate::{cli::WorkoutCommands, workout_state::WorkoutState};
+use crate::{
+    cli::{parse_workout_command, WorkoutCommands},
+    workout_state::WorkoutState,
+    AppState, WorkoutEvent,
+};
 
 ///! Actor implementation for handling websocket endpoint for workout_state
 
@@ -19,6 +24,8 @@ const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 pub struct WebSocketActor {
     pub workout_state_rx: broadcast::Receiver<WorkoutState>,
     pub control_workout_tx: mpsc::Sender<WorkoutCommands>,
+    /// Source of the ride summary, sent as the last message
+    pub app_state: Data<AppState>,
     pub hb: Instant,
 }
 
@@ -27,14 +34,32 @@ impl Actor for WebSocketActor {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("WS actor started - client connected");
-        let workout_state_rx =
-            BroadcastStream::new(self.workout_state_rx.resubscribe()).map(|msg| {
-                let state = msg.unwrap();
-                NewWorkoutState::from(state)
+        let workout_state_rx = BroadcastStream::new(self.workout_state_rx.resubscribe())
+            .filter_map(|msg| async move {
+                match msg {
+                    Ok(state) => Some(NewWorkoutState::from(state)),
+                    Err(e) => {
+                        warn!("WS client lags behind workout state: {e}");
+                        None
+                    }
+                }
             });
 
         ctx.add_stream(workout_state_rx);
 
+        let workout_events_rx = BroadcastStream::new(self.app_state.workout_events_tx.subscribe())
+            .filter_map(|msg| async move {
+                match msg {
+                    Ok(event) => Some(NewWorkoutEvent(event)),
+                    Err(e) => {
+                        warn!("WS client lags behind workout events: {e}");
+                        None
+                    }
+                }
+            });
+
+        ctx.add_stream(workout_events_rx);
+
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
             // check client heartbeats
             if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
@@ -69,6 +94,31 @@ impl StreamHandler<NewWorkoutState> for WebSocketActor {
         // Push the workout state to the WebSocket as a text
         ctx.text(serde_json::to_string(&item.0).unwrap());
     }
+
+    /// Workout state stream closes once the workout is done, send the summary and close the connection
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        let guard = self.app_state.ride_summary.read().unwrap();
+
+        if let Some(summary) = guard.as_ref() {
+            ctx.text(serde_json::json!({ "summary": summary }).to_string());
+        }
+
+        ctx.stop();
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct NewWorkoutEvent(WorkoutEvent);
+
+impl StreamHandler<NewWorkoutEvent> for WebSocketActor {
+    fn handle(&mut self, item: NewWorkoutEvent, ctx: &mut Self::Context) {
+        // Events are tagged, to tell them apart from the workout state
+        ctx.text(serde_json::to_string(&item.0).unwrap());
+    }
+
+    /// Connection is closed by the workout state stream
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
 }
 
 /// WebSocket messages that comes from the client
@@ -93,7 +143,9 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketActor {
                         let tx = self.control_workout_tx.clone();
                         ctx.spawn(
                             async move {
-                                tx.send(WorkoutCommands::SkipStep).await.unwrap();
+                                if tx.send(WorkoutCommands::SkipStep).await.is_err() {
+                                    warn!("Workout is not running anymore");
+                                }
                             }
                             .into_actor(self),
                         );
@@ -102,29 +154,39 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketActor {
                         // TODO: We are in async context, cannot call blocking_send in it, need to
                         // spawn a dedicated task for it, it sucks, note handle is not async, so cannot
                         // call .await either :\
-                        // let _ = self
-                        //     .control_workout_tx
-                        //     .blocking_send(WorkoutCommands::Abort);
 
+                        // Abort during the final cooldown needs confirmation, "Q" has to be sent again
                         let tx = self.control_workout_tx.clone();
                         // TODO: no way to wait on a spawned handle, WTF!
                         ctx.spawn(
                             async move {
-                                tx.send(WorkoutCommands::SkipStep).await.unwrap();
+                                if tx.send(WorkoutCommands::Abort).await.is_err() {
+                                    warn!("Workout is not running anymore");
+                                }
                             }
                             .into_actor(self),
                         );
-
-
-
-                    }
-                    other => {
-                        warn!("Unexpected user input {other}");
                     }
+                    // Commands with an argument
+                    other => match parse_workout_command(other) {
+                        Some(command) => {
+                            let tx = self.control_workout_tx.clone();
+                            ctx.spawn(
+                                async move {
+                                    if tx.send(command).await.is_err() {
+                                        warn!("Workout is not running anymore");
+                                    }
+                                }
+                                .into_actor(self),
+                            );
+                        }
+                        None => warn!("Unexpected user input {other}"),
+                    },
                 }
             }
-            ws::Message::Binary(_) => todo!(),
-            ws::Message::Continuation(_) => todo!(),
+            ws::Message::Binary(_) | ws::Message::Continuation(_) | ws::Message::Nop => {
+                trace!("Ignoring non-text message");
+            }
             ws::Message::Ping(msg) => {
                 self.hb = Instant::now();
                 ctx.pong(&msg);
@@ -132,8 +194,12 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketActor {
             ws::Message::Pong(_) => {
                 self.hb = Instant::now();
             }
-            ws::Message::Close(e) => info!("Connection closed! {e:?}"),
-            ws::Message::Nop => todo!(),
+            ws::Message::Close(reason) => {
+                info!("Connection closed by the client {reason:?}");
+                // Respond with close frame, as protocol requires
+                ctx.close(reason);
+                ctx.stop();
+            }
         }
     }
 }
@@ -1,73 +1,538 @@
-use std::{path::Path, pin::Pin, task::Poll, time::Duration};
+use std::{path::Path, pin::Pin, str::FromStr, task::Poll, time::Duration};
 
-use anyhow::Result;
+use anyhow::{anyhow, ensure, Context, Result};
 use futures::{Future, Stream};
+use serde::Serialize;
 
-use tokio::time::{Instant, Sleep};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::{Instant, Sleep},
+};
+
+/// Deadline of the timer while workout is paused, practically never reached
+const FAR_FUTURE: Duration = Duration::from_secs(86400 * 365 * 30);
+/// With smooth ramps, ramp seconds differing at most that much from the current target are merged into it
+const RAMP_COALESCE_WATTS: i16 = 1;
 
 use crate::{
     cli::UserCommands,
     common::get_power,
-    workout_state::WorkoutState,
-    zwo_workout_file::{PowerDuration, WorkoutFile, WorkoutSteps},
+    indoor_bike_data_defs::Range,
+    workout_state::{WorkoutState, WorkoutStateActor, WorkoutStateUpdate},
+    zwo_workout_file::{PowerDuration, WorkoutFile, WorkoutInfo, WorkoutSteps},
 };
 
+/// Transitions of the workout, sent at the moment they happen,
+/// so clients do not have to diff consecutive workout states
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event")]
+pub enum WorkoutEvent {
+    Started,
+    /// Step number is 1-based
+    StepStarted {
+        number: usize,
+    },
+    /// Next part of the intervals started, work or rest
+    IntervalFlipped {
+        work: bool,
+    },
+    Paused,
+    Resumed,
+    Skipped,
+    Completed,
+    /// Trainer stopped sending bike data, workout is paused
+    TrainerSilent,
+    /// Trainer sends bike data again, workout stays paused until rider resumes it
+    TrainerBack,
+}
+
+/// How many times the workout is executed, it starts from the top once completed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Repeat {
+    Times(u32),
+    Infinite,
+}
+
+impl Repeat {
+    /// Is there another loop after given one (1-based)
+    fn has_next(self, loop_number: u32) -> bool {
+        match self {
+            Repeat::Times(times) => loop_number < times,
+            Repeat::Infinite => true,
+        }
+    }
+}
+
+impl Default for Repeat {
+    fn default() -> Self {
+        Repeat::Times(1)
+    }
+}
+
+/// Lowest rest target, trainers cannot hold very low power in ERG mode, the flywheel surges
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestPowerFloor {
+    Watts(i16),
+    /// Fraction of FTP
+    FtpFraction(f64),
+}
+
+impl RestPowerFloor {
+    fn watts(self, ftp_base: f64) -> i16 {
+        match self {
+            RestPowerFloor::Watts(watts) => watts,
+            RestPowerFloor::FtpFraction(fraction) => get_power(ftp_base, fraction),
+        }
+    }
+}
+
+/// Watts like "60" or "60W", or percent of FTP like "30%"
+impl FromStr for RestPowerFloor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            anyhow!("Invalid rest power floor '{s}', expected watts (60W) or percent of FTP (30%)")
+        };
+
+        if let Some(percent) = s.strip_suffix('%') {
+            let percent: f64 = percent.trim().parse().map_err(|_| invalid())?;
+            ensure!((0.0..=100.0).contains(&percent), invalid());
+
+            return Ok(RestPowerFloor::FtpFraction(percent / 100.0));
+        }
+
+        let watts = s.strip_suffix('W').unwrap_or(s).trim();
+        match watts.parse::<i16>() {
+            Ok(watts) if watts >= 0 => Ok(RestPowerFloor::Watts(watts)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl FromStr for Repeat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "infinite" => Ok(Repeat::Infinite),
+            times => match times.parse::<u32>() {
+                Ok(times) if times > 0 => Ok(Repeat::Times(times)),
+                _ => Err(anyhow!(
+                    "Invalid repeat '{times}', expected a positive number or 'infinite'"
+                )),
+            },
+        }
+    }
+}
+
 pub struct ZwoWorkout {
     workout_file: WorkoutFile,
+    workout_info: WorkoutInfo,
     pending: Pin<Box<Sleep>>,
-    pub workout_state: WorkoutState,
+    ftp_base: f64,
+    state_tx: mpsc::UnboundedSender<WorkoutStateUpdate>,
+    /// Do not wait for the step to finish, yield all commands back-to-back
+    fast_forward: bool,
+    /// Coalesce ramp seconds with (almost) the same power, instead of writing the target every second
+    smooth_ramps: bool,
+    /// Time left to the next command, when the workout got paused
+    paused_remaining: Option<Duration>,
+    /// Steps as they were loaded, to restore them when jumping back
+    all_steps: Vec<WorkoutSteps>,
+    repeat: Repeat,
+    /// Loop of the workout being executed, 1-based
+    loop_number: u32,
+    /// Watts added by the user to the target power of the workout, with --power-offset-watts, or nudges
+    power_nudge: i16,
+    /// Targets shifted by the nudge are clamped to the range of the trainer, once it's known
+    power_range: Option<Range<i16, u16>>,
+    /// Power level of the last command, as a fraction of FTP
+    power_level: f64,
+    /// Grade simulated by free rides not on the flat road, in percent
+    route_grade: f64,
+    /// Target of the last SetTargetPower yielded, ramp ticks rounding to the same watts are not yielded again
+    last_target_power: Option<i16>,
+    rest_power_floor: Option<RestPowerFloor>,
+    events_tx: Option<broadcast::Sender<WorkoutEvent>>,
+    started: bool,
+    completed: bool,
     pub current_step: WorkoutSteps,
 }
 
 impl ZwoWorkout {
-    pub(crate) async fn new(workout_path: &Path, ftp_base: f64) -> Result<Self> {
-        let mut workout = WorkoutFile::new(workout_path).await?;
+    /// Loads the workout, returns it together with the actor owning its state.
+    /// Actor has to be run, in order to get workout state broadcasts.
+    pub async fn new(workout_path: &Path, ftp_base: f64) -> Result<(Self, WorkoutStateActor)> {
+        let workout = WorkoutFile::new(workout_path).await?;
+        Self::from_workout_file(workout, ftp_base)
+    }
 
-        let workout_state = WorkoutState::new(&workout, ftp_base);
+    /// Same as `new`, but for a workout that is already parsed.
+    pub fn from_workout_file(
+        mut workout: WorkoutFile,
+        ftp_base: f64,
+    ) -> Result<(Self, WorkoutStateActor)> {
+        let workout_info = workout.info();
+        let all_steps = workout.workout.steps.iter().cloned().collect();
+
+        let (state_tx, state_rx) = mpsc::unbounded_channel();
+        let workout_state_actor =
+            WorkoutStateActor::new(WorkoutState::new(&workout, ftp_base), state_rx);
 
         let current_step = workout
             .workout
             .steps
             .pop_front()
-            .expect("Workout does not contain any workout steps");
+            .context("Workout does not contain any workout steps")?;
 
         info!("Next step {current_step:?}");
 
-        Ok(ZwoWorkout {
+        let zwo_workout = ZwoWorkout {
             workout_file: workout,
+            workout_info,
             pending: Box::pin(tokio::time::sleep(Duration::from_secs(0))),
-            workout_state,
+            ftp_base,
+            state_tx,
+            fast_forward: false,
+            smooth_ramps: false,
+            paused_remaining: None,
+            all_steps,
+            repeat: Repeat::default(),
+            loop_number: 1,
+            power_nudge: 0,
+            power_range: None,
+            power_level: 0.0,
+            route_grade: 0.0,
+            last_target_power: None,
+            rest_power_floor: None,
+            events_tx: None,
+            started: false,
+            completed: false,
             current_step,
-        })
+        };
+
+        Ok((zwo_workout, workout_state_actor))
+    }
+
+    /// Metadata of the workout as it was loaded
+    pub fn workout_info(&self) -> &WorkoutInfo {
+        &self.workout_info
+    }
+
+    /// Broadcast workout events on given channel
+    pub fn with_events(mut self, events_tx: broadcast::Sender<WorkoutEvent>) -> Self {
+        self.events_tx = Some(events_tx);
+        self
+    }
+
+    /// Start the workout from the top once it is completed
+    pub fn set_repeat(&mut self, repeat: Repeat) {
+        self.repeat = repeat;
+    }
+
+    /// Ignore the timers, useful to validate the workout end-to-end without waiting
+    pub fn set_fast_forward(&mut self, fast_forward: bool) {
+        self.fast_forward = fast_forward;
+    }
+
+    /// Warmup, ramp and cooldown change the target only when it differs by more than
+    /// RAMP_COALESCE_WATTS, so the trainer ramps smoothly instead of being written every second
+    pub fn set_smooth_ramps(&mut self, smooth_ramps: bool) {
+        self.smooth_ramps = smooth_ramps;
+    }
+
+    /// Rest targets below the floor are raised to it, work parts of the intervals are left as they are
+    pub fn set_rest_power_floor(&mut self, floor: RestPowerFloor) {
+        self.rest_power_floor = Some(floor);
+    }
+
+    /// Grade of the route at the rider's position, used by free rides with FlatRoad set to 0
+    pub fn set_route_grade(&mut self, grade: f64) {
+        self.route_grade = grade;
+    }
+
+    /// Workout waits for resume before the first target is set, so the rider can get ready
+    pub fn start_paused(&mut self) {
+        info!("Workout starts once resumed");
+        self.pause();
     }
 
     pub fn pause(&mut self) {
+        if self.paused_remaining.is_some() {
+            return;
+        }
+
         info!("Workout paused");
-        self.pending.as_mut().reset(Instant::now() + Duration::MAX)
-        // let pending = self.pending.take();
-        // if let Some(timer) = pending {
-        //     timer.abort();
-        // };
+        let now = Instant::now();
+        self.paused_remaining = Some(self.pending.deadline().saturating_duration_since(now));
+        self.pending.as_mut().reset(now + FAR_FUTURE);
+        self.update_state(WorkoutStateUpdate::Pause);
+        self.send_event(WorkoutEvent::Paused);
+    }
+
+    pub fn resume(&mut self) {
+        if let Some(remaining) = self.paused_remaining.take() {
+            info!("Workout resumed");
+            self.pending.as_mut().reset(Instant::now() + remaining);
+            self.update_state(WorkoutStateUpdate::Resume);
+            self.send_event(WorkoutEvent::Resumed);
+        }
+    }
+
+    /// Cooldown is the last step, and there is no other loop after it, workout is almost done
+    pub fn is_in_final_cooldown(&self) -> bool {
+        matches!(self.current_step, WorkoutSteps::Cooldown(_))
+            && self.workout_file.workout.steps.is_empty()
+            && !self.repeat.has_next(self.loop_number)
+            && !self.completed
     }
 
     pub fn skip_step(&mut self) {
         info!("Skipping step");
         self.current_step.skip();
-        self.pending = Box::pin(tokio::time::sleep(Duration::from_secs(0)));
-        self.workout_state.handle_skip_step();
+
+        // Paused workout continues with the next step once resumed
+        match self.paused_remaining.as_mut() {
+            Some(remaining) => *remaining = Duration::from_secs(0),
+            None => self.pending = Box::pin(tokio::time::sleep(Duration::from_secs(0))),
+        }
+
+        self.update_state(WorkoutStateUpdate::Skip);
+        self.send_event(WorkoutEvent::Skipped);
+    }
+
+    /// Prolongs remaining time of the current step, for intervals only current on/off part is extended
+    pub fn extend_step(&mut self, by: Duration) {
+        info!("Extending step by {by:?}");
+
+        // Remaining time of the step is tracked by the timer only, re-arm it
+        match self.paused_remaining.as_mut() {
+            Some(remaining) => *remaining += by,
+            None => {
+                let deadline = self.pending.deadline() + by;
+                self.pending.as_mut().reset(deadline);
+            }
+        }
+
+        self.update_state(WorkoutStateUpdate::Extend(by));
+    }
+
+    /// Rebuilds the step queue, so the workout continues from the beginning of given step (1-based)
+    pub fn jump_to_step(&mut self, step_number: usize) -> Result<()> {
+        if step_number == 0 || step_number > self.all_steps.len() {
+            return Err(anyhow!(
+                "Cannot jump to step {step_number}, workout has {} steps",
+                self.all_steps.len()
+            ));
+        }
+
+        info!("Jumping to step {step_number}");
+
+        let index = step_number - 1;
+        self.current_step = self.all_steps[index].clone();
+        self.workout_file.workout.steps = self.all_steps[index + 1..].iter().cloned().collect();
+
+        // Paused workout continues with the new step once resumed
+        match self.paused_remaining.as_mut() {
+            Some(remaining) => *remaining = Duration::from_secs(0),
+            None => self.pending = Box::pin(tokio::time::sleep(Duration::from_secs(0))),
+        }
+
+        self.update_state(WorkoutStateUpdate::JumpToStep {
+            step_number,
+            step: self.current_step.clone(),
+            next_step: self.workout_file.workout.steps.front().cloned(),
+            remaining: self.all_steps[index..]
+                .iter()
+                .map(WorkoutSteps::get_step_duration)
+                .sum(),
+        });
+        self.send_event(WorkoutEvent::StepStarted {
+            number: step_number,
+        });
+
+        Ok(())
+    }
+
+    /// Rebuilds the step queue for the next loop of the workout, elapsed time keeps counting
+    fn restart(&mut self) {
+        self.loop_number += 1;
+        info!("Workout completed, starting loop {}", self.loop_number);
+
+        self.current_step = self.all_steps[0].clone();
+        self.workout_file.workout.steps = self.all_steps[1..].iter().cloned().collect();
+
+        self.update_state(WorkoutStateUpdate::JumpToStep {
+            step_number: 1,
+            step: self.current_step.clone(),
+            next_step: self.workout_file.workout.steps.front().cloned(),
+            remaining: self
+                .all_steps
+                .iter()
+                .map(WorkoutSteps::get_step_duration)
+                .sum(),
+        });
+        self.send_event(WorkoutEvent::StepStarted { number: 1 });
+    }
+
+    /// Shifts target power of the current and all following steps, so the target stays within given range.
+    /// Returns command applying new target right away, if current step is power based.
+    pub fn nudge_power(&mut self, by: i16, power_range: &Range<i16, u16>) -> Option<UserCommands> {
+        if !self.is_power_based() {
+            warn!("Current step does not target power, cannot nudge it");
+            return None;
+        }
+
+        let base = get_power(self.ftp_base, self.power_level);
+        let target = power_range.clamp(base.saturating_add(self.power_nudge).saturating_add(by));
+        self.power_nudge = target.saturating_sub(base);
+        self.power_range = Some(power_range.clone());
+
+        info!(
+            "Target power nudged to {}W, {:+}W over the workout",
+            target, self.power_nudge
+        );
+        self.update_state(WorkoutStateUpdate::PowerOffset(self.power_nudge));
+        self.update_state(WorkoutStateUpdate::PowerSet {
+            power: target,
+            level: Some(self.power_level),
+        });
+
+        let command = self.step_command(self.power_level);
+        self.is_repeated_target(&command);
+
+        Some(command)
+    }
+
+    /// Adds given watts to every target of the workout, replacing any nudge done so far.
+    /// Targets are clamped to the power range. Returns command applying new target right away,
+    /// if the workout is running a power based step.
+    pub fn set_power_offset(
+        &mut self,
+        offset: i16,
+        power_range: &Range<i16, u16>,
+    ) -> Option<UserCommands> {
+        info!("Target power offset set to {offset:+}W");
+        self.power_nudge = offset;
+        self.power_range = Some(power_range.clone());
+        self.update_state(WorkoutStateUpdate::PowerOffset(offset));
+
+        if !self.started || !self.is_power_based() {
+            return None;
+        }
+
+        let command = self.step_command(self.power_level);
+        if let UserCommands::SetTargetPower { power } = command {
+            self.update_state(WorkoutStateUpdate::PowerSet {
+                power,
+                level: Some(self.power_level),
+            });
+        }
+        self.is_repeated_target(&command);
+
+        Some(command)
+    }
+
+    /// Target power of the workout step with the offset, within the trainer range
+    fn target_power(&self, power_level: f64) -> i16 {
+        if let Some(floor) = self.rest_floor(power_level) {
+            return floor;
+        }
+
+        let power = get_power(self.ftp_base, power_level).saturating_add(self.power_nudge);
+
+        match &self.power_range {
+            Some(power_range) => power_range.clamp(power),
+            None => power,
+        }
+    }
+
+    /// The floor, if target of given power level is a rest below it
+    fn rest_floor(&self, power_level: f64) -> Option<i16> {
+        let floor = self.rest_power_floor?.watts(self.ftp_base);
+
+        let is_work = matches!(
+            &self.current_step,
+            WorkoutSteps::IntervalsT(intervals) if power_level == intervals.on_power
+        );
+        let power = get_power(self.ftp_base, power_level).saturating_add(self.power_nudge);
+
+        (!is_work && power < floor).then_some(floor)
+    }
+
+    /// Remembers the target of the command, returns true if the trainer already has it
+    fn is_repeated_target(&mut self, command: &UserCommands) -> bool {
+        match command {
+            UserCommands::SetTargetPower { power } => {
+                let repeated = self.last_target_power == Some(*power);
+                self.last_target_power = Some(*power);
+
+                repeated
+            }
+            _ => {
+                self.last_target_power = None;
+
+                false
+            }
+        }
+    }
+
+    fn update_state(&self, update: WorkoutStateUpdate) {
+        // Fails only if nobody cares about the state anymore
+        if self.state_tx.send(update).is_err() {
+            trace!("Workout state actor is gone");
+        }
+    }
+
+    fn send_event(&self, event: WorkoutEvent) {
+        if let Some(events_tx) = &self.events_tx {
+            // Fails if there are no subscribers at the moment
+            let _ = events_tx.send(event);
+        }
+    }
+
+    /// Number of the step being executed, steps are popped from the queue as the workout goes
+    fn current_step_number(&self) -> usize {
+        self.all_steps.len() - self.workout_file.workout.steps.len()
     }
 
     fn advance_workout(&mut self) -> Option<PowerDuration> {
+        if !self.started {
+            self.started = true;
+            self.send_event(WorkoutEvent::Started);
+            self.send_event(WorkoutEvent::StepStarted {
+                number: self.current_step_number(),
+            });
+        }
+
         let next_pd = {
             if let Some(next_pd) = self.advance_step() {
                 Some(next_pd)
             } else {
                 // Current step exhausted, get next one
-                self.workout_state.handle_next_step(&self.workout_file);
+                let steps = &self.workout_file.workout.steps;
+                if let Some(step) = steps.front() {
+                    self.update_state(WorkoutStateUpdate::NextStep {
+                        step: step.clone(),
+                        next_step: steps.get(1).cloned(),
+                    });
+                }
 
                 if let Some(next) = self.workout_file.workout.steps.pop_front() {
                     // Start with next workout
                     self.current_step = next;
+                    self.send_event(WorkoutEvent::StepStarted {
+                        number: self.current_step_number(),
+                    });
+
+                    let next_pd = self
+                        .advance_step()
+                        .expect("Cannot advance fresh workout step");
+
+                    Some(next_pd)
+                } else if self.repeat.has_next(self.loop_number) {
+                    self.restart();
 
                     let next_pd = self
                         .advance_step()
@@ -76,22 +541,103 @@ impl ZwoWorkout {
                     Some(next_pd)
                 } else {
                     // Nothing left
+                    if !self.completed {
+                        self.completed = true;
+                        self.update_state(WorkoutStateUpdate::Finished);
+                        self.send_event(WorkoutEvent::Completed);
+                    }
+
                     None
                 }
             }
         };
 
         if let Some(power_duration) = &next_pd {
-            self.workout_state.current_power_set =
-                get_power(self.workout_state.ftp_base, power_duration.power_level);
+            let floored = self.rest_floor(power_duration.power_level).is_some();
+            self.update_state(WorkoutStateUpdate::RestPowerFloor(floored));
+            self.update_state(WorkoutStateUpdate::PowerSet {
+                power: self.target_power(power_duration.power_level),
+                level: Some(power_duration.power_level),
+            });
         }
 
         next_pd
     }
 
+    /// Power based steps are executed in ERG mode, free ride switches ERG off, speed steps set the speed target
+    fn step_command(&self, power_level: f64) -> UserCommands {
+        match &self.current_step {
+            WorkoutSteps::FreeRide(free_ride) => UserCommands::SetSimulation {
+                grade: free_ride.grade(self.route_grade),
+            },
+            WorkoutSteps::SteadySpeed(steady_speed) => UserCommands::SetTargetSpeed {
+                speed: steady_speed.speed,
+            },
+            _ => UserCommands::SetTargetPower {
+                power: self.target_power(power_level),
+            },
+        }
+    }
+
+    fn is_power_based(&self) -> bool {
+        !matches!(
+            self.current_step,
+            WorkoutSteps::FreeRide(_) | WorkoutSteps::SteadySpeed(_)
+        )
+    }
+
     fn advance_step(&mut self) -> Option<PowerDuration> {
-        self.workout_state.handle_step_advance(&self.current_step);
-        self.current_step.advance()
+        self.update_state(WorkoutStateUpdate::StepAdvanced(self.current_step.clone()));
+
+        let work = match &self.current_step {
+            WorkoutSteps::IntervalsT(intervals) => Some(intervals.is_work_interval()),
+            _ => None,
+        };
+
+        let mut next_pd = self.current_step.advance();
+
+        if let (Some(work), Some(_)) = (work, &next_pd) {
+            self.send_event(WorkoutEvent::IntervalFlipped { work });
+        }
+
+        let is_ramp = matches!(
+            self.current_step,
+            WorkoutSteps::Warmup(_) | WorkoutSteps::Ramp(_) | WorkoutSteps::Cooldown(_)
+        );
+
+        if self.smooth_ramps && is_ramp {
+            next_pd = next_pd.map(|power_duration| self.coalesce_ramp(power_duration));
+        }
+
+        next_pd
+    }
+
+    /// Merges following seconds of the ramp into the given one, as long as their power is close enough.
+    /// Last second of the ramp is merged only if power is the same, so its end power is always set.
+    fn coalesce_ramp(&mut self, mut power_duration: PowerDuration) -> PowerDuration {
+        let power = get_power(self.ftp_base, power_duration.power_level);
+
+        loop {
+            let mut ahead = self.current_step.clone();
+
+            let next = match ahead.advance() {
+                Some(next) => next,
+                None => break,
+            };
+
+            let is_last = ahead.clone().advance().is_none();
+            let next_power = get_power(self.ftp_base, next.power_level);
+
+            if (next_power - power).abs() > RAMP_COALESCE_WATTS || (is_last && next_power != power)
+            {
+                break;
+            }
+
+            self.current_step = ahead;
+            power_duration.duration += next.duration;
+        }
+
+        power_duration
     }
 }
 
@@ -102,28 +648,42 @@ impl Stream for ZwoWorkout {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        match self.pending.as_mut().poll(cx) {
-            Poll::Ready(_) => {
-                debug!("Timer ready, advancing workout");
-
-                match self.advance_workout() {
-                    Some(PowerDuration {
-                        duration,
-                        power_level,
-                    }) => {
-                        self.pending = Box::pin(tokio::time::sleep(duration));
-
-                        Poll::Ready(Some(UserCommands::SetTargetPower {
-                            power: get_power(self.workout_state.ftp_base, power_level),
-                        }))
-                    }
+        loop {
+            let timer = if self.fast_forward {
+                Poll::Ready(())
+            } else {
+                self.pending.as_mut().poll(cx)
+            };
+
+            match timer {
+                Poll::Ready(_) => {
+                    debug!("Timer ready, advancing workout");
+
+                    match self.advance_workout() {
+                        Some(PowerDuration {
+                            duration,
+                            power_level,
+                        }) => {
+                            self.pending = Box::pin(tokio::time::sleep(duration));
+                            self.power_level = power_level;
+
+                            let command = self.step_command(power_level);
+                            if self.is_repeated_target(&command) {
+                                // Timer is re-armed, poll it so the waker is registered
+                                debug!("Target did not change, suppressing {command:?}");
+                                continue;
+                            }
+
+                            return Poll::Ready(Some(command));
+                        }
 
-                    // Whole workout exhausted
-                    None => Poll::Ready(None),
+                        // Whole workout exhausted
+                        None => return Poll::Ready(None),
+                    }
                 }
+                // Previous step should be still executed
+                Poll::Pending => return Poll::Pending,
             }
-            // Previous step should be still executed
-            Poll::Pending => Poll::Pending,
         }
     }
 
@@ -136,9 +696,453 @@ impl Stream for ZwoWorkout {
 mod tests {
     use std::path::PathBuf;
 
+    use futures::StreamExt;
     use walkdir::WalkDir;
 
     use super::*;
+    use crate::zwo_workout_file::WorkoutBuilder;
+
+    #[tokio::test]
+    async fn free_ride_switches_erg_off() {
+        let workout_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts/test.zwo");
+        let (mut workout, _) = ZwoWorkout::new(&workout_path, 200.0).await.unwrap();
+        workout.set_fast_forward(true);
+
+        let commands: Vec<_> = workout.collect().await;
+
+        let simulation: Vec<_> = commands
+            .iter()
+            .enumerate()
+            .filter(|(_, command)| matches!(command, UserCommands::SetSimulation { .. }))
+            .collect();
+
+        // FreeRide follows 26 power commands, next step is power based again
+        assert_eq!(simulation.len(), 1);
+        assert_eq!(simulation[0].0, 26);
+        assert!(matches!(
+            simulation[0].1,
+            UserCommands::SetSimulation { grade } if *grade == 0.0
+        ));
+        assert!(matches!(commands[27], UserCommands::SetTargetPower { .. }));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn steady_state_can_be_extended() {
+        let workout_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts/test.zwo");
+        let (mut workout, workout_state_actor) =
+            ZwoWorkout::new(&workout_path, 200.0).await.unwrap();
+        let (workout_state_tx, _) = tokio::sync::broadcast::channel(16);
+        let workout_state = tokio::spawn(workout_state_actor.run(workout_state_tx));
+
+        // Go straight to the SteadyState lasting 3 seconds
+        workout.next().await.unwrap();
+        workout.skip_step();
+        workout.next().await.unwrap();
+
+        let started = Instant::now();
+        assert_eq!(workout.pending.deadline(), started + Duration::from_secs(3));
+
+        workout.extend_step(Duration::from_secs(30));
+        assert_eq!(
+            workout.pending.deadline(),
+            started + Duration::from_secs(33)
+        );
+
+        // Next command comes after extended time
+        assert!(
+            tokio::time::timeout(Duration::from_secs(32), workout.next())
+                .await
+                .is_err()
+        );
+        workout.next().await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_secs(33));
+
+        // Warmup was skipped right away, 41 seconds remained, extended by 30
+        drop(workout);
+        let state = workout_state.await.unwrap();
+        assert_eq!(state.total_workout_duration, Duration::from_secs(71));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn can_jump_forward_and_backward() {
+        let workout_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts/test.zwo");
+        let (mut workout, workout_state_actor) =
+            ZwoWorkout::new(&workout_path, 200.0).await.unwrap();
+        let (workout_state_tx, _) = tokio::sync::broadcast::channel(16);
+        let workout_state = tokio::spawn(workout_state_actor.run(workout_state_tx));
+
+        let total_steps = workout.workout_info().total_steps;
+        workout.next().await.unwrap();
+
+        workout.jump_to_step(5).unwrap();
+        assert_eq!(workout.workout_file.workout.steps.len(), total_steps - 5);
+        assert_eq!(workout.current_step, workout.all_steps[4]);
+
+        // Jumped step is executed right away
+        workout.next().await.unwrap();
+
+        workout.jump_to_step(2).unwrap();
+        assert_eq!(workout.workout_file.workout.steps.len(), total_steps - 2);
+        assert_eq!(workout.current_step, workout.all_steps[1]);
+
+        assert!(workout.jump_to_step(0).is_err());
+        assert!(workout.jump_to_step(total_steps + 1).is_err());
+
+        // SteadyState lasting 3 seconds, followed by the rest of the workout
+        drop(workout);
+        let state = workout_state.await.unwrap();
+        assert_eq!(state.current_step_number, 2);
+        assert_eq!(state.total_workout_duration, Duration::from_secs(41));
+    }
+
+    #[tokio::test]
+    async fn nudge_is_layered_on_workout_target() {
+        let workout_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts/test.zwo");
+        let (mut workout, _) = ZwoWorkout::new(&workout_path, 200.0).await.unwrap();
+        workout.set_fast_forward(true);
+
+        let power_range = Range {
+            min: 0,
+            max: 150,
+            step: 1,
+        };
+
+        // Warmup starts at 45% of FTP
+        workout.next().await.unwrap();
+        assert!(matches!(
+            workout.nudge_power(10, &power_range),
+            Some(UserCommands::SetTargetPower { power: 100 })
+        ));
+
+        // Nudge is kept for the following targets
+        assert!(matches!(
+            workout.next().await.unwrap(),
+            UserCommands::SetTargetPower { power } if power > 100
+        ));
+
+        // Target does not go above the range
+        workout.jump_to_step(2).unwrap();
+        workout.next().await.unwrap();
+        assert!(matches!(
+            workout.nudge_power(10, &power_range),
+            Some(UserCommands::SetTargetPower { power: 150 })
+        ));
+
+        // Nudge or offset as large as it gets does not overflow, following targets stay in range too
+        assert!(matches!(
+            workout.nudge_power(i16::MAX, &power_range),
+            Some(UserCommands::SetTargetPower { power: 150 })
+        ));
+        workout.set_power_offset(i16::MAX, &power_range);
+        while let Some(command) = workout.next().await {
+            if let UserCommands::SetTargetPower { power } = command {
+                assert_eq!(power_range.clamp(power), power);
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paused_workout_waits_for_resume() {
+        let workout_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts/test.zwo");
+        let (mut workout, _) = ZwoWorkout::new(&workout_path, 200.0).await.unwrap();
+        workout.start_paused();
+
+        let first = tokio::time::timeout(Duration::from_secs(600), workout.next()).await;
+        assert!(first.is_err(), "{:?}", first);
+
+        workout.resume();
+        assert!(matches!(
+            workout.next().await,
+            Some(UserCommands::SetTargetPower { power: 90 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn final_cooldown_is_recognized() {
+        let workout_file = WorkoutBuilder::new("Cooldown")
+            .cooldown(60, 0.6, 0.4)
+            .steady(60, 0.8)
+            .cooldown(60, 0.6, 0.4)
+            .build();
+        let (mut workout, _) = ZwoWorkout::from_workout_file(workout_file, 200.0).unwrap();
+        workout.set_fast_forward(true);
+
+        // Cooldown is not the last step
+        workout.next().await.unwrap();
+        assert!(!workout.is_in_final_cooldown());
+
+        workout.skip_step();
+        workout.next().await.unwrap();
+        assert!(!workout.is_in_final_cooldown());
+
+        workout.skip_step();
+        workout.next().await.unwrap();
+        assert!(workout.is_in_final_cooldown());
+
+        while workout.next().await.is_some() {}
+        assert!(!workout.is_in_final_cooldown());
+    }
+
+    #[tokio::test]
+    async fn events_follow_the_workout() {
+        let workout_file = WorkoutBuilder::new("Events")
+            .intervals(2, 30, 15, 1.0, 0.5)
+            .steady(60, 0.6)
+            .build();
+        let (events_tx, mut events_rx) = broadcast::channel(16);
+        let (workout, _) = ZwoWorkout::from_workout_file(workout_file, 200.0).unwrap();
+        let mut workout = workout.with_events(events_tx);
+        workout.set_fast_forward(true);
+
+        // Skip the rest of the first work interval
+        workout.next().await.unwrap();
+        workout.skip_step();
+        while workout.next().await.is_some() {}
+
+        let mut events = vec![];
+        while let Ok(event) = events_rx.try_recv() {
+            events.push(event);
+        }
+
+        assert_eq!(
+            events,
+            vec![
+                WorkoutEvent::Started,
+                WorkoutEvent::StepStarted { number: 1 },
+                WorkoutEvent::IntervalFlipped { work: true },
+                WorkoutEvent::Skipped,
+                WorkoutEvent::IntervalFlipped { work: true },
+                WorkoutEvent::IntervalFlipped { work: false },
+                WorkoutEvent::StepStarted { number: 2 },
+                WorkoutEvent::Completed,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn last_state_is_marked_finished() {
+        let workout_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts/test.zwo");
+        let (mut workout, workout_state_actor) =
+            ZwoWorkout::new(&workout_path, 200.0).await.unwrap();
+        workout.set_fast_forward(true);
+
+        let (workout_state_tx, mut workout_state_rx) = broadcast::channel(256);
+        let workout_state = tokio::spawn(workout_state_actor.run(workout_state_tx));
+
+        while workout.next().await.is_some() {}
+        drop(workout);
+        assert!(workout_state.await.unwrap().finished);
+
+        // Final state is buffered, even though the channel is closed already
+        let mut states = vec![];
+        while let Ok(state) = workout_state_rx.recv().await {
+            states.push(state);
+        }
+
+        let (last, rest) = states.split_last().unwrap();
+        assert!(last.finished);
+        assert!(rest.iter().all(|state| !state.finished));
+    }
+
+    #[tokio::test]
+    async fn target_is_reported_in_percent_of_ftp() {
+        let workout_file = WorkoutBuilder::new("Threshold").steady(60, 1.05).build();
+        let (mut workout, workout_state_actor) =
+            ZwoWorkout::from_workout_file(workout_file, 200.0).unwrap();
+        workout.set_fast_forward(true);
+
+        let (workout_state_tx, _) = broadcast::channel(256);
+        let workout_state = tokio::spawn(workout_state_actor.run(workout_state_tx));
+
+        while workout.next().await.is_some() {}
+        drop(workout);
+        let state = workout_state.await.unwrap();
+
+        assert_eq!(state.current_power_set, 210);
+        assert_eq!(state.current_power_ftp_percent, 105.0);
+        assert_eq!(state.current_power_level, Some(1.05));
+    }
+
+    #[tokio::test]
+    async fn power_offset_shifts_the_target() {
+        let workout_file = WorkoutBuilder::new("Offset").steady(60, 0.75).build();
+        let (mut workout, workout_state_actor) =
+            ZwoWorkout::from_workout_file(workout_file, 200.0).unwrap();
+        let (workout_state_tx, _) = broadcast::channel(16);
+        let workout_state = tokio::spawn(workout_state_actor.run(workout_state_tx));
+
+        workout.set_fast_forward(true);
+        let power_range = Range {
+            min: 0,
+            max: 2000,
+            step: 1,
+        };
+        assert!(workout.set_power_offset(10, &power_range).is_none());
+
+        assert!(matches!(
+            workout.next().await,
+            Some(UserCommands::SetTargetPower { power: 160 })
+        ));
+
+        drop(workout);
+        let state = workout_state.await.unwrap();
+        assert_eq!(state.power_offset, 10);
+        assert_eq!(state.current_power_set, 160);
+    }
+
+    #[tokio::test]
+    async fn zero_duration_steps_are_skipped() {
+        let workout_file = WorkoutFile::from_zwo_str(
+            r#"<workout_file>
+                <author>velomania</author>
+                <name>Zero</name>
+                <description>Steps lasting no time</description>
+                <sportType>bike</sportType>
+                <workout>
+                    <SteadyState Duration="1" Power="0.5"/>
+                    <SteadyState Duration="0" Power="1.5"/>
+                    <Ramp Duration="0" PowerLow="0.5" PowerHigh="1.0"/>
+                    <IntervalsT Repeat="2" OnDuration="0" OffDuration="1" OnPower="1.5" OffPower="0.6"/>
+                    <SteadyState Duration="1" Power="0.7"/>
+                </workout>
+            </workout_file>"#,
+        )
+        .unwrap();
+
+        let (mut workout, workout_state_actor) =
+            ZwoWorkout::from_workout_file(workout_file, 200.0).unwrap();
+        let (workout_state_tx, _) = broadcast::channel(16);
+        let workout_state = tokio::spawn(workout_state_actor.run(workout_state_tx));
+
+        workout.set_fast_forward(true);
+
+        let mut targets = vec![];
+        while let Some(command) = workout.next().await {
+            if let UserCommands::SetTargetPower { power } = command {
+                targets.push(power);
+            }
+        }
+        // Rests of the intervals are merged, as the target does not change
+        assert_eq!(targets, vec![100, 120, 140]);
+
+        drop(workout);
+        let state = workout_state.await.unwrap();
+        assert_eq!(state.total_steps, 3);
+        assert_eq!(state.current_step_number, 3);
+        assert_eq!(state.total_workout_duration, Duration::from_secs(4));
+    }
+
+    #[tokio::test]
+    async fn rest_target_is_raised_to_the_floor() {
+        // Work 100W, rest 50W
+        let workout_file = WorkoutBuilder::new("Floor")
+            .intervals(2, 1, 1, 0.5, 0.25)
+            .build();
+        let (mut workout, workout_state_actor) =
+            ZwoWorkout::from_workout_file(workout_file, 200.0).unwrap();
+        let (workout_state_tx, _) = broadcast::channel(16);
+        let workout_state = tokio::spawn(workout_state_actor.run(workout_state_tx));
+
+        workout.set_fast_forward(true);
+        workout.set_rest_power_floor("60W".parse().unwrap());
+
+        let mut targets = vec![];
+        while let Some(command) = workout.next().await {
+            if let UserCommands::SetTargetPower { power } = command {
+                targets.push(power);
+            }
+        }
+        assert_eq!(targets, vec![100, 60, 100, 60]);
+
+        drop(workout);
+        let state = workout_state.await.unwrap();
+        assert!(state.rest_power_floor_active);
+        assert_eq!(state.current_power_set, 60);
+
+        assert_eq!("30%".parse::<RestPowerFloor>().unwrap().watts(200.0), 60);
+        assert!("-5W".parse::<RestPowerFloor>().is_err());
+        assert!("lots".parse::<RestPowerFloor>().is_err());
+    }
+
+    #[tokio::test]
+    async fn repeated_workout_starts_over() {
+        let workout_file = WorkoutBuilder::new("Repeat")
+            .steady(60, 0.5)
+            .steady(30, 0.7)
+            .build();
+        let (mut workout, _) = ZwoWorkout::from_workout_file(workout_file, 200.0).unwrap();
+        workout.set_fast_forward(true);
+        workout.set_repeat("2".parse().unwrap());
+
+        let commands: Vec<_> = workout.collect().await;
+        let powers: Vec<_> = commands
+            .iter()
+            .filter_map(|command| match command {
+                UserCommands::SetTargetPower { power } => Some(*power),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(powers, vec![100, 140, 100, 140]);
+
+        assert_eq!("infinite".parse::<Repeat>().unwrap(), Repeat::Infinite);
+        assert!("0".parse::<Repeat>().is_err());
+    }
+
+    #[tokio::test]
+    async fn smooth_ramp_is_monotonic_and_hits_endpoints() {
+        let workout_file = WorkoutBuilder::new("Ramp").ramp(300, 0.5, 1.0).build();
+        let (mut workout, _) = ZwoWorkout::from_workout_file(workout_file, 200.0).unwrap();
+        workout.set_smooth_ramps(true);
+
+        let mut schedule = vec![];
+        while let Some(power_duration) = workout.advance_workout() {
+            schedule.push((
+                get_power(200.0, power_duration.power_level),
+                power_duration.duration,
+            ));
+        }
+
+        let powers: Vec<_> = schedule.iter().map(|(power, _)| *power).collect();
+        let total: Duration = schedule.iter().map(|(_, duration)| *duration).sum();
+
+        assert_eq!(powers.first(), Some(&100));
+        assert_eq!(powers.last(), Some(&200));
+        assert_eq!(total, Duration::from_secs(300));
+        assert!(
+            powers.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            powers
+        );
+        // ~0.33W per second, targets change every few seconds only
+        assert!(schedule.len() < 300 / 2, "{}", schedule.len());
+    }
+
+    #[tokio::test]
+    async fn slow_ramp_does_not_repeat_the_target() {
+        let workout_file = WorkoutBuilder::new("Slow ramp").ramp(300, 0.5, 0.6).build();
+        let (mut workout, _) = ZwoWorkout::from_workout_file(workout_file, 100.0).unwrap();
+        workout.set_fast_forward(true);
+
+        let commands: Vec<_> = workout.collect().await;
+        let powers: Vec<_> = commands
+            .iter()
+            .filter_map(|command| match command {
+                UserCommands::SetTargetPower { power } => Some(*power),
+                _ => None,
+            })
+            .collect();
+
+        // 50W to 60W over 300 seconds, every watt is set once
+        assert!(commands.len() < 300, "{}", commands.len());
+        assert!(
+            powers.windows(2).all(|pair| pair[0] != pair[1]),
+            "{:?}",
+            powers
+        );
+        assert_eq!(powers.first(), Some(&50));
+        assert!(powers.last() >= Some(&59), "{:?}", powers);
+    }
 
     #[tokio::test]
     async fn can_correctly_parse_all_workouts() {
@@ -1,8 +1,23 @@
-use std::{collections::VecDeque, path::Path, time::Duration};
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::Context;
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{
+        value::MapAccessDeserializer, DeserializeSeed, EnumAccess, IgnoredAny, IntoDeserializer,
+        MapAccess, VariantAccess, Visitor,
+    },
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use tokio::io::AsyncReadExt;
+use walkdir::WalkDir;
+
+use crate::common::whole_secs;
 
 // XML schema definition
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -12,16 +27,251 @@ pub struct WorkoutFile {
     pub name: String,
     pub description: String,
     pub sport_type: String,
+    /// Labels of the workout, like "INTERVALS" or "FTP", for filtering the library
+    #[serde(default, deserialize_with = "deserialize_tags")]
+    pub tags: Vec<String>,
     pub workout: Workout,
 
     #[serde(skip)]
     pub total_workout_duration: Duration,
 }
 
+/// Sport type of workouts meant for the trainer
+const BIKE_SPORT_TYPE: &str = "bike";
+
+/// Tags are written as <tags><tag name="..."/></tags>
+#[derive(Deserialize)]
+struct Tags {
+    #[serde(rename = "tag", default)]
+    tags: Vec<Tag>,
+}
+
+#[derive(Deserialize)]
+struct Tag {
+    name: String,
+}
+
+fn deserialize_tags<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let tags = Tags::deserialize(deserializer)?;
+
+    Ok(tags.tags.into_iter().map(|tag| tag.name).collect())
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(from = "WorkoutElements")]
 pub struct Workout {
-    #[serde(rename = "$value")]
     pub steps: VecDeque<WorkoutSteps>,
+    /// Text events placed among the steps, their time offset is counted from the start of the workout.
+    /// Sorted by the offset, without duplicates.
+    pub text_events: Vec<TextEvent>,
+}
+
+/// Children of the workout element, in the order of the file
+#[derive(Deserialize)]
+struct WorkoutElements {
+    #[serde(rename = "$value", default)]
+    elements: Vec<WorkoutElement>,
+}
+
+enum WorkoutElement {
+    /// Step with the text events nested in it, offset from the start of the step
+    Step(WorkoutSteps, Vec<TextEvent>),
+    TextEvent(TextEvent),
+    /// Element not supported (yet), it's skipped together with its content
+    Unknown(String),
+}
+
+impl<'de> Deserialize<'de> for WorkoutElement {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_enum("WorkoutElement", WORKOUT_ELEMENTS, WorkoutElementVisitor)
+    }
+}
+
+const WORKOUT_ELEMENTS: &[&str] = &[
+    "Warmup",
+    "Ramp",
+    "SteadyState",
+    "Cooldown",
+    "IntervalsT",
+    "FreeRide",
+    "SteadySpeed",
+    "textevent",
+];
+
+/// Matches the element by its name, unlike derived Deserialize, unknown names are not an error
+struct WorkoutElementVisitor;
+
+impl<'de> Visitor<'de> for WorkoutElementVisitor {
+    type Value = WorkoutElement;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("workout step, or text event")
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+        let (name, variant): (String, _) = data.variant()?;
+
+        let element = match name.as_str() {
+            "Warmup" => step_element(variant, WorkoutSteps::Warmup)?,
+            "Ramp" => step_element(variant, WorkoutSteps::Ramp)?,
+            "SteadyState" => step_element(variant, WorkoutSteps::SteadyState)?,
+            "Cooldown" => step_element(variant, WorkoutSteps::Cooldown)?,
+            "IntervalsT" => step_element(variant, WorkoutSteps::IntervalsT)?,
+            "FreeRide" => step_element(variant, WorkoutSteps::FreeRide)?,
+            "SteadySpeed" => step_element(variant, WorkoutSteps::SteadySpeed)?,
+            "textevent" => WorkoutElement::TextEvent(variant.newtype_variant()?),
+            _ => {
+                variant.newtype_variant::<IgnoredAny>()?;
+                WorkoutElement::Unknown(name)
+            }
+        };
+
+        Ok(element)
+    }
+}
+
+fn step_element<'de, A: VariantAccess<'de>, T: Deserialize<'de>>(
+    variant: A,
+    step: impl FnOnce(T) -> WorkoutSteps,
+) -> Result<WorkoutElement, A::Error> {
+    let StepElement {
+        step: inner,
+        text_events,
+    } = variant.newtype_variant()?;
+
+    Ok(WorkoutElement::Step(step(inner), text_events))
+}
+
+/// Step deserialized from its attributes and children, with the text events nested in it
+struct StepElement<T> {
+    step: T,
+    text_events: Vec<TextEvent>,
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for StepElement<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(StepElementVisitor(PhantomData))
+    }
+}
+
+struct StepElementVisitor<T>(PhantomData<T>);
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for StepElementVisitor<T> {
+    type Value = StepElement<T>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("workout step")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        let mut text_events = vec![];
+        let step = T::deserialize(MapAccessDeserializer::new(TextEventFilter {
+            map,
+            text_events: &mut text_events,
+        }))?;
+
+        Ok(StepElement { step, text_events })
+    }
+}
+
+/// Hands the step everything but its text events, which are collected on the side.
+/// A Vec field in the step would do, but serde-xml-rs then loses track of the element following the step.
+struct TextEventFilter<'a, A> {
+    map: A,
+    text_events: &'a mut Vec<TextEvent>,
+}
+
+impl<'de, 'a, A: MapAccess<'de>> MapAccess<'de> for TextEventFilter<'a, A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        while let Some(key) = self.map.next_key::<String>()? {
+            if key != "textevent" {
+                return seed.deserialize(key.into_deserializer()).map(Some);
+            }
+
+            self.text_events.push(self.map.next_value()?);
+        }
+
+        Ok(None)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.map.next_value_seed(seed)
+    }
+}
+
+impl From<WorkoutElements> for Workout {
+    fn from(elements: WorkoutElements) -> Self {
+        let mut steps = VecDeque::new();
+        let mut text_events = vec![];
+        let mut step_start = Duration::ZERO;
+
+        for element in elements.elements {
+            let step = match element {
+                WorkoutElement::Step(step, nested) => {
+                    text_events.extend(nested.into_iter().map(|mut text_event| {
+                        text_event.timeoffset += step_start;
+                        text_event
+                    }));
+                    step
+                }
+                WorkoutElement::TextEvent(text_event) => {
+                    text_events.push(text_event);
+                    continue;
+                }
+                WorkoutElement::Unknown(name) => {
+                    warn!("Skipping unsupported workout element <{name}>");
+                    continue;
+                }
+            };
+
+            // There is nothing to execute, the step would end before it starts
+            if step.get_step_duration().is_zero() {
+                warn!("Skipping zero duration step {step:?}");
+                continue;
+            }
+
+            step_start += step.get_step_duration();
+            steps.push_back(step);
+        }
+
+        Workout {
+            steps,
+            text_events: timeline(text_events),
+        }
+    }
+}
+
+/// Sorts text events by the time offset, the same message at the same time is kept once
+fn timeline(mut text_events: Vec<TextEvent>) -> Vec<TextEvent> {
+    text_events.sort_by_key(|text_event| text_event.timeoffset);
+    text_events.dedup_by(|a, b| a.timeoffset == b.timeoffset && a.message == b.message);
+
+    text_events
+}
+
+/// How long the text event is shown, if the file does not tell
+const TEXT_EVENT_DURATION: Duration = Duration::from_secs(10);
+
+/// Message for the rider, shown at given time from the start of the workout
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct TextEvent {
+    #[serde(with = "duration_secs")]
+    pub timeoffset: Duration,
+    pub message: String,
+    #[serde(default = "text_event_duration", with = "duration_secs")]
+    pub duration: Duration,
+}
+
+fn text_event_duration() -> Duration {
+    TEXT_EVENT_DURATION
 }
 impl WorkoutFile {
     pub async fn new(workout_path: &Path) -> anyhow::Result<Self> {
@@ -33,29 +283,317 @@ impl WorkoutFile {
             .await
             .context("Reading xml to String failed")?;
 
-        let mut workout: WorkoutFile = serde_xml_rs::from_str(&content)
-            .context("Parsing xml string to Workouts struct failed")?;
-        trace!("Parsed xml {workout:#?}");
+        let workout = Self::from_zwo_str(&content)?;
 
         info!("Loaded {}", workout_path.display());
 
-        workout.total_workout_duration = Self::remaining_workout_duration(&workout.workout);
         Ok(workout)
     }
 
-    fn remaining_workout_duration(workout: &Workout) -> Duration {
-        let total_workout_duration = {
-            workout
-                .steps
-                .iter()
-                .fold(Duration::from_secs(0), |acc, step| {
-                    acc + step.get_step_duration()
-                })
-        };
-        total_workout_duration
+    /// Parses content of the ZWO file
+    pub fn from_zwo_str(content: &str) -> anyhow::Result<Self> {
+        let mut workout: WorkoutFile = serde_xml_rs::from_str(content)
+            .context("Parsing xml string to Workouts struct failed")?;
+        trace!("Parsed xml {workout:#?}");
+
+        workout.total_workout_duration = Self::remaining_workout_duration(&workout.workout)
+            .context("Workout duration is out of range")?;
+        Ok(workout)
+    }
+
+    /// Serializes the workout back to ZWO format. Steps are written the way Zwift writes them,
+    /// as empty elements with attributes, which serde-xml-rs serializer is not capable of.
+    pub fn to_zwo_string(&self) -> anyhow::Result<String> {
+        let mut zwo = String::from("<workout_file>\n");
+
+        for (tag, text) in [
+            ("author", &self.author),
+            ("name", &self.name),
+            ("description", &self.description),
+            ("sportType", &self.sport_type),
+        ] {
+            zwo += &format!("    <{tag}>{}</{tag}>\n", escape_xml(text));
+        }
+
+        if !self.tags.is_empty() {
+            zwo += "    <tags>\n";
+            for tag in &self.tags {
+                zwo += &format!("        <tag name=\"{}\"/>\n", escape_xml(tag));
+            }
+            zwo += "    </tags>\n";
+        }
+
+        zwo += "    <workout>\n";
+        for step in &self.workout.steps {
+            zwo += &format!("        {}\n", step_to_zwo(step)?);
+        }
+        for text_event in &self.workout.text_events {
+            zwo += &format!(
+                "        <textevent timeoffset=\"{}\" message=\"{}\" duration=\"{}\"/>\n",
+                text_event.timeoffset.as_secs_f64(),
+                escape_xml(&text_event.message),
+                text_event.duration.as_secs_f64()
+            );
+        }
+        zwo += "    </workout>\n</workout_file>\n";
+
+        Ok(zwo)
+    }
+
+    /// Static description of the workout
+    pub fn info(&self) -> WorkoutInfo {
+        WorkoutInfo {
+            author: self.author.clone(),
+            name: self.name.clone(),
+            description: self.description.clone(),
+            sport_type: self.sport_type.clone(),
+            tags: self.tags.clone(),
+            total_workout_duration: self.total_workout_duration,
+            total_steps: self.workout.steps.len(),
+        }
+    }
+
+    /// None if the duration does not fit in Duration
+    fn remaining_workout_duration(workout: &Workout) -> Option<Duration> {
+        workout.steps.iter().try_fold(Duration::ZERO, |acc, step| {
+            acc.checked_add(step.checked_step_duration()?)
+        })
     }
 }
 
+/// Constructs the workout programmatically, durations are in seconds, powers are fractions of FTP
+#[derive(Debug)]
+pub struct WorkoutBuilder {
+    workout: WorkoutFile,
+}
+
+impl WorkoutBuilder {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            workout: WorkoutFile {
+                author: String::new(),
+                name: name.into(),
+                description: String::new(),
+                sport_type: BIKE_SPORT_TYPE.to_string(),
+                tags: vec![],
+                workout: Workout {
+                    steps: VecDeque::new(),
+                    text_events: vec![],
+                },
+                total_workout_duration: Duration::ZERO,
+            },
+        }
+    }
+
+    pub fn author(mut self, author: impl Into<String>) -> Self {
+        self.workout.author = author.into();
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.workout.description = description.into();
+        self
+    }
+
+    pub fn warmup(self, secs: u64, power_low: f64, power_high: f64) -> Self {
+        self.step(WorkoutSteps::Warmup(Warmup {
+            duration: Duration::from_secs(secs),
+            power_low,
+            power_high,
+            elapsed: Duration::ZERO,
+        }))
+    }
+
+    pub fn ramp(self, secs: u64, power_low: f64, power_high: f64) -> Self {
+        self.step(WorkoutSteps::Ramp(Ramp {
+            duration: Duration::from_secs(secs),
+            power_low,
+            power_high,
+            elapsed: Duration::ZERO,
+        }))
+    }
+
+    pub fn steady(self, secs: u64, power: f64) -> Self {
+        self.step(WorkoutSteps::SteadyState(SteadyState {
+            duration: Duration::from_secs(secs),
+            power,
+            cadence: None,
+        }))
+    }
+
+    pub fn intervals(
+        self,
+        repeat: u64,
+        on_secs: u64,
+        off_secs: u64,
+        on_power: f64,
+        off_power: f64,
+    ) -> Self {
+        self.step(WorkoutSteps::IntervalsT(IntervalsT {
+            repeat,
+            on_duration: Duration::from_secs(on_secs),
+            off_duration: Duration::from_secs(off_secs),
+            on_power,
+            off_power,
+            cadence: None,
+            cadence_resting: None,
+            current_interval: 0,
+        }))
+    }
+
+    pub fn cooldown(self, secs: u64, power_low: f64, power_high: f64) -> Self {
+        self.step(WorkoutSteps::Cooldown(Cooldown {
+            duration: Duration::from_secs(secs),
+            power_low,
+            power_high,
+            elapsed: Duration::ZERO,
+        }))
+    }
+
+    /// Free ride on the flat road
+    pub fn free_ride(self, secs: u64) -> Self {
+        self.step(WorkoutSteps::FreeRide(FreeRide {
+            duration: Duration::from_secs(secs),
+            flat_road: 1.0,
+        }))
+    }
+
+    pub fn step(mut self, step: WorkoutSteps) -> Self {
+        self.workout.workout.steps.push_back(step);
+        self
+    }
+
+    /// Message shown given number of seconds from the start of the workout
+    pub fn text_event(mut self, secs: u64, message: impl Into<String>) -> Self {
+        self.workout.workout.text_events.push(TextEvent {
+            timeoffset: Duration::from_secs(secs),
+            message: message.into(),
+            duration: TEXT_EVENT_DURATION,
+        });
+        self
+    }
+
+    pub fn build(mut self) -> WorkoutFile {
+        self.workout.workout.text_events = timeline(self.workout.workout.text_events);
+        self.workout.total_workout_duration =
+            WorkoutFile::remaining_workout_duration(&self.workout.workout).unwrap_or(Duration::MAX);
+        self.workout
+    }
+}
+
+/// Step element named after the variant, serialized fields become attributes
+fn step_to_zwo(step: &WorkoutSteps) -> anyhow::Result<String> {
+    let value = serde_json::to_value(step)?;
+
+    let (element, fields) = value
+        .as_object()
+        .and_then(|step| step.iter().next())
+        .and_then(|(element, fields)| Some((element, fields.as_object()?)))
+        .with_context(|| format!("Unexpected serialized form of the step {value}"))?;
+
+    let attributes: String = fields
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                serde_json::Value::String(value) => value.clone(),
+                other => other.to_string(),
+            };
+
+            format!(" {name}=\"{}\"", escape_xml(&value))
+        })
+        .collect();
+
+    Ok(format!("<{element}{attributes}/>"))
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Workout metadata that does not change during the workout
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkoutInfo {
+    pub author: String,
+    pub name: String,
+    pub description: String,
+    pub sport_type: String,
+    pub tags: Vec<String>,
+    #[serde(with = "whole_secs")]
+    pub total_workout_duration: Duration,
+    pub total_steps: usize,
+}
+
+impl WorkoutInfo {
+    /// Workouts for other sports (like running) would not make sense on the trainer,
+    /// these are refused if strict, otherwise just reported
+    pub fn check_sport_type(&self, strict: bool) -> anyhow::Result<()> {
+        if self.sport_type.eq_ignore_ascii_case(BIKE_SPORT_TYPE) {
+            return Ok(());
+        }
+
+        let problem = format!(
+            "Workout '{}' is meant for sport type '{}', not '{BIKE_SPORT_TYPE}'",
+            self.name, self.sport_type
+        );
+
+        if strict {
+            anyhow::bail!(problem);
+        }
+
+        warn!("{problem}, targets may not make sense on the trainer");
+        Ok(())
+    }
+}
+
+/// Entry of the workout library
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkoutListing {
+    /// Path relative to the library directory
+    pub path: PathBuf,
+    pub name: String,
+    pub author: String,
+    #[serde(with = "whole_secs")]
+    pub duration: Duration,
+    pub tags: Vec<String>,
+}
+
+/// Walks the directory looking for .zwo files, files that cannot be parsed are skipped
+pub async fn list_workouts(dir: &Path) -> Vec<WorkoutListing> {
+    let paths: Vec<_> = WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry.into_path()),
+            Err(e) => {
+                warn!("Cannot access workout library entry: {e}");
+                None
+            }
+        })
+        .filter(|path| path.extension() == Some("zwo".as_ref()))
+        .collect();
+
+    let mut workouts = vec![];
+
+    for path in paths {
+        match WorkoutFile::new(&path).await {
+            Ok(workout) => workouts.push(WorkoutListing {
+                path: path.strip_prefix(dir).unwrap_or(&path).to_path_buf(),
+                name: workout.name,
+                author: workout.author,
+                duration: workout.total_workout_duration,
+                tags: workout.tags,
+            }),
+            Err(e) => warn!("Skipping workout {}: {e:?}", path.display()),
+        }
+    }
+
+    workouts
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub enum WorkoutSteps {
     Warmup(Warmup),
@@ -64,6 +602,7 @@ pub enum WorkoutSteps {
     Cooldown(Cooldown),
     IntervalsT(IntervalsT),
     FreeRide(FreeRide),
+    SteadySpeed(SteadySpeed),
 }
 
 pub(crate) trait WorkoutStep {
@@ -71,6 +610,19 @@ pub(crate) trait WorkoutStep {
 }
 
 impl WorkoutSteps {
+    /// Name of the step, as in the ZWO file
+    pub fn name(&self) -> &'static str {
+        match self {
+            WorkoutSteps::Warmup(_) => "Warmup",
+            WorkoutSteps::Ramp(_) => "Ramp",
+            WorkoutSteps::SteadyState(_) => "SteadyState",
+            WorkoutSteps::Cooldown(_) => "Cooldown",
+            WorkoutSteps::IntervalsT(_) => "IntervalsT",
+            WorkoutSteps::FreeRide(_) => "FreeRide",
+            WorkoutSteps::SteadySpeed(_) => "SteadySpeed",
+        }
+    }
+
     pub(crate) fn advance(&mut self) -> Option<PowerDuration> {
         match self {
             WorkoutSteps::Warmup(w) => w.advance(),
@@ -79,141 +631,146 @@ impl WorkoutSteps {
             WorkoutSteps::IntervalsT(w) => w.advance(),
             WorkoutSteps::Ramp(w) => w.advance(),
             WorkoutSteps::FreeRide(w) => w.advance(),
+            WorkoutSteps::SteadySpeed(w) => w.advance(),
         }
     }
 
     pub(crate) fn skip(&mut self) {
         match self {
             // Enforce next call to advance() will return None
-            WorkoutSteps::Warmup(w) => w.duration = 0,
-            WorkoutSteps::SteadyState(w) => w.duration = 0,
-            WorkoutSteps::Cooldown(w) => w.duration = 0,
-            WorkoutSteps::Ramp(w) => w.duration = 0,
-            WorkoutSteps::FreeRide(w) => w.duration = 0,
-            // In case of intervals, skip just the current part of it
-            // no need to change anything
-            WorkoutSteps::IntervalsT(_) => (),
+            WorkoutSteps::Warmup(w) => w.duration = Duration::ZERO,
+            WorkoutSteps::SteadyState(w) => w.duration = Duration::ZERO,
+            WorkoutSteps::Cooldown(w) => w.duration = Duration::ZERO,
+            WorkoutSteps::Ramp(w) => w.duration = Duration::ZERO,
+            WorkoutSteps::FreeRide(w) => w.duration = Duration::ZERO,
+            WorkoutSteps::SteadySpeed(w) => w.duration = Duration::ZERO,
+            // In case of intervals, skip just the current on+off pair
+            WorkoutSteps::IntervalsT(w) => w.skip_pair(),
         }
     }
 
+    /// Saturates at Duration::MAX, loaded workouts are checked to fit
     pub(crate) fn get_step_duration(&self) -> Duration {
-        let secs = match self {
-            WorkoutSteps::Warmup(w) => w.duration,
-            WorkoutSteps::Ramp(w) => w.duration,
-            WorkoutSteps::SteadyState(w) => w.duration,
-            WorkoutSteps::Cooldown(w) => w.duration,
-            WorkoutSteps::IntervalsT(w) => w.repeat * (w.off_duration + w.on_duration),
-            WorkoutSteps::FreeRide(w) => w.duration,
-        };
+        self.checked_step_duration().unwrap_or(Duration::MAX)
+    }
 
-        Duration::from_secs(secs)
+    /// None if the duration does not fit in Duration
+    fn checked_step_duration(&self) -> Option<Duration> {
+        match self {
+            WorkoutSteps::Warmup(w) => Some(w.duration),
+            WorkoutSteps::Ramp(w) => Some(w.duration),
+            WorkoutSteps::SteadyState(w) => Some(w.duration),
+            WorkoutSteps::Cooldown(w) => Some(w.duration),
+            WorkoutSteps::IntervalsT(w) => w
+                .off_duration
+                .checked_add(w.on_duration)?
+                .checked_mul(u32::try_from(w.repeat).ok()?),
+            WorkoutSteps::FreeRide(w) => Some(w.duration),
+            WorkoutSteps::SteadySpeed(w) => Some(w.duration),
+        }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct Warmup {
-    pub duration: u64,
+    #[serde(with = "duration_secs")]
+    pub duration: Duration,
     pub power_low: f64,
     pub power_high: f64,
+
+    /// Part of the step already executed
+    #[serde(skip)]
+    pub elapsed: Duration,
 }
 
 impl WorkoutStep for Warmup {
-    /// Get power level lasting for one second from span [low; high)
+    /// Get power level lasting for one second from span [low; high]
     fn advance(&mut self) -> Option<PowerDuration> {
-        if self.duration == 0 {
-            return None;
-        }
-
-        let power_level = self.power_low;
-
-        let span = self.power_high - self.power_low;
-        let step = span / self.duration as f64;
-
-        self.duration -= 1;
-        self.power_low += step;
-        Some(PowerDuration {
-            duration: Duration::from_secs(1),
-            power_level,
-        })
+        ramp_chunk(
+            &mut self.elapsed,
+            self.duration,
+            self.power_low,
+            self.power_high,
+        )
     }
 }
 
+/// Like in Zwift, the ramp goes from PowerLow to PowerHigh, either way,
+/// PowerLow above PowerHigh makes it descending
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct Ramp {
-    pub duration: u64,
+    #[serde(with = "duration_secs")]
+    pub duration: Duration,
+    /// Power at the start of the ramp
     pub power_low: f64,
+    /// Power at the end of the ramp
     pub power_high: f64,
+
+    /// Part of the step already executed
+    #[serde(skip)]
+    pub elapsed: Duration,
 }
 
 impl WorkoutStep for Ramp {
-    /// Get power level lasting for one second from span [low; high)
+    /// Get power level lasting for one second from span [low; high], or [high; low] if it descends
     fn advance(&mut self) -> Option<PowerDuration> {
-        if self.duration == 0 {
-            return None;
-        }
-
-        let power_level = self.power_low;
-
-        let span = self.power_high - self.power_low;
-        let step = span / self.duration as f64;
-
-        self.duration -= 1;
-        self.power_low += step;
-        Some(PowerDuration {
-            duration: Duration::from_secs(1),
-            power_level,
-        })
+        ramp_chunk(
+            &mut self.elapsed,
+            self.duration,
+            self.power_low,
+            self.power_high,
+        )
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct Cooldown {
-    pub duration: u64,
+    #[serde(with = "duration_secs")]
+    pub duration: Duration,
     pub power_low: f64,
     pub power_high: f64,
+
+    /// Part of the step already executed
+    #[serde(skip)]
+    pub elapsed: Duration,
 }
 
 impl WorkoutStep for Cooldown {
-    /// Get power level lasting for one second from span [high; low)
+    /// Get power level lasting for one second from span [high; low]
     fn advance(&mut self) -> Option<PowerDuration> {
-        if self.duration == 0 {
-            return None;
-        }
-
-        let power_level = self.power_low;
-
         // In cool down, low keeps high value, high keeps low....
-        let span = self.power_low - self.power_high;
-        let step = span / self.duration as f64;
-
-        self.duration -= 1;
-        self.power_low -= step;
-        Some(PowerDuration {
-            duration: Duration::from_secs(1),
-            power_level,
-        })
+        ramp_chunk(
+            &mut self.elapsed,
+            self.duration,
+            self.power_low,
+            self.power_high,
+        )
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct SteadyState {
-    pub duration: u64,
+    #[serde(with = "duration_secs")]
+    pub duration: Duration,
     pub power: f64,
+    /// Target cadence in RPM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cadence: Option<f64>,
 }
 
 impl WorkoutStep for SteadyState {
     fn advance(&mut self) -> Option<PowerDuration> {
-        if self.duration == 0 {
+        if self.duration.is_zero() {
             return None;
         }
 
-        let duration = Duration::from_secs(self.duration);
+        let duration = self.duration;
 
-        self.duration = 0;
+        self.duration = Duration::ZERO;
 
         Some(PowerDuration {
             duration,
@@ -226,10 +783,18 @@ impl WorkoutStep for SteadyState {
 #[serde(rename_all = "PascalCase")]
 pub struct IntervalsT {
     pub repeat: u64,
-    pub on_duration: u64,
-    pub off_duration: u64,
+    #[serde(with = "duration_secs")]
+    pub on_duration: Duration,
+    #[serde(with = "duration_secs")]
+    pub off_duration: Duration,
     pub on_power: f64,
     pub off_power: f64,
+    /// Target cadence of work intervals in RPM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cadence: Option<f64>,
+    /// Target cadence of rest intervals in RPM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cadence_resting: Option<f64>,
 
     #[serde(skip)]
     pub current_interval: usize,
@@ -239,58 +804,176 @@ impl IntervalsT {
     pub fn is_work_interval(&self) -> bool {
         self.current_interval % 2 == 0
     }
-}
 
-impl WorkoutStep for IntervalsT {
-    fn advance(&mut self) -> Option<PowerDuration> {
+    /// Drops the rest of the on+off pair being executed, next advance() starts the next pair.
+    /// Note current_interval points to the part to be executed next.
+    fn skip_pair(&mut self) {
         if self.repeat == 0 {
-            return None;
+            return;
         }
 
-        let step = if self.is_work_interval() {
-            Some(PowerDuration {
-                duration: Duration::from_secs(self.on_duration),
-                power_level: self.on_power,
-            })
-        } else {
+        if self.current_interval == 0 {
+            // Nothing executed yet, drop the first pair as a whole
             self.repeat -= 1;
-            Some(PowerDuration {
-                duration: Duration::from_secs(self.off_duration),
-                power_level: self.off_power,
-            })
-        };
-
-        self.current_interval += 1;
+            self.current_interval += 2;
+        } else if !self.is_work_interval() {
+            // Work part is executed, drop it together with the following rest.
+            // Repeat is decremented on rest part, which is not going to happen.
+            self.repeat -= 1;
+            self.current_interval += 1;
+        }
+        // Otherwise rest part is executed, pair is already accounted
+    }
+}
 
-        step
+impl WorkoutStep for IntervalsT {
+    /// Parts of zero duration are skipped, like work parts of the intervals with rests only
+    fn advance(&mut self) -> Option<PowerDuration> {
+        loop {
+            if self.repeat == 0 {
+                return None;
+            }
+
+            let step = if self.is_work_interval() {
+                PowerDuration {
+                    duration: self.on_duration,
+                    power_level: self.on_power,
+                }
+            } else {
+                self.repeat -= 1;
+                PowerDuration {
+                    duration: self.off_duration,
+                    power_level: self.off_power,
+                }
+            };
+
+            self.current_interval += 1;
+
+            if !step.duration.is_zero() {
+                return Some(step);
+            }
+        }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct FreeRide {
-    pub duration: u64,
+    #[serde(with = "duration_secs")]
+    pub duration: Duration,
     pub flat_road: f64,
 }
 
+impl FreeRide {
+    /// Grade to simulate during free ride, flat road unless FlatRoad is 0, then the route's grade
+    pub fn grade(&self, route_grade: f64) -> f64 {
+        if self.flat_road != 0.0 {
+            0.0
+        } else {
+            route_grade
+        }
+    }
+}
+
 impl WorkoutStep for FreeRide {
     fn advance(&mut self) -> Option<PowerDuration> {
-        if self.duration == 0 {
+        if self.duration.is_zero() {
+            return None;
+        }
+
+        let duration = self.duration;
+
+        self.duration = Duration::ZERO;
+
+        Some(PowerDuration {
+            duration,
+            // Power is not used, trainer is switched to simulation mode for the free ride
+            power_level: 0.0,
+        })
+    }
+}
+
+/// Not a part of Zwift format, holds given speed instead of power,
+/// executed only by machines supporting speed target
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct SteadySpeed {
+    #[serde(with = "duration_secs")]
+    pub duration: Duration,
+    /// In km/h
+    pub speed: f64,
+}
+
+impl WorkoutStep for SteadySpeed {
+    fn advance(&mut self) -> Option<PowerDuration> {
+        if self.duration.is_zero() {
             return None;
         }
 
-        let duration = Duration::from_secs(self.duration);
+        let duration = self.duration;
 
-        self.duration = 0;
+        self.duration = Duration::ZERO;
 
         Some(PowerDuration {
             duration,
-            // TODO: there should be something like ERG mode off, IDK if 0 is valid
+            // Power is not used, machine is given the speed target
             power_level: 0.0,
         })
     }
 }
 
+/// Takes next chunk of the ramp lasting one second, or less if that's the fractional remainder.
+/// Power is interpolated linearly over the whole duration, first chunk starts at `from`, last one lands on `to`.
+/// Interpolating against the original duration (instead of what remains) does not accumulate the error.
+fn ramp_chunk(
+    elapsed: &mut Duration,
+    duration: Duration,
+    from: f64,
+    to: f64,
+) -> Option<PowerDuration> {
+    if *elapsed >= duration {
+        return None;
+    }
+
+    let chunk = (duration - *elapsed).min(Duration::from_secs(1));
+
+    // Elapsed is always a whole number of seconds, only the last chunk may be shorter
+    let nr_chunks = duration.as_secs_f64().ceil();
+    let power_level = if nr_chunks > 1.0 {
+        from + (to - from) * elapsed.as_secs_f64() / (nr_chunks - 1.0)
+    } else {
+        from
+    };
+
+    *elapsed += chunk;
+
+    Some(PowerDuration {
+        duration: chunk,
+        power_level,
+    })
+}
+
+/// ZWO keeps durations as a number of seconds, some generated workouts use fractions
+mod duration_secs {
+    use super::*;
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        if duration.subsec_nanos() == 0 {
+            serializer.serialize_u64(duration.as_secs())
+        } else {
+            serializer.serialize_f64(duration.as_secs_f64())
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let secs = f64::deserialize(deserializer)?;
+
+        Duration::try_from_secs_f64(secs)
+            .map_err(|e| D::Error::custom(format!("Invalid duration {secs}: {e}")))
+    }
+}
+
 /// How much power should be set for how long
 #[derive(Debug, PartialEq, Clone)]
 pub struct PowerDuration {
@@ -302,13 +985,163 @@ pub struct PowerDuration {
 mod tests {
     use super::*;
 
+    #[tokio::test]
+    async fn unparseable_workouts_are_skipped() {
+        let dir = std::env::temp_dir().join(format!("velomania_library_{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+
+        let test_workout = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts/test.zwo");
+        tokio::fs::copy(&test_workout, dir.join("test.zwo"))
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("broken.zwo"), "<workout_file>")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("notes.txt"), "not a workout")
+            .await
+            .unwrap();
+
+        let workouts = list_workouts(&dir).await;
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+
+        assert_eq!(workouts.len(), 1);
+        assert_eq!(workouts[0].path, PathBuf::from("test.zwo"));
+        assert_eq!(workouts[0].duration, Duration::from_secs(46));
+    }
+
+    #[test]
+    fn sport_type_and_tags_are_parsed() {
+        let zwo = r#"
+            <workout_file>
+                <author>velomania</author>
+                <name>Tempo run</name>
+                <description></description>
+                <sportType>run</sportType>
+                <tags>
+                    <tag name="TEMPO"/>
+                    <tag name="RUN"/>
+                </tags>
+                <workout>
+                    <SteadyState Duration="600" Power="0.8"/>
+                </workout>
+            </workout_file>"#;
+
+        let workout = WorkoutFile::from_zwo_str(zwo).unwrap();
+        assert_eq!(workout.sport_type, "run");
+        assert_eq!(workout.tags, vec!["TEMPO", "RUN"]);
+
+        let info = workout.info();
+        assert!(info.check_sport_type(false).is_ok());
+        assert!(info.check_sport_type(true).is_err());
+
+        // Tags survive the round trip
+        let written = WorkoutFile::from_zwo_str(&workout.to_zwo_string().unwrap()).unwrap();
+        assert_eq!(written.tags, workout.tags);
+
+        // Tags are optional
+        let bike = WorkoutBuilder::new("Bike").steady(60, 0.5).build();
+        let bike = WorkoutFile::from_zwo_str(&bike.to_zwo_string().unwrap()).unwrap();
+        assert!(bike.tags.is_empty());
+        assert!(bike.info().check_sport_type(true).is_ok());
+    }
+
+    #[test]
+    fn unknown_elements_are_skipped() {
+        let zwo = r#"
+            <workout_file>
+                <author>velomania</author>
+                <name>Exotic</name>
+                <description></description>
+                <sportType>bike</sportType>
+                <workout>
+                    <Warmup Duration="60" PowerLow="0.4" PowerHigh="0.6">
+                        <textevent timeoffset="10" message="Easy"/>
+                    </Warmup>
+                    <MaxEffort Duration="20"/>
+                    <SolidState Duration="30" Power="0.9">
+                        <Cadence Low="90" High="100"/>
+                    </SolidState>
+                    <SteadyState Duration="120" Power="0.8"/>
+                </workout>
+            </workout_file>
+        "#;
+
+        let workout = WorkoutFile::from_zwo_str(zwo).unwrap();
+
+        assert_eq!(workout.workout.steps.len(), 2);
+        assert!(matches!(workout.workout.steps[0], WorkoutSteps::Warmup(_)));
+        assert!(matches!(
+            workout.workout.steps[1],
+            WorkoutSteps::SteadyState(_)
+        ));
+        assert_eq!(workout.total_workout_duration, Duration::from_secs(180));
+        assert_eq!(workout.workout.text_events.len(), 1);
+    }
+
+    #[test]
+    fn nested_text_events_are_offset_by_step_start() {
+        let zwo = r#"
+            <workout_file>
+                <author>velomania</author>
+                <name>Chatty</name>
+                <description></description>
+                <sportType>bike</sportType>
+                <workout>
+                    <Warmup Duration="60" PowerLow="0.4" PowerHigh="0.6">
+                        <textevent timeoffset="10" message="Easy"/>
+                    </Warmup>
+                    <SteadyState Duration="120" Power="0.8">
+                        <textevent timeoffset="0" message="Go"/>
+                        <textevent timeoffset="90" message="Half a minute left"/>
+                    </SteadyState>
+                    <IntervalsT Repeat="2" OnDuration="30" OffDuration="30" OnPower="1.2" OffPower="0.5">
+                        <textevent timeoffset="60" message="Second one" duration="5"/>
+                    </IntervalsT>
+                    <textevent timeoffset="100" message="Top level"/>
+                </workout>
+            </workout_file>
+        "#;
+
+        let workout = WorkoutFile::from_zwo_str(zwo).unwrap();
+
+        let timeline: Vec<_> = workout
+            .workout
+            .text_events
+            .iter()
+            .map(|text_event| (text_event.timeoffset.as_secs(), text_event.message.as_str()))
+            .collect();
+        assert_eq!(
+            timeline,
+            vec![
+                (10, "Easy"),
+                (60, "Go"),
+                (100, "Top level"),
+                (150, "Half a minute left"),
+                (240, "Second one"),
+            ]
+        );
+        assert_eq!(
+            workout.workout.text_events[4].duration,
+            Duration::from_secs(5)
+        );
+
+        // Events are on the workout timeline, not left in the steps
+        let expected = WorkoutBuilder::new("Chatty")
+            .warmup(60, 0.4, 0.6)
+            .steady(120, 0.8)
+            .intervals(2, 30, 30, 1.2, 0.5)
+            .build();
+        assert_eq!(workout.workout.steps, expected.workout.steps);
+    }
+
     #[test]
     fn warmup_works() {
         // Of course implementation suffers because of the rounding errors
         let mut w = Warmup {
-            duration: 4,
+            duration: Duration::from_secs(4),
             power_low: 0.0,
             power_high: 100.0,
+            elapsed: Duration::ZERO,
         };
 
         assert_eq!(
@@ -322,35 +1155,34 @@ mod tests {
             w.advance(),
             Some(PowerDuration {
                 duration: Duration::from_secs(1),
-                power_level: 25.0
+                power_level: 100.0 / 3.0
             })
         );
         assert_eq!(
             w.advance(),
             Some(PowerDuration {
                 duration: Duration::from_secs(1),
-                power_level: 50.0
+                power_level: 200.0 / 3.0
             })
         );
         assert_eq!(
             w.advance(),
             Some(PowerDuration {
                 duration: Duration::from_secs(1),
-                power_level: 75.0
+                power_level: 100.0
             })
         );
-        // note no power level 100, that's the result of quantization.
-        // in real workouts difference between last level end expected will not be that huge
+        // Last second lands on the target
         assert_eq!(w.advance(), None);
     }
 
     #[test]
     fn ramp_works() {
-        // Of course implementation suffers because of the rounding errors
         let mut w = Ramp {
-            duration: 4,
+            duration: Duration::from_secs(4),
             power_low: 0.0,
             power_high: 100.0,
+            elapsed: Duration::ZERO,
         };
 
         assert_eq!(
@@ -364,35 +1196,79 @@ mod tests {
             w.advance(),
             Some(PowerDuration {
                 duration: Duration::from_secs(1),
-                power_level: 25.0
+                power_level: 100.0 / 3.0
             })
         );
         assert_eq!(
             w.advance(),
             Some(PowerDuration {
                 duration: Duration::from_secs(1),
-                power_level: 50.0
+                power_level: 200.0 / 3.0
             })
         );
         assert_eq!(
             w.advance(),
             Some(PowerDuration {
                 duration: Duration::from_secs(1),
-                power_level: 75.0
+                power_level: 100.0
             })
         );
-        // note no power level 100, that's the result of quantization.
-        // in real workouts difference between last level end expected will not be that huge
+        // Last second lands on the target
         assert_eq!(w.advance(), None);
     }
 
+    #[test]
+    fn ramp_goes_either_way() {
+        let levels = |power_low, power_high| {
+            let mut w = Ramp {
+                duration: Duration::from_secs(10),
+                power_low,
+                power_high,
+                elapsed: Duration::ZERO,
+            };
+
+            std::iter::from_fn(|| w.advance())
+                .map(|pd| pd.power_level)
+                .collect::<Vec<_>>()
+        };
+
+        let ascending = levels(0.5, 1.0);
+        assert_eq!(ascending.len(), 10);
+        assert_eq!((ascending[0], ascending[9]), (0.5, 1.0));
+        assert!(ascending.windows(2).all(|pair| pair[1] > pair[0]));
+
+        let descending = levels(1.0, 0.5);
+        assert_eq!(descending.len(), 10);
+        assert_eq!((descending[0], descending[9]), (1.0, 0.5));
+        assert!(descending.windows(2).all(|pair| pair[1] < pair[0]));
+
+        // The same ramp parsed from the file
+        let workout = WorkoutFile::from_zwo_str(
+            r#"<workout_file>
+                <author>velomania</author>
+                <name>Down</name>
+                <description>Descending ramp</description>
+                <sportType>bike</sportType>
+                <workout>
+                    <Ramp Duration="10" PowerLow="1.0" PowerHigh="0.5"/>
+                </workout>
+            </workout_file>"#,
+        )
+        .unwrap();
+        let mut step = workout.workout.steps[0].clone();
+        let parsed: Vec<_> = std::iter::from_fn(|| step.advance())
+            .map(|pd| pd.power_level)
+            .collect();
+        assert_eq!(parsed, descending);
+    }
+
     #[test]
     fn cooldown_works() {
-        // Of course implementation suffers because of the rounding errors
         let mut w = Cooldown {
-            duration: 4,
+            duration: Duration::from_secs(4),
             power_low: 100.0,
             power_high: 0.0,
+            elapsed: Duration::ZERO,
         };
 
         assert_eq!(
@@ -406,34 +1282,58 @@ mod tests {
             w.advance(),
             Some(PowerDuration {
                 duration: Duration::from_secs(1),
-                power_level: 75.0
+                power_level: 100.0 - 100.0 / 3.0
             })
         );
         assert_eq!(
             w.advance(),
             Some(PowerDuration {
                 duration: Duration::from_secs(1),
-                power_level: 50.0
+                power_level: 100.0 - 200.0 / 3.0
             })
         );
         assert_eq!(
             w.advance(),
             Some(PowerDuration {
                 duration: Duration::from_secs(1),
-                power_level: 25.0
+                power_level: 0.0
             })
         );
-        // note no power level 0, that's the result of quantization.
-        // in real workouts difference between last level end expected will not be that huge
+        // Last second lands on the target
         assert_eq!(w.advance(), None);
     }
 
+    #[test]
+    fn long_ramp_lands_on_target() {
+        // 0.5 -> 1.0 FTP over 20 minutes, interpolated per second
+        let mut w = Warmup {
+            duration: Duration::from_secs(1200),
+            power_low: 0.5,
+            power_high: 1.0,
+            elapsed: Duration::ZERO,
+        };
+
+        let ftp_base = 250.0;
+        let mut levels = vec![];
+        while let Some(pd) = w.advance() {
+            assert_eq!(pd.duration, Duration::from_secs(1));
+            levels.push(pd.power_level);
+        }
+
+        assert_eq!(levels.len(), 1200);
+        assert_eq!(levels[0], 0.5);
+        assert!((levels[1199] - 1.0).abs() * ftp_base < 1.0);
+        // Halfway through the ramp, power is halfway through the span
+        assert!((levels[600] - 0.75).abs() * ftp_base < 1.0);
+    }
+
     #[test]
     fn steady_works() {
         // Of course implementation suffers because of the rounding errors
         let mut w = SteadyState {
-            duration: 4,
+            duration: Duration::from_secs(4),
             power: 1.23,
+            cadence: None,
         };
 
         assert_eq!(
@@ -450,7 +1350,7 @@ mod tests {
     fn free_ride_works() {
         // Of course implementation suffers because of the rounding errors
         let mut w = FreeRide {
-            duration: 4,
+            duration: Duration::from_secs(4),
             flat_road: 1.0,
         };
 
@@ -469,10 +1369,12 @@ mod tests {
         // Of course implementation suffers because of the rounding errors
         let mut w = IntervalsT {
             repeat: 3,
-            on_duration: 10,
-            off_duration: 20,
+            on_duration: Duration::from_secs(10),
+            off_duration: Duration::from_secs(20),
             on_power: 80.0,
             off_power: 150.0,
+            cadence: None,
+            cadence_resting: None,
             current_interval: 0,
         };
 
@@ -526,4 +1428,218 @@ mod tests {
 
         assert_eq!(w.advance(), None);
     }
+
+    fn intervals() -> WorkoutSteps {
+        WorkoutSteps::IntervalsT(IntervalsT {
+            repeat: 3,
+            on_duration: Duration::from_secs(10),
+            off_duration: Duration::from_secs(20),
+            on_power: 80.0,
+            off_power: 150.0,
+            cadence: None,
+            cadence_resting: None,
+            current_interval: 0,
+        })
+    }
+
+    fn remaining_parts(step: &mut WorkoutSteps) -> Vec<f64> {
+        std::iter::from_fn(|| step.advance())
+            .map(|pd| pd.power_level)
+            .collect()
+    }
+
+    #[test]
+    fn skip_during_work_interval_drops_the_pair() {
+        let mut step = intervals();
+
+        // First work interval
+        step.advance();
+        step.skip();
+
+        // Two pairs left
+        assert_eq!(remaining_parts(&mut step), vec![80.0, 150.0, 80.0, 150.0]);
+    }
+
+    #[test]
+    fn skip_during_rest_interval_drops_the_rest() {
+        let mut step = intervals();
+
+        // First work, then rest interval
+        step.advance();
+        step.advance();
+        step.skip();
+
+        // Two pairs left
+        assert_eq!(remaining_parts(&mut step), vec![80.0, 150.0, 80.0, 150.0]);
+    }
+
+    #[test]
+    fn skip_keeps_repetition_counting() {
+        let mut step = intervals();
+
+        // Skip second work interval, then the last rest
+        step.advance();
+        step.advance();
+        step.advance();
+        step.skip();
+
+        let WorkoutSteps::IntervalsT(interval) = &step else {
+            unreachable!()
+        };
+        assert_eq!(interval.repeat, 1);
+        // Third pair is next
+        assert_eq!(interval.current_interval, 4);
+        assert!(interval.is_work_interval());
+
+        step.advance();
+        step.advance();
+        step.skip();
+        assert_eq!(remaining_parts(&mut step), Vec::<f64>::new());
+    }
+
+    #[tokio::test]
+    async fn workouts_survive_zwo_round_trip() {
+        let workouts_root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("workouts");
+
+        let paths = WalkDir::new(workouts_root)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path());
+
+        for path in paths {
+            let workout = WorkoutFile::new(&path).await.unwrap();
+
+            let zwo = workout.to_zwo_string().unwrap();
+            let reparsed = WorkoutFile::from_zwo_str(&zwo)
+                .unwrap_or_else(|e| panic!("{}: {e:?}\n{zwo}", path.display()));
+
+            assert_eq!(workout, reparsed, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn workout_is_built_programmatically() {
+        let workout = WorkoutBuilder::new("Sweet spot")
+            .author("velomania")
+            .warmup(600, 0.4, 0.75)
+            .intervals(3, 300, 120, 0.9, 0.5)
+            .steady(60, 0.6)
+            .free_ride(120)
+            .cooldown(300, 0.7, 0.4)
+            .build();
+
+        let steps: Vec<_> = workout.workout.steps.iter().collect();
+        assert_eq!(steps.len(), 5);
+        assert!(matches!(steps[0], WorkoutSteps::Warmup(w) if w.power_high == 0.75));
+        assert!(matches!(steps[1], WorkoutSteps::IntervalsT(w) if w.repeat == 3));
+        assert!(matches!(steps[2], WorkoutSteps::SteadyState(w) if w.power == 0.6));
+        assert!(matches!(steps[3], WorkoutSteps::FreeRide(_)));
+        assert!(matches!(steps[4], WorkoutSteps::Cooldown(w) if w.power_low == 0.7));
+
+        // 600 + 3 * (300 + 120) + 60 + 120 + 300
+        assert_eq!(workout.total_workout_duration, Duration::from_secs(2340));
+
+        let reparsed = WorkoutFile::from_zwo_str(&workout.to_zwo_string().unwrap()).unwrap();
+        assert_eq!(reparsed, workout);
+    }
+
+    #[test]
+    fn special_characters_are_escaped() {
+        let mut workout = WorkoutFile::from_zwo_str(
+            r#"<workout_file><author>a</author><name>n</name><description>d</description>
+            <sportType>bike</sportType><workout><SteadyState Duration="60" Power="0.5"/></workout>
+            </workout_file>"#,
+        )
+        .unwrap();
+        workout.name = r#"Over & under <"hard">"#.to_string();
+
+        let reparsed = WorkoutFile::from_zwo_str(&workout.to_zwo_string().unwrap()).unwrap();
+
+        assert_eq!(reparsed.name, workout.name);
+        assert_eq!(reparsed.total_workout_duration, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn fractional_duration_is_parsed_and_executed() {
+        let workout: Workout = serde_xml_rs::from_str(
+            r#"<workout><Warmup Duration="150.5" PowerLow="0.5" PowerHigh="0.8"/></workout>"#,
+        )
+        .unwrap();
+
+        let mut step = workout.steps[0].clone();
+        assert_eq!(step.get_step_duration(), Duration::from_millis(150_500));
+
+        let mut chunks = vec![];
+        while let Some(pd) = step.advance() {
+            chunks.push(pd);
+        }
+
+        // 150 full seconds, and the remainder
+        assert_eq!(chunks.len(), 151);
+        assert_eq!(chunks[149].duration, Duration::from_secs(1));
+        assert_eq!(chunks[150].duration, Duration::from_millis(500));
+
+        // No drift, whole step is executed
+        let total: Duration = chunks.iter().map(|pd| pd.duration).sum();
+        assert_eq!(total, Duration::from_millis(150_500));
+
+        // Power grows evenly, the remainder lands on the target
+        let regular_step = chunks[1].power_level - chunks[0].power_level;
+        assert!((regular_step - 0.3 / 150.0).abs() < 1e-9);
+        assert!((chunks[150].power_level - 0.8).abs() < 1e-9);
+    }
+
+    #[test]
+    fn free_ride_grade_follows_the_route_unless_flat() {
+        let mut free_ride = FreeRide {
+            duration: Duration::from_secs(60),
+            flat_road: 1.0,
+        };
+        assert_eq!(free_ride.grade(4.5), 0.0);
+
+        free_ride.flat_road = 0.0;
+        assert_eq!(free_ride.grade(4.5), 4.5);
+        assert_eq!(free_ride.grade(-2.0), -2.0);
+    }
+
+    #[test]
+    fn invalid_durations_are_rejected() {
+        for duration in ["-1", "NaN", "inf", "1e30"] {
+            let zwo = format!(
+                r#"<workout><Warmup Duration="{duration}" PowerLow="0.5" PowerHigh="0.8"/></workout>"#
+            );
+            assert!(
+                serde_xml_rs::from_str::<Workout>(&zwo).is_err(),
+                "{}",
+                duration
+            );
+        }
+
+        let zwo = |repeat: u64| {
+            format!(
+                r#"
+                <workout_file>
+                    <author>velomania</author>
+                    <name>Endless</name>
+                    <description></description>
+                    <sportType>bike</sportType>
+                    <workout>
+                        <IntervalsT Repeat="{repeat}" OnDuration="1e10" OffDuration="1e10" OnPower="1.2" OffPower="0.5"/>
+                    </workout>
+                </workout_file>
+                "#
+            )
+        };
+
+        // Does not fit in u32, and does not fit in Duration once multiplied
+        assert!(WorkoutFile::from_zwo_str(&zwo(u64::from(u32::MAX) + 1)).is_err());
+        assert!(WorkoutFile::from_zwo_str(&zwo(u64::from(u32::MAX))).is_err());
+        assert_eq!(
+            WorkoutFile::from_zwo_str(&zwo(2))
+                .unwrap()
+                .total_workout_duration,
+            Duration::from_secs(40_000_000_000)
+        );
+    }
 }